@@ -0,0 +1,235 @@
+use self_cell::self_cell;
+use simd_json::{BorrowedValue, OwnedValue};
+use std::borrow::Cow;
+use std::fmt;
+use std::sync::OnceLock;
+
+use crate::bytecode;
+use crate::limits::{LimitExceeded, Limits, Meter, ParseLimits};
+use crate::optimizer::optimize;
+use crate::parser::Filter;
+use crate::printer;
+use crate::projection::{self, Projection};
+use crate::syntax::{self, SyntaxError};
+use crate::variables::{Declared, Values};
+use crate::vm::{Execution, Halt, RuntimeError};
+
+/// A JSON value, as taken and returned by [`Program::run`].
+///
+/// This is simd-json's owned value; the [`simd_json`] re-export has its constructors, accessors
+/// and the traits to serialize it.
+pub type Value = OwnedValue;
+
+/// Why a query did not compile, some input did not parse, or a run ended with an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The query is not valid jq.
+    Syntax(SyntaxError),
+    /// The query reads a variable that is not defined.
+    Compile(String),
+    /// The text given to [`parse`] is not valid JSON, or goes over its [`ParseLimits`].
+    Parse(String),
+    /// `halt_error` stopped the run: its exit code, and its input as a string or as JSON text.
+    Halt { code: i32, message: String },
+    /// The query failed on the input, such as by adding a number to a string.
+    Runtime(RuntimeError),
+    /// The run went over one of the [`Limits`] set with [`Program::with_limits`].
+    Limit(LimitExceeded),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Syntax(e) => e.fmt(f),
+            Error::Compile(message) | Error::Parse(message) => f.write_str(message),
+            Error::Halt { code, message } => write!(f, "halt_error with exit code {}: {}", code, message.trim_end()),
+            Error::Runtime(e) => e.fmt(f),
+            Error::Limit(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A compiled query.
+///
+/// Compiling is the expensive part, so a program is meant to be compiled once and run on many
+/// inputs; it can be shared between threads and run on all of them at once.
+pub struct Program {
+    program: bytecode::Program,
+    // the parts of an input the program can read, the only ones borrowed for a run
+    projection: Projection,
+    // the query, to quote the failing part of in runtime errors
+    query: String,
+    // the query as parsed, before optimization, for `to_jq`
+    parsed: Vec<Filter>,
+    // `$ARGS` and `$__prog_args`, the only variables a program compiled here can read
+    vars: Values,
+    limits: Limits,
+}
+
+/// Compile a jq query.
+///
+/// ```
+/// let program = rusty_jq::compile(".users | .[] | select(.id == 2) | .name")?;
+/// # Ok::<(), rusty_jq::Error>(())
+/// ```
+pub fn compile(query: &str) -> Result<Program, Error> {
+    let parsed = syntax::parse(query).map_err(Error::Syntax)?;
+    let filters = optimize(parsed.clone(), &Limits::default());
+    let program = bytecode::Program::compile(&filters);
+    let declared = Declared::default();
+    if let Some(name) = declared.undefined(&program) {
+        return Err(Error::Compile(format!("${} is not defined", name)));
+    }
+    let vars = declared.bind(Vec::new()).map_err(Error::Compile)?.values_for(&program);
+    Ok(Program { program, projection: projection::analyze(&filters), query: query.to_string(), parsed, vars, limits: Limits::default() })
+}
+
+/// Parse one JSON document into a [`Value`], held to the default [`ParseLimits`].
+///
+/// ```
+/// let input = rusty_jq::parse(r#"{"id": 1}"#)?;
+/// # Ok::<(), rusty_jq::Error>(())
+/// ```
+pub fn parse(text: &str) -> Result<Value, Error> {
+    parse_with_limits(text, &ParseLimits::default())
+}
+
+/// Parse one JSON document into a [`Value`], failing with an [`Error::Parse`] if it goes over
+/// one of `limits`.
+///
+/// ```
+/// use rusty_jq::{DuplicateKeys, Error, ParseLimits};
+///
+/// let limits = ParseLimits { max_depth: 2, duplicate_keys: DuplicateKeys::Error, ..ParseLimits::default() };
+/// assert!(rusty_jq::parse_with_limits(r#"{"a": [1]}"#, &limits).is_ok());
+/// assert!(matches!(rusty_jq::parse_with_limits(r#"{"a": [[1]]}"#, &limits), Err(Error::Parse(_))));
+/// assert!(matches!(rusty_jq::parse_with_limits(r#"{"a": 1, "a": 2}"#, &limits), Err(Error::Parse(_))));
+/// ```
+pub fn parse_with_limits(text: &str, limits: &ParseLimits) -> Result<Value, Error> {
+    let mut bytes = text.as_bytes().to_vec();
+    let value = projection::parse(&mut bytes, &Projection::all(), limits).map_err(|e| Error::Parse(e.to_string()))?;
+    Ok(Value::from(value))
+}
+
+impl Program {
+    /// Run the program on `input`, pulling its outputs one at a time.
+    ///
+    /// Nothing is evaluated before the first call to `next`, and dropping the iterator stops the
+    /// run. `halt` ends the outputs; `halt_error` ends them with an [`Error::Halt`], and a failing
+    /// filter with an [`Error::Runtime`]. The parts of `input` the query reads are borrowed anew on
+    /// every run; [`run_borrowed`](Program::run_borrowed) reads an input parsed once in place.
+    ///
+    /// ```
+    /// use rusty_jq::simd_json::json;
+    ///
+    /// let program = rusty_jq::compile(".[] | .name")?;
+    /// let input = json!([{"name": "John"}, {"name": "Bob"}]);
+    /// let names = program.run(&input).collect::<Result<Vec<_>, _>>()?;
+    /// assert_eq!(names, vec![json!("John"), json!("Bob")]);
+    /// # Ok::<(), rusty_jq::Error>(())
+    /// ```
+    pub fn run<'a>(&'a self, input: &'a Value) -> Run<'a> {
+        // borrowed rather than handed over, so runtime errors can locate the values they blame, and
+        // only as far as the query can read
+        self.start(Cow::Owned(projection::borrow(input, &self.projection)))
+    }
+
+    /// Run the program on a value parsed with [`simd_json::to_borrowed_value`], which is read in
+    /// place.
+    ///
+    /// [`run`](Program::run) borrows the parts of its input the query reads again on every run;
+    /// this borrows the value as it is, so one input can be parsed once and run many times.
+    ///
+    /// ```
+    /// use rusty_jq::simd_json::{self, json};
+    ///
+    /// let mut text = br#"[{"name": "John"}, {"name": "Bob"}]"#.to_vec();
+    /// let input = simd_json::to_borrowed_value(&mut text).unwrap();
+    /// let names = rusty_jq::compile(".[] | .name")?.run_borrowed(&input).collect::<Result<Vec<_>, _>>()?;
+    /// assert_eq!(names, vec![json!("John"), json!("Bob")]);
+    /// # Ok::<(), rusty_jq::Error>(())
+    /// ```
+    pub fn run_borrowed<'a>(&'a self, input: &'a BorrowedValue<'a>) -> Run<'a> {
+        self.start(Cow::Borrowed(input))
+    }
+
+    fn start<'a>(&'a self, root: Cow<'a, BorrowedValue<'a>>) -> Run<'a> {
+        let state = State { root, meter: Meter::new(self.limits), halt: OnceLock::new() };
+        let run = Running::new(state, |state| {
+            self.program.run(Cow::Borrowed(&*state.root)).with_vars(&self.vars).with_halt(&state.halt).with_meter(&state.meter)
+        });
+        Run { run: Some(run), query: &self.query }
+    }
+
+    /// Enforce `limits` on every run of the program, for queries that cannot be trusted.
+    ///
+    /// A run that goes over one of them ends with an [`Error::Limit`].
+    ///
+    /// ```
+    /// use rusty_jq::{simd_json::json, Error, Limits};
+    ///
+    /// let program = rusty_jq::compile(".[]")?.with_limits(Limits { max_outputs: Some(2), ..Limits::default() });
+    /// let outputs: Vec<_> = program.run(&json!([1, 2, 3])).collect();
+    /// assert!(matches!(outputs[..], [Ok(_), Ok(_), Err(Error::Limit(_))]));
+    /// # Ok::<(), rusty_jq::Error>(())
+    /// ```
+    pub fn with_limits(self, limits: Limits) -> Program {
+        Program { limits, ..self }
+    }
+
+    /// The query in a canonical form, which compiles into the same program.
+    ///
+    /// Whitespace is normalized, filters in a pipe are joined with ` | ` and calls such as
+    /// `INDEX(key)` are written out in full, so two spellings of one query print the same.
+    ///
+    /// ```
+    /// let program = rusty_jq::compile(".users|.[]|select(.id==2)|{name:.name}")?;
+    /// assert_eq!(program.to_jq(), ".users | .[] | select(.id == 2) | {name: .name}");
+    /// # Ok::<(), rusty_jq::Error>(())
+    /// ```
+    pub fn to_jq(&self) -> String {
+        // the program is compiled from the optimized query, so print the query as parsed instead
+        printer::to_jq(&self.parsed)
+    }
+}
+
+// what a run borrows besides the program: its input, the meter and the cell that `halt` and
+// runtime errors end it through
+struct State<'a> {
+    root: Cow<'a, BorrowedValue<'a>>,
+    meter: Meter,
+    halt: OnceLock<Halt>,
+}
+
+self_cell!(
+    struct Running<'a> {
+        owner: State<'a>,
+
+        #[not_covariant]
+        dependent: Execution,
+    }
+);
+
+/// The outputs of one [`Program::run`].
+pub struct Run<'a> {
+    // None once the run is over
+    run: Option<Running<'a>>,
+    query: &'a str,
+}
+
+impl Iterator for Run<'_> {
+    type Item = Result<Value, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.run.as_mut()?.with_dependent_mut(|_, run| run.next().map(|val| Value::from(val.into_owned())));
+        if let Some(val) = next { return Some(Ok(val)); }
+        let state = self.run.take()?.into_owner();
+        match state.halt.into_inner()? {
+            Halt::Exit { code, message } => Some(Err(Error::Halt { code, message: message? })),
+            Halt::Error(e) => Some(Err(Error::Runtime(e.in_query(self.query)))),
+            Halt::Limit(e) => Some(Err(Error::Limit(e))),
+        }
+    }
+}
@@ -0,0 +1,50 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use rayon_core::{ThreadPool, ThreadPoolBuilder};
+
+// the worker threads every batch shares, one per core, started by the first batch that needs them
+static POOL: OnceLock<ThreadPool> = OnceLock::new();
+
+// workers get a main thread's stack, as queries walk values as deep as they nest
+const WORKER_STACK: usize = 8 << 20;
+
+fn pool() -> &'static ThreadPool {
+    POOL.get_or_init(|| {
+        ThreadPoolBuilder::new()
+            .num_threads(std::thread::available_parallelism().map_or(1, |n| n.get()))
+            .stack_size(WORKER_STACK)
+            .thread_name(|i| format!("rusty-jq-batch-{}", i))
+            .build()
+            .expect("batch workers start")
+    })
+}
+
+// map `f` over `items` on up to `threads` of the shared workers, returning results in input order
+// workers claim items one at a time, so a few large documents do not hold up the rest of the batch
+pub fn parallel_map<'t, T: Sync, R: Send>(items: &'t [T], threads: usize, f: impl Fn(&'t T) -> R + Sync) -> Vec<R> {
+    let threads = threads.clamp(1, items.len().max(1));
+    if threads == 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let claimed = Mutex::new(Vec::with_capacity(items.len()));
+    // the scope returns once every worker is done, and passes on a panic in any of them
+    pool().scope(|scope| {
+        for _ in 0..threads {
+            scope.spawn(|_| {
+                let mut done = Vec::new();
+                loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= items.len() { break; }
+                    done.push((i, f(&items[i])));
+                }
+                claimed.lock().unwrap().append(&mut done);
+            });
+        }
+    });
+    let mut results: Vec<Option<R>> = (0..items.len()).map(|_| None).collect();
+    for (i, r) in claimed.into_inner().unwrap() {
+        results[i] = Some(r);
+    }
+    results.into_iter().map(|r| r.expect("every item is claimed once")).collect()
+}
@@ -0,0 +1,722 @@
+use simd_json::BorrowedValue;
+
+use crate::engine::{compare_values, is_truthy, literal_to_value, lookup_path};
+use crate::parser::{ArithOp, AssignOp, Builtin0, Builtin1, CompareOp, Condition, Expr, Filter, Literal, PathStep, RustyFilter, Span};
+
+// Flat instruction set for the backtracking VM, modelled on jq's interpreter.
+// Every instruction works on the value stack; the value on top is the current input `.`.
+// Operands that would bloat the enum (strings, literals) live in side tables on `Program`.
+#[derive(Debug, Clone, Copy)]
+pub enum Op {
+    // stack shuffling
+    Dup, // [.., x] -> [.., x, x]
+    Over, // [.., x, y] -> [.., x, y, x]
+    Swap, // [.., x, y] -> [.., y, x]
+    Pop, // [.., x] -> [..]
+    LoadConst(u32), // replace the top value with a constant
+
+    // path primitives, replace the top value with their output(s)
+    Field(u32),
+    Index(i32),
+    Path(u32),
+    Slice(Option<i64>, Option<i64>),
+    Each,
+    EachWhere(u32), // `.[] | select(...)` with a native predicate
+    Recurse,
+    Builtin0(Builtin0),
+    Builtin1(Builtin1, u32),
+
+    // binary operators, [.., rhs, lhs] -> [.., result]
+    Arith(ArithOp),
+    Compare(CompareOp),
+    ToBool, // replace the top value with its truthiness
+    Negate,
+
+    // object construction
+    ObjNew, // push an empty object
+    ObjInsert(u32), // [.., obj, v] -> [.., obj + {key: v}]
+
+    // control flow
+    Fork(u32), // continue, resuming at the target on backtrack
+    Jump(u32),
+    JumpIfFalse(u32), // pops the top value
+    Guard, // pops the top value, backtracks if it is falsy
+    Backtrack,
+
+    // errors: `TryBegin` catches those raised until the matching `TryEnd`, resuming at the handler
+    // with the message as the input; backtracking into the body catches them again
+    TryBegin(u32),
+    TryEnd,
+
+    // flags in slots, for `//` to know whether its left side had an output
+    ClearFlag(u32),
+    SetFlag(u32),
+    UnlessFlag(u32), // backtracks if the flag is set
+
+    // cuts: `Label` records the fork height, `Cut` discards every fork above it
+    Label(u32),
+    Cut(u32),
+    LimitInit(u32, u32), // set a countdown slot, backtracking straight away on zero
+    LimitCheck(u32, u32), // count one output down, cutting to the label when exhausted
+
+    // input stream, replace the top value with the next document or every remaining one
+    Input,
+    Inputs,
+    // replace the top value with the current document's file name or line number
+    InputFilename,
+    InputLineNumber,
+
+    // end the run, `halt_error` with the top value as its message
+    Halt,
+    HaltError(i32),
+
+    // replace the top value with the value bound to a variable, indexed as in `Program::vars`
+    LoadVar(u32),
+    // variables bound in the query, such as by `as`, and the state of `reduce`, `foreach` and `[...]`
+    StoreLocal(u32), // pops the top value into the local
+    LoadLocal(u32), // replace the top value with the local's
+    TakeLocal(u32), // the same, leaving null in the local
+    Append(u32), // pops the top value onto the end of the local, an array
+
+    // [.., input, value] -> [.., input with the paths of `Program::assignments` set]; `|=` takes no value
+    Assign(u32),
+
+    // stream events, running a sub-program kept aside in `Program::subprograms`
+    FromStream(u32), // replace the top value with the values rebuilt from the sub-program's events on it
+    TruncateStream(u32), // pop the depth, pushing the sub-program's events on null with that many steps cut off
+    Collect(u32), // replace the top value with an array of the sub-program's outputs on it
+
+    // lookup tables, running sub-programs on the top value
+    IndexBy(u32, u32), // an object of the rows of the first by the `tostring` of each key the second gives for them
+    In(u32, u32), // whether any output of the first equals any output of the second
+    Join(u32, u32, u32, Option<u32>), // `[row, $idx[key]]` for each index, row of the stream and key, then the join
+
+    Output,
+}
+
+// compiled query: bytecode plus the tables its operands index into
+#[derive(Debug)]
+pub struct Program {
+    pub code: Vec<Op>,
+    // the part of the query each instruction was compiled from, for runtime errors
+    pub spans: Vec<Span>,
+    pub consts: Vec<BorrowedValue<'static>>,
+    pub keys: Vec<String>,
+    pub args: Vec<Literal>,
+    pub paths: Vec<Vec<PathStep>>,
+    pub predicates: Vec<Predicate>,
+    pub slots: usize,
+    // queries whose outputs are consumed as a whole stream rather than one at a time
+    pub subprograms: Vec<Program>,
+    // the variables the program and its subprograms read; a run binds a value to each, in this order
+    pub vars: Vec<String>,
+    pub assignments: Vec<Assignment>,
+}
+
+impl Program {
+    pub fn compile(filters: &[Filter]) -> Program {
+        let mut names = Names::default();
+        let mut program = Compiler::build(filters, &mut names, Vec::new());
+        program.vars = names.vars;
+        program
+    }
+}
+
+// `paths op value`, the paths found on the input as jq's `path(f)` does
+#[derive(Debug)]
+pub struct Assignment {
+    pub paths: PathExpr,
+    pub op: AssignOp,
+    // the sub-program `|=` runs on the value at each path
+    pub update: Option<u32>,
+}
+
+// the paths a filter on the left of an assignment refers to
+#[derive(Debug)]
+pub enum PathExpr {
+    Step(PathStep),
+    Each,
+    Recurse,
+    // keep the path when the sub-program, a `select`, has an output on the value there
+    Select(u32),
+    Pipe(Vec<PathExpr>),
+    Comma(Vec<PathExpr>),
+    Limit(usize, Box<PathExpr>),
+    // each condition is a sub-program run on the value, its branch taken for each truthy output
+    If(Vec<(u32, PathExpr)>, Box<PathExpr>),
+    Alternative(Box<PathExpr>, Box<PathExpr>),
+    // a filter that is no path, like `1` or `length`: its first output is the error
+    Invalid(u32),
+}
+
+// select condition over plain paths and literals, evaluated natively for each element of a fused
+// `.[] | select(...)` so non-matching elements never reach the VM
+#[derive(Debug)]
+pub enum Predicate {
+    Compare(Vec<PathStep>, CompareOp, BorrowedValue<'static>),
+    Truthy(Vec<PathStep>),
+    And(Box<Predicate>, Box<Predicate>),
+    Or(Box<Predicate>, Box<Predicate>),
+    Not(Box<Predicate>),
+}
+
+impl Predicate {
+    // same semantics as the compiled condition: a missing path is false
+    pub fn eval(&self, value: &BorrowedValue) -> bool {
+        match self {
+            Predicate::Compare(path, op, rhs) => lookup_path(value, path).is_some_and(|lhs| compare_values(lhs, op, rhs)),
+            Predicate::Truthy(path) => lookup_path(value, path).is_some_and(is_truthy),
+            Predicate::And(l, r) => l.eval(value) && r.eval(value),
+            Predicate::Or(l, r) => l.eval(value) || r.eval(value),
+            Predicate::Not(inner) => !inner.eval(value),
+        }
+    }
+
+    fn from_condition(condition: &Condition) -> Option<Predicate> {
+        Some(match condition {
+            Condition::Comparison(path, op, Expr::Literal(lit)) => Predicate::Compare(path_steps(path)?, *op, literal_to_value(lit)),
+            Condition::Comparison(_, _, Expr::Path(_)) => return None,
+            Condition::BoolPath(path) => Predicate::Truthy(path_steps(path)?),
+            Condition::And(l, r) => Predicate::And(Box::new(Predicate::from_condition(l)?), Box::new(Predicate::from_condition(r)?)),
+            Condition::Or(l, r) => Predicate::Or(Box::new(Predicate::from_condition(l)?), Box::new(Predicate::from_condition(r)?)),
+            Condition::Not(inner) => Predicate::Not(Box::new(Predicate::from_condition(inner)?)),
+        })
+    }
+}
+
+// the steps of a pipeline made only of field and index lookups
+fn path_steps(filters: &[Filter]) -> Option<Vec<PathStep>> {
+    let mut steps = Vec::new();
+    for filter in filters {
+        match &filter.kind {
+            RustyFilter::Identity => {}
+            RustyFilter::Field(key) => steps.push(PathStep::Field(key.clone())),
+            RustyFilter::Index(i) => steps.push(PathStep::Index(*i)),
+            RustyFilter::Path(p) => steps.extend(p.iter().cloned()),
+            _ => return None,
+        }
+    }
+    Some(steps)
+}
+
+// names shared with subprograms, so a variable has the same index everywhere
+#[derive(Default)]
+struct Names {
+    vars: Vec<String>,
+    // how many locals have been handed out
+    locals: u32,
+}
+
+struct Compiler<'v> {
+    program: Program,
+    // the span of the filter being compiled
+    span: Span,
+    names: &'v mut Names,
+    // the locals bound where the filter being compiled is, innermost last
+    scope: Vec<(String, u32)>,
+}
+
+impl<'v> Compiler<'v> {
+    fn build(filters: &[Filter], names: &'v mut Names, scope: Vec<(String, u32)>) -> Program {
+        let mut c = Compiler {
+            program: Program {
+                code: Vec::new(),
+                spans: Vec::new(),
+                consts: Vec::new(),
+                keys: Vec::new(),
+                args: Vec::new(),
+                paths: Vec::new(),
+                predicates: Vec::new(),
+                slots: 0,
+                subprograms: Vec::new(),
+                vars: Vec::new(),
+                assignments: Vec::new(),
+            },
+            span: Span::default(),
+            names,
+            scope,
+        };
+        c.pipeline(filters);
+        c.emit(Op::Output);
+        c.program
+    }
+
+    fn emit(&mut self, op: Op) -> usize {
+        self.program.code.push(op);
+        self.program.spans.push(self.span);
+        self.program.code.len() - 1
+    }
+
+    fn here(&self) -> u32 {
+        self.program.code.len() as u32
+    }
+
+    // point a previously emitted jump at the current position
+    fn patch(&mut self, at: usize) {
+        let target = self.here();
+        match &mut self.program.code[at] {
+            Op::Fork(t) | Op::Jump(t) | Op::JumpIfFalse(t) | Op::TryBegin(t) => *t = target,
+            op => unreachable!("cannot patch {:?}", op),
+        }
+    }
+
+    fn slot(&mut self) -> u32 {
+        self.program.slots += 1;
+        (self.program.slots - 1) as u32
+    }
+
+    fn local(&mut self) -> u32 {
+        self.names.locals += 1;
+        self.names.locals - 1
+    }
+
+    // compile `body` with `name` bound to the local `l`
+    fn scoped(&mut self, name: &str, l: u32, body: impl FnOnce(&mut Self)) {
+        self.scope.push((name.to_string(), l));
+        body(self);
+        self.scope.pop();
+    }
+
+    fn subprogram(&mut self, filters: &[Filter]) -> u32 {
+        let program = Compiler::build(filters, self.names, self.scope.clone());
+        self.program.subprograms.push(program);
+        (self.program.subprograms.len() - 1) as u32
+    }
+
+    fn constant(&mut self, lit: &Literal) -> u32 {
+        self.program.consts.push(literal_to_value(lit));
+        (self.program.consts.len() - 1) as u32
+    }
+
+    fn key(&mut self, key: &str) -> u32 {
+        match self.program.keys.iter().position(|k| k == key) {
+            Some(i) => i as u32,
+            None => {
+                self.program.keys.push(key.to_string());
+                (self.program.keys.len() - 1) as u32
+            }
+        }
+    }
+
+    fn var(&mut self, name: &str) -> u32 {
+        match self.names.vars.iter().position(|v| v == name) {
+            Some(i) => i as u32,
+            None => {
+                self.names.vars.push(name.to_string());
+                (self.names.vars.len() - 1) as u32
+            }
+        }
+    }
+
+    fn arg(&mut self, lit: &Literal) -> u32 {
+        self.program.args.push(lit.clone());
+        (self.program.args.len() - 1) as u32
+    }
+
+    fn pipeline(&mut self, filters: &[Filter]) {
+        for filter in filters {
+            self.filter(filter);
+        }
+    }
+
+    fn filter(&mut self, filter: &Filter) {
+        let outer = std::mem::replace(&mut self.span, filter.span);
+        match &filter.kind {
+            RustyFilter::Identity => {}
+            RustyFilter::Field(key) => {
+                let k = self.key(key);
+                self.emit(Op::Field(k));
+            }
+            RustyFilter::Index(i) => { self.emit(Op::Index(*i)); }
+            RustyFilter::Path(steps) => {
+                self.program.paths.push(steps.clone());
+                self.emit(Op::Path((self.program.paths.len() - 1) as u32));
+            }
+            RustyFilter::Slice(start, end) => { self.emit(Op::Slice(*start, *end)); }
+            RustyFilter::Iterator => { self.emit(Op::Each); }
+            RustyFilter::RecurseDescent | RustyFilter::Builtin0(Builtin0::Recurse) => { self.emit(Op::Recurse); }
+            RustyFilter::Builtin0(b) => { self.emit(Op::Builtin0(*b)); }
+            RustyFilter::Builtin1(b, lit) => {
+                let a = self.arg(lit);
+                self.emit(Op::Builtin1(*b, a));
+            }
+            RustyFilter::LiteralValue(lit) => {
+                let k = self.constant(lit);
+                self.emit(Op::LoadConst(k));
+            }
+            RustyFilter::Comma(branches) => {
+                // every branch but the last leaves a fork to resume the next one from
+                let mut exits = Vec::new();
+                for (i, branch) in branches.iter().enumerate() {
+                    if i + 1 < branches.len() {
+                        let fork = self.emit(Op::Fork(0));
+                        self.pipeline(branch);
+                        exits.push(self.emit(Op::Jump(0)));
+                        self.patch(fork);
+                    } else {
+                        self.pipeline(branch);
+                    }
+                }
+                for exit in exits { self.patch(exit); }
+            }
+            RustyFilter::Arithmetic(left, op, right) => {
+                // rhs first so that lhs outputs vary fastest, matching jq
+                self.emit(Op::Dup);
+                self.pipeline(right);
+                self.emit(Op::Swap);
+                self.pipeline(left);
+                self.emit(Op::Arith(*op));
+            }
+            RustyFilter::Object(pairs) => {
+                self.emit(Op::ObjNew);
+                for (key, value) in pairs {
+                    self.emit(Op::Over);
+                    self.pipeline(value);
+                    let k = self.key(key);
+                    self.emit(Op::ObjInsert(k));
+                }
+                self.emit(Op::Swap);
+                self.emit(Op::Pop);
+            }
+            RustyFilter::Select(condition) => {
+                self.emit(Op::Dup);
+                self.condition(condition);
+                self.emit(Op::Guard);
+            }
+            RustyFilter::IterSelect(condition) => match Predicate::from_condition(condition) {
+                Some(predicate) => {
+                    self.program.predicates.push(predicate);
+                    self.emit(Op::EachWhere((self.program.predicates.len() - 1) as u32));
+                }
+                None => {
+                    self.emit(Op::Each);
+                    self.filter(&Filter::new(RustyFilter::Select(condition.clone()), filter.span));
+                }
+            },
+            RustyFilter::Limit(n, sub_query) => {
+                let label = self.slot();
+                let count = self.slot();
+                self.emit(Op::Label(label));
+                self.emit(Op::LimitInit(count, *n as u32));
+                self.pipeline(sub_query);
+                self.emit(Op::LimitCheck(count, label));
+            }
+            RustyFilter::FirstOf(sub_query) => {
+                let label = self.slot();
+                self.emit(Op::Label(label));
+                self.pipeline(sub_query);
+                self.emit(Op::Cut(label));
+            }
+            RustyFilter::IsEmpty(sub_query) => {
+                let label = self.slot();
+                let (f, t) = (self.constant(&Literal::Bool(false)), self.constant(&Literal::Bool(true)));
+                self.emit(Op::Label(label));
+                let fork = self.emit(Op::Fork(0));
+                self.pipeline(sub_query);
+                self.emit(Op::Cut(label));
+                self.emit(Op::LoadConst(f));
+                let exit = self.emit(Op::Jump(0));
+                self.patch(fork);
+                self.emit(Op::LoadConst(t));
+                self.patch(exit);
+            }
+            RustyFilter::FromStream(sub_query) => {
+                let p = self.subprogram(sub_query);
+                self.emit(Op::FromStream(p));
+            }
+            RustyFilter::TruncateStream(sub_query) => {
+                let p = self.subprogram(sub_query);
+                self.emit(Op::TruncateStream(p));
+            }
+            RustyFilter::IndexBy(stream, key) => {
+                let (stream, key) = (self.subprogram(stream), self.subprogram(key));
+                self.emit(Op::IndexBy(stream, key));
+            }
+            RustyFilter::In(source, values) => {
+                let (source, values) = (self.subprogram(source), self.subprogram(values));
+                self.emit(Op::In(source, values));
+            }
+            RustyFilter::Join { index, stream: Some(stream), key, join } => {
+                let (index, stream, key) = (self.subprogram(index), self.subprogram(stream), self.subprogram(key));
+                let join = join.as_ref().map(|join| self.subprogram(join));
+                self.emit(Op::Join(index, stream, key, join));
+            }
+            // `JOIN($idx; idx_expr)` collects the pairs for the elements of `.`
+            RustyFilter::Join { index, stream: None, key, join } => {
+                let each = Filter::new(RustyFilter::Iterator, filter.span);
+                let pairs = RustyFilter::Join { index: index.clone(), stream: Some(vec![each]), key: key.clone(), join: join.clone() };
+                let p = self.subprogram(&[Filter::new(pairs, filter.span)]);
+                self.emit(Op::Collect(p));
+            }
+            RustyFilter::Variable(name) => match self.scope.iter().rev().find(|(bound, _)| bound == name) {
+                Some(&(_, l)) => { self.emit(Op::LoadLocal(l)); }
+                None => {
+                    let v = self.var(name);
+                    self.emit(Op::LoadVar(v));
+                }
+            },
+            RustyFilter::Compare(left, op, right) => {
+                self.emit(Op::Dup);
+                self.pipeline(right);
+                self.emit(Op::Swap);
+                self.pipeline(left);
+                self.emit(Op::Compare(*op));
+            }
+            RustyFilter::And(left, right) => {
+                let f = self.constant(&Literal::Bool(false));
+                self.emit(Op::Dup);
+                self.pipeline(left);
+                let short = self.emit(Op::JumpIfFalse(0));
+                self.pipeline(right);
+                self.emit(Op::ToBool);
+                let exit = self.emit(Op::Jump(0));
+                self.patch(short);
+                self.emit(Op::LoadConst(f));
+                self.patch(exit);
+            }
+            RustyFilter::Or(left, right) => {
+                let t = self.constant(&Literal::Bool(true));
+                self.emit(Op::Dup);
+                self.pipeline(left);
+                let next = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::LoadConst(t));
+                let exit = self.emit(Op::Jump(0));
+                self.patch(next);
+                self.pipeline(right);
+                self.emit(Op::ToBool);
+                self.patch(exit);
+            }
+            RustyFilter::Negate(operand) => {
+                self.pipeline(operand);
+                self.emit(Op::Negate);
+            }
+            RustyFilter::Alternative(left, right) => {
+                // the left side's truthy outputs, errors ending it quietly; the flag records
+                // whether there was one, so the right side runs only when there was none
+                let flag = self.slot();
+                self.emit(Op::ClearFlag(flag));
+                let fork = self.emit(Op::Fork(0));
+                let catch = self.emit(Op::TryBegin(0));
+                self.pipeline(left);
+                self.emit(Op::TryEnd);
+                self.emit(Op::Dup);
+                self.emit(Op::Guard);
+                self.emit(Op::SetFlag(flag));
+                let exit = self.emit(Op::Jump(0));
+                self.patch(catch);
+                self.emit(Op::Backtrack);
+                self.patch(fork);
+                self.emit(Op::UnlessFlag(flag));
+                self.pipeline(right);
+                self.patch(exit);
+            }
+            RustyFilter::Assign(paths, op, value) => {
+                let paths = self.path_expr(paths);
+                let update = match op {
+                    AssignOp::Update => Some(self.subprogram(value)),
+                    _ => {
+                        self.emit(Op::Dup);
+                        self.pipeline(value);
+                        None
+                    }
+                };
+                self.program.assignments.push(Assignment { paths, op: *op, update });
+                self.emit(Op::Assign((self.program.assignments.len() - 1) as u32));
+            }
+            RustyFilter::Array(items) => {
+                // the input waits in a local, so the fork point keeps no hold on it and the items
+                // can take it apart, or append it, without copying it
+                let (input, array) = (self.local(), self.local());
+                let empty = self.constant(&Literal::Array(Vec::new()));
+                self.emit(Op::Dup);
+                self.emit(Op::StoreLocal(input));
+                self.emit(Op::LoadConst(empty));
+                self.emit(Op::Dup);
+                self.emit(Op::StoreLocal(array));
+                let fork = self.emit(Op::Fork(0));
+                self.emit(Op::TakeLocal(input));
+                self.pipeline(items);
+                self.emit(Op::Append(array));
+                self.emit(Op::Backtrack);
+                self.patch(fork);
+                self.emit(Op::TakeLocal(array));
+            }
+            RustyFilter::If(branches, otherwise) => {
+                let mut exits = Vec::new();
+                for (cond, then) in branches {
+                    self.emit(Op::Dup);
+                    self.pipeline(cond);
+                    let next = self.emit(Op::JumpIfFalse(0));
+                    self.pipeline(then);
+                    exits.push(self.emit(Op::Jump(0)));
+                    self.patch(next);
+                }
+                if let Some(otherwise) = otherwise { self.pipeline(otherwise); }
+                for exit in exits { self.patch(exit); }
+            }
+            RustyFilter::Try(body, handler) => {
+                let catch = self.emit(Op::TryBegin(0));
+                self.pipeline(body);
+                self.emit(Op::TryEnd);
+                let exit = self.emit(Op::Jump(0));
+                self.patch(catch);
+                match handler {
+                    Some(handler) => self.pipeline(handler),
+                    None => { self.emit(Op::Backtrack); }
+                }
+                self.patch(exit);
+            }
+            RustyFilter::Bind(source, name, body) => {
+                let var = self.local();
+                self.emit(Op::Dup);
+                self.pipeline(source);
+                self.emit(Op::StoreLocal(var));
+                self.scoped(name, var, |c| c.pipeline(body));
+            }
+            RustyFilter::Reduce { source, name, init, update } => {
+                // every output of the source runs the update on the state, which is the output
+                // once the source is exhausted; the last output of the update is the new state
+                let (state, var) = (self.local(), self.local());
+                self.emit(Op::Dup);
+                self.pipeline(init);
+                self.emit(Op::StoreLocal(state));
+                let fork = self.emit(Op::Fork(0));
+                self.emit(Op::Dup);
+                self.pipeline(source);
+                self.emit(Op::StoreLocal(var));
+                self.emit(Op::Dup);
+                self.emit(Op::TakeLocal(state));
+                self.scoped(name, var, |c| c.pipeline(update));
+                self.emit(Op::StoreLocal(state));
+                self.emit(Op::Backtrack);
+                self.patch(fork);
+                self.emit(Op::TakeLocal(state));
+            }
+            RustyFilter::Foreach { source, name, init, update, extract } => {
+                let (state, var) = (self.local(), self.local());
+                self.emit(Op::Dup);
+                self.pipeline(init);
+                self.emit(Op::StoreLocal(state));
+                self.pipeline(source);
+                self.emit(Op::Dup);
+                self.emit(Op::StoreLocal(var));
+                // the update owns the state, which is null if it has no output, as in jq 1.6
+                self.emit(Op::TakeLocal(state));
+                self.scoped(name, var, |c| {
+                    c.pipeline(update);
+                    c.emit(Op::Dup);
+                    c.emit(Op::StoreLocal(state));
+                    if let Some(extract) = extract { c.pipeline(extract); }
+                });
+            }
+            RustyFilter::Halt => { self.emit(Op::Halt); }
+            RustyFilter::HaltError(code) => { self.emit(Op::HaltError(*code)); }
+            RustyFilter::Input => { self.emit(Op::Input); }
+            RustyFilter::Inputs => { self.emit(Op::Inputs); }
+            RustyFilter::InputFilename => { self.emit(Op::InputFilename); }
+            RustyFilter::InputLineNumber => { self.emit(Op::InputLineNumber); }
+        }
+        self.span = outer;
+    }
+
+    // the paths a pipeline on the left of an assignment refers to
+    fn path_expr(&mut self, filters: &[Filter]) -> PathExpr {
+        PathExpr::Pipe(filters.iter().map(|f| self.path_of(f)).collect())
+    }
+
+    fn path_of(&mut self, filter: &Filter) -> PathExpr {
+        match &filter.kind {
+            RustyFilter::Identity => PathExpr::Pipe(Vec::new()),
+            RustyFilter::Field(key) => PathExpr::Step(PathStep::Field(key.clone())),
+            RustyFilter::Index(i) => PathExpr::Step(PathStep::Index(*i)),
+            RustyFilter::Path(steps) => PathExpr::Pipe(steps.iter().cloned().map(PathExpr::Step).collect()),
+            RustyFilter::Builtin0(Builtin0::First) => PathExpr::Step(PathStep::Index(0)),
+            RustyFilter::Builtin0(Builtin0::Last) => PathExpr::Step(PathStep::Index(-1)),
+            RustyFilter::Builtin0(Builtin0::Empty) => PathExpr::Comma(Vec::new()),
+            RustyFilter::Iterator => PathExpr::Each,
+            RustyFilter::RecurseDescent | RustyFilter::Builtin0(Builtin0::Recurse) => PathExpr::Recurse,
+            RustyFilter::Select(_) => PathExpr::Select(self.subprogram(std::slice::from_ref(filter))),
+            RustyFilter::IterSelect(condition) => {
+                let select = Filter::new(RustyFilter::Select(condition.clone()), filter.span);
+                PathExpr::Pipe(vec![PathExpr::Each, PathExpr::Select(self.subprogram(&[select]))])
+            }
+            RustyFilter::Comma(branches) => PathExpr::Comma(branches.iter().map(|b| self.path_expr(b)).collect()),
+            // paths never fail, so there is nothing to catch
+            RustyFilter::Try(body, _) => self.path_expr(body),
+            RustyFilter::FirstOf(f) => PathExpr::Limit(1, Box::new(self.path_expr(f))),
+            RustyFilter::Limit(n, f) => PathExpr::Limit(*n, Box::new(self.path_expr(f))),
+            RustyFilter::If(branches, otherwise) => PathExpr::If(
+                branches.iter().map(|(cond, then)| (self.subprogram(cond), self.path_expr(then))).collect(),
+                Box::new(otherwise.as_ref().map_or(PathExpr::Pipe(Vec::new()), |f| self.path_expr(f))),
+            ),
+            RustyFilter::Alternative(left, right) => PathExpr::Alternative(Box::new(self.path_expr(left)), Box::new(self.path_expr(right))),
+            _ => PathExpr::Invalid(self.subprogram(std::slice::from_ref(filter))),
+        }
+    }
+
+    // first output of `body`, or `default` when it produces none
+    fn first_or(&mut self, body: impl FnOnce(&mut Self), default: &Literal) {
+        let label = self.slot();
+        let d = self.constant(default);
+        self.emit(Op::Label(label));
+        let fork = self.emit(Op::Fork(0));
+        body(self);
+        self.emit(Op::Cut(label));
+        let exit = self.emit(Op::Jump(0));
+        self.patch(fork);
+        self.emit(Op::LoadConst(d));
+        self.patch(exit);
+    }
+
+    // conditions replace the input with exactly one value whose truthiness is the result
+    fn condition(&mut self, condition: &Condition) {
+        match condition {
+            Condition::Comparison(path, op, expr) => {
+                self.first_or(|c| {
+                    c.emit(Op::Dup);
+                    match expr {
+                        Expr::Literal(lit) => {
+                            let k = c.constant(lit);
+                            c.emit(Op::LoadConst(k));
+                        }
+                        Expr::Path(rhs) => c.pipeline(rhs),
+                    }
+                    c.emit(Op::Swap);
+                    c.pipeline(path);
+                    c.emit(Op::Compare(*op));
+                }, &Literal::Bool(false));
+            }
+            Condition::BoolPath(path) => {
+                self.first_or(|c| c.pipeline(path), &Literal::Bool(false));
+            }
+            Condition::And(l, r) => {
+                let f = self.constant(&Literal::Bool(false));
+                self.emit(Op::Dup);
+                self.condition(l);
+                let short = self.emit(Op::JumpIfFalse(0));
+                self.condition(r);
+                self.emit(Op::ToBool);
+                let exit = self.emit(Op::Jump(0));
+                self.patch(short);
+                self.emit(Op::LoadConst(f));
+                self.patch(exit);
+            }
+            Condition::Or(l, r) => {
+                let t = self.constant(&Literal::Bool(true));
+                self.emit(Op::Dup);
+                self.condition(l);
+                let next = self.emit(Op::JumpIfFalse(0));
+                self.emit(Op::LoadConst(t));
+                let exit = self.emit(Op::Jump(0));
+                self.patch(next);
+                self.condition(r);
+                self.emit(Op::ToBool);
+                self.patch(exit);
+            }
+            Condition::Not(inner) => {
+                self.condition(inner);
+                self.emit(Op::Builtin0(Builtin0::Not));
+            }
+        }
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+use std::sync::Arc;
+
+// a least-recently-used cache of up to `capacity` values, counting hits and misses
+// values pushed out are handed back rather than dropped, so callers holding a lock can drop them
+// after releasing it
+pub struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<Arc<K>, (V, u64)>,
+    // the keys by when they were last used, oldest first
+    recency: BTreeMap<u64, Arc<K>>,
+    clock: u64,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl<K: Hash + Eq, V> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Lru { capacity, entries: HashMap::new(), recency: BTreeMap::new(), clock: 0, hits: 0, misses: 0 }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    // the value for `key`, which becomes the most recently used
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let Some((value, used)) = self.entries.get_mut(key) else {
+            self.misses += 1;
+            return None;
+        };
+        self.hits += 1;
+        self.clock += 1;
+        let key = self.recency.remove(used).expect("every entry has a place in the recency order");
+        self.recency.insert(self.clock, key);
+        *used = self.clock;
+        Some(value)
+    }
+
+    // add `value` as the most recently used, returning the values it replaced or pushed out
+    pub fn insert(&mut self, key: K, value: V) -> Vec<V> {
+        if self.capacity == 0 {
+            return vec![value];
+        }
+        self.clock += 1;
+        let key = Arc::new(key);
+        let mut dropped = Vec::new();
+        if let Some((old, used)) = self.entries.insert(Arc::clone(&key), (value, self.clock)) {
+            self.recency.remove(&used);
+            dropped.push(old);
+        }
+        self.recency.insert(self.clock, key);
+        dropped.extend(self.shrink());
+        dropped
+    }
+
+    // keep at most `capacity` values from now on, returning those pushed out
+    pub fn resize(&mut self, capacity: usize) -> Vec<V> {
+        self.capacity = capacity;
+        self.shrink()
+    }
+
+    // empty the cache and reset its counts, returning the values it held
+    pub fn clear(&mut self) -> Vec<V> {
+        self.recency.clear();
+        self.hits = 0;
+        self.misses = 0;
+        self.entries.drain().map(|(_, (value, _))| value).collect()
+    }
+
+    fn shrink(&mut self) -> Vec<V> {
+        let mut dropped = Vec::new();
+        while self.entries.len() > self.capacity {
+            let Some((_, oldest)) = self.recency.pop_first() else { break };
+            dropped.extend(self.entries.remove(&oldest).map(|(value, _)| value));
+        }
+        dropped
+    }
+}
@@ -0,0 +1,344 @@
+use simd_json::BorrowedValue;
+use std::borrow::Cow;
+use std::fmt::Display;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::bytecode::Program;
+use crate::document::Document;
+use crate::engine::is_truthy;
+use crate::limits::{Limits, ParseLimits};
+use crate::optimizer::optimize;
+use crate::projection::{self, Projection};
+use crate::results::{Failure, Results};
+use crate::serialize::{write_json, JsonFormat};
+use crate::stream::{decompress, open_file, open_reader, start, DocumentStream};
+use crate::syntax;
+use crate::variables::{check_name, Declared};
+use crate::vm::Halt;
+
+// jq's exit statuses, besides 0 and those given to `halt_error`
+const EXIT_FALSY: i32 = 1; // `-e` and the last output was false or null
+const EXIT_USAGE: i32 = 2; // bad arguments, or inputs that cannot be read or parsed
+const EXIT_COMPILE: i32 = 3;
+const EXIT_NO_OUTPUT: i32 = 4; // `-e` and there was no output at all
+const EXIT_RUNTIME: i32 = 5; // the filter failed on some input
+
+// `--seq` writes this before each output, as in RFC 7464
+const RECORD_SEPARATOR: u8 = 0x1e;
+
+const USAGE: &str = "\
+Usage: rusty-jq [OPTIONS] FILTER [FILES...]
+       rusty-jq [OPTIONS] -f PROGRAM_FILE [FILES...]
+
+Runs FILTER on each JSON document of FILES, or of standard input.
+
+Options:
+  -c, --compact-output        write each output on one line
+  -r, --raw-output            write strings without quotes
+  -j, --join-output           like -r, without a newline after each output
+  -n, --null-input            run the filter once on null; inputs are read with input/inputs
+  -s, --slurp                 read every input into one array
+  -e, --exit-status           exit 1 if the last output is false or null, 4 if there is none
+  -S, --sort-keys             write object keys in sorted order
+  -a, --ascii-output          escape every non-ASCII character
+      --tab                   indent with a tab per level
+      --indent n              indent with n spaces (0 to 7, default 2)
+      --seq                   write an ASCII RS character before each output
+      --stream                read inputs as [path, leaf] events
+  -f, --from-file FILE        read the filter from FILE
+      --arg NAME VALUE        bind $NAME to the string VALUE
+      --argjson NAME TEXT     bind $NAME to the JSON value TEXT
+      --slurpfile NAME FILE   bind $NAME to an array of the JSON documents in FILE
+      --rawfile NAME FILE     bind $NAME to the contents of FILE as a string
+  -h, --help                  show this help
+  -V, --version               show the version
+";
+
+// what the command line asks for
+struct Options {
+    filter: Option<String>,
+    from_file: Option<PathBuf>,
+    files: Vec<PathBuf>,
+    raw: bool,
+    // raw, and nothing written after each output
+    join: bool,
+    null_input: bool,
+    slurp: bool,
+    exit_status: bool,
+    seq: bool,
+    stream: bool,
+    format: JsonFormat,
+    // variables in the order given, for `$ARGS.named` as well
+    named: Vec<(String, Arc<Document>)>,
+    help: bool,
+    version: bool,
+}
+
+impl Options {
+    fn parse(args: impl IntoIterator<Item = String>) -> Result<Self, String> {
+        let mut options = Options {
+            filter: None,
+            from_file: None,
+            files: Vec::new(),
+            raw: false,
+            join: false,
+            null_input: false,
+            slurp: false,
+            exit_status: false,
+            seq: false,
+            stream: false,
+            // jq indents with two spaces unless told otherwise
+            format: JsonFormat { indent: Some(2), ..JsonFormat::default() },
+            named: Vec::new(),
+            help: false,
+            version: false,
+        };
+        let mut positional = Vec::new();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                positional.extend(args.by_ref());
+            } else if let Some(long) = arg.strip_prefix("--") {
+                options.apply(long, &mut args)?;
+            } else if let Some(flags) = arg.strip_prefix('-').filter(|flags| !flags.is_empty()) {
+                // short options can be combined, as in `-rc`; those taking a value read the next argument
+                for flag in flags.chars() {
+                    let long = match flag {
+                        'c' => "compact-output",
+                        'r' => "raw-output",
+                        'j' => "join-output",
+                        'n' => "null-input",
+                        's' => "slurp",
+                        'e' => "exit-status",
+                        'S' => "sort-keys",
+                        'a' => "ascii-output",
+                        'f' => "from-file",
+                        'h' => "help",
+                        'V' => "version",
+                        _ => return Err(format!("unknown option -{}", flag)),
+                    };
+                    options.apply(long, &mut args)?;
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+        let mut positional = positional.into_iter();
+        if options.from_file.is_none() {
+            options.filter = positional.next();
+        }
+        options.files = positional.map(PathBuf::from).collect();
+        Ok(options)
+    }
+
+    // one long option, reading its values from `args`
+    fn apply(&mut self, name: &str, args: &mut impl Iterator<Item = String>) -> Result<(), String> {
+        let mut value = |what: &str| args.next().ok_or_else(|| format!("--{} takes {}", name, what));
+        match name {
+            "compact-output" => self.format.indent = None,
+            "raw-output" => self.raw = true,
+            "join-output" => { self.raw = true; self.join = true; }
+            "null-input" => self.null_input = true,
+            "slurp" => self.slurp = true,
+            "exit-status" => self.exit_status = true,
+            "sort-keys" => self.format.sort_keys = true,
+            "ascii-output" => self.format.ascii = true,
+            "tab" => self.format = JsonFormat { indent: Some(1), tab: true, ..self.format },
+            "indent" => {
+                let n: usize = value("a number")?.parse().map_err(|_| "--indent takes a number".to_string())?;
+                if n > 7 { return Err("cannot indent more than 7 characters".to_string()); }
+                self.format = JsonFormat { indent: (n > 0).then_some(n), tab: false, ..self.format };
+            }
+            "seq" => self.seq = true,
+            "stream" => self.stream = true,
+            "from-file" => self.from_file = Some(PathBuf::from(value("a file name")?)),
+            "arg" => {
+                let (name, text) = (value("a name and a value")?, value("a name and a value")?);
+                let document = Document::from_value(BorrowedValue::String(Cow::Owned(text)));
+                self.named.push((name, Arc::new(document)));
+            }
+            "argjson" => {
+                let (name, text) = (value("a name and a JSON text")?, value("a name and a JSON text")?);
+                let document = Document::parse(text.into_bytes(), &Projection::all(), &ParseLimits::default())
+                    .map_err(|e| format!("invalid JSON text passed to --argjson: {}", e))?;
+                self.named.push((name, Arc::new(document)));
+            }
+            "slurpfile" => {
+                let (name, path) = (value("a name and a file name")?, value("a name and a file name")?);
+                self.named.push((name, Arc::new(slurp_file(Path::new(&path))?)));
+            }
+            "rawfile" => {
+                let (name, path) = (value("a name and a file name")?, value("a name and a file name")?);
+                let text = fs::read_to_string(&path).map_err(|e| open_error(Path::new(&path), e))?;
+                let document = Document::from_value(BorrowedValue::String(Cow::Owned(text)));
+                self.named.push((name, Arc::new(document)));
+            }
+            "help" => self.help = true,
+            "version" => self.version = true,
+            _ => return Err(format!("unknown option --{}", name)),
+        }
+        match self.named.last() {
+            Some((name, _)) => check_name(name),
+            None => Ok(()),
+        }
+    }
+}
+
+// every document of a file in one array, for `--slurpfile`
+fn slurp_file(path: &Path) -> Result<Document, String> {
+    let stream = open_file(path, &Projection::all(), &ParseLimits::default(), true, false).map_err(|e| open_error(path, e))?;
+    let stream = start(stream, true).map_err(|e| e.to_string())?;
+    match stream.next_document() {
+        Some(Ok(document)) => Ok(Arc::try_unwrap(document).unwrap_or_else(|document| Document::assemble(&[&document], |mut values| values.remove(0)))),
+        Some(Err(e)) => Err(e.to_string()),
+        None => Ok(Document::from_value(BorrowedValue::Array(Box::default()))),
+    }
+}
+
+fn open_error(path: &Path, e: io::Error) -> String {
+    format!("could not open {}: {}", path.display(), e)
+}
+
+// the documents of the input files one after another, or of standard input without any
+// several files are read as one stream, like jq does, so `input_filename` is only known for one
+fn open_inputs(options: &Options, projection: &Projection) -> Result<DocumentStream, String> {
+    match options.files.as_slice() {
+        [] => Ok(open_reader(Box::new(io::stdin()), projection, &ParseLimits::default(), options.slurp, options.stream)),
+        [path] => open_file(path, projection, &ParseLimits::default(), options.slurp, options.stream).map_err(|e| open_error(path, e)),
+        paths => {
+            let mut reader: Box<dyn Read + Send> = Box::new(io::empty());
+            for path in paths {
+                let file = File::open(path).and_then(decompress).map_err(|e| open_error(path, e))?;
+                reader = Box::new(reader.chain(file));
+            }
+            Ok(open_reader(reader, projection, &ParseLimits::default(), options.slurp, options.stream))
+        }
+    }
+}
+
+fn fail(status: i32, message: impl Display) -> i32 {
+    eprintln!("rusty-jq: error: {}", message);
+    status
+}
+
+// run the command line `args` (without the program name), returning the exit status
+pub fn run(args: impl IntoIterator<Item = String>) -> i32 {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("rusty-jq: error: {}\nUse rusty-jq --help for help with command-line options.", message);
+            return EXIT_USAGE;
+        }
+    };
+    if options.help {
+        print!("{}", USAGE);
+        return 0;
+    }
+    if options.version {
+        println!("rusty-jq {}", env!("CARGO_PKG_VERSION"));
+        return 0;
+    }
+
+    let query = match (&options.from_file, &options.filter) {
+        (Some(path), _) => match fs::read_to_string(path) {
+            Ok(query) => query,
+            Err(e) => return fail(EXIT_USAGE, open_error(path, e)),
+        },
+        (None, Some(filter)) => filter.clone(),
+        // like jq, no filter is a usage error rather than `.`
+        (None, None) => {
+            eprint!("{}", USAGE);
+            return EXIT_USAGE;
+        }
+    };
+    let filters = match syntax::parse(&query) {
+        Ok(filters) => optimize(filters, &Limits::default()),
+        Err(e) => return fail(EXIT_COMPILE, format!("syntax error: {}", e)),
+    };
+    let projection = projection::analyze(&filters);
+    let program = Program::compile(&filters);
+    let declared = Declared::new(options.named.iter().map(|(name, value)| (name.clone(), Some(Arc::clone(value)))).collect(), Vec::new());
+    if let Some(name) = declared.undefined(&program) {
+        return fail(EXIT_COMPILE, format!("${} is not defined", name));
+    }
+    let vars = match declared.bind(Vec::new()) {
+        Ok(bound) => bound.values_for(&program),
+        Err(message) => return fail(EXIT_COMPILE, message),
+    };
+
+    let documents = match open_inputs(&options, &projection) {
+        Ok(documents) => documents,
+        Err(message) => return fail(EXIT_USAGE, message),
+    };
+    // with -n nothing is read until `input` asks for it, so a terminal on stdin does not block
+    let documents = if options.null_input && !options.slurp {
+        documents
+    } else {
+        match start(documents, options.slurp) {
+            Ok(documents) => documents,
+            Err(e) => return fail(EXIT_USAGE, e),
+        }
+    };
+
+    let mut results = Results::new(documents, Arc::new(program), vars, options.null_input);
+    let mut out = BufWriter::new(io::stdout().lock());
+    let mut status = 0;
+    // the truthiness of the last output, for -e
+    let mut last = None;
+    let mut text = String::new();
+    loop {
+        // each output is written out to `text` while the document it borrows from is current
+        let result = results.next_with(|val| {
+            text.clear();
+            match val {
+                BorrowedValue::String(s) if options.raw => text.push_str(s),
+                val => write_json(&mut text, val, &options.format, 0),
+            }
+            is_truthy(val)
+        });
+        match result {
+            None => break,
+            Some(Ok(truthy)) => last = Some(truthy),
+            // a malformed document or a failing filter is reported, and the documents after it still run
+            Some(Err(Failure::Stream(e))) => {
+                status = fail(EXIT_USAGE, e);
+                continue;
+            }
+            Some(Err(Failure::Runtime(e))) => {
+                status = fail(EXIT_RUNTIME, e.in_query(&query));
+                continue;
+            }
+        }
+        if !options.join { text.push('\n'); }
+        let written = match options.seq {
+            true => out.write_all(&[RECORD_SEPARATOR]).and_then(|_| out.write_all(text.as_bytes())),
+            false => out.write_all(text.as_bytes()),
+        };
+        match written {
+            Ok(()) => {}
+            // whoever reads the output has seen enough, as with `rusty-jq ... | head`
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return status,
+            Err(e) => return fail(EXIT_USAGE, e),
+        }
+    }
+    match out.flush() {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return status,
+        Err(e) => return fail(EXIT_USAGE, e),
+    }
+
+    if let Some(Halt::Exit { code, message }) = results.halted() {
+        if let Some(message) = message {
+            let _ = io::stderr().write_all(message.as_bytes());
+        }
+        return *code;
+    }
+    match (status, options.exit_status, last) {
+        (0, true, None) => EXIT_NO_OUTPUT,
+        (0, true, Some(false)) => EXIT_FALSY,
+        _ => status,
+    }
+}
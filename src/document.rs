@@ -0,0 +1,314 @@
+use memmap2::MmapMut;
+#[cfg(feature = "python")]
+use pyo3::{
+    buffer::PyBuffer,
+    exceptions::{PyTypeError, PyUnicodeDecodeError, PyValueError},
+    prelude::*,
+    types::{PyBool, PyBytes, PyDict, PyFloat, PyList, PyLong, PyString, PyTuple},
+};
+#[cfg(feature = "python")]
+use simd_json::{borrowed::Object, ErrorType, StaticNode};
+#[cfg(feature = "python")]
+use std::borrow::Cow;
+use simd_json::BorrowedValue;
+use std::ops::Range;
+use std::sync::Arc;
+
+use crate::limits::{ParseError, ParseLimits};
+use crate::projection::{self, Projection};
+
+// the bytes documents are parsed from and point into
+pub struct Buffer {
+    ptr: *mut u8,
+    len: usize,
+    _owner: Owner,
+}
+
+// keeps a buffer's memory alive; a PyBuffer export also stops a bytearray from resizing
+#[allow(dead_code)]
+enum Owner {
+    Vec(Vec<u8>),
+    #[cfg(feature = "python")]
+    Python(PyBuffer<u8>),
+    Mmap(MmapMut),
+}
+
+// SAFETY: the memory is only written while parsing, and each byte range is parsed at most once
+unsafe impl Send for Buffer {}
+unsafe impl Sync for Buffer {}
+
+impl Buffer {
+    pub fn from_vec(mut bytes: Vec<u8>) -> Self {
+        Buffer { ptr: bytes.as_mut_ptr(), len: bytes.len(), _owner: Owner::Vec(bytes) }
+    }
+
+    // a caller's writable, contiguous buffer, parsed in place
+    #[cfg(feature = "python")]
+    pub fn from_python(buffer: PyBuffer<u8>) -> Self {
+        Buffer { ptr: buffer.buf_ptr().cast(), len: buffer.len_bytes(), _owner: Owner::Python(buffer) }
+    }
+
+    // a private copy-on-write mapping of a file
+    pub fn from_mmap(mut map: MmapMut) -> Self {
+        Buffer { ptr: map.as_mut_ptr(), len: map.len(), _owner: Owner::Mmap(map) }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    // the raw bytes, for splitting them into documents before any of them is parsed
+    pub fn bytes(&self) -> &[u8] {
+        // SAFETY: `ptr` and `len` describe memory owned by `_owner`
+        unsafe { std::slice::from_raw_parts(self.ptr, self.len) }
+    }
+}
+
+// a parsed JSON document together with the buffers its strings point into
+pub struct Document {
+    // declared first so it is dropped before the buffers it borrows
+    value: BorrowedValue<'static>,
+    _buffers: Vec<Arc<Buffer>>,
+}
+
+impl Document {
+    pub fn parse(bytes: Vec<u8>, projection: &Projection, limits: &ParseLimits) -> Result<Self, ParseError> {
+        let buffer = Arc::new(Buffer::from_vec(bytes));
+        let whole = 0..buffer.len();
+        // SAFETY: the buffer is fresh, so this is the only parse of its bytes
+        unsafe { Document::parse_range(&buffer, whole, projection, limits) }
+    }
+
+    // parse the document in `range` of a shared buffer, rewriting those bytes
+    // SAFETY: every range of a buffer must be parsed at most once, and ranges must not overlap
+    pub unsafe fn parse_range(buffer: &Arc<Buffer>, range: Range<usize>, projection: &Projection, limits: &ParseLimits) -> Result<Self, ParseError> {
+        assert!(range.start <= range.end && range.end <= buffer.len);
+        // the value may point into the range for as long as the Arc keeps the buffer alive,
+        // and `value` is dropped before the Arc
+        let buf: &'static mut [u8] = std::slice::from_raw_parts_mut(buffer.ptr.add(range.start), range.len());
+        let value = projection::parse(buf, projection, limits)?;
+        Ok(Document { value, _buffers: vec![Arc::clone(buffer)] })
+    }
+
+    // a document built from Python objects or events owns all of its strings
+    pub fn from_value(value: BorrowedValue<'static>) -> Self {
+        Document { value, _buffers: Vec::new() }
+    }
+
+    // one array holding every document, as `slurp=True` sees them
+    pub fn slurp(documents: Vec<Document>) -> Self {
+        let mut buffers: Vec<Arc<Buffer>> = Vec::new();
+        let mut values = Vec::with_capacity(documents.len());
+        for doc in documents {
+            // documents split from one buffer come in a row
+            for buffer in doc._buffers {
+                if !buffers.last().is_some_and(|last| Arc::ptr_eq(last, &buffer)) { buffers.push(buffer); }
+            }
+            values.push(doc.value);
+        }
+        Document { value: BorrowedValue::Array(Box::new(values)), _buffers: buffers }
+    }
+
+    // a value built from copies of other documents' values, which keeps their buffers alive
+    pub fn assemble(parts: &[&Document], build: impl FnOnce(Vec<BorrowedValue<'static>>) -> BorrowedValue<'static>) -> Self {
+        let buffers = parts.iter().flat_map(|doc| doc._buffers.iter().cloned()).collect();
+        let values = parts.iter().map(|doc| doc.value.clone()).collect();
+        Document { value: build(values), _buffers: buffers }
+    }
+
+    // the value, if it borrows from no buffer
+    pub fn into_value(self) -> Result<BorrowedValue<'static>, Document> {
+        if self._buffers.is_empty() { Ok(self.value) } else { Err(self) }
+    }
+
+    pub fn root(&self) -> &BorrowedValue<'_> {
+        &self.value
+    }
+}
+
+// parse a Python input holding a single document: `str`, or any bytes-like object
+// (bytes, bytearray, memoryview, ...); with `inplace`, a writable buffer is parsed without any copy
+#[cfg(feature = "python")]
+pub fn load(py: Python, json: &PyAny, projection: &Projection, limits: &ParseLimits, inplace: bool) -> PyResult<Document> {
+    let parsed = if inplace {
+        let buffer = Arc::new(writable_buffer(json)?);
+        let whole = 0..buffer.len();
+        // SAFETY: the buffer is fresh, so this is the only parse of its bytes
+        unsafe { Document::parse_range(&buffer, whole, projection, limits) }
+    } else {
+        Document::parse(input_bytes(py, json)?.into_owned(), projection, limits)
+    };
+    // a failed parse may already have unescaped strings in place, but invalid UTF-8, the only error
+    // that reads the input again, is found before anything is rewritten
+    parsed.map_err(|e| input_error(py, &input_bytes(py, json).unwrap_or_default(), e, ""))
+}
+
+// the caller's buffer for `inplace=True`
+#[cfg(feature = "python")]
+pub fn writable_buffer(json: &PyAny) -> PyResult<Buffer> {
+    match PyBuffer::<u8>::get(json).ok().filter(|b| !b.readonly() && b.is_c_contiguous()) {
+        Some(buffer) => Ok(Buffer::from_python(buffer)),
+        None => Err(PyErr::new::<PyTypeError, _>(format!(
+            "inplace parsing needs a writable contiguous buffer such as bytearray, got {}", json.get_type().name()?))),
+    }
+}
+
+// the raw bytes of a Python input; immutable `str` and `bytes` are borrowed, other buffers copied
+#[cfg(feature = "python")]
+pub fn input_bytes<'py>(py: Python, json: &'py PyAny) -> PyResult<Cow<'py, [u8]>> {
+    if let Ok(s) = json.downcast::<PyString>() {
+        Ok(Cow::Borrowed(s.to_str()?.as_bytes()))
+    } else if let Ok(b) = json.downcast::<PyBytes>() {
+        Ok(Cow::Borrowed(b.as_bytes()))
+    } else if let Ok(buffer) = PyBuffer::<u8>::get(json) {
+        Ok(Cow::Owned(buffer.to_vec(py)?))
+    } else {
+        Err(PyErr::new::<PyTypeError, _>(format!("expected str or a bytes-like object, got {}", json.get_type().name()?)))
+    }
+}
+
+// invalid UTF-8 becomes a UnicodeDecodeError pointing at the offending byte, anything else a ValueError
+// `context` prefixes the message, e.g. with the position of the input within a batch
+#[cfg(feature = "python")]
+pub fn input_error(py: Python, text: &[u8], e: ParseError, context: &str) -> PyErr {
+    if matches!(&e, ParseError::Json(e) if *e.error() == ErrorType::InvalidUtf8) {
+        if let Err(utf8) = std::str::from_utf8(text) {
+            let start = utf8.valid_up_to();
+            let end = start + utf8.error_len().unwrap_or(text.len() - start);
+            let reason = std::ffi::CString::new(format!("{}invalid utf-8", context)).unwrap_or_default();
+            let encoding = std::ffi::CString::new("utf-8").unwrap_or_default();
+            return match PyUnicodeDecodeError::new(py, &encoding, text, start..end, &reason) {
+                Ok(err) => PyErr::from_value(err),
+                Err(err) => err,
+            };
+        }
+    }
+    PyErr::new::<PyValueError, _>(format!("{}{}", context, e))
+}
+
+// convert a Python object graph into a document, following the conventions of json.dumps;
+// `default` is called for objects of any other type and its result converted instead
+#[cfg(feature = "python")]
+pub fn load_obj(py: Python, obj: &PyAny, projection: &Projection, limits: &ParseLimits, default: Option<&PyAny>) -> PyResult<Document> {
+    let mut converter = FromPy { py, default, limits: *limits, active: Vec::new() };
+    Ok(Document::from_value(converter.convert(obj, projection)?))
+}
+
+// a whole Python object graph as a value, e.g. for a query variable
+#[cfg(feature = "python")]
+pub fn value_from_py(py: Python, obj: &PyAny) -> PyResult<BorrowedValue<'static>> {
+    FromPy { py, default: None, limits: ParseLimits::default(), active: Vec::new() }.convert(obj, &Projection::all())
+}
+
+// the inverse of value_to_py; only the parts of the object the projection needs are converted
+// the objects are held to the depth and string length limits, as parsed text would be
+#[cfg(feature = "python")]
+struct FromPy<'py> {
+    py: Python<'py>,
+    default: Option<&'py PyAny>,
+    limits: ParseLimits,
+    // containers being converted, to reject circular references like json.dumps does
+    active: Vec<usize>,
+}
+
+#[cfg(feature = "python")]
+impl<'py> FromPy<'py> {
+    fn convert(&mut self, obj: &'py PyAny, projection: &Projection) -> PyResult<BorrowedValue<'static>> {
+        if obj.is_none() {
+            Ok(BorrowedValue::Static(StaticNode::Null))
+        } else if let Ok(b) = obj.downcast::<PyBool>() {
+            Ok(BorrowedValue::Static(StaticNode::Bool(b.is_true())))
+        } else if obj.is_instance_of::<PyLong>() {
+            int_to_value(obj)
+        } else if let Ok(f) = obj.downcast::<PyFloat>() {
+            Ok(BorrowedValue::Static(StaticNode::F64(f.value())))
+        } else if let Ok(s) = obj.downcast::<PyString>() {
+            Ok(BorrowedValue::String(Cow::Owned(self.string(s.to_str()?.to_string())?)))
+        } else if let Ok(dict) = obj.downcast::<PyDict>() {
+            self.enter(obj)?;
+            let mut map = Object::with_capacity_and_hasher(dict.len(), Default::default());
+            for (k, v) in dict.iter() {
+                let key = self.string(dict_key(k)?)?;
+                if let Some(below) = projection.child(&key) {
+                    let value = self.convert(v, &below)?;
+                    map.insert(Cow::Owned(key), value);
+                }
+            }
+            self.active.pop();
+            Ok(BorrowedValue::Object(Box::new(map)))
+        } else if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+            self.enter(obj)?;
+            let mut elements = Vec::new();
+            if let Some(each) = projection.element() {
+                for item in obj.iter()? {
+                    elements.push(self.convert(item?, each)?);
+                }
+            }
+            self.active.pop();
+            Ok(BorrowedValue::Array(Box::new(elements)))
+        } else if obj.is_instance(self.py.import("decimal")?.getattr("Decimal")?)? {
+            // integral decimals stay exact, others become doubles like any JSON number
+            if obj.call_method0("is_finite")?.is_true()? && obj.eq(obj.call_method0("to_integral_value")?)? {
+                int_to_value(obj.call_method0("__int__")?)
+            } else {
+                Ok(BorrowedValue::Static(StaticNode::F64(obj.call_method0("__float__")?.extract()?)))
+            }
+        } else if let Some(default) = self.default {
+            self.enter(obj)?;
+            let value = self.convert(default.call1((obj,))?, projection)?;
+            self.active.pop();
+            Ok(value)
+        } else {
+            Err(PyErr::new::<PyTypeError, _>(format!("Object of type {} is not JSON serializable", obj.get_type().name()?)))
+        }
+    }
+
+    fn enter(&mut self, obj: &PyAny) -> PyResult<()> {
+        let id = obj.as_ptr() as usize;
+        if self.active.contains(&id) {
+            return Err(PyErr::new::<PyValueError, _>("Circular reference detected"));
+        }
+        let max_depth = self.limits.depth();
+        if self.active.len() == max_depth {
+            return Err(PyErr::new::<PyValueError, _>(ParseError::Depth(max_depth).to_string()));
+        }
+        self.active.push(id);
+        Ok(())
+    }
+
+    fn string(&self, s: String) -> PyResult<String> {
+        match self.limits.max_string_length {
+            Some(max) if s.len() > max => Err(PyErr::new::<PyValueError, _>(ParseError::StringLength(max).to_string())),
+            _ => Ok(s),
+        }
+    }
+}
+
+// integers keep 64-bit precision; larger ones become doubles, which is all jq numbers are anyway
+#[cfg(feature = "python")]
+fn int_to_value(obj: &PyAny) -> PyResult<BorrowedValue<'static>> {
+    let node = if let Ok(i) = obj.extract::<i64>() {
+        StaticNode::I64(i)
+    } else if let Ok(u) = obj.extract::<u64>() {
+        StaticNode::U64(u)
+    } else {
+        StaticNode::F64(obj.call_method0("__float__")?.extract()?)
+    };
+    Ok(BorrowedValue::Static(node))
+}
+
+// dict keys are converted to strings the way json.dumps does
+#[cfg(feature = "python")]
+fn dict_key(key: &PyAny) -> PyResult<String> {
+    if let Ok(s) = key.downcast::<PyString>() {
+        Ok(s.to_str()?.to_string())
+    } else if key.is_none() {
+        Ok("null".to_string())
+    } else if let Ok(b) = key.downcast::<PyBool>() {
+        Ok(if b.is_true() { "true" } else { "false" }.to_string())
+    } else if key.is_instance_of::<PyLong>() || key.is_instance_of::<PyFloat>() {
+        Ok(key.str()?.to_str()?.to_string())
+    } else {
+        Err(PyErr::new::<PyTypeError, _>(format!("keys must be str, int, float, bool or None, not {}", key.get_type().name()?)))
+    }
+}
@@ -0,0 +1,84 @@
+use bincode::Options;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+use crate::document::Document;
+use crate::limits::{Limits, ParseLimits};
+use crate::parser::Filter;
+use crate::projection::Projection;
+use crate::serialize::{to_json, JsonFormat};
+use crate::syntax;
+use crate::variables::Declared;
+
+// the version of the encoding below; bincode writes no field or variant names, so any change to the
+// shape of `Encoded` or of the AST has to bump it, and bytes of another version are then rejected
+// instead of being misread
+pub const FORMAT_VERSION: u16 = 4;
+// leads every encoded program, so other bytes are not taken for one
+const MAGIC: &[u8] = b"RJQP";
+
+// variable-length integers, and no bytes left over after the program
+fn options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+// a compiled program as bytes, for pickling: the optimized filters rather than the query, so loading
+// it skips parsing and optimizing
+#[derive(Serialize, Deserialize)]
+struct Encoded {
+    // kept for error messages and to_jq()
+    query: String,
+    filters: Vec<Filter>,
+    // the declared variables and their defaults, and the positional arguments, as JSON text
+    named: Vec<(String, Option<String>)>,
+    positional: Vec<String>,
+    limits: Limits,
+    parse_limits: ParseLimits,
+}
+
+pub fn encode(query: &str, filters: &[Filter], declared: &Declared, limits: &Limits, parse_limits: &ParseLimits) -> Vec<u8> {
+    let json = |document: &Arc<Document>| to_json(document.root(), &JsonFormat::default());
+    let encoded = Encoded {
+        query: query.to_string(),
+        filters: filters.to_vec(),
+        named: declared.named().iter().map(|(name, default)| (name.clone(), default.as_ref().map(json))).collect(),
+        positional: declared.positional().iter().map(json).collect(),
+        limits: *limits,
+        parse_limits: *parse_limits,
+    };
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(FORMAT_VERSION.to_le_bytes());
+    options().serialize_into(&mut bytes, &encoded).expect("serializing into memory cannot fail");
+    bytes
+}
+
+// the query, its optimized filters, its variables and both of its limits, from the bytes of `encode`
+pub fn decode(bytes: &[u8]) -> Result<(String, Vec<Filter>, Declared, Limits, ParseLimits), String> {
+    let rest = bytes.strip_prefix(MAGIC).ok_or("not a serialized rusty-jq program")?;
+    let (version, rest) = match rest {
+        [a, b, rest @ ..] => (u16::from_le_bytes([*a, *b]), rest),
+        _ => return Err("truncated serialized program".to_string()),
+    };
+    if version != FORMAT_VERSION {
+        return Err(format!("program serialized in format version {}, but this version of rusty-jq reads version {}", version, FORMAT_VERSION));
+    }
+    let encoded: Encoded = options().deserialize(rest).map_err(|e| match *e {
+        // running out of bytes
+        bincode::ErrorKind::Io(_) => "truncated serialized program".to_string(),
+        e => format!("corrupt serialized program: {}", e),
+    })?;
+    // `ast()` and `to_jq()` parse the query again
+    syntax::parse(&encoded.query).map_err(|e| format!("corrupt serialized program: its query does not parse: {}", e))?;
+    // the values were written out by `encode`, so only the ceiling on their depth applies
+    let trusted = ParseLimits { max_depth: ParseLimits::MAX_DEPTH, ..ParseLimits::default() };
+    let document = |json: String| {
+        Document::parse(json.into_bytes(), &Projection::all(), &trusted)
+            .map(Arc::new)
+            .map_err(|e| format!("corrupt serialized program: {}", e))
+    };
+    let named = encoded.named.into_iter()
+        .map(|(name, default)| Ok((name, default.map(document).transpose()?)))
+        .collect::<Result<_, String>>()?;
+    let positional = encoded.positional.into_iter().map(document).collect::<Result<_, _>>()?;
+    Ok((encoded.query, encoded.filters, Declared::new(named, positional), encoded.limits, encoded.parse_limits))
+}
@@ -1,10 +1,15 @@
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use simd_json::BorrowedValue;
 use simd_json::borrowed::Object;
 use simd_json::prelude::*;
 
-use crate::parser::{RustyFilter, CompareOp, ArithOp, Literal, Condition, Expr, Builtin0, Builtin1};
+use crate::events::to_stream;
+use crate::limits::ParseLimits;
+use crate::parser::{CompareOp, ArithOp, Literal, Builtin0, Builtin1, PathStep};
+use crate::projection::{parse, Projection};
+use crate::serialize::{to_json, JsonFormat};
 
 fn apply_op<T: PartialOrd>(a: &T, b: &T, op: &CompareOp) -> bool {
     match op {
@@ -17,33 +22,161 @@ fn apply_op<T: PartialOrd>(a: &T, b: &T, op: &CompareOp) -> bool {
     }
 }
 
-fn literal_to_value(lit: &Literal) -> BorrowedValue<'static> {
+pub fn literal_to_value(lit: &Literal) -> BorrowedValue<'static> {
     match lit {
         Literal::Int(i) => BorrowedValue::Static(StaticNode::I64(*i)),
         Literal::Float(f) => BorrowedValue::Static(StaticNode::F64(*f)),
         Literal::String(s) => BorrowedValue::String(Cow::Owned(s.clone())),
         Literal::Bool(b) => BorrowedValue::Static(StaticNode::Bool(*b)),
         Literal::Null => BorrowedValue::Static(StaticNode::Null),
+        Literal::Array(items) => make_array(items.iter().map(literal_to_value).collect()),
+        Literal::Object(pairs) => {
+            let mut obj = Object::with_capacity(pairs.len());
+            for (k, v) in pairs { obj.insert(Cow::Owned(k.clone()), literal_to_value(v)); }
+            BorrowedValue::Object(Box::new(obj))
+        }
     }
 }
 
+// inverse of `literal_to_value`, used when folding constants at compile time
+// non-finite floats have no literal form
+pub fn value_to_literal(val: &BorrowedValue) -> Option<Literal> {
+    Some(match val {
+        BorrowedValue::Static(StaticNode::Null) => Literal::Null,
+        BorrowedValue::Static(StaticNode::Bool(b)) => Literal::Bool(*b),
+        BorrowedValue::Static(StaticNode::I64(i)) => Literal::Int(*i),
+        BorrowedValue::Static(StaticNode::U64(u)) => Literal::Int(i64::try_from(*u).ok()?),
+        BorrowedValue::Static(StaticNode::F64(f)) => if f.is_finite() { Literal::Float(*f) } else { return None },
+        BorrowedValue::String(s) => Literal::String(s.as_ref().to_string()),
+        BorrowedValue::Array(arr) => Literal::Array(arr.iter().map(value_to_literal).collect::<Option<_>>()?),
+        BorrowedValue::Object(obj) => Literal::Object(
+            obj.iter().map(|(k, v)| Some((k.as_ref().to_string(), value_to_literal(v)?))).collect::<Option<_>>()?,
+        ),
+    })
+}
+
 fn make_array<'a>(v: Vec<BorrowedValue<'a>>) -> BorrowedValue<'a> {
     BorrowedValue::Array(Box::new(v))
 }
 
-fn apply_arith<'a>(left: &BorrowedValue, op: &ArithOp, right: &BorrowedValue) -> Option<BorrowedValue<'a>> {
+// ─── runtime errors ────────────────────────────────────────────────────────────
+
+// a filter given a value it cannot work with: what went wrong, the JSON of the value to blame,
+// and the steps from the filter's input down to that value
+#[derive(Debug)]
+pub struct Fault {
+    pub message: String,
+    pub value: String,
+    pub at: Vec<PathStep>,
+}
+
+// values in error messages are cut to this many characters of JSON
+const ERROR_VALUE_CHARS: usize = 30;
+
+pub fn type_name(val: &BorrowedValue) -> &'static str {
+    match val {
+        BorrowedValue::Static(StaticNode::Null) => "null",
+        BorrowedValue::Static(StaticNode::Bool(_)) => "boolean",
+        BorrowedValue::Static(StaticNode::I64(_)) | BorrowedValue::Static(StaticNode::U64(_)) | BorrowedValue::Static(StaticNode::F64(_)) => "number",
+        BorrowedValue::String(_) => "string",
+        BorrowedValue::Array(_) => "array",
+        BorrowedValue::Object(_) => "object",
+    }
+}
+
+// the JSON text of a value, cut short for an error message
+pub fn truncated_json(val: &BorrowedValue) -> String {
+    let text = value_to_json_string(val);
+    match text.char_indices().nth(ERROR_VALUE_CHARS) {
+        Some((end, _)) => format!("{}...", &text[..end]),
+        None => text,
+    }
+}
+
+// `number (1)`, as jq names values in its errors
+fn describe(val: &BorrowedValue) -> String {
+    format!("{} ({})", type_name(val), truncated_json(val))
+}
+
+#[cold]
+fn arith_error(left: &BorrowedValue, op: &ArithOp, right: &BorrowedValue) -> String {
+    let verb = match op {
+        ArithOp::Add => "added",
+        ArithOp::Sub => "subtracted",
+        ArithOp::Mul => "multiplied",
+        ArithOp::Div | ArithOp::Mod => "divided",
+    };
+    format!("{} and {} cannot be {}", describe(left), describe(right), verb)
+}
+
+// whether the right operand of a failed arithmetic operation is to blame rather than the left:
+// it is when it is the one that is not a number, or a zero divisor
+pub fn arith_blames_right(left: &BorrowedValue, right: &BorrowedValue) -> bool {
+    to_f64(left).is_some() && to_f64(right).is_none_or(|(n, _)| n == 0.0)
+}
+
+// the steps from `root` down to `target`, found by address, so only for values borrowed from it
+pub fn path_to(root: &BorrowedValue, target: &BorrowedValue) -> Option<Vec<PathStep>> {
+    if std::ptr::eq(root, target) { return Some(Vec::new()); }
+    let (step, mut path) = match root {
+        BorrowedValue::Array(arr) => arr.iter().enumerate()
+            .find_map(|(i, v)| Some((PathStep::Index(i as i32), path_to(v, target)?)))?,
+        BorrowedValue::Object(obj) => obj.iter()
+            .find_map(|(k, v)| Some((PathStep::Field(k.as_ref().to_string()), path_to(v, target)?)))?,
+        _ => return None,
+    };
+    path.insert(0, step);
+    Some(path)
+}
+
+// ─── arithmetic ────────────────────────────────────────────────────────────────
+
+// the error message when the operands' types do not go together
+pub fn apply_arith<'a>(left: &BorrowedValue, op: &ArithOp, right: &BorrowedValue) -> Result<BorrowedValue<'a>, String> {
     // Fast path: both sides are numbers (overwhelmingly common case)
     if let Some((a_f, b_f, a_int, b_int)) = extract_numbers(left, right) {
-        return match op {
-            ArithOp::Add => if a_int && b_int { Some(BorrowedValue::Static(StaticNode::I64(a_f as i64 + b_f as i64))) } else { Some(BorrowedValue::Static(StaticNode::F64(a_f + b_f))) },
-            ArithOp::Sub => if a_int && b_int { Some(BorrowedValue::Static(StaticNode::I64(a_f as i64 - b_f as i64))) } else { Some(BorrowedValue::Static(StaticNode::F64(a_f - b_f))) },
-            ArithOp::Mul => if a_int && b_int { Some(BorrowedValue::Static(StaticNode::I64(a_f as i64 * b_f as i64))) } else { Some(BorrowedValue::Static(StaticNode::F64(a_f * b_f))) },
-            ArithOp::Div => Some(BorrowedValue::Static(StaticNode::F64(a_f / b_f))),
-            ArithOp::Mod => if a_int && b_int { Some(BorrowedValue::Static(StaticNode::I64(a_f as i64 % b_f as i64))) } else { Some(BorrowedValue::Static(StaticNode::F64(a_f % b_f))) },
+        if matches!(op, ArithOp::Div | ArithOp::Mod) && b_f == 0.0 {
+            return Err(format!("{} and {} cannot be divided because the divisor is zero", describe(left), describe(right)));
+        }
+        // integers stay integers unless the result overflows, which falls back to f64
+        let exact = if a_int && b_int {
+            let (a, b) = (a_f as i64, b_f as i64);
+            match op {
+                ArithOp::Add => a.checked_add(b),
+                ArithOp::Sub => a.checked_sub(b),
+                ArithOp::Mul => a.checked_mul(b),
+                ArithOp::Mod => a.checked_rem(b),
+                ArithOp::Div => None,
+            }
+        } else {
+            None
         };
+        return Ok(BorrowedValue::Static(match exact {
+            Some(n) => StaticNode::I64(n),
+            None => StaticNode::F64(match op {
+                ArithOp::Add => a_f + b_f,
+                ArithOp::Sub => a_f - b_f,
+                ArithOp::Mul => a_f * b_f,
+                ArithOp::Div => a_f / b_f,
+                ArithOp::Mod => a_f % b_f,
+            }),
+        }));
     }
     // Slow path: type-specific operations (strings, arrays, objects, null)
-    apply_arith_nonnum(left, op, right)
+    apply_arith_nonnum(left, op, right).ok_or_else(|| arith_error(left, op, right))
+}
+
+// `left + right` into `left` itself, for a sum nothing else holds; false when it has to be built anew
+pub fn add_in_place<'a>(left: &mut BorrowedValue<'a>, right: &BorrowedValue) -> bool {
+    match (left, right) {
+        (BorrowedValue::String(a), BorrowedValue::String(b)) => a.to_mut().push_str(b),
+        (BorrowedValue::Array(a), BorrowedValue::Array(b)) => a.extend(b.iter().map(clone_value)),
+        (BorrowedValue::Object(a), BorrowedValue::Object(b)) => {
+            for (k, v) in b.iter() { a.insert(Cow::Owned(k.as_ref().to_string()), clone_value(v)); }
+        }
+        _ => return false,
+    }
+    true
 }
 
 #[inline(never)]
@@ -84,6 +217,22 @@ fn apply_arith_nonnum<'a>(left: &BorrowedValue, op: &ArithOp, right: &BorrowedVa
     }
 }
 
+// unary minus, the error message when the value is no number
+pub fn negate<'a>(value: &BorrowedValue) -> Result<BorrowedValue<'a>, String> {
+    match value {
+        BorrowedValue::Static(StaticNode::I64(n)) => Ok(match n.checked_neg() {
+            Some(n) => BorrowedValue::Static(StaticNode::I64(n)),
+            None => BorrowedValue::Static(StaticNode::F64(-(*n as f64))),
+        }),
+        BorrowedValue::Static(StaticNode::U64(n)) => Ok(match i64::try_from(*n) {
+            Ok(n) => BorrowedValue::Static(StaticNode::I64(-n)),
+            Err(_) => BorrowedValue::Static(StaticNode::F64(-(*n as f64))),
+        }),
+        BorrowedValue::Static(StaticNode::F64(n)) => Ok(BorrowedValue::Static(StaticNode::F64(-n))),
+        _ => Err(format!("{} cannot be negated", describe(value))),
+    }
+}
+
 fn extract_numbers(left: &BorrowedValue, right: &BorrowedValue) -> Option<(f64, f64, bool, bool)> {
     let (a_f, a_int) = to_f64(left)?;
     let (b_f, b_int) = to_f64(right)?;
@@ -99,11 +248,11 @@ fn to_f64(v: &BorrowedValue) -> Option<(f64, bool)> {
     }
 }
 
-fn is_truthy(val: &BorrowedValue) -> bool {
+pub fn is_truthy(val: &BorrowedValue) -> bool {
     !matches!(val, BorrowedValue::Static(StaticNode::Null) | BorrowedValue::Static(StaticNode::Bool(false)))
 }
 
-fn compare_values(left: &BorrowedValue, op: &CompareOp, right: &BorrowedValue) -> bool {
+pub fn compare_values(left: &BorrowedValue, op: &CompareOp, right: &BorrowedValue) -> bool {
     match (left, right) {
         (BorrowedValue::Static(StaticNode::I64(a)), BorrowedValue::Static(StaticNode::I64(b))) => apply_op(a, b, op),
         (BorrowedValue::Static(StaticNode::U64(a)), BorrowedValue::Static(StaticNode::U64(b))) => apply_op(a, b, op),
@@ -121,30 +270,8 @@ fn compare_values(left: &BorrowedValue, op: &CompareOp, right: &BorrowedValue) -
         (BorrowedValue::String(a), BorrowedValue::String(b)) => apply_op(&a.as_ref(), &b.as_ref(), op),
         (BorrowedValue::Static(StaticNode::Bool(a)), BorrowedValue::Static(StaticNode::Bool(b))) => apply_op(a, b, op),
         (BorrowedValue::Static(StaticNode::Null), BorrowedValue::Static(StaticNode::Null)) => matches!(op, CompareOp::Eq | CompareOp::Lte | CompareOp::Gte),
-        _ => matches!(op, CompareOp::Neq),
-    }
-}
-
-fn evaluate_condition_tree(value: &BorrowedValue, condition: &Condition) -> bool {
-    match condition {
-        Condition::Comparison(path, op, expr) => {
-            let test_results = process_rust_value(Cow::Borrowed(value), path, None);
-            let lhs = match test_results.first() { Some(v) => v, None => return false };
-            match expr {
-                Expr::Literal(lit) => { let rhs = literal_to_value(lit); compare_values(lhs, op, &rhs) }
-                Expr::Path(rhs_path) => {
-                    let rhs_results = process_rust_value(Cow::Borrowed(value), rhs_path, None);
-                    match rhs_results.first() { Some(r) => compare_values(lhs, op, r), None => false }
-                }
-            }
-        }
-        Condition::BoolPath(path) => {
-            let results = process_rust_value(Cow::Borrowed(value), path, None);
-            results.first().map_or(false, |v| is_truthy(v))
-        }
-        Condition::And(l, r) => evaluate_condition_tree(value, l) && evaluate_condition_tree(value, r),
-        Condition::Or(l, r) => evaluate_condition_tree(value, l) || evaluate_condition_tree(value, r),
-        Condition::Not(inner) => !evaluate_condition_tree(value, inner),
+        // arrays, objects and values of different types go by jq's order of all values
+        _ => apply_op(&cmp_values(left, right), &Ordering::Equal, op),
     }
 }
 
@@ -191,47 +318,55 @@ fn cmp_values(a: &BorrowedValue, b: &BorrowedValue) -> Ordering {
             for (a, b) in x.iter().zip(y.iter()) { let c = cmp_values(a, b); if c != Ordering::Equal { return c; } }
             x.len().cmp(&y.len())
         }
+        // objects go by their sorted keys first, then by their values in that order
+        (BorrowedValue::Object(x), BorrowedValue::Object(y)) => {
+            let mut keys: Vec<&str> = x.keys().map(|k| k.as_ref()).collect();
+            let mut other: Vec<&str> = y.keys().map(|k| k.as_ref()).collect();
+            keys.sort_unstable();
+            other.sort_unstable();
+            keys.cmp(&other).then_with(|| {
+                keys.iter().map(|k| cmp_values(&x[*k], &y[*k])).find(|c| c.is_ne()).unwrap_or(Ordering::Equal)
+            })
+        }
         _ => Ordering::Equal,
     }
 }
 
-fn value_to_json_string(val: &BorrowedValue) -> String {
+// a hash that values equal under `==` share, so sets of values can be looked up by it
+pub fn value_hash(val: &BorrowedValue) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    hash_value(val, &mut hasher);
+    hasher.finish()
+}
+
+fn hash_value(val: &BorrowedValue, hasher: &mut DefaultHasher) {
+    type_order(val).hash(hasher);
     match val {
-        BorrowedValue::Static(StaticNode::Null) => "null".to_string(),
-        BorrowedValue::Static(StaticNode::Bool(b)) => b.to_string(),
-        BorrowedValue::Static(StaticNode::I64(i)) => i.to_string(),
-        BorrowedValue::Static(StaticNode::U64(u)) => u.to_string(),
-        BorrowedValue::Static(StaticNode::F64(f)) => {
-            if f.is_infinite() { if *f > 0.0 { "1.7976931348623157e+308".into() } else { "-1.7976931348623157e+308".into() } }
-            else if f.is_nan() { "null".into() }
-            else { format!("{}", f) }
-        }
-        BorrowedValue::String(s) => {
-            let mut out = String::with_capacity(s.len() + 2);
-            out.push('"');
-            for c in s.as_ref().chars() {
-                match c {
-                    '"' => out.push_str("\\\""), '\\' => out.push_str("\\\\"),
-                    '\n' => out.push_str("\\n"), '\t' => out.push_str("\\t"), '\r' => out.push_str("\\r"),
-                    c if (c as u32) < 0x20 => { out.push_str(&format!("\\u{:04x}", c as u32)); }
-                    c => out.push(c),
-                }
-            }
-            out.push('"');
-            out
-        }
+        BorrowedValue::String(s) => s.hash(hasher),
         BorrowedValue::Array(arr) => {
-            let items: Vec<String> = arr.iter().map(value_to_json_string).collect();
-            format!("[{}]", items.join(","))
+            arr.len().hash(hasher);
+            for v in arr.iter() { hash_value(v, hasher); }
         }
+        // summed per entry, as the keys may come in any order
         BorrowedValue::Object(obj) => {
-            let items: Vec<String> = obj.iter().map(|(k, v)| format!("\"{}\":{}", k.as_ref(), value_to_json_string(v))).collect();
-            format!("{{{}}}", items.join(","))
-        }
+            let sum = obj.iter().fold(0u64, |sum, (k, v)| {
+                let mut entry = DefaultHasher::new();
+                k.hash(&mut entry);
+                hash_value(v, &mut entry);
+                sum.wrapping_add(entry.finish())
+            });
+            sum.hash(hasher);
+        }
+        // integers and floats are equal by value, and so are 0 and -0
+        _ => if let Some((n, _)) = to_f64(val) { (n + 0.0).to_bits().hash(hasher); },
     }
 }
 
-fn value_to_string_repr(val: &BorrowedValue) -> String {
+fn value_to_json_string(val: &BorrowedValue) -> String {
+    to_json(val, &JsonFormat::default())
+}
+
+pub fn value_to_string_repr(val: &BorrowedValue) -> String {
     match val {
         BorrowedValue::String(s) => s.as_ref().to_string(),
         BorrowedValue::Static(StaticNode::Null) => "null".to_string(),
@@ -243,16 +378,6 @@ fn value_to_string_repr(val: &BorrowedValue) -> String {
     }
 }
 
-#[inline(never)]
-fn recurse_values<'a>(val: &'a BorrowedValue<'a>, out: &mut Vec<Cow<'a, BorrowedValue<'a>>>) {
-    out.push(Cow::Borrowed(val));
-    match val {
-        BorrowedValue::Array(arr) => { for item in arr.iter() { recurse_values(item, out); } }
-        BorrowedValue::Object(obj) => { for (_, v) in obj.iter() { recurse_values(v, out); } }
-        _ => {}
-    }
-}
-
 #[inline(never)]
 fn recurse_owned<'a>(val: BorrowedValue<'a>, out: &mut Vec<Cow<'a, BorrowedValue<'a>>>) {
     match val {
@@ -286,7 +411,7 @@ fn flatten_array<'a>(arr: &[BorrowedValue<'a>], depth: Option<u64>) -> Vec<Borro
     let mut out = Vec::new();
     for v in arr {
         if let BorrowedValue::Array(inner) = v {
-            if depth.map_or(true, |d| d > 0) {
+            if depth.is_none_or(|d| d > 0) {
                 out.extend(flatten_array(inner, depth.map(|d| d - 1)));
             } else {
                 out.push(clone_value(v));
@@ -307,159 +432,256 @@ fn value_contains(a: &BorrowedValue, b: &BorrowedValue) -> bool {
     match (a, b) {
         (BorrowedValue::String(a), BorrowedValue::String(b)) => a.as_ref().contains(b.as_ref()),
         (BorrowedValue::Array(a), BorrowedValue::Array(b)) => b.iter().all(|bv| a.iter().any(|av| value_contains(av, bv))),
-        (BorrowedValue::Object(a), BorrowedValue::Object(b)) => b.iter().all(|(bk, bv)| a.get(bk.as_ref()).map_or(false, |av| value_contains(av, bv))),
+        (BorrowedValue::Object(a), BorrowedValue::Object(b)) => b.iter().all(|(bk, bv)| a.get(bk.as_ref()).is_some_and(|av| value_contains(av, bv))),
         _ => values_equal(a, b),
     }
 }
 
-fn owned_to_borrowed<'a>(val: simd_json::OwnedValue) -> BorrowedValue<'a> {
-    match val {
-        simd_json::OwnedValue::Static(s) => BorrowedValue::Static(s),
-        simd_json::OwnedValue::String(s) => BorrowedValue::String(Cow::Owned(s)),
-        simd_json::OwnedValue::Array(arr) => make_array(arr.into_iter().map(owned_to_borrowed).collect()),
-        simd_json::OwnedValue::Object(obj) => {
-            let mut new_obj = Object::with_capacity(obj.len());
-            for (k, v) in obj.into_iter() { new_obj.insert(Cow::Owned(k.to_string()), owned_to_borrowed(v)); }
-            BorrowedValue::Object(Box::new(new_obj))
+// ─── lazy streams ──────────────────────────────────────────────────────────────
+
+// generator outputs are pulled on demand, so cuts like `first(f)` and `limit` stop upstream work
+pub type ValueStream<'a> = Box<dyn Iterator<Item = Cow<'a, BorrowedValue<'a>>> + Send + 'a>;
+
+// outputs of one filter applied to one value
+// most filters yield zero or one value, so those cases stay allocation-free
+pub enum Outputs<'a> {
+    Empty,
+    One(Cow<'a, BorrowedValue<'a>>),
+    Many(ValueStream<'a>),
+}
+
+impl<'a> Outputs<'a> {
+    fn push(&mut self, v: Cow<'a, BorrowedValue<'a>>) {
+        *self = match std::mem::replace(self, Outputs::Empty) {
+            Outputs::Empty => Outputs::One(v),
+            Outputs::One(first) => Outputs::Many(Box::new([first, v].into_iter())),
+            Outputs::Many(rest) => Outputs::Many(Box::new(rest.chain(std::iter::once(v)))),
+        };
+    }
+}
+
+impl<'a> Iterator for Outputs<'a> {
+    type Item = Cow<'a, BorrowedValue<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Outputs::Empty => None,
+            Outputs::One(_) => match std::mem::replace(self, Outputs::Empty) {
+                Outputs::One(v) => Some(v),
+                _ => None,
+            },
+            Outputs::Many(stream) => stream.next(),
         }
     }
 }
 
-// ─── main pipeline ─────────────────────────────────────────────────────────────
+// pre-order walk over a borrowed value and all of its descendants
+struct Descendants<'a> {
+    stack: Vec<&'a BorrowedValue<'a>>,
+}
 
-pub fn process_rust_value<'a>(root: Cow<'a, BorrowedValue<'a>>, filters: &[RustyFilter], limit: Option<usize>) -> Vec<Cow<'a, BorrowedValue<'a>>> {
-    let mut current_results: Vec<Cow<'a, BorrowedValue<'a>>> = vec![root];
+impl<'a> Iterator for Descendants<'a> {
+    type Item = Cow<'a, BorrowedValue<'a>>;
 
-    for (filter_idx, filter) in filters.iter().enumerate() {
-        let mut next_results = Vec::with_capacity(current_results.len());
+    fn next(&mut self) -> Option<Self::Item> {
+        let val = self.stack.pop()?;
+        let start = self.stack.len();
+        match val {
+            BorrowedValue::Array(arr) => self.stack.extend(arr.iter()),
+            BorrowedValue::Object(obj) => self.stack.extend(obj.values()),
+            _ => {}
+        }
+        // children are popped from the end, so reverse them to keep document order
+        self.stack[start..].reverse();
+        Some(Cow::Borrowed(val))
+    }
+}
 
-        for value in current_results {
-            match filter {
-                RustyFilter::Identity => { next_results.push(value); }
-                RustyFilter::Field(key) => {
-                    match value {
-                        Cow::Borrowed(b_val) => {
-                            if let Some(obj) = b_val.as_object() {
-                                if let Some(child) = obj.get(key.as_str()) { next_results.push(Cow::Borrowed(child)); }
-                            }
-                        }
-                        Cow::Owned(o_val) => {
-                            if let BorrowedValue::Object(mut obj) = o_val {
-                                if let Some(child) = obj.remove(key.as_str()) { next_results.push(Cow::Owned(child)); }
-                            }
-                        }
-                    }
-                }
-                RustyFilter::Index(idx) => {
-                    match value {
-                        Cow::Borrowed(b_val) => {
-                            if let Some(arr) = b_val.as_array() {
-                                let len = arr.len() as isize;
-                                let abs_idx = if *idx < 0 { len + *idx as isize } else { *idx as isize };
-                                if abs_idx >= 0 && (abs_idx as usize) < (len as usize) { next_results.push(Cow::Borrowed(&arr[abs_idx as usize])); }
-                            }
-                        }
-                        Cow::Owned(o_val) => {
-                            if let BorrowedValue::Array(mut arr) = o_val {
-                                let len = arr.len() as isize;
-                                let abs_idx = if *idx < 0 { len + *idx as isize } else { *idx as isize };
-                                if abs_idx >= 0 && (abs_idx as usize) < (len as usize) { next_results.push(Cow::Owned(arr.swap_remove(abs_idx as usize))); }
-                            }
-                        }
-                    }
-                }
-                RustyFilter::Iterator => {
-                    match value {
-                        Cow::Borrowed(b_val) => {
-                            if let Some(arr) = b_val.as_array() { next_results.extend(arr.iter().map(Cow::Borrowed)); }
-                            else if let Some(obj) = b_val.as_object() { next_results.extend(obj.values().map(Cow::Borrowed)); }
-                        }
-                        Cow::Owned(o_val) => {
-                            match o_val {
-                                BorrowedValue::Array(arr) => { next_results.extend(arr.into_iter().map(Cow::Owned)); }
-                                BorrowedValue::Object(obj) => { next_results.extend(obj.into_iter().map(|(_, v)| Cow::Owned(v))); }
-                                _ => {}
-                            }
-                        }
-                    }
-                }
-                RustyFilter::Object(pairs) => {
-                    let mut product_objects: Vec<Object> = vec![Object::new()];
-                    for (key, sub_query) in pairs {
-                        let field_results = process_rust_value(value.clone(), sub_query, None);
-                        if field_results.is_empty() { product_objects.clear(); break; }
-                        let mut new_product_objects = Vec::with_capacity(product_objects.len() * field_results.len());
-                        for partial_obj in &product_objects {
-                            for field_val in &field_results {
-                                let mut new_obj: Object = partial_obj.clone();
-                                new_obj.insert(Cow::Owned(key.clone()), field_val.clone().into_owned());
-                                new_product_objects.push(new_obj);
-                            }
-                        }
-                        product_objects = new_product_objects;
-                    }
-                    for obj in product_objects { next_results.push(Cow::Owned(BorrowedValue::Object(Box::new(obj)))); }
-                }
-                RustyFilter::Select(condition) => {
-                    if evaluate_condition_tree(&value, condition) { next_results.push(value); }
-                }
-                RustyFilter::Comma(branches) => {
-                    for branch in branches { next_results.extend(process_rust_value(value.clone(), branch, None)); }
-                }
-                RustyFilter::LiteralValue(lit) => { next_results.push(Cow::Owned(literal_to_value(lit))); }
-                RustyFilter::Arithmetic(left, op, right) => {
-                    let left_results = process_rust_value(value.clone(), left, None);
-                    let right_results = process_rust_value(value, right, None);
-                    if let (Some(lv), Some(rv)) = (left_results.first(), right_results.first()) {
-                        if let Some(result) = apply_arith(lv, op, rv) { next_results.push(Cow::Owned(result)); }
-                    }
-                }
-                RustyFilter::RecurseDescent => {
-                    match &value {
-                        Cow::Borrowed(b_val) => { recurse_values(b_val, &mut next_results); }
-                        Cow::Owned(_) => {
-                            let owned = value.into_owned();
-                            next_results.push(Cow::Owned(clone_value(&owned)));
-                            recurse_owned(owned, &mut next_results);
-                        }
-                    }
+pub fn recurse_lazy<'a>(value: Cow<'a, BorrowedValue<'a>>) -> Outputs<'a> {
+    match value {
+        Cow::Borrowed(b_val) => Outputs::Many(Box::new(Descendants { stack: vec![b_val] })),
+        Cow::Owned(owned) => {
+            let mut out = vec![Cow::Owned(clone_value(&owned))];
+            recurse_owned(owned, &mut out);
+            Outputs::Many(Box::new(out.into_iter()))
+        }
+    }
+}
+
+// ─── path primitives ───────────────────────────────────────────────────────────
+// owned inputs are taken apart instead of cloned, borrowed inputs stay zero-copy
+
+pub fn get_field<'a>(value: Cow<'a, BorrowedValue<'a>>, key: &str) -> Option<Cow<'a, BorrowedValue<'a>>> {
+    match value {
+        Cow::Borrowed(b_val) => b_val.as_object().and_then(|obj| obj.get(key)).map(Cow::Borrowed),
+        Cow::Owned(BorrowedValue::Object(mut obj)) => obj.remove(key).map(Cow::Owned),
+        Cow::Owned(_) => None,
+    }
+}
+
+fn resolve_index(idx: i32, len: usize) -> Option<usize> {
+    let abs_idx = if idx < 0 { len as isize + idx as isize } else { idx as isize };
+    if abs_idx < 0 || abs_idx >= len as isize { None } else { Some(abs_idx as usize) }
+}
+
+pub fn get_index<'a>(value: Cow<'a, BorrowedValue<'a>>, idx: i32) -> Option<Cow<'a, BorrowedValue<'a>>> {
+    let i = resolve_index(idx, value.as_array()?.len())?;
+    match value {
+        Cow::Borrowed(b_val) => b_val.as_array().map(|arr| Cow::Borrowed(&arr[i])),
+        Cow::Owned(BorrowedValue::Array(mut arr)) => Some(Cow::Owned(arr.swap_remove(i))),
+        Cow::Owned(_) => None,
+    }
+}
+
+// fused `.a.b[0]` lookup, walks borrowed values without intermediate stack traffic
+pub fn lookup_path<'v, 'a>(value: &'v BorrowedValue<'a>, steps: &[PathStep]) -> Option<&'v BorrowedValue<'a>> {
+    steps.iter().try_fold(value, |v, step| match step {
+        PathStep::Field(key) => v.as_object()?.get(key.as_str()),
+        PathStep::Index(idx) => {
+            let arr = v.as_array()?;
+            arr.get(resolve_index(*idx, arr.len())?)
+        }
+    })
+}
+
+pub fn get_path<'a>(value: Cow<'a, BorrowedValue<'a>>, steps: &[PathStep]) -> Option<Cow<'a, BorrowedValue<'a>>> {
+    match value {
+        Cow::Borrowed(b_val) => lookup_path(b_val, steps).map(Cow::Borrowed),
+        owned => steps.iter().try_fold(owned, |v, step| match step {
+            PathStep::Field(key) => get_field(v, key),
+            PathStep::Index(idx) => get_index(v, *idx),
+        }),
+    }
+}
+
+pub fn iterate<'a>(value: Cow<'a, BorrowedValue<'a>>) -> Outputs<'a> {
+    match value {
+        Cow::Borrowed(BorrowedValue::Array(arr)) => Outputs::Many(Box::new(arr.as_slice().iter().map(Cow::Borrowed))),
+        Cow::Borrowed(BorrowedValue::Object(obj)) => Outputs::Many(Box::new(obj.values().map(Cow::Borrowed))),
+        Cow::Owned(BorrowedValue::Array(arr)) => Outputs::Many(Box::new(arr.into_iter().map(Cow::Owned))),
+        Cow::Owned(BorrowedValue::Object(obj)) => Outputs::Many(Box::new(obj.into_iter().map(|(_, v)| Cow::Owned(v)))),
+        _ => Outputs::Empty,
+    }
+}
+
+pub fn slice<'a>(value: &BorrowedValue, start: Option<i64>, end: Option<i64>) -> Option<BorrowedValue<'a>> {
+    match value {
+        BorrowedValue::Array(arr) => {
+            let len = arr.len() as i64;
+            let s = resolve_slice_index(start.unwrap_or(0), len);
+            let e = resolve_slice_index(end.unwrap_or(len), len);
+            let sliced: Vec<BorrowedValue<'a>> = if s < e { arr[s..e].iter().map(clone_value).collect() } else { Vec::new() };
+            Some(make_array(sliced))
+        }
+        BorrowedValue::String(s_val) => {
+            let chars: Vec<char> = s_val.as_ref().chars().collect();
+            let len = chars.len() as i64;
+            let s = resolve_slice_index(start.unwrap_or(0), len);
+            let e = resolve_slice_index(end.unwrap_or(len), len);
+            let sliced: String = if s < e { chars[s..e].iter().collect() } else { String::new() };
+            Some(BorrowedValue::String(Cow::Owned(sliced)))
+        }
+        _ => None,
+    }
+}
+
+// ─── assignment ────────────────────────────────────────────────────────────────
+// paths are followed as jq's `setpath` does: null grows into the object or array a step needs
+
+// a step that cannot be taken from `value`
+#[cold]
+fn index_fault(value: &BorrowedValue, step: &PathStep, at: &[PathStep]) -> Fault {
+    let message = match step {
+        PathStep::Field(key) => format!("Cannot index {} with \"{}\"", type_name(value), key),
+        PathStep::Index(_) => format!("Cannot index {} with number", type_name(value)),
+    };
+    Fault { message, value: truncated_json(value), at: at.to_vec() }
+}
+
+// replace the value at `path` in `root` with `value`
+pub fn set_path<'a>(root: &mut BorrowedValue<'a>, path: &[PathStep], value: BorrowedValue<'a>) -> Result<(), Fault> {
+    let mut current = root;
+    for (depth, step) in path.iter().enumerate() {
+        if matches!(current, BorrowedValue::Static(StaticNode::Null)) {
+            *current = match step {
+                PathStep::Field(_) => BorrowedValue::Object(Box::new(Object::new())),
+                PathStep::Index(_) => make_array(Vec::new()),
+            };
+        }
+        current = match (current, step) {
+            (BorrowedValue::Object(obj), PathStep::Field(key)) => {
+                if !obj.contains_key(key.as_str()) {
+                    obj.insert(Cow::Owned(key.clone()), BorrowedValue::Static(StaticNode::Null));
                 }
-                RustyFilter::Slice(start, end) => {
-                    match &*value {
-                        BorrowedValue::Array(arr) => {
-                            let len = arr.len() as i64;
-                            let s = resolve_slice_index(start.unwrap_or(0), len);
-                            let e = resolve_slice_index(end.unwrap_or(len), len);
-                            let sliced: Vec<BorrowedValue<'a>> = if s < e { arr[s..e].iter().map(clone_value).collect() } else { Vec::new() };
-                            next_results.push(Cow::Owned(make_array(sliced)));
-                        }
-                        BorrowedValue::String(s_val) => {
-                            let chars: Vec<char> = s_val.as_ref().chars().collect();
-                            let len = chars.len() as i64;
-                            let s = resolve_slice_index(start.unwrap_or(0), len);
-                            let e = resolve_slice_index(end.unwrap_or(len), len);
-                            let sliced: String = if s < e { chars[s..e].iter().collect() } else { String::new() };
-                            next_results.push(Cow::Owned(BorrowedValue::String(Cow::Owned(sliced))));
-                        }
-                        _ => {}
-                    }
+                obj.get_mut(key.as_str()).expect("inserted above")
+            }
+            (BorrowedValue::Array(arr), PathStep::Index(idx)) => {
+                let i = if *idx < 0 { arr.len() as i64 + *idx as i64 } else { *idx as i64 };
+                if i < 0 {
+                    return Err(Fault { message: "Out of bounds negative array index".to_string(), value: idx.to_string(), at: path[..depth].to_vec() });
                 }
-                RustyFilter::Builtin0(b) => { exec_builtin0(b, value, &mut next_results); }
-                RustyFilter::Builtin1(b, arg) => { exec_builtin1(b, arg, value, &mut next_results); }
+                let i = i as usize;
+                if i >= arr.len() { arr.resize(i + 1, BorrowedValue::Static(StaticNode::Null)); }
+                &mut arr[i]
             }
-            if filter_idx == filters.len() - 1 {
-                if let Some(lim) = limit { if next_results.len() >= lim { break; } }
+            (other, step) => return Err(index_fault(other, step, &path[..depth])),
+        };
+    }
+    *current = value;
+    Ok(())
+}
+
+// remove the value at each of `paths` from `root`, the later elements of an array first so the
+// indices of the others stay put; paths that lead nowhere are left alone
+pub fn delete_paths(root: &mut BorrowedValue, mut paths: Vec<Vec<PathStep>>) {
+    paths.sort_by(|a, b| compare_paths(a, b));
+    for path in paths.into_iter().rev() {
+        let Some((last, parent)) = path.split_last() else {
+            *root = BorrowedValue::Static(StaticNode::Null);
+            continue;
+        };
+        let parent = parent.iter().try_fold(&mut *root, |v, step| match (v, step) {
+            (BorrowedValue::Object(obj), PathStep::Field(key)) => obj.get_mut(key.as_str()),
+            (BorrowedValue::Array(arr), PathStep::Index(idx)) => {
+                let i = resolve_index(*idx, arr.len())?;
+                arr.get_mut(i)
+            }
+            _ => None,
+        });
+        match (parent, last) {
+            (Some(BorrowedValue::Object(obj)), PathStep::Field(key)) => { obj.remove(key.as_str()); }
+            (Some(BorrowedValue::Array(arr)), PathStep::Index(idx)) => {
+                if let Some(i) = resolve_index(*idx, arr.len()) { arr.remove(i); }
             }
+            _ => {}
         }
-        current_results = next_results;
     }
-    current_results
+}
+
+fn compare_paths(a: &[PathStep], b: &[PathStep]) -> Ordering {
+    for (x, y) in a.iter().zip(b) {
+        let order = match (x, y) {
+            (PathStep::Index(x), PathStep::Index(y)) => x.cmp(y),
+            (PathStep::Field(x), PathStep::Field(y)) => x.cmp(y),
+            (PathStep::Index(_), PathStep::Field(_)) => Ordering::Less,
+            (PathStep::Field(_), PathStep::Index(_)) => Ordering::Greater,
+        };
+        if order != Ordering::Equal { return order; }
+    }
+    a.len().cmp(&b.len())
+}
+
+// the message when a filter on the left of an assignment is no path
+#[cold]
+pub fn invalid_path(value: &BorrowedValue) -> Fault {
+    let value = truncated_json(value);
+    Fault { message: format!("Invalid path expression with result {}", value), value, at: Vec::new() }
 }
 
 // ─── builtin0 (no-arg) ─────────────────────────────────────────────────────────
 
 #[inline(never)]
-fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut Vec<Cow<'a, BorrowedValue<'a>>>) {
+pub fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut Outputs<'a>) -> Result<(), Fault> {
     match b {
         Builtin0::Length => {
             let n: i64 = match &*value {
@@ -469,8 +691,8 @@ fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut
                 BorrowedValue::Static(StaticNode::Null) => 0,
                 BorrowedValue::Static(StaticNode::I64(i)) => i.abs(),
                 BorrowedValue::Static(StaticNode::U64(u)) => *u as i64,
-                BorrowedValue::Static(StaticNode::F64(f)) => { out.push(Cow::Owned(BorrowedValue::Static(StaticNode::F64(f.abs())))); return; }
-                _ => return,
+                BorrowedValue::Static(StaticNode::F64(f)) => { out.push(Cow::Owned(BorrowedValue::Static(StaticNode::F64(f.abs())))); return Ok(()); }
+                _ => return Ok(()),
             };
             out.push(Cow::Owned(BorrowedValue::Static(StaticNode::I64(n))));
         }
@@ -506,15 +728,7 @@ fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut
             }
         }
         Builtin0::Type => {
-            let t = match &*value {
-                BorrowedValue::Static(StaticNode::Null) => "null",
-                BorrowedValue::Static(StaticNode::Bool(_)) => "boolean",
-                BorrowedValue::Static(StaticNode::I64(_)) | BorrowedValue::Static(StaticNode::U64(_)) | BorrowedValue::Static(StaticNode::F64(_)) => "number",
-                BorrowedValue::String(_) => "string",
-                BorrowedValue::Array(_) => "array",
-                BorrowedValue::Object(_) => "object",
-            };
-            out.push(Cow::Owned(BorrowedValue::String(Cow::Owned(t.to_string()))));
+            out.push(Cow::Owned(BorrowedValue::String(Cow::Owned(type_name(&value).to_string()))));
         }
         Builtin0::Reverse => {
             match &*value {
@@ -549,7 +763,7 @@ fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut
                 } else {
                     let mut acc = clone_value(&arr[0]);
                     for item in arr.iter().skip(1) {
-                        if let Some(result) = apply_arith(&acc, &ArithOp::Add, item) { acc = result; }
+                        if let Ok(result) = apply_arith(&acc, &ArithOp::Add, item) { acc = result; }
                     }
                     out.push(Cow::Owned(acc));
                 }
@@ -647,9 +861,11 @@ fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut
         }
         Builtin0::Fromjson => {
             if let BorrowedValue::String(s) = &*value {
+                // held to the default parse limits, as untrusted input may carry JSON text inside strings
                 let mut bytes = s.as_ref().as_bytes().to_vec();
-                if let Ok(parsed) = simd_json::to_owned_value(&mut bytes) {
-                    out.push(Cow::Owned(owned_to_borrowed(parsed)));
+                let parsed = parse(&mut bytes, &Projection::all(), &ParseLimits::default()).map(|parsed| clone_value(&parsed));
+                if let Ok(parsed) = parsed {
+                    out.push(Cow::Owned(parsed));
                 }
             }
         }
@@ -660,14 +876,25 @@ fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut
             }
         }
         Builtin0::Implode => {
-            if let BorrowedValue::Array(arr) = &*value {
-                let s: String = arr.iter().filter_map(|v| match v {
-                    BorrowedValue::Static(StaticNode::I64(i)) => char::from_u32(*i as u32),
-                    BorrowedValue::Static(StaticNode::U64(u)) => char::from_u32(*u as u32),
+            let BorrowedValue::Array(arr) = &*value else {
+                return Err(Fault { message: format!("implode input must be an array, not {}", describe(&value)), value: truncated_json(&value), at: Vec::new() });
+            };
+            let mut s = String::with_capacity(arr.len());
+            for (i, v) in arr.iter().enumerate() {
+                let c = match v {
+                    BorrowedValue::Static(StaticNode::I64(n)) => u32::try_from(*n).ok().and_then(char::from_u32),
+                    BorrowedValue::Static(StaticNode::U64(n)) => u32::try_from(*n).ok().and_then(char::from_u32),
                     _ => None,
-                }).collect();
-                out.push(Cow::Owned(BorrowedValue::String(Cow::Owned(s))));
+                };
+                match c {
+                    Some(c) => s.push(c),
+                    None => {
+                        let message = format!("{} is not a valid codepoint to implode", describe(v));
+                        return Err(Fault { message, value: truncated_json(v), at: vec![PathStep::Index(i as i32)] });
+                    }
+                }
             }
+            out.push(Cow::Owned(BorrowedValue::String(Cow::Owned(s))));
         }
         Builtin0::Floor => {
             match &*value {
@@ -704,34 +931,27 @@ fn exec_builtin0<'a>(b: &Builtin0, value: Cow<'a, BorrowedValue<'a>>, out: &mut
         Builtin0::Nan => { out.push(Cow::Owned(BorrowedValue::Static(StaticNode::F64(f64::NAN)))); }
         Builtin0::Infinite => { out.push(Cow::Owned(BorrowedValue::Static(StaticNode::F64(f64::INFINITY)))); }
         Builtin0::Isinfinite => {
-            let r = to_f64(&value).map_or(false, |(f, _)| f.is_infinite());
+            let r = to_f64(&value).is_some_and(|(f, _)| f.is_infinite());
             out.push(Cow::Owned(BorrowedValue::Static(StaticNode::Bool(r))));
         }
         Builtin0::Isnan => {
-            let r = to_f64(&value).map_or(false, |(f, _)| f.is_nan());
+            let r = to_f64(&value).is_some_and(|(f, _)| f.is_nan());
             out.push(Cow::Owned(BorrowedValue::Static(StaticNode::Bool(r))));
         }
         Builtin0::Isnormal => {
-            let r = to_f64(&value).map_or(false, |(f, _)| f.is_normal());
+            let r = to_f64(&value).is_some_and(|(f, _)| f.is_normal());
             out.push(Cow::Owned(BorrowedValue::Static(StaticNode::Bool(r))));
         }
-        Builtin0::Recurse => {
-            match &value {
-                Cow::Borrowed(b_val) => { recurse_values(b_val, out); }
-                Cow::Owned(_) => {
-                    let owned = value.into_owned();
-                    out.push(Cow::Owned(clone_value(&owned)));
-                    recurse_owned(owned, out);
-                }
-            }
-        }
+        Builtin0::Recurse => { *out = recurse_lazy(value); }
+        Builtin0::ToStream => { *out = Outputs::Many(Box::new(to_stream(&value).into_iter().map(Cow::Owned))); }
     }
+    Ok(())
 }
 
 // ─── builtin1 (one-arg) ────────────────────────────────────────────────────────
 
 #[inline(never)]
-fn exec_builtin1<'a>(b: &Builtin1, arg: &Literal, value: Cow<'a, BorrowedValue<'a>>, out: &mut Vec<Cow<'a, BorrowedValue<'a>>>) {
+pub fn exec_builtin1<'a>(b: &Builtin1, arg: &Literal, value: Cow<'a, BorrowedValue<'a>>, out: &mut Outputs<'a>) {
     match b {
         Builtin1::Has => {
             let r = match (&*value, arg) {
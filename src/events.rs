@@ -0,0 +1,358 @@
+// jq's streaming form of JSON: a value is described by `[path, leaf]` events for its scalars and
+// empty containers, and a `[path]` event closing each non-empty container, naming its last child
+// `{"a":[1,2]}` streams as `[["a",0],1]`, `[["a",1],2]`, `[["a",1]]`, `[["a"]]`
+use simd_json::borrowed::Object;
+use simd_json::prelude::*;
+use simd_json::{BorrowedValue, StaticNode};
+use std::borrow::Cow;
+
+use crate::limits::{ParseError, ParseLimits};
+use crate::stream::{ChunkReader, StreamError};
+
+type Value<'a> = Cow<'a, BorrowedValue<'a>>;
+
+fn leaf_event<'a>(path: Vec<BorrowedValue<'a>>, leaf: BorrowedValue<'a>) -> BorrowedValue<'a> {
+    BorrowedValue::Array(Box::new(vec![BorrowedValue::Array(Box::new(path)), leaf]))
+}
+
+fn closing_event(path: Vec<BorrowedValue<'_>>) -> BorrowedValue<'_> {
+    BorrowedValue::Array(Box::new(vec![BorrowedValue::Array(Box::new(path))]))
+}
+
+fn index(i: usize) -> BorrowedValue<'static> {
+    BorrowedValue::Static(StaticNode::I64(i as i64))
+}
+
+// ─── tostream ─────────────────────────────────────────────────────────────────
+
+// the events of a value, in the order a streaming parse produces them
+pub fn to_stream<'a>(value: &BorrowedValue<'a>) -> Vec<BorrowedValue<'a>> {
+    let mut events = Vec::new();
+    walk(value, &mut Vec::new(), &mut events);
+    events
+}
+
+fn walk<'a>(value: &BorrowedValue<'a>, path: &mut Vec<BorrowedValue<'a>>, events: &mut Vec<BorrowedValue<'a>>) {
+    match value {
+        BorrowedValue::Array(arr) if !arr.is_empty() => {
+            for (i, item) in arr.iter().enumerate() {
+                path.push(index(i));
+                walk(item, path, events);
+                path.pop();
+            }
+            path.push(index(arr.len() - 1));
+            events.push(closing_event(path.clone()));
+            path.pop();
+        }
+        BorrowedValue::Object(obj) if !obj.is_empty() => {
+            let mut last = None;
+            for (k, v) in obj.iter() {
+                path.push(BorrowedValue::String(k.clone()));
+                walk(v, path, events);
+                last = path.pop();
+            }
+            path.extend(last);
+            events.push(closing_event(path.clone()));
+            path.pop();
+        }
+        leaf => events.push(leaf_event(path.clone(), leaf.clone())),
+    }
+}
+
+// ─── fromstream ───────────────────────────────────────────────────────────────
+
+// rebuilds the top-level values whose events come out of `events`
+// events that do not fit the value being built, or would nest it too deep, are skipped
+pub struct FromStream<'a, I> {
+    events: I,
+    value: BorrowedValue<'a>,
+}
+
+impl<'a, I: Iterator<Item = Value<'a>>> FromStream<'a, I> {
+    pub fn new(events: I) -> Self {
+        FromStream { events, value: BorrowedValue::Static(StaticNode::Null) }
+    }
+}
+
+impl<'a, I: Iterator<Item = Value<'a>>> Iterator for FromStream<'a, I> {
+    type Item = Value<'a>;
+
+    fn next(&mut self) -> Option<Value<'a>> {
+        loop {
+            let BorrowedValue::Array(mut items) = self.events.next()?.into_owned() else { continue };
+            let leaf = if items.len() == 2 { items.pop() } else { None };
+            let Some(BorrowedValue::Array(path)) = items.first() else { continue };
+            // a value deeper than any document may be is not rebuilt
+            if path.len() > ParseLimits::MAX_DEPTH { continue; }
+            match leaf {
+                // a top-level scalar or empty container is complete on its own
+                Some(leaf) if path.is_empty() => return Some(Cow::Owned(leaf)),
+                Some(leaf) => set_path(&mut self.value, path, leaf),
+                // closing a top-level container completes it
+                None if path.len() == 1 => {
+                    let value = std::mem::replace(&mut self.value, BorrowedValue::Static(StaticNode::Null));
+                    return Some(Cow::Owned(value));
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+// store `leaf` at `path`, creating the objects and arrays on the way
+fn set_path<'a>(target: &mut BorrowedValue<'a>, path: &[BorrowedValue<'a>], leaf: BorrowedValue<'a>) {
+    let Some((step, rest)) = path.split_first() else {
+        *target = leaf;
+        return;
+    };
+    match step {
+        BorrowedValue::String(key) => {
+            if !matches!(target, BorrowedValue::Object(_)) {
+                *target = BorrowedValue::Object(Box::new(Object::default()));
+            }
+            if let BorrowedValue::Object(obj) = target {
+                let child = obj.entry(key.clone()).or_insert(BorrowedValue::Static(StaticNode::Null));
+                set_path(child, rest, leaf);
+            }
+        }
+        // indices parsed from JSON text are unsigned, those the query builds signed
+        index => {
+            let Some(i) = index.as_usize() else { return };
+            if !matches!(target, BorrowedValue::Array(_)) {
+                *target = BorrowedValue::Array(Box::default());
+            }
+            if let BorrowedValue::Array(arr) = target {
+                if arr.len() <= i { arr.resize(i + 1, BorrowedValue::Static(StaticNode::Null)); }
+                set_path(&mut arr[i], rest, leaf);
+            }
+        }
+    }
+}
+
+// ─── truncate_stream ──────────────────────────────────────────────────────────
+
+// an event with the first `depth` steps of its path removed, dropping events at or above that depth
+pub fn truncate(event: Value<'_>, depth: usize) -> Option<Value<'_>> {
+    let BorrowedValue::Array(items) = event.as_ref() else { return None };
+    if !matches!(items.first(), Some(BorrowedValue::Array(path)) if path.len() > depth) { return None; }
+    let mut event = event.into_owned();
+    if let BorrowedValue::Array(items) = &mut event {
+        if let Some(BorrowedValue::Array(path)) = items.first_mut() { path.drain(..depth); }
+    }
+    Some(Cow::Owned(event))
+}
+
+// ─── streaming parser ─────────────────────────────────────────────────────────
+
+enum Token {
+    // one of `{}[],:`
+    Punct(u8),
+    Leaf(BorrowedValue<'static>),
+}
+
+// the containers the parser is inside, and where in each
+enum Frame {
+    Array(usize),
+    // `None` until the first key is read
+    Object(Option<Cow<'static, str>>),
+}
+
+#[derive(Clone, Copy)]
+enum Expect {
+    Value,
+    // right after `[` or `{`, where the container may also close
+    FirstElement,
+    FirstKey,
+    Key,
+    Colon,
+    CommaOrClose,
+}
+
+// turns JSON text into events without ever holding more than one scalar of it
+pub struct EventReader {
+    input: ChunkReader,
+    limits: ParseLimits,
+    stack: Vec<Frame>,
+    expect: Expect,
+    // the text is malformed; nothing after it can be made sense of
+    failed: bool,
+}
+
+impl EventReader {
+    pub fn new(input: ChunkReader, limits: &ParseLimits) -> Self {
+        EventReader { input, limits: *limits, stack: Vec::new(), expect: Expect::Value, failed: false }
+    }
+
+    pub fn next(&mut self) -> Option<Result<BorrowedValue<'static>, StreamError>> {
+        if self.failed { return None; }
+        let event = self.next_event().transpose();
+        if matches!(event, Some(Err(_))) { self.failed = true; }
+        event
+    }
+
+    fn next_event(&mut self) -> Result<Option<BorrowedValue<'static>>, StreamError> {
+        loop {
+            let Some(token) = self.token()? else {
+                return match self.expect {
+                    Expect::Value if self.stack.is_empty() => Ok(None),
+                    _ => Err(self.error("unfinished value at the end of the input")),
+                };
+            };
+            if let Token::Leaf(BorrowedValue::String(s)) = &token {
+                if let Some(max) = self.limits.max_string_length.filter(|max| s.len() > *max) {
+                    return Err(self.limit(ParseError::StringLength(max)));
+                }
+            }
+            if matches!(token, Token::Punct(b'[' | b'{')) && self.stack.len() == self.limits.depth() {
+                return Err(self.limit(ParseError::Depth(self.limits.depth())));
+            }
+            match (self.expect, token) {
+                (Expect::Value | Expect::FirstElement, Token::Punct(b'[')) => {
+                    self.stack.push(Frame::Array(0));
+                    self.expect = Expect::FirstElement;
+                }
+                (Expect::Value | Expect::FirstElement, Token::Punct(b'{')) => {
+                    self.stack.push(Frame::Object(None));
+                    self.expect = Expect::FirstKey;
+                }
+                (Expect::Value | Expect::FirstElement, Token::Leaf(leaf)) => return Ok(Some(self.leaf(leaf))),
+                (Expect::FirstElement, Token::Punct(b']')) => {
+                    self.stack.pop();
+                    return Ok(Some(self.leaf(BorrowedValue::Array(Box::default()))));
+                }
+                (Expect::FirstKey, Token::Punct(b'}')) => {
+                    self.stack.pop();
+                    return Ok(Some(self.leaf(BorrowedValue::Object(Box::default()))));
+                }
+                (Expect::FirstKey | Expect::Key, Token::Leaf(BorrowedValue::String(key))) => {
+                    if let Some(Frame::Object(current)) = self.stack.last_mut() { *current = Some(key); }
+                    self.expect = Expect::Colon;
+                }
+                (Expect::Colon, Token::Punct(b':')) => self.expect = Expect::Value,
+                (Expect::CommaOrClose, Token::Punct(b',')) => {
+                    self.expect = match self.stack.last_mut() {
+                        Some(Frame::Array(i)) => { *i += 1; Expect::Value }
+                        _ => Expect::Key,
+                    };
+                }
+                (Expect::CommaOrClose, Token::Punct(close @ (b']' | b'}'))) => {
+                    if !matches!((self.stack.last(), close), (Some(Frame::Array(_)), b']') | (Some(Frame::Object(_)), b'}')) {
+                        return Err(self.error("mismatched closing bracket"));
+                    }
+                    let event = closing_event(self.path());
+                    self.stack.pop();
+                    self.after_value();
+                    return Ok(Some(event));
+                }
+                _ => return Err(self.error("unexpected token")),
+            }
+        }
+    }
+
+    // the event of a scalar or empty container at the current path
+    fn leaf(&mut self, leaf: BorrowedValue<'static>) -> BorrowedValue<'static> {
+        let event = leaf_event(self.path(), leaf);
+        self.after_value();
+        event
+    }
+
+    fn after_value(&mut self) {
+        self.expect = if self.stack.is_empty() { Expect::Value } else { Expect::CommaOrClose };
+    }
+
+    fn path(&self) -> Vec<BorrowedValue<'static>> {
+        self.stack.iter().map(|frame| match frame {
+            Frame::Array(i) => index(*i),
+            Frame::Object(key) => BorrowedValue::String(key.clone().unwrap_or_default()),
+        }).collect()
+    }
+
+    pub fn line(&self) -> usize {
+        self.input.line
+    }
+
+    fn error(&self, message: &'static str) -> StreamError {
+        StreamError::structure(self.input.line, message)
+    }
+
+    // the input going over one of its parse limits
+    fn limit(&self, error: ParseError) -> StreamError {
+        StreamError::parse(self.input.line, error, Vec::new())
+    }
+
+    // the next punctuation mark or scalar, reading more input as needed
+    fn token(&mut self) -> Result<Option<Token>, StreamError> {
+        loop {
+            let input = &mut self.input;
+            while input.pos < input.pending.len() && input.pending[input.pos].is_ascii_whitespace() {
+                if input.pending[input.pos] == b'\n' { input.line += 1; }
+                input.pos += 1;
+            }
+            let bytes = &input.pending[input.pos..];
+            let Some(&c) = bytes.first() else {
+                if input.eof { return Ok(None); }
+                input.fill().map_err(StreamError::read)?;
+                continue;
+            };
+            if b"{}[],:".contains(&c) {
+                input.pos += 1;
+                return Ok(Some(Token::Punct(c)));
+            }
+            let end = if c == b'"' {
+                let mut i = 1;
+                loop {
+                    match bytes.get(i) {
+                        Some(b'"') => break Some(i + 1),
+                        Some(b'\\') => i += 2,
+                        Some(_) => i += 1,
+                        None => break None,
+                    }
+                }
+            } else {
+                bytes.iter().position(|b| b.is_ascii_whitespace() || b"{}[],:\"".contains(b))
+            };
+            let end = match end {
+                Some(end) => end,
+                None if input.eof => bytes.len(),
+                None => {
+                    input.fill().map_err(StreamError::read)?;
+                    continue;
+                }
+            };
+            let text = &bytes[..end];
+            let leaf = match simple_leaf(text) {
+                Some(leaf) => Ok(leaf),
+                None => {
+                    let mut text = text.to_vec();
+                    simd_json::to_borrowed_value(&mut text).map(BorrowedValue::into_static).map_err(|error| (error, text))
+                }
+            };
+            input.pos += end;
+            return match leaf {
+                Ok(leaf) => Ok(Some(Token::Leaf(leaf))),
+                Err((error, text)) => Err(StreamError::parse(input.line, error.into(), text)),
+            };
+        }
+    }
+}
+
+// literals, integers and strings without escapes, decoded without setting up a full parse
+fn simple_leaf(text: &[u8]) -> Option<BorrowedValue<'static>> {
+    Some(match text {
+        b"null" => BorrowedValue::Static(StaticNode::Null),
+        b"true" => BorrowedValue::Static(StaticNode::Bool(true)),
+        b"false" => BorrowedValue::Static(StaticNode::Bool(false)),
+        [b'"', inner @ .., b'"'] if !inner.iter().any(|&b| b == b'\\' || b < 0x20) => {
+            BorrowedValue::String(Cow::Owned(std::str::from_utf8(inner).ok()?.to_owned()))
+        }
+        [b'-', digits @ ..] | digits if is_integer(digits) => {
+            BorrowedValue::Static(StaticNode::I64(std::str::from_utf8(text).ok()?.parse().ok()?))
+        }
+        _ => return None,
+    })
+}
+
+// digits without a leading zero, which JSON does not allow
+fn is_integer(digits: &[u8]) -> bool {
+    matches!(digits, [b'0'] | [b'1'..=b'9', ..]) && digits.iter().all(u8::is_ascii_digit)
+}
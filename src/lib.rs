@@ -1,111 +1,61 @@
-use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList};
-use simd_json::{BorrowedValue, StaticNode};
-use std::borrow::Cow;
+//! A jq query engine over [simd-json] values.
+//!
+//! Queries are compiled once into bytecode for a backtracking VM, then run on any number of
+//! inputs, each run pulling its outputs lazily:
+//!
+//! ```
+//! let program = rusty_jq::compile(".users | .[] | select(.id == 1) | .name")?;
+//! let input = rusty_jq::parse(r#"{"users": [{"id": 1, "name": "John"}, {"id": 2, "name": "Bob"}]}"#)?;
+//! for name in program.run(&input) {
+//!     println!("{}", name?);
+//! }
+//! # Ok::<(), rusty_jq::Error>(())
+//! ```
+//!
+//! The Python extension module is built with the `python` feature, which maturin turns on; without
+//! it the crate has no Python dependency.
+//!
+//! [simd-json]: https://docs.rs/simd-json
 
 mod parser;
-use parser::{parse_query, RustyFilter};
-
 mod engine;
-use engine::process_rust_value;
-
-// converts a simd-json BorrowedValue into a native Python object
-// operates on zero-copy references
-// Python allocation happens at the end, so hot path stays allocation-free
-fn value_to_py(py: Python, val: &BorrowedValue) -> PyResult<PyObject> {
-    match val {
-        BorrowedValue::Static(StaticNode::Null) => Ok(py.None()),
-        BorrowedValue::Static(StaticNode::Bool(b)) => Ok(b.into_py(py)),
-        BorrowedValue::Static(StaticNode::I64(i)) => Ok(i.into_py(py)),
-        BorrowedValue::Static(StaticNode::U64(u)) => Ok(u.into_py(py)),
-        BorrowedValue::Static(StaticNode::F64(f)) => Ok(f.into_py(py)),
-        
-        BorrowedValue::String(s) => Ok(s.as_ref().into_py(py)),
-        
-        BorrowedValue::Array(arr) => {
-            let items: PyResult<Vec<PyObject>> = arr.iter()
-                .map(|item| value_to_py(py, item))
-                .collect();
-            Ok(PyList::new(py, items?).into())
-        },
-        BorrowedValue::Object(map) => {
-            let dict = PyDict::new(py);
-            for (k, v) in map.iter() {
-                dict.set_item(k.as_ref(), value_to_py(py, v)?)?;
-            }
-            Ok(dict.into())
-        }
-    }
-}
-
-#[pyclass]
-pub struct RustyJqIter {
-    items: std::vec::IntoIter<PyObject>,
-}
-
-#[pymethods]
-impl RustyJqIter {
-    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
-        slf
-    }
-
-    fn __next__(mut slf: PyRefMut<'_, Self>) -> Option<PyObject> {
-        slf.items.next()
-    }
-}
-
-// compiled jq-style query, exposed to Python as RustyProgram
-#[pyclass]
-struct RustyProgram {
-    filters: Vec<RustyFilter>,
-}
-
-#[pymethods]
-impl RustyProgram {
-    fn input(&self, py: Python, json_text: &str) -> PyResult<RustyJqIter> {
-        let mut bytes = json_text.as_bytes().to_vec();
-        let json_data = simd_json::to_borrowed_value(&mut bytes)
-            .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string()))?;
-        let result = process_rust_value(Cow::Borrowed(&json_data), &self.filters, None);
-        let items: PyResult<Vec<PyObject>> = result.iter()
-            .map(|v| value_to_py(py, &*v))
-            .collect();
-        Ok(RustyJqIter { items: items?.into_iter() })
-    }
-
-    fn first(&self, py: Python, json_text: &str) -> PyResult<PyObject> {
-        let mut bytes = json_text.as_bytes().to_vec();
-        let json_data = simd_json::to_borrowed_value(&mut bytes)
-            .map_err(|e| PyErr::new::<pyo3::exceptions::PyValueError, _>(e.to_string()))?;
-        let result = process_rust_value(Cow::Borrowed(&json_data), &self.filters, Some(1));
-        match result.first() {
-            Some(val) => value_to_py(py, &*val),
-            None => Ok(py.None()),
-        }
-    }
-}
-
-#[pyfunction]
-fn compile(query: &str) -> PyResult<RustyProgram> {
-    // returns IResult<&str, Vec<RustyFilter>>
-    let (remaining, filters) = match parse_query(query) {
-        Ok(x) => x,
-        Err(e) => return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>(format!("Invalid query syntax: {}", e))),
-    };
-
-    // ensure the parser consumed the entire query string
-    if !remaining.trim().is_empty() {
-        return Err(PyErr::new::<pyo3::exceptions::PyValueError, _>("Extra chars"));
-    }
-
-    Ok(RustyProgram { filters })
-}
-
-// PyO3 module initialisation (entry-point)
-#[pymodule]
-fn rusty_jq(_py: Python, m: &PyModule) -> PyResult<()> {
-    m.add_function(wrap_pyfunction!(compile, m)?)?;
-    m.add_class::<RustyProgram>()?;
-    m.add_class::<RustyJqIter>()?;
-    Ok(())
-}
\ No newline at end of file
+mod optimizer;
+mod bytecode;
+mod vm;
+mod projection;
+mod serialize;
+mod document;
+mod stream;
+mod events;
+mod variables;
+mod results;
+mod syntax;
+mod printer;
+mod limits;
+mod api;
+
+pub use api::{compile, parse, parse_with_limits, Error, Program, Run, Value};
+pub use syntax::SyntaxError;
+pub use vm::RuntimeError;
+pub use limits::{DuplicateKeys, LimitExceeded, Limits, ParseLimits};
+pub use parser::PathStep;
+// the value types and the traits to build, read and serialize them
+pub use simd_json;
+
+// the Python extension module, and what only it uses
+#[cfg(feature = "python")]
+// PyO3 0.20 expands `#[new]` into impls that newer compilers warn about
+#[allow(non_local_definitions)]
+mod python;
+#[cfg(feature = "python")]
+mod batch;
+#[cfg(feature = "python")]
+mod streamer;
+#[cfg(feature = "python")]
+mod encoding;
+#[cfg(feature = "python")]
+mod cache;
+
+// the `rusty-jq` command-line tool, public only for `main.rs`; not part of the library's API
+#[doc(hidden)]
+pub mod cli;
@@ -0,0 +1,287 @@
+use serde::{Deserialize, Serialize};
+use simd_json::BorrowedValue;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::serialize::write_string;
+
+/// Caps on what one run of a program may do, for queries that cannot be trusted.
+///
+/// Each limit left as `None` is not enforced. They apply to each input document separately; a run
+/// that goes over one ends with [`LimitExceeded`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct Limits {
+    /// How many VM instructions a run may execute, sub-queries such as `[...]` included.
+    pub max_steps: Option<u64>,
+    /// How many outputs a run may produce.
+    pub max_outputs: Option<u64>,
+    /// How many levels deep `..` and `recurse` may descend into a value.
+    pub max_depth: Option<usize>,
+    /// How long a run may take. The clock is read between instructions, so one builtin working
+    /// through a large value, such as `tojson` or `sort`, finishes before the run is stopped.
+    pub timeout: Option<Duration>,
+    /// An estimate of how many bytes the values a run builds may take, such as the arrays collected
+    /// by `[...]` and the objects and strings it creates, counted as they are built. It is a running
+    /// total rather than what the run holds at once: values dropped along the way still count.
+    pub max_memory_bytes: Option<usize>,
+}
+
+impl Limits {
+    fn unlimited(&self) -> bool {
+        *self == Limits::default()
+    }
+}
+
+/// The limit a run went over, with the value it was set to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitExceeded {
+    Steps(u64),
+    Outputs(u64),
+    Depth(usize),
+    Timeout(Duration),
+    Memory(usize),
+}
+
+impl LimitExceeded {
+    /// The name of the limit, as a field of [`Limits`].
+    pub fn name(&self) -> &'static str {
+        match self {
+            LimitExceeded::Steps(_) => "max_steps",
+            LimitExceeded::Outputs(_) => "max_outputs",
+            LimitExceeded::Depth(_) => "max_depth",
+            LimitExceeded::Timeout(_) => "timeout",
+            LimitExceeded::Memory(_) => "max_memory_bytes",
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LimitExceeded::Steps(n) => write!(f, "query ran for more than {} steps", n),
+            LimitExceeded::Outputs(n) => write!(f, "query produced more than {} outputs", n),
+            LimitExceeded::Depth(n) => write!(f, "query descended more than {} levels deep", n),
+            LimitExceeded::Timeout(d) => write!(f, "query ran for more than {} ms", d.as_millis()),
+            LimitExceeded::Memory(n) => write!(f, "query built more than {} bytes of values", n),
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// How much of its input a query may be made to read, for documents that cannot be trusted.
+///
+/// The limits apply to each document separately, whether it comes from a string, a file, a stream
+/// or `fromjson`; a document that goes over one fails to parse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct ParseLimits {
+    /// How many arrays and objects may nest inside each other, at most [`ParseLimits::MAX_DEPTH`].
+    pub max_depth: usize,
+    /// How many bytes of JSON text a document may take.
+    pub max_bytes: Option<usize>,
+    /// How many bytes a string or object key may take once decoded.
+    pub max_string_length: Option<usize>,
+    /// Which value a key that appears twice in one object keeps.
+    pub duplicate_keys: DuplicateKeys,
+}
+
+impl ParseLimits {
+    /// The deepest nesting any document may have, whatever `max_depth` says; values are walked
+    /// recursively, and deeper ones could run out of stack.
+    pub const MAX_DEPTH: usize = 1024;
+
+    // the depth enforced, which never goes past the ceiling
+    pub(crate) fn depth(&self) -> usize {
+        self.max_depth.min(ParseLimits::MAX_DEPTH)
+    }
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits { max_depth: 512, max_bytes: None, max_string_length: None, duplicate_keys: DuplicateKeys::Last }
+    }
+}
+
+/// What to do with a key that appears more than once in one object.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DuplicateKeys {
+    /// Keep the first value.
+    First,
+    /// Keep the last value, as jq does.
+    #[default]
+    Last,
+    /// Fail to parse the document.
+    Error,
+}
+
+impl DuplicateKeys {
+    /// The policy's name: `first`, `last` or `error`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            DuplicateKeys::First => "first",
+            DuplicateKeys::Last => "last",
+            DuplicateKeys::Error => "error",
+        }
+    }
+
+    /// The policy called `name`, if there is one.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "first" => Some(DuplicateKeys::First),
+            "last" => Some(DuplicateKeys::Last),
+            "error" => Some(DuplicateKeys::Error),
+            _ => None,
+        }
+    }
+}
+
+// why a document did not parse: invalid JSON, or JSON going over one of its parse limits
+#[derive(Debug)]
+pub enum ParseError {
+    Json(simd_json::Error),
+    Depth(usize),
+    Size(usize),
+    StringLength(usize),
+    DuplicateKey(String),
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParseError::Json(e) => e.fmt(f),
+            ParseError::Depth(n) => write!(f, "document nests more than {} levels deep", n),
+            ParseError::Size(n) => write!(f, "document is larger than {} bytes", n),
+            ParseError::StringLength(n) => write!(f, "document has a string longer than {} bytes", n),
+            ParseError::DuplicateKey(key) => {
+                let mut quoted = String::new();
+                write_string(&mut quoted, key, false);
+                write!(f, "duplicate key {} in an object", quoted)
+            }
+        }
+    }
+}
+
+impl From<simd_json::Error> for ParseError {
+    fn from(e: simd_json::Error) -> Self {
+        ParseError::Json(e)
+    }
+}
+
+// the clock is read once every this many steps
+const CLOCK_EVERY: u64 = 256;
+
+// what one run has used of its limits, shared with the sub-programs it runs
+pub struct Meter {
+    limits: Limits,
+    // set on the first step, so runs prepared ahead of time are not charged for the wait
+    started: OnceLock<Instant>,
+    steps: AtomicU64,
+    outputs: AtomicU64,
+    memory: AtomicUsize,
+}
+
+impl Meter {
+    pub fn new(limits: Limits) -> Self {
+        Meter { limits, started: OnceLock::new(), steps: AtomicU64::new(0), outputs: AtomicU64::new(0), memory: AtomicUsize::new(0) }
+    }
+
+    // whether there is anything to enforce, so unlimited runs skip the metering
+    pub fn limited(&self) -> bool {
+        !self.limits.unlimited()
+    }
+
+    // count one instruction
+    pub fn step(&self) -> Result<(), LimitExceeded> {
+        let steps = self.steps.fetch_add(1, Ordering::Relaxed) + 1;
+        if let Some(max) = self.limits.max_steps.filter(|max| steps > *max) {
+            return Err(LimitExceeded::Steps(max));
+        }
+        if let Some(timeout) = self.limits.timeout {
+            let started = self.started.get_or_init(Instant::now);
+            if steps.is_multiple_of(CLOCK_EVERY) && started.elapsed() > timeout {
+                return Err(LimitExceeded::Timeout(timeout));
+            }
+        }
+        Ok(())
+    }
+
+    // count one output of the run
+    pub fn output(&self) -> Result<(), LimitExceeded> {
+        let outputs = self.outputs.fetch_add(1, Ordering::Relaxed) + 1;
+        match self.limits.max_outputs {
+            Some(max) if outputs > max => Err(LimitExceeded::Outputs(max)),
+            _ => Ok(()),
+        }
+    }
+
+    // count `bytes` more of values built
+    pub fn allocate(&self, bytes: usize) -> Result<(), LimitExceeded> {
+        let Some(max) = self.limits.max_memory_bytes else { return Ok(()) };
+        let memory = self.memory.fetch_add(bytes, Ordering::Relaxed) + bytes;
+        if memory > max { Err(LimitExceeded::Memory(max)) } else { Ok(()) }
+    }
+
+    // count a value built by the run
+    pub fn build(&self, value: &BorrowedValue) -> Result<(), LimitExceeded> {
+        if self.limits.max_memory_bytes.is_none() { return Ok(()); }
+        self.allocate(value_size(value))
+    }
+
+    // check a value about to be recursed into with `..`; recursing into a value the run built
+    // copies every part of it, which is counted too
+    pub fn recurse(&self, value: &BorrowedValue, built: bool) -> Result<(), LimitExceeded> {
+        if let Some(max) = self.limits.max_depth.filter(|max| deeper_than(value, *max)) {
+            return Err(LimitExceeded::Depth(max));
+        }
+        if built && self.limits.max_memory_bytes.is_some() {
+            let mut copied = 0;
+            value_size_with_parts(value, &mut copied);
+            self.allocate(copied)?;
+        }
+        Ok(())
+    }
+}
+
+// whether `value` nests more than `depth` levels, without looking any deeper than that
+fn deeper_than(value: &BorrowedValue, depth: usize) -> bool {
+    match value {
+        BorrowedValue::Array(items) => (depth == 0 && !items.is_empty()) || items.iter().any(|v| deeper_than(v, depth - 1)),
+        BorrowedValue::Object(fields) => (depth == 0 && !fields.is_empty()) || fields.values().any(|v| deeper_than(v, depth - 1)),
+        _ => false,
+    }
+}
+
+// how deep the values a run builds may nest; they are walked recursively like documents, and so is
+// dropping one, so a value built deeper by a loop could run out of stack
+pub const MAX_VALUE_DEPTH: usize = 2 * ParseLimits::MAX_DEPTH;
+
+// whether `value`, put `below` levels down in a new value, would nest deeper than MAX_VALUE_DEPTH
+pub fn nests_too_deep(value: &BorrowedValue, below: usize) -> bool {
+    below > MAX_VALUE_DEPTH || deeper_than(value, MAX_VALUE_DEPTH - below)
+}
+
+// roughly the bytes `value` takes in memory
+pub fn value_size(value: &BorrowedValue) -> usize {
+    let own = std::mem::size_of::<BorrowedValue>();
+    match value {
+        BorrowedValue::Static(_) => own,
+        BorrowedValue::String(s) => own + s.len(),
+        BorrowedValue::Array(items) => own + items.iter().map(value_size).sum::<usize>(),
+        BorrowedValue::Object(fields) => own + fields.iter().map(|(k, v)| k.len() + value_size(v)).sum::<usize>(),
+    }
+}
+
+// the size of `value`, adding to `total` the sizes of it and of every value inside it
+fn value_size_with_parts(value: &BorrowedValue, total: &mut usize) -> usize {
+    let own = std::mem::size_of::<BorrowedValue>();
+    let size = match value {
+        BorrowedValue::Static(_) => own,
+        BorrowedValue::String(s) => own + s.len(),
+        BorrowedValue::Array(items) => own + items.iter().map(|v| value_size_with_parts(v, total)).sum::<usize>(),
+        BorrowedValue::Object(fields) => own + fields.iter().map(|(k, v)| k.len() + value_size_with_parts(v, total)).sum::<usize>(),
+    };
+    *total += size;
+    size
+}
@@ -0,0 +1,3 @@
+fn main() {
+    std::process::exit(rusty_jq::cli::run(std::env::args().skip(1)));
+}
@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+use std::sync::OnceLock;
+use simd_json::BorrowedValue;
+use simd_json::prelude::*;
+
+use crate::bytecode::Program;
+use crate::engine::value_to_literal;
+use crate::limits::{Limits, Meter};
+use crate::parser::{pipeline_span, Builtin0, Condition, Expr, Filter, Literal, PathStep, RustyFilter};
+
+// Rewrites a parsed query into an equivalent one that is cheaper to run.
+// Passes, applied bottom-up to every nested pipeline:
+//  - drop `.` no-ops
+//  - fold constant subexpressions; this also hoists input-independent work out of `.[]`
+//    bodies, since it is evaluated once here instead of once per element
+//  - fuse runs of `.field` / `.[n]` into a single path lookup
+//  - fuse `.[] | select(...)` into a filtering iterator
+// A fused or folded filter spans the filters it replaces.
+pub fn optimize(filters: Vec<Filter>, limits: &Limits) -> Vec<Filter> {
+    let meter = Meter::new(fold_limits(limits));
+    optimize_pipeline(filters, &meter)
+}
+
+// folding runs parts of the query at compile time, so all the folding of one query shares a small
+// budget of steps and memory, within the limits its runs will have; what goes over it stays unfolded
+const FOLD_STEPS: u64 = 10_000;
+const FOLD_MEMORY_BYTES: usize = 1 << 20;
+
+fn fold_limits(limits: &Limits) -> Limits {
+    Limits {
+        max_steps: Some(limits.max_steps.map_or(FOLD_STEPS, |max| max.min(FOLD_STEPS))),
+        max_outputs: None,
+        max_depth: limits.max_depth,
+        timeout: limits.timeout,
+        max_memory_bytes: Some(limits.max_memory_bytes.map_or(FOLD_MEMORY_BYTES, |max| max.min(FOLD_MEMORY_BYTES))),
+    }
+}
+
+fn optimize_pipeline(filters: Vec<Filter>, meter: &Meter) -> Vec<Filter> {
+    let filters: Vec<Filter> = filters.into_iter()
+        .map(|f| Filter::new(optimize_children(f.kind, meter), f.span))
+        .filter(|f| !matches!(f.kind, RustyFilter::Identity))
+        .collect();
+    let filters = fold_constants(filters, meter);
+    fuse_iter_select(fuse_paths(filters))
+}
+
+fn optimize_children(filter: RustyFilter, meter: &Meter) -> RustyFilter {
+    match filter {
+        RustyFilter::Object(pairs) => RustyFilter::Object(pairs.into_iter().map(|(k, v)| (k, optimize_pipeline(v, meter))).collect()),
+        RustyFilter::Select(condition) => RustyFilter::Select(optimize_condition(condition, meter)),
+        RustyFilter::Comma(branches) => RustyFilter::Comma(branches.into_iter().map(|f| optimize_pipeline(f, meter)).collect()),
+        RustyFilter::Arithmetic(left, op, right) => RustyFilter::Arithmetic(optimize_pipeline(left, meter), op, optimize_pipeline(right, meter)),
+        RustyFilter::Limit(n, sub_query) => RustyFilter::Limit(n, optimize_pipeline(sub_query, meter)),
+        RustyFilter::FirstOf(sub_query) => RustyFilter::FirstOf(optimize_pipeline(sub_query, meter)),
+        RustyFilter::IsEmpty(sub_query) => RustyFilter::IsEmpty(optimize_pipeline(sub_query, meter)),
+        RustyFilter::FromStream(sub_query) => RustyFilter::FromStream(optimize_pipeline(sub_query, meter)),
+        RustyFilter::TruncateStream(sub_query) => RustyFilter::TruncateStream(optimize_pipeline(sub_query, meter)),
+        RustyFilter::IterSelect(condition) => RustyFilter::IterSelect(optimize_condition(condition, meter)),
+        RustyFilter::IndexBy(stream, key) => RustyFilter::IndexBy(optimize_pipeline(stream, meter), optimize_pipeline(key, meter)),
+        RustyFilter::In(source, values) => RustyFilter::In(optimize_pipeline(source, meter), optimize_pipeline(values, meter)),
+        RustyFilter::Join { index, stream, key, join } => RustyFilter::Join {
+            index: optimize_pipeline(index, meter),
+            stream: stream.map(|f| optimize_pipeline(f, meter)),
+            key: optimize_pipeline(key, meter),
+            join: join.map(|f| optimize_pipeline(f, meter)),
+        },
+        RustyFilter::Compare(left, op, right) => RustyFilter::Compare(optimize_pipeline(left, meter), op, optimize_pipeline(right, meter)),
+        RustyFilter::And(left, right) => RustyFilter::And(optimize_pipeline(left, meter), optimize_pipeline(right, meter)),
+        RustyFilter::Or(left, right) => RustyFilter::Or(optimize_pipeline(left, meter), optimize_pipeline(right, meter)),
+        RustyFilter::Negate(operand) => RustyFilter::Negate(optimize_pipeline(operand, meter)),
+        RustyFilter::Alternative(left, right) => RustyFilter::Alternative(optimize_pipeline(left, meter), optimize_pipeline(right, meter)),
+        RustyFilter::Assign(paths, op, value) => RustyFilter::Assign(optimize_pipeline(paths, meter), op, optimize_pipeline(value, meter)),
+        RustyFilter::Array(items) => RustyFilter::Array(optimize_pipeline(items, meter)),
+        RustyFilter::If(branches, otherwise) => RustyFilter::If(
+            branches.into_iter().map(|(cond, then)| (optimize_pipeline(cond, meter), optimize_pipeline(then, meter))).collect(),
+            otherwise.map(|f| optimize_pipeline(f, meter)),
+        ),
+        RustyFilter::Try(body, handler) => RustyFilter::Try(optimize_pipeline(body, meter), handler.map(|f| optimize_pipeline(f, meter))),
+        RustyFilter::Bind(source, name, body) => RustyFilter::Bind(optimize_pipeline(source, meter), name, optimize_pipeline(body, meter)),
+        RustyFilter::Reduce { source, name, init, update } => RustyFilter::Reduce {
+            source: optimize_pipeline(source, meter),
+            name,
+            init: optimize_pipeline(init, meter),
+            update: optimize_pipeline(update, meter),
+        },
+        RustyFilter::Foreach { source, name, init, update, extract } => RustyFilter::Foreach {
+            source: optimize_pipeline(source, meter),
+            name,
+            init: optimize_pipeline(init, meter),
+            update: optimize_pipeline(update, meter),
+            extract: extract.map(|f| optimize_pipeline(f, meter)),
+        },
+        other => other,
+    }
+}
+
+fn optimize_condition(condition: Condition, meter: &Meter) -> Condition {
+    match condition {
+        Condition::Comparison(path, op, expr) => {
+            let expr = match expr {
+                Expr::Path(rhs) => match optimize_pipeline(rhs, meter) {
+                    rhs if is_independent(&rhs) => match const_eval(&rhs, meter) {
+                        Some(lit) => Expr::Literal(lit),
+                        None => Expr::Path(rhs),
+                    },
+                    rhs => Expr::Path(rhs),
+                },
+                lit => lit,
+            };
+            Condition::Comparison(optimize_pipeline(path, meter), op, expr)
+        }
+        Condition::BoolPath(path) => Condition::BoolPath(optimize_pipeline(path, meter)),
+        Condition::And(l, r) => Condition::And(Box::new(optimize_condition(*l, meter)), Box::new(optimize_condition(*r, meter))),
+        Condition::Or(l, r) => Condition::Or(Box::new(optimize_condition(*l, meter)), Box::new(optimize_condition(*r, meter))),
+        Condition::Not(inner) => Condition::Not(Box::new(optimize_condition(*inner, meter))),
+    }
+}
+
+// true when a filter's outputs do not depend on its input at all
+fn is_independent_filter(filter: &Filter) -> bool {
+    match &filter.kind {
+        RustyFilter::LiteralValue(_) => true,
+        RustyFilter::Builtin0(Builtin0::Nan | Builtin0::Infinite | Builtin0::Empty) => true,
+        RustyFilter::Arithmetic(left, _, right) | RustyFilter::Compare(left, _, right) => is_independent(left) && is_independent(right),
+        RustyFilter::Negate(operand) | RustyFilter::Array(operand) => is_independent(operand),
+        RustyFilter::Object(pairs) => pairs.iter().all(|(_, v)| is_independent(v)),
+        RustyFilter::Comma(branches) => branches.iter().all(|b| is_independent(b)),
+        _ => false,
+    }
+}
+
+// a pipeline is independent of its input when its first stage is
+fn is_independent(filters: &[Filter]) -> bool {
+    filters.first().is_some_and(is_independent_filter)
+}
+
+// true when a filter is a deterministic function of its input, so it may run at compile time
+fn is_pure(filter: &Filter) -> bool {
+    match &filter.kind {
+        RustyFilter::Identity | RustyFilter::Field(_) | RustyFilter::Index(_) | RustyFilter::Iterator
+        | RustyFilter::Slice(..) | RustyFilter::RecurseDescent | RustyFilter::Path(_)
+        | RustyFilter::LiteralValue(_) | RustyFilter::Builtin0(_) | RustyFilter::Builtin1(..) => true,
+        RustyFilter::Object(pairs) => pairs.iter().all(|(_, v)| v.iter().all(is_pure)),
+        RustyFilter::Comma(branches) => branches.iter().all(|b| b.iter().all(is_pure)),
+        RustyFilter::Arithmetic(left, _, right) | RustyFilter::Compare(left, _, right)
+        | RustyFilter::And(left, right) | RustyFilter::Or(left, right) | RustyFilter::Alternative(left, right)
+        | RustyFilter::Assign(left, _, right) => left.iter().chain(right.iter()).all(is_pure),
+        RustyFilter::Negate(f) | RustyFilter::Array(f) => f.iter().all(is_pure),
+        RustyFilter::If(branches, otherwise) => branches.iter().all(|(cond, then)| cond.iter().chain(then.iter()).all(is_pure))
+            && otherwise.iter().flatten().all(is_pure),
+        RustyFilter::Try(body, handler) => body.iter().chain(handler.iter().flatten()).all(is_pure),
+        RustyFilter::Bind(source, _, body) => source.iter().chain(body.iter()).all(is_pure),
+        RustyFilter::Reduce { source, init, update, .. } => source.iter().chain(init.iter()).chain(update.iter()).all(is_pure),
+        RustyFilter::Foreach { source, init, update, extract, .. } => source.iter().chain(init.iter()).chain(update.iter())
+            .chain(extract.iter().flatten()).all(is_pure),
+        RustyFilter::Limit(_, f) | RustyFilter::FirstOf(f) | RustyFilter::IsEmpty(f)
+        | RustyFilter::FromStream(f) | RustyFilter::TruncateStream(f) => f.iter().all(is_pure),
+        RustyFilter::IndexBy(stream, key) | RustyFilter::In(stream, key) => stream.iter().chain(key.iter()).all(is_pure),
+        RustyFilter::Join { index, stream, key, join } => index.iter().chain(stream.iter().flatten()).chain(key.iter())
+            .chain(join.iter().flatten()).all(is_pure),
+        RustyFilter::Select(condition) | RustyFilter::IterSelect(condition) => condition_is_pure(condition),
+        RustyFilter::Input | RustyFilter::Inputs | RustyFilter::InputFilename | RustyFilter::InputLineNumber => false,
+        RustyFilter::Halt | RustyFilter::HaltError(_) => false,
+        // bound per run, so unknown at compile time
+        RustyFilter::Variable(_) => false,
+    }
+}
+
+fn condition_is_pure(condition: &Condition) -> bool {
+    match condition {
+        Condition::Comparison(path, _, Expr::Path(rhs)) => path.iter().chain(rhs.iter()).all(is_pure),
+        Condition::Comparison(path, _, Expr::Literal(_)) | Condition::BoolPath(path) => path.iter().all(is_pure),
+        Condition::And(l, r) | Condition::Or(l, r) => condition_is_pure(l) && condition_is_pure(r),
+        Condition::Not(inner) => condition_is_pure(inner),
+    }
+}
+
+// run an input-independent pipeline once, returning its single output as a literal
+fn const_eval(filters: &[Filter], meter: &Meter) -> Option<Literal> {
+    if !filters.iter().all(is_pure) { return None; }
+    let program = Program::compile(filters);
+    let null = BorrowedValue::Static(StaticNode::Null);
+    // an error, or running out of the folding budget, ends the outputs early, and is left to be
+    // raised when the query runs
+    let halt = OnceLock::new();
+    let mut outputs = program.run(Cow::Borrowed(&null)).with_halt(&halt).with_meter(meter);
+    let value = outputs.next()?;
+    if outputs.next().is_some() || halt.get().is_some() { return None; }
+    value_to_literal(&value)
+}
+
+fn fold_constants(filters: Vec<Filter>, meter: &Meter) -> Vec<Filter> {
+    let already_folded = filters.len() == 1 && matches!(filters[0].kind, RustyFilter::LiteralValue(_));
+    if already_folded || !is_independent(&filters) { return filters; }
+    match const_eval(&filters, meter) {
+        Some(lit) => vec![Filter::new(RustyFilter::LiteralValue(lit), pipeline_span(&filters))],
+        None => filters,
+    }
+}
+
+fn fuse_paths(filters: Vec<Filter>) -> Vec<Filter> {
+    let mut out: Vec<Filter> = Vec::with_capacity(filters.len());
+    for filter in filters {
+        let step = match &filter.kind {
+            RustyFilter::Field(key) => PathStep::Field(key.clone()),
+            RustyFilter::Index(i) => PathStep::Index(*i),
+            _ => { out.push(filter); continue; }
+        };
+        match out.last_mut() {
+            Some(prev) if matches!(prev.kind, RustyFilter::Path(_) | RustyFilter::Field(_) | RustyFilter::Index(_)) => {
+                prev.span = prev.span.to(filter.span);
+                match &mut prev.kind {
+                    RustyFilter::Path(steps) => steps.push(step),
+                    RustyFilter::Field(key) => prev.kind = RustyFilter::Path(vec![PathStep::Field(std::mem::take(key)), step]),
+                    RustyFilter::Index(i) => prev.kind = RustyFilter::Path(vec![PathStep::Index(*i), step]),
+                    _ => unreachable!(),
+                }
+            }
+            _ => out.push(filter),
+        }
+    }
+    out
+}
+
+fn fuse_iter_select(filters: Vec<Filter>) -> Vec<Filter> {
+    let mut out: Vec<Filter> = Vec::with_capacity(filters.len());
+    for filter in filters {
+        match (out.last(), filter) {
+            (Some(Filter { kind: RustyFilter::Iterator, span }), Filter { kind: RustyFilter::Select(condition), span: end }) => {
+                let span = span.to(end);
+                out.pop();
+                out.push(Filter::new(RustyFilter::IterSelect(condition), span));
+            }
+            (_, filter) => out.push(filter),
+        }
+    }
+    out
+}
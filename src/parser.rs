@@ -1,14 +1,17 @@
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_while},
-    character::complete::{alpha1, char, digit1, multispace0},
-    combinator::{map, map_res, opt, recognize},
-    multi::{many1, separated_list1},
+    bytes::complete::take_while,
+    character::complete::{alpha1, digit1, one_of},
+    combinator::{consumed, map, map_opt, map_res, opt, recognize, success},
+    multi::separated_list1,
     sequence::{delimited, pair, preceded, separated_pair, tuple},
     IResult,
 };
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::syntax::{expect, label, quiet, span, tried, Expected};
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum CompareOp {
     Eq,  // ==
     Neq, // !=
@@ -18,31 +21,34 @@ pub enum CompareOp {
     Lte, // <=
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Literal {
     Int(i64),
     Float(f64),
     String(String),
     Bool(bool),
     Null,
+    // produced by the optimizer when hoisting constant subexpressions
+    Array(Vec<Literal>),
+    Object(Vec<(String, Literal)>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Expr {
     Literal(Literal),
-    Path(Vec<RustyFilter>),
+    Path(Vec<Filter>),
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum Condition {
-    Comparison(Vec<RustyFilter>, CompareOp, Expr),
-    BoolPath(Vec<RustyFilter>),
+    Comparison(Vec<Filter>, CompareOp, Expr),
+    BoolPath(Vec<Filter>),
     And(Box<Condition>, Box<Condition>),
     Or(Box<Condition>, Box<Condition>),
     Not(Box<Condition>),
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
 pub enum ArithOp {
     Add,
     Sub,
@@ -51,7 +57,16 @@ pub enum ArithOp {
     Mod,
 }
 
-#[derive(Debug, Clone)]
+// `=`, `|=`, `//=` and the arithmetic update-assignments like `+=`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum AssignOp {
+    Set,
+    Update,
+    Arith(ArithOp),
+    Alternative,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Builtin0 {
     Length, Keys, KeysUnsorted, Values, Type,
     Reverse, Sort, Flatten, Add, Min, Max, Unique,
@@ -64,9 +79,10 @@ pub enum Builtin0 {
     Floor, Ceil, Round, Sqrt, Fabs,
     Nan, Infinite, Isinfinite, Isnan, Isnormal,
     Recurse,
+    ToStream,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum Builtin1 {
     Has, Startswith, Endswith, Contains, Inside,
     Split, Join, Ltrimstr, Rtrimstr,
@@ -75,138 +91,426 @@ pub enum Builtin1 {
     Limit,
 }
 
+impl CompareOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            CompareOp::Eq => "==",
+            CompareOp::Neq => "!=",
+            CompareOp::Gt => ">",
+            CompareOp::Lt => "<",
+            CompareOp::Gte => ">=",
+            CompareOp::Lte => "<=",
+        }
+    }
+}
+
+impl ArithOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            ArithOp::Add => "+",
+            ArithOp::Sub => "-",
+            ArithOp::Mul => "*",
+            ArithOp::Div => "/",
+            ArithOp::Mod => "%",
+        }
+    }
+}
+
+impl AssignOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            AssignOp::Set => "=",
+            AssignOp::Update => "|=",
+            AssignOp::Arith(ArithOp::Add) => "+=",
+            AssignOp::Arith(ArithOp::Sub) => "-=",
+            AssignOp::Arith(ArithOp::Mul) => "*=",
+            AssignOp::Arith(ArithOp::Div) => "/=",
+            AssignOp::Arith(ArithOp::Mod) => "%=",
+            AssignOp::Alternative => "//=",
+        }
+    }
+}
+
+impl Builtin0 {
+    // the name the builtin is called by in a query
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin0::Length => "length",
+            Builtin0::Keys => "keys",
+            Builtin0::KeysUnsorted => "keys_unsorted",
+            Builtin0::Values => "values",
+            Builtin0::Type => "type",
+            Builtin0::Reverse => "reverse",
+            Builtin0::Sort => "sort",
+            Builtin0::Flatten => "flatten",
+            Builtin0::Add => "add",
+            Builtin0::Min => "min",
+            Builtin0::Max => "max",
+            Builtin0::Unique => "unique",
+            Builtin0::First => "first",
+            Builtin0::Last => "last",
+            Builtin0::Not => "not",
+            Builtin0::Empty => "empty",
+            Builtin0::Tostring => "tostring",
+            Builtin0::Tonumber => "tonumber",
+            Builtin0::ToEntries => "to_entries",
+            Builtin0::FromEntries => "from_entries",
+            Builtin0::AsciiDowncase => "ascii_downcase",
+            Builtin0::AsciiUpcase => "ascii_upcase",
+            Builtin0::Tojson => "tojson",
+            Builtin0::Fromjson => "fromjson",
+            Builtin0::Explode => "explode",
+            Builtin0::Implode => "implode",
+            Builtin0::Floor => "floor",
+            Builtin0::Ceil => "ceil",
+            Builtin0::Round => "round",
+            Builtin0::Sqrt => "sqrt",
+            Builtin0::Fabs => "fabs",
+            Builtin0::Nan => "nan",
+            Builtin0::Infinite => "infinite",
+            Builtin0::Isinfinite => "isinfinite",
+            Builtin0::Isnan => "isnan",
+            Builtin0::Isnormal => "isnormal",
+            Builtin0::Recurse => "recurse",
+            Builtin0::ToStream => "tostream",
+        }
+    }
+}
+
+impl Builtin1 {
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin1::Has => "has",
+            Builtin1::Startswith => "startswith",
+            Builtin1::Endswith => "endswith",
+            Builtin1::Contains => "contains",
+            Builtin1::Inside => "inside",
+            Builtin1::Split => "split",
+            Builtin1::Join => "join",
+            Builtin1::Ltrimstr => "ltrimstr",
+            Builtin1::Rtrimstr => "rtrimstr",
+            Builtin1::FlattenDepth => "flatten",
+            Builtin1::Index => "index",
+            Builtin1::Rindex => "rindex",
+            Builtin1::Indices => "indices",
+            Builtin1::Limit => "limit",
+        }
+    }
+}
+
+/// One step of a path into a JSON value: an object key or an array index.
+// also a step of a fused path lookup, produced by the optimizer
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum PathStep {
+    Field(String),
+    Index(i32),
+}
+
+// the bytes of the query a node was parsed from, `start..end`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    // from the start of `self` to the end of `other`
+    pub fn to(self, other: Span) -> Span {
+        Span { start: self.start, end: other.end }
+    }
+}
+
+// a filter and the part of the query it came from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Filter {
+    pub kind: RustyFilter,
+    pub span: Span,
+}
+
+impl Filter {
+    pub fn new(kind: RustyFilter, span: Span) -> Filter {
+        Filter { kind, span }
+    }
+}
+
+// the span of a whole pipeline, empty for `.` written as nothing
+pub fn pipeline_span(filters: &[Filter]) -> Span {
+    match (filters.first(), filters.last()) {
+        (Some(first), Some(last)) => first.span.to(last.span),
+        _ => Span::default(),
+    }
+}
+
 // Represents filters operation in a jq-style query
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum RustyFilter {
     Identity,
     Field(String),
     Index(i32),
     Iterator,
-    Object(Vec<(String, Vec<RustyFilter>)>),
+    Object(Vec<(String, Vec<Filter>)>),
     Select(Condition),
-    Comma(Vec<Vec<RustyFilter>>),
-    Arithmetic(Vec<RustyFilter>, ArithOp, Vec<RustyFilter>),
+    Comma(Vec<Vec<Filter>>),
+    Arithmetic(Vec<Filter>, ArithOp, Vec<Filter>),
     LiteralValue(Literal),
     Builtin0(Builtin0),
     Builtin1(Builtin1, Literal),
     RecurseDescent,
     Slice(Option<i64>, Option<i64>),
+    Limit(usize, Vec<Filter>),
+    FirstOf(Vec<Filter>),
+    IsEmpty(Vec<Filter>),
+    // rebuild values from the `[path, leaf]` events of a sub-query, or cut `.` steps off their paths
+    FromStream(Vec<Filter>),
+    TruncateStream(Vec<Filter>),
+    // jq's SQL-style builtins: `INDEX(stream; key)`, `IN(source; s)` with an empty source for `IN(s)`,
+    // and `JOIN($idx; stream; key; join)`, whose stream is `.[]` collected into an array when left out
+    IndexBy(Vec<Filter>, Vec<Filter>),
+    In(Vec<Filter>, Vec<Filter>),
+    Join { index: Vec<Filter>, stream: Option<Vec<Filter>>, key: Vec<Filter>, join: Option<Vec<Filter>> },
+    // read the next document, or every remaining one, from the input stream
+    Input,
+    Inputs,
+    // where the current document was read from
+    InputFilename,
+    InputLineNumber,
+    // stop the whole run, `halt_error` reporting `.` and exiting with its status (5 by default)
+    Halt,
+    HaltError(i32),
+    // `$name`, bound when the program is run or by `as`, `reduce` and `foreach` in the query
+    Variable(String),
+    // jq's operators over whole filters; inside `select` comparisons, `and`, `or` and `not` are
+    // parsed into its condition instead
+    Compare(Vec<Filter>, CompareOp, Vec<Filter>),
+    And(Vec<Filter>, Vec<Filter>),
+    Or(Vec<Filter>, Vec<Filter>),
+    Negate(Vec<Filter>),
+    // `a // b`: the outputs of `a` that are neither false nor null, or those of `b` when there are none
+    Alternative(Vec<Filter>, Vec<Filter>),
+    // `paths = value`, `paths |= update` and the like
+    Assign(Vec<Filter>, AssignOp, Vec<Filter>),
+    // `[f]`, the outputs of `f` collected; `[]` is a literal
+    Array(Vec<Filter>),
+    // `if c then a elif c2 then b else d end`, each condition with its branch; no `else` is `.`
+    If(Vec<(Vec<Filter>, Vec<Filter>)>, Option<Vec<Filter>>),
+    // `try body catch handler`, and `body?`, which has no handler
+    Try(Vec<Filter>, Option<Vec<Filter>>),
+    // `source as $name | body`
+    Bind(Vec<Filter>, String, Vec<Filter>),
+    Reduce { source: Vec<Filter>, name: String, init: Vec<Filter>, update: Vec<Filter> },
+    Foreach { source: Vec<Filter>, name: String, init: Vec<Filter>, update: Vec<Filter>, extract: Option<Vec<Filter>> },
+    // produced by the optimizer, never by the parser
+    Path(Vec<PathStep>),
+    IterSelect(Condition),
+}
+
+
+// how tightly a filter binds, loosest first: the operands of an operator bind at least as tightly as
+// the operator itself, and the printer puts anything looser in parentheses
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Prec {
+    Pipe,
+    Comma,
+    Alternative,
+    Assign,
+    Or,
+    And,
+    Compare,
+    Add,
+    // unary minus, whose operand is a product
+    Negate,
+    Mul,
+    // `try body catch handler`
+    Try,
+    // a primary filter and the `.name`, `[...]` and `?` suffixes written after it
+    Term,
+}
+
+impl Prec {
+    // the next tighter level
+    pub fn above(self) -> Prec {
+        match self {
+            Prec::Pipe => Prec::Comma,
+            Prec::Comma => Prec::Alternative,
+            Prec::Alternative => Prec::Assign,
+            Prec::Assign => Prec::Or,
+            Prec::Or => Prec::And,
+            Prec::And => Prec::Compare,
+            Prec::Compare => Prec::Add,
+            Prec::Add => Prec::Negate,
+            Prec::Negate => Prec::Mul,
+            Prec::Mul => Prec::Try,
+            Prec::Try | Prec::Term => Prec::Term,
+        }
+    }
+}
+
+// a binary operator between two filters
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Pipe,
+    Comma,
+    Alternative,
+    Assign(AssignOp),
+    Or,
+    And,
+    Compare(CompareOp),
+    Arith(ArithOp),
+}
+
+impl Operator {
+    fn prec(self) -> Prec {
+        match self {
+            Operator::Pipe => Prec::Pipe,
+            Operator::Comma => Prec::Comma,
+            Operator::Alternative => Prec::Alternative,
+            Operator::Assign(_) => Prec::Assign,
+            Operator::Or => Prec::Or,
+            Operator::And => Prec::And,
+            Operator::Compare(_) => Prec::Compare,
+            Operator::Arith(ArithOp::Add | ArithOp::Sub) => Prec::Add,
+            Operator::Arith(_) => Prec::Mul,
+        }
+    }
+}
+
+// nom's `char` and `tag`, noting what was expected where they fail for syntax errors
+fn char<'a>(c: char) -> impl FnMut(&'a str) -> IResult<&'a str, char> {
+    move |input: &'a str| expect(input, Expected::Char(c), nom::character::complete::char(c)(input))
+}
+
+fn tag<'a>(token: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
+    move |input: &'a str| expect(input, Expected::Token(token), nom::bytes::complete::tag(token)(input))
+}
+
+// whitespace and `#` comments, which run to the end of the line
+fn ws(input: &str) -> IResult<&str, ()> {
+    let mut rest = input.trim_start();
+    while let Some(comment) = rest.strip_prefix('#') {
+        rest = comment.find('\n').map_or("", |end| &comment[end..]).trim_start();
+    }
+    Ok((rest, ()))
+}
+
+// `parser`'s filter, with the span of the query it was parsed from
+fn spanned<'a>(mut parser: impl FnMut(&'a str) -> IResult<&'a str, RustyFilter>) -> impl FnMut(&'a str) -> IResult<&'a str, Filter> {
+    move |input: &'a str| {
+        let (rest, kind) = parser(input)?;
+        Ok((rest, Filter::new(kind, span(input, rest))))
+    }
+}
+
+// `parser`'s filter as a pipeline of its own
+fn single<'a>(parser: impl FnMut(&'a str) -> IResult<&'a str, RustyFilter>) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<Filter>> {
+    map(spanned(parser), |filter| vec![filter])
 }
 
 // keyword parser with word-boundary check
 fn parse_keyword<'a>(kw: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, &'a str> {
     move |input: &'a str| {
-        let (rest, matched) = tag(kw)(input)?;
-        if rest.starts_with(|c: char| c.is_alphanumeric() || c == '_') {
-            Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag)))
-        } else {
-            Ok((rest, matched))
+        match nom::bytes::complete::tag::<_, _, nom::error::Error<&str>>(kw)(input) {
+            Ok((rest, matched)) if !rest.starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_') => Ok((rest, matched)),
+            _ => {
+                tried(input, kw);
+                Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag)))
+            }
         }
     }
 }
 
-fn parse_dot(input: &str) -> IResult<&str, &str> {
-    tag(".")(input)
-}
-
+// a name as jq reads them, `[A-Za-z_][A-Za-z0-9_]*`, so `.a-1` is `.a - 1`
 fn parse_word(input: &str) -> IResult<&str, &str> {
-    recognize(pair(
+    label("a name", recognize(pair(
         alt((alpha1, tag("_"))),
-        take_while(|c: char| c.is_alphanumeric() || c == '_' || c == '-')
-    ))(input)
+        take_while(|c: char| c.is_ascii_alphanumeric() || c == '_')
+    )))(input)
 }
-fn parse_field(input: &str) -> IResult<&str, RustyFilter> {
-    map(
-        preceded(
-            parse_dot,
-            parse_word
-        ),
-        |s: &str| RustyFilter::Field(s.to_string())
-    )(input)
+
+fn parse_variable_name(input: &str) -> IResult<&str, String> {
+    map(preceded(char('$'), label("a variable name", parse_word)), str::to_string)(input)
 }
 
-fn parse_index(input: &str) -> IResult<&str, RustyFilter> {
-    map(
-        preceded(
-            parse_dot,
-            delimited(
-                char('['),
-                map_res(
-                    recognize(pair(opt(char('-')), digit1)),
-                    |s: &str| s.parse::<i32>()
-                ),
-                char(']')
-            )
-        ),
-        RustyFilter::Index
-    )(input)
+fn parse_variable(input: &str) -> IResult<&str, RustyFilter> {
+    map(parse_variable_name, RustyFilter::Variable)(input)
 }
 
-fn parse_slice(input: &str) -> IResult<&str, RustyFilter> {
-    preceded(
-        parse_dot,
-        delimited(
-            char('['),
-            map(
-                tuple((
-                    opt(map_res(recognize(pair(opt(char('-')), digit1)), |s: &str| s.parse::<i64>())),
-                    char(':'),
-                    opt(map_res(recognize(pair(opt(char('-')), digit1)), |s: &str| s.parse::<i64>())),
-                )),
-                |(start, _, end)| RustyFilter::Slice(start, end),
-            ),
-            char(']'),
-        ),
-    )(input)
+fn parse_int<T: std::str::FromStr>(input: &str) -> IResult<&str, T> {
+    map_res(recognize(pair(opt(char('-')), digit1)), |s: &str| s.parse::<T>())(input)
 }
 
-fn parse_iterator(input: &str) -> IResult<&str, RustyFilter> {
-    map(
-        preceded(parse_dot, tag("[]")),
-        |_| RustyFilter::Iterator
+// the inside of `[...]` after a filter or a `.`: `[]`, `[n]`, `[start:end]` and `["key"]`
+fn parse_brackets(input: &str) -> IResult<&str, RustyFilter> {
+    delimited(
+        pair(char('['), ws),
+        alt((
+            map(parse_quoted, RustyFilter::Field),
+            map(
+                separated_pair(opt(parse_int), delimited(ws, char(':'), ws), opt(parse_int)),
+                |(start, end)| RustyFilter::Slice(start, end),
+            ),
+            map(parse_int, RustyFilter::Index),
+            success(RustyFilter::Iterator),
+        )),
+        pair(ws, char(']')),
     )(input)
 }
 
-fn parse_recursive_descent(input: &str) -> IResult<&str, RustyFilter> {
-    map(tag(".."), |_| RustyFilter::RecurseDescent)(input)
+// `..`, `.`, and `.name`, `."name"`, `.[n]` and the other lookups; any name follows a `.`,
+// keywords included
+fn parse_dot_filter(input: &str) -> IResult<&str, RustyFilter> {
+    alt((
+        map(tag(".."), |_| RustyFilter::RecurseDescent),
+        preceded(
+            char('.'),
+            alt((
+                map(parse_word, |s: &str| RustyFilter::Field(s.to_string())),
+                map(parse_quoted, RustyFilter::Field),
+                parse_brackets,
+                success(RustyFilter::Identity),
+            )),
+        ),
+    ))(input)
 }
 
-fn parse_identity(input: &str) -> IResult<&str, RustyFilter> {
-    let (rest, _) = parse_dot(input)?;
-    if rest.starts_with('.') {
-        Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Tag)))
-    } else {
-        Ok((rest, RustyFilter::Identity))
+// one `key: value` of an object, or the `key`, `"key"` and `$name` shorthands for `key: .key` and
+// `name: $name`
+fn parse_object_entry(input: &str) -> IResult<&str, (String, Vec<Filter>)> {
+    if let Ok((rest, name)) = parse_variable_name(input) {
+        let value = Filter::new(RustyFilter::Variable(name.clone()), span(input, rest));
+        return Ok((rest, (name, vec![value])));
+    }
+    let (rest, key) = alt((map(parse_word, str::to_string), parse_quoted))(input)?;
+    let key_span = span(input, rest);
+    match preceded(delimited(ws, char(':'), ws), |i| parse_binary(i, Prec::Pipe, false))(rest) {
+        Ok((rest, value)) => Ok((rest, (key, value))),
+        Err(nom::Err::Error(_)) => {
+            let value = Filter::new(RustyFilter::Field(key.clone()), key_span);
+            Ok((rest, (key, vec![value])))
+        }
+        Err(e) => Err(e),
     }
 }
 
-fn parse_key_value_pair(input: &str) -> IResult<&str, (String, Vec<RustyFilter>)> {
+// object construction; the values cannot hold a `,` outside parentheses
+fn parse_object(input: &str) -> IResult<&str, RustyFilter> {
     map(
-        separated_pair(
-            parse_word,
-            delimited(multispace0, char(':'), multispace0),
-            parse_pipeline,
+        delimited(
+            pair(char('{'), ws),
+            opt(separated_list1(delimited(ws, char(','), ws), parse_object_entry)),
+            pair(ws, char('}')),
         ),
-        |(k, v)| (k.to_string(), v),
+        |pairs| RustyFilter::Object(pairs.unwrap_or_default()),
     )(input)
 }
 
-// object construction
-fn parse_object(input: &str) -> IResult<&str, RustyFilter> {
+// array construction
+fn parse_array(input: &str) -> IResult<&str, RustyFilter> {
     map(
-        delimited(
-            char('{'),
-            delimited(
-                multispace0,
-                separated_list1(
-                    delimited(multispace0, char(','), multispace0),
-                    parse_key_value_pair
-                ),
-                multispace0
-            ),
-            char('}')
-        ),
-        RustyFilter::Object
+        delimited(pair(char('['), ws), opt(parse_pipe), pair(ws, char(']'))),
+        |items| match items {
+            Some(items) => RustyFilter::Array(items),
+            None => RustyFilter::LiteralValue(Literal::Array(Vec::new())),
+        },
     )(input)
 }
 
@@ -221,6 +525,40 @@ fn parse_compare_op(input: &str) -> IResult<&str, CompareOp> {
     ))(input)
 }
 
+// any binary operator, the longest first where one is the start of another
+fn parse_operator(input: &str) -> IResult<&str, Operator> {
+    alt((
+        map(tag("|="), |_| Operator::Assign(AssignOp::Update)),
+        map(tag("|"), |_| Operator::Pipe),
+        map(tag(","), |_| Operator::Comma),
+        map(tag("//="), |_| Operator::Assign(AssignOp::Alternative)),
+        map(tag("//"), |_| Operator::Alternative),
+        map(parse_compare_op, Operator::Compare),
+        map(tag("="), |_| Operator::Assign(AssignOp::Set)),
+        map(parse_keyword("or"), |_| Operator::Or),
+        map(parse_keyword("and"), |_| Operator::And),
+        map(
+            pair(
+                alt((
+                    map(tag("+"), |_| ArithOp::Add),
+                    map(tag("-"), |_| ArithOp::Sub),
+                    map(tag("*"), |_| ArithOp::Mul),
+                    map(tag("/"), |_| ArithOp::Div),
+                    map(tag("%"), |_| ArithOp::Mod),
+                )),
+                opt(quiet(tag("="))),
+            ),
+            |(op, assign)| match assign {
+                Some(_) => Operator::Assign(AssignOp::Arith(op)),
+                None => Operator::Arith(op),
+            },
+        ),
+    ))(input)
+}
+
+fn parse_quoted(input: &str) -> IResult<&str, String> {
+    delimited(char('"'), parse_string_contents, char('"'))(input)
+}
 fn parse_string_contents(input: &str) -> IResult<&str, String> {
     let mut result = String::new();
     let mut chars = input.char_indices();
@@ -239,28 +577,28 @@ fn parse_string_contents(input: &str) -> IResult<&str, String> {
                     for _ in 0..4 {
                         match chars.next() {
                             Some((_, c)) if c.is_ascii_hexdigit() => hex.push(c),
-                            _ => return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))),
+                            _ => return Err(nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char))),
                         }
                     }
                     let code = u32::from_str_radix(&hex, 16)
-                        .map_err(|_| nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
+                        .map_err(|_| nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char)))?;
                     // handle UTF-16 surrogate pairs
                     let code = if (0xD800..=0xDBFF).contains(&code) {
                         match (chars.next(), chars.next()) {
                             (Some((_, '\\')), Some((_, 'u'))) => {}
-                            _ => return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))),
+                            _ => return Err(nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char))),
                         }
                         let mut hex2 = String::with_capacity(4);
                         for _ in 0..4 {
                             match chars.next() {
                                 Some((_, c)) if c.is_ascii_hexdigit() => hex2.push(c),
-                                _ => return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))),
+                                _ => return Err(nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char))),
                             }
                         }
                         let low = u32::from_str_radix(&hex2, 16)
-                            .map_err(|_| nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char)))?;
+                            .map_err(|_| nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char)))?;
                         if !(0xDC00..=0xDFFF).contains(&low) {
-                            return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char)));
+                            return Err(nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char)));
                         }
                         0x10000 + ((code - 0xD800) << 10) + (low - 0xDC00)
                     } else {
@@ -268,20 +606,20 @@ fn parse_string_contents(input: &str) -> IResult<&str, String> {
                     };
                     match char::from_u32(code) {
                         Some(c) => result.push(c),
-                        None => return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))),
+                        None => return Err(nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char))),
                     }
                 },
-                _ => return Err(nom::Err::Failure(nom::error::Error::new(input, nom::error::ErrorKind::Char))),
+                _ => return Err(nom::Err::Failure(nom::error::Error::new(&input[i..], nom::error::ErrorKind::Char))),
             },
             _ => result.push(c),
         }
     }
     // reached end of input without closing quote
-    Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char)))
+    expect(&input[input.len()..], Expected::Char('"'), Err(nom::Err::Error(nom::error::Error::new(input, nom::error::ErrorKind::Char))))
 }
 
 fn parse_literal(input: &str) -> IResult<&str, Literal> {
-    alt((
+    label("a literal", alt((
         map(parse_keyword("true"), |_| Literal::Bool(true)),
         map(parse_keyword("false"), |_| Literal::Bool(false)),
         map(parse_keyword("null"), |_| Literal::Null),
@@ -289,141 +627,21 @@ fn parse_literal(input: &str) -> IResult<&str, Literal> {
             delimited(char('"'), parse_string_contents, char('"')),
             Literal::String
         ),
-        map(
-            map_res(
-                recognize(tuple((opt(char('-')), digit1, char('.'), digit1))),
-                |s: &str| s.parse::<f64>()
-            ),
-            Literal::Float
-        ),
-        map(
-            map_res(recognize(pair(opt(char('-')), digit1)), |s: &str| s.parse::<i64>()),
-            Literal::Int
-        ),
-    ))(input)
-}
-
-// parse an expression: literal or path
-fn parse_expr(input: &str) -> IResult<&str, Expr> {
-    alt((
-        map(parse_literal, Expr::Literal),
-        map(parse_pipeline, Expr::Path),
-    ))(input)
-}
-
-// parse a comparison or bare boolean path
-// path [op expr] [| not]
-fn parse_comparison_or_bool_path(input: &str) -> IResult<&str, Condition> {
-    let (rest, path) = parse_pipeline(input)?;
-    // try to parse an operator + expression
-    if let Ok((rest2, (_, op, _, expr, _))) = tuple((
-        multispace0::<&str, nom::error::Error<&str>>,
-        parse_compare_op,
-        multispace0,
-        parse_expr,
-        multispace0,
-    ))(rest) {
-        // check for trailing `| not`
-        if let Ok((rest3, _)) = tuple((
-            multispace0::<&str, nom::error::Error<&str>>,
-            char('|'),
-            multispace0,
-            parse_keyword("not"),
-        ))(rest2) {
-            Ok((rest3, Condition::Not(Box::new(Condition::Comparison(path, op, expr)))))
-        } else {
-            Ok((rest2, Condition::Comparison(path, op, expr)))
-        }
-    } else {
-        // no operator — check for `| not` on bare path
-        if let Ok((rest2, _)) = tuple((
-            multispace0::<&str, nom::error::Error<&str>>,
-            char('|'),
-            multispace0,
-            parse_keyword("not"),
-        ))(rest) {
-            Ok((rest2, Condition::Not(Box::new(Condition::BoolPath(path)))))
-        } else {
-            Ok((rest, Condition::BoolPath(path)))
-        }
-    }
-}
-
-// atom: parenthesized condition or comparison/bool
-fn parse_condition_atom(input: &str) -> IResult<&str, Condition> {
-    alt((
-        delimited(
-            pair(char('('), multispace0),
-            parse_condition,
-            pair(multispace0, char(')')),
-        ),
-        parse_comparison_or_bool_path,
-    ))(input)
-}
-
-// not-expr: "not" atom | atom  (prefix not)
-fn parse_condition_not(input: &str) -> IResult<&str, Condition> {
-    alt((
-        map(
-            preceded(pair(parse_keyword("not"), multispace0), parse_condition_atom),
-            |c| Condition::Not(Box::new(c)),
-        ),
-        parse_condition_atom,
-    ))(input)
-}
-
-// and-expr: not-expr ("and" not-expr)*
-fn parse_condition_and(input: &str) -> IResult<&str, Condition> {
-    let (mut rest, mut left) = parse_condition_not(input)?;
-    loop {
-        let try_and = tuple((
-            multispace0::<&str, nom::error::Error<&str>>,
-            parse_keyword("and"),
-            multispace0,
-        ))(rest);
-        match try_and {
-            Ok((rest2, _)) => {
-                let (rest3, right) = parse_condition_not(rest2)?;
-                left = Condition::And(Box::new(left), Box::new(right));
-                rest = rest3;
-            }
-            Err(_) => break,
-        }
-    }
-    Ok((rest, left))
-}
-
-// condition: and-expr ("or" and-expr)*
-fn parse_condition(input: &str) -> IResult<&str, Condition> {
-    let (mut rest, mut left) = parse_condition_and(input)?;
-    loop {
-        let try_or = tuple((
-            multispace0::<&str, nom::error::Error<&str>>,
-            parse_keyword("or"),
-            multispace0,
-        ))(rest);
-        match try_or {
-            Ok((rest2, _)) => {
-                let (rest3, right) = parse_condition_and(rest2)?;
-                left = Condition::Or(Box::new(left), Box::new(right));
-                rest = rest3;
+        map_res(
+            // a number without a fraction or an exponent is no mistake, so neither is expected
+            recognize(tuple((
+                opt(char('-')),
+                digit1,
+                opt(pair(nom::character::complete::char('.'), digit1)),
+                opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+            ))),
+            // integers too large for i64 are floats, as every number is in jq
+            |s: &str| match s.parse::<i64>() {
+                Ok(n) if !s.contains(['.', 'e', 'E']) => Ok(Literal::Int(n)),
+                _ => s.parse::<f64>().map(Literal::Float),
             }
-            Err(_) => break,
-        }
-    }
-    Ok((rest, left))
-}
-
-// boolean selection filter
-fn parse_select(input: &str) -> IResult<&str, RustyFilter> {
-    map(
-        delimited(
-            tuple((tag("select"), multispace0, char('('))),
-            delimited(multispace0, parse_condition, multispace0),
-            char(')')
         ),
-        RustyFilter::Select
-    )(input)
+    )))(input)
 }
 
 // no-arg builtins
@@ -469,6 +687,7 @@ fn parse_builtin0(input: &str) -> IResult<&str, RustyFilter> {
             map(parse_keyword("isnan"), |_| RustyFilter::Builtin0(Builtin0::Isnan)),
             map(parse_keyword("isnormal"), |_| RustyFilter::Builtin0(Builtin0::Isnormal)),
             map(parse_keyword("recurse"), |_| RustyFilter::Builtin0(Builtin0::Recurse)),
+            map(parse_keyword("tostream"), |_| RustyFilter::Builtin0(Builtin0::ToStream)),
         )),
     ))(input)
 }
@@ -477,13 +696,13 @@ fn parse_builtin0(input: &str) -> IResult<&str, RustyFilter> {
 fn parse_builtin1_call<'a>(kw: &'static str, b: Builtin1) -> impl FnMut(&'a str) -> IResult<&'a str, RustyFilter> {
     move |input: &'a str| {
         let (rest, _) = parse_keyword(kw)(input)?;
-        let (rest, _) = multispace0(rest)?;
+        let (rest, _) = ws(rest)?;
         let (rest, _) = char('(')(rest)?;
-        let (rest, _) = multispace0(rest)?;
+        let (rest, _) = ws(rest)?;
         let (rest, lit) = parse_literal(rest)?;
-        let (rest, _) = multispace0(rest)?;
+        let (rest, _) = ws(rest)?;
         let (rest, _) = char(')')(rest)?;
-        Ok((rest, RustyFilter::Builtin1(b.clone(), lit)))
+        Ok((rest, RustyFilter::Builtin1(b, lit)))
     }
 }
 
@@ -511,124 +730,357 @@ fn parse_builtin1(input: &str) -> IResult<&str, RustyFilter> {
     ))(input)
 }
 
-// parses any single filter token
-fn parse_single_filter(input: &str) -> IResult<&str, RustyFilter> {
+// generator builtins taking a sub-query: `limit(n; f)`, `first(f)`, `isempty(f)`,
+// `fromstream(f)`, `truncate_stream(f)`
+fn parse_generator_call(input: &str) -> IResult<&str, RustyFilter> {
     alt((
-        parse_select,
-        parse_builtin1,
-        parse_builtin0,
-        parse_iterator,
-        parse_slice,
-        parse_index,
-        parse_recursive_descent,
-        parse_field,
-        parse_object,
-        parse_identity
+        map(
+            tuple((
+                parse_keyword("limit"),
+                delimited(ws, char('('), ws),
+                map_res(digit1, |s: &str| s.parse::<usize>()),
+                delimited(ws, char(';'), ws),
+                parse_pipe,
+                preceded(ws, char(')')),
+            )),
+            |(_, _, n, _, f, _)| RustyFilter::Limit(n, f),
+        ),
+        map(
+            delimited(
+                tuple((parse_keyword("first"), ws, char('('), ws)),
+                parse_pipe,
+                preceded(ws, char(')')),
+            ),
+            RustyFilter::FirstOf,
+        ),
+        map(
+            delimited(
+                tuple((parse_keyword("isempty"), ws, char('('), ws)),
+                parse_pipe,
+                preceded(ws, char(')')),
+            ),
+            RustyFilter::IsEmpty,
+        ),
+        map(
+            delimited(
+                tuple((parse_keyword("fromstream"), ws, char('('), ws)),
+                parse_pipe,
+                preceded(ws, char(')')),
+            ),
+            RustyFilter::FromStream,
+        ),
+        map(
+            delimited(
+                tuple((parse_keyword("truncate_stream"), ws, char('('), ws)),
+                parse_pipe,
+                preceded(ws, char(')')),
+            ),
+            RustyFilter::TruncateStream,
+        ),
+        map(parse_keyword("inputs"), |_| RustyFilter::Inputs),
+        map(parse_keyword("input"), |_| RustyFilter::Input),
+        map(parse_keyword("input_filename"), |_| RustyFilter::InputFilename),
+        map(parse_keyword("input_line_number"), |_| RustyFilter::InputLineNumber),
     ))(input)
 }
 
-// parses a pipeline: sequence of filters with optional | separators (no commas)
-// used internally by object values, select conditions, etc.
-// arithmetic-aware so `.price + .tax` works inside objects and select
-fn parse_pipeline(input: &str) -> IResult<&str, Vec<RustyFilter>> {
-    let (rest, segments) = many1(
-        preceded(
-            opt(delimited(multispace0, char('|'), multispace0)),
-            parse_add_sub
-        )
-    )(input)?;
-    Ok((rest, segments.into_iter().flatten().collect()))
+// the `;`-separated arguments of a call to `name`
+fn parse_call_args<'a>(name: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, Vec<Vec<Filter>>> {
+    delimited(
+        tuple((parse_keyword(name), ws, char('('), ws)),
+        separated_list1(delimited(ws, char(';'), ws), parse_pipe),
+        preceded(ws, char(')')),
+    )
+}
+
+// `INDEX(stream; idx_expr)`, `INDEX(idx_expr)`, `IN(s)`, `IN(source; s)`,
+// `JOIN($idx; idx_expr)`, `JOIN($idx; stream; idx_expr)` and `JOIN($idx; stream; idx_expr; join_expr)`
+fn parse_sql_call(input: &str) -> IResult<&str, RustyFilter> {
+    // the `.[]` that INDEX(idx_expr) stands for spans the whole call
+    let start = span(input, input).start;
+    let each = move |call: &str| vec![Filter::new(RustyFilter::Iterator, Span { start, end: start + call.len() })];
+    alt((
+        map_opt(consumed(parse_call_args("INDEX")), move |(call, mut args)| {
+            let key = args.pop()?;
+            match args.pop() {
+                Some(stream) if args.is_empty() => Some(RustyFilter::IndexBy(stream, key)),
+                None => Some(RustyFilter::IndexBy(each(call), key)),
+                Some(_) => None,
+            }
+        }),
+        map_opt(parse_call_args("IN"), |mut args| {
+            let values = args.pop()?;
+            match args.pop() {
+                Some(source) if args.is_empty() => Some(RustyFilter::In(source, values)),
+                None => Some(RustyFilter::In(Vec::new(), values)),
+                Some(_) => None,
+            }
+        }),
+        map_opt(parse_call_args("JOIN"), |args| {
+            let mut args = args.into_iter();
+            let index = args.next()?;
+            match (args.next()?, args.next(), args.next(), args.next()) {
+                (key, None, None, None) => Some(RustyFilter::Join { index, stream: None, key, join: None }),
+                (stream, Some(key), join, None) => Some(RustyFilter::Join { index, stream: Some(stream), key, join }),
+                _ => None,
+            }
+        }),
+    ))(input)
 }
 
-// arithmetic atom: a chain of single filters, or a bare literal (for `.price + 10`)
-fn parse_arith_atom(input: &str) -> IResult<&str, Vec<RustyFilter>> {
+// `halt`, `halt_error` and `halt_error(code)`
+fn parse_halt(input: &str) -> IResult<&str, RustyFilter> {
     alt((
-        many1(parse_single_filter),
-        map(parse_literal, |lit| vec![RustyFilter::LiteralValue(lit)]),
+        map(
+            delimited(
+                tuple((parse_keyword("halt_error"), ws, char('('), ws)),
+                map_res(digit1, |s: &str| s.parse::<i32>()),
+                preceded(ws, char(')')),
+            ),
+            RustyFilter::HaltError,
+        ),
+        map(parse_keyword("halt_error"), |_| RustyFilter::HaltError(5)),
+        map(parse_keyword("halt"), |_| RustyFilter::Halt),
     ))(input)
 }
 
-// mul/div/mod: arith_atom ((*|/|%) arith_atom)*
-fn parse_mul_div(input: &str) -> IResult<&str, Vec<RustyFilter>> {
-    let (mut rest, mut left) = parse_arith_atom(input)?;
+// `select(f)`, with `f` taken apart into a condition
+fn parse_select(input: &str) -> IResult<&str, RustyFilter> {
+    map(
+        delimited(
+            tuple((parse_keyword("select"), ws, char('('), ws)),
+            parse_pipe,
+            pair(ws, char(')')),
+        ),
+        |filters| RustyFilter::Select(condition(filters)),
+    )(input)
+}
+
+// the condition `select(f)` tests: `and`, `or`, `not` and comparisons with a literal are taken apart
+// so a fused `.[] | select(...)` can test them natively, anything else is tested for truthiness
+fn condition(mut filters: Vec<Filter>) -> Condition {
+    let Some(last) = filters.pop() else { return Condition::BoolPath(filters) };
+    match last.kind {
+        RustyFilter::Or(l, r) if filters.is_empty() => Condition::Or(Box::new(condition(l)), Box::new(condition(r))),
+        RustyFilter::And(l, r) if filters.is_empty() => Condition::And(Box::new(condition(l)), Box::new(condition(r))),
+        RustyFilter::Compare(l, op, r) if filters.is_empty() => match literal_of(&r) {
+            Some(lit) => Condition::Comparison(l, op, Expr::Literal(lit)),
+            None => Condition::Comparison(l, op, Expr::Path(r)),
+        },
+        RustyFilter::Builtin0(Builtin0::Not) if !filters.is_empty() => Condition::Not(Box::new(condition(filters))),
+        // `.a | .b == 1` compares `.a.b`
+        RustyFilter::Compare(l, op, r) if literal_of(&r).is_some() => {
+            filters.extend(l);
+            Condition::Comparison(filters, op, Expr::Literal(literal_of(&r).expect("checked above")))
+        }
+        kind => {
+            filters.push(Filter::new(kind, last.span));
+            Condition::BoolPath(filters)
+        }
+    }
+}
+
+fn literal_of(filters: &[Filter]) -> Option<Literal> {
+    match filters {
+        [Filter { kind: RustyFilter::LiteralValue(lit), .. }] => Some(lit.clone()),
+        _ => None,
+    }
+}
+
+// `if c then a elif c2 then b else d end`
+fn parse_if(input: &str) -> IResult<&str, RustyFilter> {
+    let (mut rest, _) = parse_keyword("if")(input)?;
+    let mut branches = Vec::new();
     loop {
-        let try_op = tuple((
-            multispace0::<&str, nom::error::Error<&str>>,
-            alt((
-                map(char('*'), |_| ArithOp::Mul),
-                map(char('/'), |_| ArithOp::Div),
-                map(char('%'), |_| ArithOp::Mod),
-            )),
-            multispace0,
-        ))(rest);
-        match try_op {
-            Ok((rest2, (_, op, _))) => {
-                let (rest3, right) = parse_arith_atom(rest2)?;
-                left = vec![RustyFilter::Arithmetic(left, op, right)];
-                rest = rest3;
+        let (r, cond) = delimited(ws, parse_pipe, pair(ws, parse_keyword("then")))(rest)?;
+        let (r, body) = preceded(ws, parse_pipe)(r)?;
+        branches.push((cond, body));
+        let (r, _) = ws(r)?;
+        match parse_keyword("elif")(r) {
+            Ok((r, _)) => rest = r,
+            Err(_) => {
+                rest = r;
+                break;
             }
-            Err(_) => break,
         }
     }
-    Ok((rest, left))
+    let (rest, otherwise) = opt(delimited(parse_keyword("else"), preceded(ws, parse_pipe), ws))(rest)?;
+    let (rest, _) = parse_keyword("end")(rest)?;
+    Ok((rest, RustyFilter::If(branches, otherwise)))
+}
+
+// `source as $name (`, which `reduce` and `foreach` start with
+fn parse_loop_head<'a>(kw: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, (Vec<Filter>, String)> {
+    move |input: &'a str| {
+        let (rest, _) = pair(parse_keyword(kw), ws)(input)?;
+        let (rest, source) = label("a filter", parse_term)(rest)?;
+        let (rest, name) = preceded(tuple((ws, parse_keyword("as"), ws)), parse_variable_name)(rest)?;
+        let (rest, _) = tuple((ws, char('('), ws))(rest)?;
+        Ok((rest, (source, name)))
+    }
 }
 
-// add/sub: mul_div ((+|-) mul_div)*
-// `-` requires surrounding spaces to avoid ambiguity with hyphenated field names
-fn parse_add_sub(input: &str) -> IResult<&str, Vec<RustyFilter>> {
-    let (mut rest, mut left) = parse_mul_div(input)?;
+// `reduce source as $name (init; update)`
+fn parse_reduce(input: &str) -> IResult<&str, RustyFilter> {
+    map(
+        tuple((
+            parse_loop_head("reduce"),
+            parse_pipe,
+            preceded(tuple((ws, char(';'), ws)), parse_pipe),
+            pair(ws, char(')')),
+        )),
+        |((source, name), init, update, _)| RustyFilter::Reduce { source, name, init, update },
+    )(input)
+}
+
+// `foreach source as $name (init; update)` and `foreach source as $name (init; update; extract)`
+fn parse_foreach(input: &str) -> IResult<&str, RustyFilter> {
+    map(
+        tuple((
+            parse_loop_head("foreach"),
+            parse_pipe,
+            preceded(tuple((ws, char(';'), ws)), parse_pipe),
+            opt(preceded(tuple((ws, char(';'), ws)), parse_pipe)),
+            pair(ws, char(')')),
+        )),
+        |((source, name), init, update, extract, _)| RustyFilter::Foreach { source, name, init, update, extract },
+    )(input)
+}
+
+// filters that may follow another with nothing in between, as in `.a.b`, `.[]length` or `$x.a`
+fn parse_adjacent(input: &str) -> IResult<&str, Filter> {
+    spanned(alt((
+        parse_dot_filter,
+        parse_select,
+        parse_generator_call,
+        parse_sql_call,
+        parse_halt,
+        parse_builtin1,
+        parse_builtin0,
+        parse_object,
+        parse_variable,
+    )))(input)
+}
+
+// a filter that needs no operator: a parenthesized query, array, literal, `reduce`, `foreach`, `if`,
+// or anything that may follow another
+fn parse_primary(input: &str) -> IResult<&str, Vec<Filter>> {
+    alt((
+        delimited(pair(char('('), ws), parse_pipe, pair(ws, char(')'))),
+        single(parse_array),
+        single(parse_reduce),
+        single(parse_foreach),
+        single(parse_if),
+        map(parse_adjacent, |filter| vec![filter]),
+        single(map(parse_literal, RustyFilter::LiteralValue)),
+    ))(input)
+}
+
+// a primary filter and what is written right after it: more filters, as in `.a.b`, `[...]` lookups,
+// as in `.a[0]`, and `?`, which wraps everything before it in `try`
+fn parse_term(input: &str) -> IResult<&str, Vec<Filter>> {
+    let (mut rest, mut filters) = parse_primary(input)?;
     loop {
-        // try `+` with optional whitespace
-        let try_add = tuple((
-            multispace0::<&str, nom::error::Error<&str>>,
-            map(char('+'), |_| ArithOp::Add),
-            multispace0,
-        ))(rest);
-        if let Ok((rest2, (_, op, _))) = try_add {
-            let (rest3, right) = parse_mul_div(rest2)?;
-            left = vec![RustyFilter::Arithmetic(left, op, right)];
-            rest = rest3;
-            continue;
+        if let Some(after) = rest.strip_prefix('?') {
+            filters = vec![Filter::new(RustyFilter::Try(filters, None), span(input, after))];
+            rest = after;
+        } else if rest.starts_with('[') {
+            let (after, filter) = spanned(parse_brackets)(rest)?;
+            filters.push(filter);
+            rest = after;
+        } else {
+            match quiet(parse_adjacent)(rest) {
+                Ok((after, filter)) => {
+                    filters.push(filter);
+                    rest = after;
+                }
+                Err(nom::Err::Error(_)) => return Ok((rest, filters)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+// an operand of the binary operators: unary minus, `try`, or a term, which may bind a variable for
+// the rest of the pipeline
+fn parse_operand(input: &str, commas: bool) -> IResult<&str, Vec<Filter>> {
+    label("a filter", move |input: &'_ str| {
+        // a `-` before a number is part of the literal
+        if let Some(after) = input.strip_prefix('-').filter(|after| !after.starts_with(|c: char| c.is_ascii_digit())) {
+            let (rest, operand) = preceded(ws, |i| parse_binary(i, Prec::Mul, commas))(after)?;
+            return Ok((rest, vec![Filter::new(RustyFilter::Negate(operand), span(input, rest))]));
+        }
+        if let Ok((after, _)) = parse_keyword("try")(input) {
+            let (rest, body) = preceded(ws, label("a filter", parse_term))(after)?;
+            let (rest, handler) = opt(preceded(tuple((ws, quiet(parse_keyword("catch")), ws)), label("a filter", parse_term)))(rest)?;
+            return Ok((rest, vec![Filter::new(RustyFilter::Try(body, handler), span(input, rest))]));
         }
-        // try `-` only when preceded by whitespace (to avoid `.a-b` field names)
-        if rest.starts_with(' ') || rest.starts_with('\t') || rest.starts_with('\n') {
-            let try_sub = tuple((
-                multispace0::<&str, nom::error::Error<&str>>,
-                map(char('-'), |_| ArithOp::Sub),
-                multispace0,
-            ))(rest);
-            if let Ok((rest2, (_, op, _))) = try_sub {
-                let (rest3, right) = parse_mul_div(rest2)?;
-                left = vec![RustyFilter::Arithmetic(left, op, right)];
-                rest = rest3;
-                continue;
+        let (rest, term) = parse_term(input)?;
+        match preceded(ws, quiet(parse_keyword("as")))(rest) {
+            Ok((after, _)) => {
+                let (rest, name) = preceded(ws, parse_variable_name)(after)?;
+                let (rest, body) = preceded(tuple((ws, char('|'), ws)), |i| parse_binary(i, Prec::Pipe, commas))(rest)?;
+                Ok((rest, vec![Filter::new(RustyFilter::Bind(term, name, body), span(input, rest))]))
             }
+            Err(_) => Ok((rest, term)),
         }
-        break;
+    })(input)
+}
+
+// the filters joined by operators binding at least as tightly as `min`; `commas` is false for the
+// values of an object, which end at a `,`
+fn parse_binary(input: &str, min: Prec, commas: bool) -> IResult<&str, Vec<Filter>> {
+    let (mut rest, mut left) = parse_operand(input, commas)?;
+    // whether `left` is a `,` this loop built, which takes further branches in
+    let mut comma = false;
+    // comparisons and assignments do not chain
+    let mut nonassoc = None;
+    loop {
+        let (at, _) = ws(rest)?;
+        let Ok((after, op)) = label("an operator", parse_operator)(at) else { break };
+        let prec = op.prec();
+        if nonassoc == Some(prec) {
+            return Err(nom::Err::Failure(nom::error::Error::new(at, nom::error::ErrorKind::Verify)));
+        }
+        if prec < min || (op == Operator::Comma && !commas) {
+            break;
+        }
+        // `|` and `//` group to the right
+        let operand = if matches!(op, Operator::Pipe | Operator::Alternative) { prec } else { prec.above() };
+        let (after, right) = preceded(ws, |i| parse_binary(i, operand, commas))(after)?;
+        let whole = span(input, after);
+        left = match op {
+            Operator::Pipe => {
+                left.extend(right);
+                left
+            }
+            Operator::Comma if comma => {
+                let mut filter = left.pop().expect("a comma filter");
+                if let RustyFilter::Comma(branches) = &mut filter.kind { branches.push(right); }
+                filter.span = whole;
+                vec![filter]
+            }
+            Operator::Comma => vec![Filter::new(RustyFilter::Comma(vec![left, right]), whole)],
+            Operator::Alternative => vec![Filter::new(RustyFilter::Alternative(left, right), whole)],
+            Operator::Assign(op) => vec![Filter::new(RustyFilter::Assign(left, op, right), whole)],
+            Operator::Or => vec![Filter::new(RustyFilter::Or(left, right), whole)],
+            Operator::And => vec![Filter::new(RustyFilter::And(left, right), whole)],
+            Operator::Compare(op) => vec![Filter::new(RustyFilter::Compare(left, op, right), whole)],
+            Operator::Arith(op) => vec![Filter::new(RustyFilter::Arithmetic(left, op, right), whole)],
+        };
+        comma = op == Operator::Comma;
+        nonassoc = matches!(op, Operator::Compare(_) | Operator::Assign(_)).then_some(prec);
+        rest = after;
     }
     Ok((rest, left))
 }
 
-// parses a comma-separated segment: arith_expr (, arith_expr)*
-// if single expression, returns it as-is; if multiple, wraps in Comma
-fn parse_comma_segment(input: &str) -> IResult<&str, Vec<RustyFilter>> {
-    let (rest, chains) = separated_list1(
-        delimited(multispace0, char(','), multispace0),
-        parse_add_sub
-    )(input)?;
-    if chains.len() == 1 {
-        Ok((rest, chains.into_iter().next().unwrap()))
-    } else {
-        Ok((rest, vec![RustyFilter::Comma(chains)]))
-    }
+// a whole pipeline, as in parentheses and the arguments of calls
+fn parse_pipe(input: &str) -> IResult<&str, Vec<Filter>> {
+    parse_binary(input, Prec::Pipe, true)
 }
 
-// top-level query: pipe-separated comma segments
-// pipe has lowest precedence, comma has higher precedence
-pub fn parse_query(input: &str) -> IResult<&str, Vec<RustyFilter>> {
-    let (rest, segments) = separated_list1(
-        delimited(multispace0, char('|'), multispace0),
-        parse_comma_segment
-    )(input)?;
-    Ok((rest, segments.into_iter().flatten().collect()))
+// top-level query, with the whitespace and comments around it
+pub fn parse_query(input: &str) -> IResult<&str, Vec<Filter>> {
+    delimited(ws, parse_pipe, ws)(input)
 }
@@ -0,0 +1,378 @@
+use crate::parser::{ArithOp, Builtin0, Condition, Expr, Filter, Literal, PathStep, Prec, RustyFilter};
+use crate::serialize::write_string;
+
+// jq text for a parsed query, in a canonical form that parses back into the same filters
+// operators are written with a space on each side, and an operand that binds more loosely than
+// its operator allows is put in parentheses
+pub fn to_jq(filters: &[Filter]) -> String {
+    let mut out = String::new();
+    pipeline(&mut out, filters);
+    out
+}
+
+// the filters joined with `|`; only the last can be a `source as $name | body`, which takes in
+// everything after it
+fn pipeline(out: &mut String, filters: &[Filter]) {
+    if filters.is_empty() { out.push('.'); }
+    for (i, f) in filters.iter().enumerate() {
+        if i > 0 { out.push_str(" | "); }
+        single(out, f, if i + 1 == filters.len() { Prec::Pipe } else { Prec::Comma });
+    }
+}
+
+// `filters` where something binding at least as tightly as `min` goes
+fn expr(out: &mut String, filters: &[Filter], min: Prec) {
+    match filters {
+        [f] => single(out, f, min),
+        _ if level(filters) >= min => if chains(filters) { chain(out, filters) } else { pipeline(out, filters) },
+        _ => {
+            out.push('(');
+            pipeline(out, filters);
+            out.push(')');
+        }
+    }
+}
+
+fn single(out: &mut String, f: &Filter, min: Prec) {
+    if own(f) < min {
+        out.push('(');
+        filter(out, f);
+        out.push(')');
+    } else {
+        filter(out, f);
+    }
+}
+
+// how tightly a pipeline binds as written
+fn level(filters: &[Filter]) -> Prec {
+    match filters {
+        [] => Prec::Term,
+        [f] => own(f),
+        _ if chains(filters) => Prec::Term,
+        _ => Prec::Pipe,
+    }
+}
+
+// how tightly a filter binds as written
+fn own(f: &Filter) -> Prec {
+    match &f.kind {
+        RustyFilter::Comma(_) => Prec::Comma,
+        RustyFilter::Alternative(..) => Prec::Alternative,
+        RustyFilter::Assign(..) => Prec::Assign,
+        RustyFilter::Or(..) => Prec::Or,
+        RustyFilter::And(..) => Prec::And,
+        RustyFilter::Compare(..) => Prec::Compare,
+        RustyFilter::Arithmetic(_, ArithOp::Add | ArithOp::Sub, _) => Prec::Add,
+        RustyFilter::Arithmetic(..) => Prec::Mul,
+        RustyFilter::Negate(_) => Prec::Negate,
+        RustyFilter::Try(_, Some(_)) => Prec::Try,
+        RustyFilter::Bind(..) | RustyFilter::IterSelect(_) => Prec::Pipe,
+        _ => Prec::Term,
+    }
+}
+
+// whether a pipeline can be written as one term, like `.a.b[0]` or `$x.a`: lookups after a first
+// filter that more can be written right after
+fn chains(filters: &[Filter]) -> bool {
+    let Some((first, rest)) = filters.split_first() else { return false };
+    let head = own(first) == Prec::Term && !matches!(first.kind,
+        RustyFilter::Identity | RustyFilter::RecurseDescent | RustyFilter::Builtin0(Builtin0::Not)
+        | RustyFilter::LiteralValue(Literal::Int(_) | Literal::Float(_)));
+    head && !rest.is_empty() && rest.iter().all(|f| matches!(f.kind,
+        RustyFilter::Field(_) | RustyFilter::Index(_) | RustyFilter::Iterator | RustyFilter::Slice(..) | RustyFilter::Path(_)))
+}
+
+fn chain(out: &mut String, filters: &[Filter]) {
+    for f in filters {
+        filter(out, f);
+    }
+}
+
+// `.name`, or `."name"` for a name that is not a word
+fn field(out: &mut String, name: &str) {
+    out.push('.');
+    key(out, name);
+}
+
+fn key(out: &mut String, name: &str) {
+    let word = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if word { out.push_str(name) } else { write_string(out, name, false) }
+}
+
+// `name(arg; ...)`
+fn call(out: &mut String, name: &str, args: &[&[Filter]]) {
+    out.push_str(name);
+    out.push('(');
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 { out.push_str("; "); }
+        pipeline(out, arg);
+    }
+    out.push(')');
+}
+
+// `a op b`
+fn binary(out: &mut String, left: &[Filter], left_min: Prec, op: &str, right: &[Filter], right_min: Prec) {
+    expr(out, left, left_min);
+    out.push(' ');
+    out.push_str(op);
+    out.push(' ');
+    expr(out, right, right_min);
+}
+
+fn filter(out: &mut String, filter: &Filter) {
+    match &filter.kind {
+        RustyFilter::Identity => out.push('.'),
+        RustyFilter::Field(name) => field(out, name),
+        RustyFilter::Index(i) => out.push_str(&format!(".[{}]", i)),
+        RustyFilter::Iterator => out.push_str(".[]"),
+        RustyFilter::Slice(start, end) => {
+            let bound = |b: &Option<i64>| b.map_or(String::new(), |b| b.to_string());
+            out.push_str(&format!(".[{}:{}]", bound(start), bound(end)));
+        }
+        RustyFilter::RecurseDescent => out.push_str(".."),
+        RustyFilter::Object(fields) => {
+            out.push('{');
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 { out.push_str(", "); }
+                self::key(out, key);
+                out.push_str(": ");
+                // a value cannot hold a `,` outside parentheses
+                match value.as_slice() {
+                    [] => out.push('.'),
+                    value if chains(value) => chain(out, value),
+                    value => for (i, f) in value.iter().enumerate() {
+                        if i > 0 { out.push_str(" | "); }
+                        single(out, f, Prec::Alternative);
+                    },
+                }
+            }
+            out.push('}');
+        }
+        RustyFilter::Select(c) => {
+            out.push_str("select(");
+            condition(out, c, Prec::Pipe);
+            out.push(')');
+        }
+        RustyFilter::Comma(branches) => {
+            for (i, branch) in branches.iter().enumerate() {
+                if i > 0 { out.push_str(", "); }
+                expr(out, branch, Prec::Alternative);
+            }
+        }
+        RustyFilter::Arithmetic(left, op @ (ArithOp::Add | ArithOp::Sub), right) =>
+            binary(out, left, Prec::Add, op.symbol(), right, Prec::Negate),
+        RustyFilter::Arithmetic(left, op, right) => binary(out, left, Prec::Mul, op.symbol(), right, Prec::Try),
+        RustyFilter::LiteralValue(lit) => literal(out, lit),
+        RustyFilter::Builtin0(b) => out.push_str(b.name()),
+        RustyFilter::Builtin1(b, arg) => {
+            out.push_str(b.name());
+            out.push('(');
+            literal(out, arg);
+            out.push(')');
+        }
+        RustyFilter::Limit(n, f) => {
+            out.push_str(&format!("limit({}; ", n));
+            pipeline(out, f);
+            out.push(')');
+        }
+        RustyFilter::FirstOf(f) => call(out, "first", &[f]),
+        RustyFilter::IsEmpty(f) => call(out, "isempty", &[f]),
+        RustyFilter::FromStream(f) => call(out, "fromstream", &[f]),
+        RustyFilter::TruncateStream(f) => call(out, "truncate_stream", &[f]),
+        // `INDEX(key)` is `INDEX(.[]; key)`, so the stream is always written out
+        RustyFilter::IndexBy(stream, key) => call(out, "INDEX", &[stream, key]),
+        RustyFilter::In(source, values) if source.is_empty() => call(out, "IN", &[values]),
+        RustyFilter::In(source, values) => call(out, "IN", &[source, values]),
+        RustyFilter::Join { index, stream, key, join } => match (stream, join) {
+            (Some(stream), Some(join)) => call(out, "JOIN", &[index, stream, key, join]),
+            (Some(stream), None) => call(out, "JOIN", &[index, stream, key]),
+            // the parser only leaves the stream out together with the join
+            (None, _) => call(out, "JOIN", &[index, key]),
+        },
+        RustyFilter::Input => out.push_str("input"),
+        RustyFilter::Inputs => out.push_str("inputs"),
+        RustyFilter::InputFilename => out.push_str("input_filename"),
+        RustyFilter::InputLineNumber => out.push_str("input_line_number"),
+        RustyFilter::Halt => out.push_str("halt"),
+        RustyFilter::HaltError(5) => out.push_str("halt_error"),
+        RustyFilter::HaltError(code) => out.push_str(&format!("halt_error({})", code)),
+        RustyFilter::Variable(name) => {
+            out.push('$');
+            out.push_str(name);
+        }
+        RustyFilter::Compare(left, op, right) => binary(out, left, Prec::Add, op.symbol(), right, Prec::Add),
+        RustyFilter::And(left, right) => binary(out, left, Prec::And, "and", right, Prec::Compare),
+        RustyFilter::Or(left, right) => binary(out, left, Prec::Or, "or", right, Prec::And),
+        RustyFilter::Negate(operand) => {
+            out.push('-');
+            // `-1` would read as a number
+            let mut text = String::new();
+            expr(&mut text, operand, Prec::Mul);
+            if text.starts_with(|c: char| c.is_ascii_digit()) {
+                out.push('(');
+                out.push_str(&text);
+                out.push(')');
+            } else {
+                out.push_str(&text);
+            }
+        }
+        RustyFilter::Alternative(left, right) => binary(out, left, Prec::Assign, "//", right, Prec::Alternative),
+        RustyFilter::Assign(paths, op, value) => binary(out, paths, Prec::Or, op.symbol(), value, Prec::Or),
+        RustyFilter::Array(items) => {
+            out.push('[');
+            pipeline(out, items);
+            out.push(']');
+        }
+        RustyFilter::If(branches, otherwise) => {
+            for (i, (cond, then)) in branches.iter().enumerate() {
+                out.push_str(if i == 0 { "if " } else { " elif " });
+                pipeline(out, cond);
+                out.push_str(" then ");
+                pipeline(out, then);
+            }
+            if let Some(otherwise) = otherwise {
+                out.push_str(" else ");
+                pipeline(out, otherwise);
+            }
+            out.push_str(" end");
+        }
+        RustyFilter::Try(body, Some(handler)) => {
+            out.push_str("try ");
+            expr(out, body, Prec::Term);
+            out.push_str(" catch ");
+            expr(out, handler, Prec::Term);
+        }
+        RustyFilter::Try(body, None) => {
+            expr(out, body, Prec::Term);
+            out.push('?');
+        }
+        RustyFilter::Bind(source, name, body) => {
+            expr(out, source, Prec::Term);
+            out.push_str(&format!(" as ${} | ", name));
+            pipeline(out, body);
+        }
+        RustyFilter::Reduce { source, name, init, update } => {
+            out.push_str("reduce ");
+            expr(out, source, Prec::Term);
+            out.push_str(&format!(" as ${} (", name));
+            pipeline(out, init);
+            out.push_str("; ");
+            pipeline(out, update);
+            out.push(')');
+        }
+        RustyFilter::Foreach { source, name, init, update, extract } => {
+            out.push_str("foreach ");
+            expr(out, source, Prec::Term);
+            out.push_str(&format!(" as ${} (", name));
+            pipeline(out, init);
+            out.push_str("; ");
+            pipeline(out, update);
+            if let Some(extract) = extract {
+                out.push_str("; ");
+                pipeline(out, extract);
+            }
+            out.push(')');
+        }
+        // the optimizer's rewrites, written as the filters they replaced
+        RustyFilter::Path(steps) => for step in steps {
+            match step {
+                PathStep::Field(name) => field(out, name),
+                PathStep::Index(i) => out.push_str(&format!(".[{}]", i)),
+            }
+        },
+        RustyFilter::IterSelect(c) => {
+            out.push_str(".[] | select(");
+            condition(out, c, Prec::Pipe);
+            out.push(')');
+        }
+    }
+}
+
+// `c`, in parentheses if it binds more loosely than `min`
+fn condition(out: &mut String, c: &Condition, min: Prec) {
+    if condition_level(c) < min {
+        out.push('(');
+        condition(out, c, Prec::Pipe);
+        out.push(')');
+        return;
+    }
+    match c {
+        Condition::Comparison(path, op, Expr::Literal(lit)) => {
+            match path.split_last() {
+                // `.a | .b == 1` where the path is no single term
+                Some((last, prefix)) if level(path) < Prec::Add => {
+                    for f in prefix {
+                        single(out, f, Prec::Comma);
+                        out.push_str(" | ");
+                    }
+                    single(out, last, Prec::Add);
+                }
+                _ => expr(out, path, Prec::Add),
+            }
+            out.push_str(&format!(" {} ", op.symbol()));
+            literal(out, lit);
+        }
+        Condition::Comparison(path, op, Expr::Path(rhs)) => binary(out, path, Prec::Add, op.symbol(), rhs, Prec::Add),
+        Condition::BoolPath(path) => expr(out, path, min),
+        Condition::And(left, right) => {
+            condition(out, left, Prec::And);
+            out.push_str(" and ");
+            condition(out, right, Prec::Compare);
+        }
+        Condition::Or(left, right) => {
+            condition(out, left, Prec::Or);
+            out.push_str(" or ");
+            condition(out, right, Prec::And);
+        }
+        Condition::Not(inner) => {
+            condition(out, inner, Prec::Comma);
+            out.push_str(" | not");
+        }
+    }
+}
+
+// how tightly a condition binds as written
+fn condition_level(c: &Condition) -> Prec {
+    match c {
+        Condition::Or(..) => Prec::Or,
+        Condition::And(..) => Prec::And,
+        Condition::Not(_) => Prec::Pipe,
+        Condition::Comparison(path, _, Expr::Literal(_)) if level(path) < Prec::Add => Prec::Pipe,
+        Condition::Comparison(..) => Prec::Compare,
+        Condition::BoolPath(path) => level(path),
+    }
+}
+
+fn literal(out: &mut String, lit: &Literal) {
+    match lit {
+        Literal::Int(i) => out.push_str(&i.to_string()),
+        Literal::Float(f) => {
+            // the grammar needs the fraction to read a number as a float
+            let text = f.to_string();
+            out.push_str(&text);
+            if !text.contains('.') { out.push_str(".0"); }
+        }
+        Literal::String(s) => write_string(out, s, false),
+        Literal::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Literal::Null => out.push_str("null"),
+        Literal::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 { out.push_str(", "); }
+                literal(out, item);
+            }
+            out.push(']');
+        }
+        Literal::Object(fields) => {
+            out.push('{');
+            for (i, (key, value)) in fields.iter().enumerate() {
+                if i > 0 { out.push_str(", "); }
+                write_string(out, key, false);
+                out.push_str(": ");
+                literal(out, value);
+            }
+            out.push('}');
+        }
+    }
+}
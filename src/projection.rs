@@ -0,0 +1,318 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use simd_json::{BorrowedValue, Node, OwnedValue};
+use simd_json::borrowed::Object;
+
+use crate::limits::{DuplicateKeys, ParseError, ParseLimits};
+use crate::parser::{Builtin0, Condition, Expr, Filter, PathStep, RustyFilter};
+
+// The parts of a document a query can observe.
+// Subtrees outside the projection are never materialized: a dropped key behaves exactly like a
+// missing one (no output), and a container whose contents are not needed is kept as an empty shell
+// so that type and truthiness checks still see it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Projection {
+    // the whole subtree is needed
+    all: bool,
+    // object keys that are needed, and what is needed below each of them
+    fields: Vec<(String, Projection)>,
+    // what is needed below every array element and every object value
+    elements: Option<Box<Projection>>,
+}
+
+static ALL: Projection = Projection { all: true, fields: Vec::new(), elements: None };
+
+impl Projection {
+    pub fn all() -> Self {
+        Projection { all: true, ..Projection::default() }
+    }
+
+    fn field(key: &str, below: Projection) -> Self {
+        Projection { fields: vec![(key.to_string(), below)], ..Projection::default() }
+    }
+
+    fn elements(below: Projection) -> Self {
+        Projection { elements: Some(Box::new(below)), ..Projection::default() }
+    }
+
+    fn merge(mut self, other: Projection) -> Self {
+        if self.all || other.all { return Projection::all(); }
+        for (key, below) in other.fields {
+            match self.fields.iter_mut().find(|(k, _)| *k == key) {
+                Some((_, existing)) => *existing = std::mem::take(existing).merge(below),
+                None => self.fields.push((key, below)),
+            }
+        }
+        self.elements = match (self.elements, other.elements) {
+            (Some(a), Some(b)) => Some(Box::new(a.merge(*b))),
+            (a, b) => a.or(b),
+        };
+        self
+    }
+
+    // what is needed below `key` of an object, `None` when the key can be dropped
+    pub fn child(&self, key: &str) -> Option<Cow<'_, Projection>> {
+        if self.all { return Some(Cow::Borrowed(&ALL)); }
+        let field = self.fields.iter().find(|(k, _)| k == key).map(|(_, p)| p);
+        match (field, self.elements.as_deref()) {
+            (Some(f), Some(e)) => Some(Cow::Owned(f.clone().merge(e.clone()))),
+            (Some(p), None) | (None, Some(p)) => Some(Cow::Borrowed(p)),
+            (None, None) => None,
+        }
+    }
+
+    // what is needed below every array element, `None` when the elements can be dropped
+    pub fn element(&self) -> Option<&Projection> {
+        if self.all { Some(&ALL) } else { self.elements.as_deref() }
+    }
+}
+
+// derive what a query needs of its input; its outputs are handed back whole
+// documents read through `input` are parsed with the projection of `.`, so a query reading them
+// needs every document whole, even when it throws `.` away as `1 | inputs` does
+pub fn analyze(filters: &[Filter]) -> Projection {
+    if filters.iter().any(reads_inputs) { return Projection::all(); }
+    pipeline(filters, Projection::all())
+}
+
+fn reads_inputs(filter: &Filter) -> bool {
+    let any = |filters: &[Filter]| filters.iter().any(reads_inputs);
+    match &filter.kind {
+        RustyFilter::Input | RustyFilter::Inputs => true,
+        RustyFilter::Object(pairs) => pairs.iter().any(|(_, v)| any(v)),
+        RustyFilter::Comma(branches) => branches.iter().any(|b| any(b)),
+        RustyFilter::Arithmetic(left, _, right) | RustyFilter::Compare(left, _, right)
+        | RustyFilter::And(left, right) | RustyFilter::Or(left, right) | RustyFilter::Alternative(left, right)
+        | RustyFilter::Assign(left, _, right) | RustyFilter::IndexBy(left, right) | RustyFilter::In(left, right) => any(left) || any(right),
+        RustyFilter::Negate(f) | RustyFilter::Array(f) | RustyFilter::Limit(_, f) | RustyFilter::FirstOf(f)
+        | RustyFilter::IsEmpty(f) | RustyFilter::FromStream(f) | RustyFilter::TruncateStream(f) => any(f),
+        RustyFilter::If(branches, otherwise) => branches.iter().any(|(cond, then)| any(cond) || any(then))
+            || otherwise.as_deref().is_some_and(any),
+        RustyFilter::Try(body, handler) => any(body) || handler.as_deref().is_some_and(any),
+        RustyFilter::Bind(source, _, body) => any(source) || any(body),
+        RustyFilter::Reduce { source, init, update, .. } => any(source) || any(init) || any(update),
+        RustyFilter::Foreach { source, init, update, extract, .. } => any(source) || any(init) || any(update)
+            || extract.as_deref().is_some_and(any),
+        RustyFilter::Join { index, stream, key, join } => any(index) || stream.as_deref().is_some_and(any) || any(key)
+            || join.as_deref().is_some_and(any),
+        RustyFilter::Select(condition) | RustyFilter::IterSelect(condition) => condition_reads_inputs(condition),
+        _ => false,
+    }
+}
+
+fn condition_reads_inputs(condition: &Condition) -> bool {
+    match condition {
+        Condition::Comparison(path, _, Expr::Path(rhs)) => path.iter().chain(rhs.iter()).any(reads_inputs),
+        Condition::Comparison(path, _, Expr::Literal(_)) | Condition::BoolPath(path) => path.iter().any(reads_inputs),
+        Condition::And(l, r) | Condition::Or(l, r) => condition_reads_inputs(l) || condition_reads_inputs(r),
+        Condition::Not(inner) => condition_reads_inputs(inner),
+    }
+}
+
+// walk a pipeline backwards, turning what is needed of its output into what is needed of its input
+fn pipeline(filters: &[Filter], out: Projection) -> Projection {
+    filters.iter().rev().fold(out, |out, f| filter(f, out))
+}
+
+fn filter(filter: &Filter, out: Projection) -> Projection {
+    match &filter.kind {
+        RustyFilter::Identity => out,
+        RustyFilter::Field(key) => Projection::field(key, out),
+        RustyFilter::Index(_) | RustyFilter::Iterator | RustyFilter::Slice(..) => Projection::elements(out),
+        RustyFilter::Path(steps) => steps.iter().rev().fold(out, |out, step| match step {
+            PathStep::Field(key) => Projection::field(key, out),
+            PathStep::Index(_) => Projection::elements(out),
+        }),
+        RustyFilter::Select(condition) => out.merge(condition_needs(condition)),
+        RustyFilter::IterSelect(condition) => Projection::elements(out.merge(condition_needs(condition))),
+        RustyFilter::Object(pairs) => pairs.iter().fold(Projection::default(), |acc, (key, value)| {
+            let below = out.child(key).map(Cow::into_owned).unwrap_or_default();
+            acc.merge(pipeline(value, below))
+        }),
+        RustyFilter::Comma(branches) => branches.iter()
+            .fold(Projection::default(), |acc, branch| acc.merge(pipeline(branch, out.clone()))),
+        RustyFilter::Arithmetic(left, _, right) | RustyFilter::Compare(left, _, right) =>
+            pipeline(left, Projection::all()).merge(pipeline(right, Projection::all())),
+        RustyFilter::Negate(operand) => pipeline(operand, Projection::all()),
+        // only truthiness matters
+        RustyFilter::And(left, right) | RustyFilter::Or(left, right) =>
+            pipeline(left, Projection::default()).merge(pipeline(right, Projection::default())),
+        RustyFilter::Alternative(left, right) => pipeline(left, out.clone()).merge(pipeline(right, out)),
+        RustyFilter::Array(items) => pipeline(items, out.element().cloned().unwrap_or_default()),
+        RustyFilter::If(branches, otherwise) => {
+            let taken = match otherwise {
+                Some(otherwise) => pipeline(otherwise, out.clone()),
+                None => out.clone(),
+            };
+            branches.iter().fold(taken, |acc, (cond, then)| {
+                acc.merge(pipeline(cond, Projection::default())).merge(pipeline(then, out.clone()))
+            })
+        }
+        RustyFilter::Try(body, handler) => {
+            let caught = handler.as_ref().map(|h| pipeline(h, Projection::default())).unwrap_or_default();
+            pipeline(body, out).merge(caught)
+        }
+        // what the body reads of the variable is not tracked
+        RustyFilter::Bind(source, _, body) => pipeline(source, Projection::all()).merge(pipeline(body, out)),
+        // the input comes back whole with its paths updated, and the state of a loop is not tracked
+        RustyFilter::Assign(..) | RustyFilter::Reduce { .. } | RustyFilter::Foreach { .. } => Projection::all(),
+        RustyFilter::Limit(_, sub_query) | RustyFilter::FirstOf(sub_query) => pipeline(sub_query, out),
+        // only whether there is an output matters
+        RustyFilter::IsEmpty(sub_query) => pipeline(sub_query, Projection::default()),
+        // events are taken apart and rebuilt, so what is read of them is not tracked;
+        // the events of `truncate_stream` come from its own input of null, and `.` is only the depth
+        RustyFilter::FromStream(sub_query) | RustyFilter::TruncateStream(sub_query) => pipeline(sub_query, Projection::all()),
+        // rows end up whole in the tables, and keys and values are compared whole
+        RustyFilter::IndexBy(stream, _) => pipeline(stream, Projection::all()),
+        RustyFilter::In(source, values) => pipeline(source, Projection::all()).merge(pipeline(values, Projection::all())),
+        RustyFilter::Join { index, stream, .. } => {
+            let rows = match stream {
+                Some(stream) => pipeline(stream, Projection::all()),
+                None => Projection::all(),
+            };
+            pipeline(index, Projection::all()).merge(rows)
+        }
+        RustyFilter::LiteralValue(_) => Projection::default(),
+        RustyFilter::Builtin0(Builtin0::First | Builtin0::Last) => Projection::elements(out),
+        // these only look at the value's own type
+        RustyFilter::Builtin0(Builtin0::Type | Builtin0::Not | Builtin0::Empty | Builtin0::Nan | Builtin0::Infinite) => Projection::default(),
+        // documents read through `input` are parsed with the same projection as `.`,
+        // and what the query does with them is not tracked
+        RustyFilter::Input | RustyFilter::Inputs => Projection::all(),
+        RustyFilter::InputFilename | RustyFilter::InputLineNumber | RustyFilter::Variable(_) | RustyFilter::Halt => Projection::default(),
+        // the message is written out whole
+        RustyFilter::HaltError(_) => Projection::all(),
+        // `..`, `keys`, `length`, `tojson` and the like see the whole value
+        RustyFilter::RecurseDescent | RustyFilter::Builtin0(_) | RustyFilter::Builtin1(..) => Projection::all(),
+    }
+}
+
+fn condition_needs(condition: &Condition) -> Projection {
+    match condition {
+        Condition::Comparison(path, _, Expr::Literal(_)) => pipeline(path, Projection::all()),
+        Condition::Comparison(path, _, Expr::Path(rhs)) => pipeline(path, Projection::all()).merge(pipeline(rhs, Projection::all())),
+        // truthiness only depends on the value's type
+        Condition::BoolPath(path) => pipeline(path, Projection::default()),
+        Condition::And(l, r) | Condition::Or(l, r) => condition_needs(l).merge(condition_needs(r)),
+        Condition::Not(inner) => condition_needs(inner),
+    }
+}
+
+// parse `buf`, materializing only what `projection` needs and holding it to `limits`
+// the parts the projection drops are still checked, so a document parses the same whatever reads it
+pub fn parse<'a>(buf: &'a mut [u8], projection: &Projection, limits: &ParseLimits) -> Result<BorrowedValue<'a>, ParseError> {
+    if let Some(max) = limits.max_bytes.filter(|max| buf.len() > *max) {
+        return Err(ParseError::Size(max));
+    }
+    let tape = simd_json::to_tape(buf)?;
+    let nodes = &tape.0;
+    check(nodes, limits)?;
+    Ok(build(nodes, Some(projection), limits.duplicate_keys)?.expect("a projection builds a value"))
+}
+
+// number of tape nodes taken by the value starting at `node`
+fn span(node: &Node) -> usize {
+    match node {
+        Node::Object { count, .. } | Node::Array { count, .. } => count + 1,
+        _ => 1,
+    }
+}
+
+// hold every node of the tape to the depth and string length limits
+// the tape is flat, so this walks it without recursing, however deep the document
+fn check(nodes: &[Node], limits: &ParseLimits) -> Result<(), ParseError> {
+    let max_depth = limits.depth();
+    // where each open container ends on the tape
+    let mut open: Vec<usize> = Vec::new();
+    for (i, node) in nodes.iter().enumerate() {
+        while open.last().is_some_and(|end| *end <= i) { open.pop(); }
+        match node {
+            Node::Array { count, .. } | Node::Object { count, .. } => {
+                if open.len() == max_depth { return Err(ParseError::Depth(max_depth)); }
+                open.push(i + 1 + count);
+            }
+            Node::String(s) => {
+                if let Some(max) = limits.max_string_length.filter(|max| s.len() > *max) {
+                    return Err(ParseError::StringLength(max));
+                }
+            }
+            Node::Static(_) => {}
+        }
+    }
+    Ok(())
+}
+
+// a view of an owned value keeping only the projection, with its strings borrowed; what `parse`
+// builds from a tape, built from a value the caller already has
+pub fn borrow<'a>(value: &'a OwnedValue, projection: &Projection) -> BorrowedValue<'a> {
+    match value {
+        OwnedValue::Static(node) => BorrowedValue::Static(*node),
+        OwnedValue::String(s) => BorrowedValue::String(Cow::Borrowed(s)),
+        OwnedValue::Array(items) => BorrowedValue::Array(Box::new(match projection.element() {
+            Some(each) => items.iter().map(|item| borrow(item, each)).collect(),
+            None => Vec::new(),
+        })),
+        OwnedValue::Object(fields) => {
+            let fields: Object = if projection.element().is_some() {
+                fields.iter()
+                    .filter_map(|(key, val)| Some((Cow::Borrowed(key.as_str()), borrow(val, projection.child(key)?.as_ref()))))
+                    .collect()
+            } else {
+                // only the keys the query reads are looked up, however large the object
+                projection.fields.iter()
+                    .filter_map(|(key, below)| fields.get(key.as_str()).map(|val| (Cow::Owned(key.clone()), borrow(val, below))))
+                    .collect()
+            };
+            BorrowedValue::Object(Box::new(fields))
+        }
+    }
+}
+
+// materialize the value at the start of `nodes`, skipping subtrees outside the projection
+// without a projection nothing is built, but with `DuplicateKeys::Error` the objects are still
+// checked, so the projection never changes whether a document parses
+fn build<'a>(nodes: &[Node<'a>], projection: Option<&Projection>, duplicates: DuplicateKeys) -> Result<Option<BorrowedValue<'a>>, ParseError> {
+    if projection.is_none() && duplicates != DuplicateKeys::Error { return Ok(None); }
+    let value = match nodes[0] {
+        Node::Static(s) => BorrowedValue::Static(s),
+        Node::String(s) => BorrowedValue::String(Cow::Borrowed(s)),
+        Node::Array { len, .. } => {
+            let each = projection.and_then(Projection::element);
+            let mut elements = Vec::with_capacity(if each.is_some() { len } else { 0 });
+            let mut i = 1;
+            for _ in 0..len {
+                elements.extend(build(&nodes[i..], each, duplicates)?);
+                i += span(&nodes[i]);
+            }
+            BorrowedValue::Array(Box::new(elements))
+        }
+        Node::Object { len, .. } => {
+            let capacity = match projection {
+                Some(p) if p.element().is_some() => len,
+                Some(p) => p.fields.len().min(len),
+                None => 0,
+            };
+            let mut obj = Object::with_capacity_and_hasher(capacity, Default::default());
+            // every key so far, including those the projection drops
+            let mut seen = HashSet::new();
+            let mut i = 1;
+            for _ in 0..len {
+                if let Node::String(key) = nodes[i] {
+                    if duplicates == DuplicateKeys::Error && !seen.insert(key) {
+                        return Err(ParseError::DuplicateKey(key.to_string()));
+                    }
+                    // with `First` the later value is not even looked at
+                    if duplicates != DuplicateKeys::First || !obj.contains_key(key) {
+                        let below = projection.and_then(|p| p.child(key));
+                        if let Some(value) = build(&nodes[i + 1..], below.as_deref(), duplicates)? {
+                            obj.insert(Cow::Borrowed(key), value);
+                        }
+                    }
+                }
+                i += 1 + span(&nodes[i + 1]);
+            }
+            BorrowedValue::Object(Box::new(obj))
+        }
+    };
+    Ok(projection.map(|_| value))
+}
@@ -0,0 +1,1109 @@
+use pyo3::create_exception;
+use pyo3::exceptions::{PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict, PyList, PyString};
+use simd_json::{BorrowedValue, StaticNode};
+use std::borrow::Cow;
+use std::path::PathBuf;
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::Duration;
+
+use crate::batch::parallel_map;
+use crate::cache::Lru;
+use crate::bytecode::Program;
+use crate::document::{input_bytes, input_error, load, load_obj, value_from_py, Document};
+use crate::encoding;
+use crate::limits::{DuplicateKeys, LimitExceeded, Limits, Meter, ParseLimits};
+use crate::optimizer::optimize;
+use crate::printer;
+use crate::parser::{Condition, Expr, Filter, Literal, PathStep, RustyFilter};
+use crate::projection::{self, Projection};
+use crate::results::{Failure, Results};
+use crate::serialize::{write_json, JsonFormat};
+use crate::stream::{load_file, load_reader, load_stream, parse_document, DocumentStream, StreamError};
+use crate::streamer::{element_query, Piece, Splitter};
+use crate::syntax::{self, SyntaxError};
+use crate::variables::{check_name, Bound, Declared, Values};
+use crate::vm::{Halt, RuntimeError};
+
+create_exception!(rusty_jq, RustyJqSyntaxError, PyValueError, "A query that is not valid jq, with where it goes wrong.");
+create_exception!(rusty_jq, RustyJqRuntimeError, PyValueError, "A query that failed on its input, with the part of the query and the value to blame.");
+create_exception!(rusty_jq, RustyJqLimitExceeded, PyRuntimeError, "A run that went over one of the limits its program was compiled with.");
+
+// converts a simd-json BorrowedValue into a native Python object
+// operates on zero-copy references
+// Python allocation happens at the end, so hot path stays allocation-free
+fn value_to_py(py: Python, val: &BorrowedValue) -> PyResult<PyObject> {
+    match val {
+        BorrowedValue::Static(StaticNode::Null) => Ok(py.None()),
+        BorrowedValue::Static(StaticNode::Bool(b)) => Ok(b.into_py(py)),
+        BorrowedValue::Static(StaticNode::I64(i)) => Ok(i.into_py(py)),
+        BorrowedValue::Static(StaticNode::U64(u)) => Ok(u.into_py(py)),
+        BorrowedValue::Static(StaticNode::F64(f)) => Ok(f.into_py(py)),
+        
+        BorrowedValue::String(s) => Ok(s.as_ref().into_py(py)),
+        
+        BorrowedValue::Array(arr) => {
+            let items: PyResult<Vec<PyObject>> = arr.iter()
+                .map(|item| value_to_py(py, item))
+                .collect();
+            Ok(PyList::new(py, items?).into())
+        },
+        BorrowedValue::Object(map) => {
+            let dict = PyDict::new(py);
+            for (k, v) in map.iter() {
+                dict.set_item(k.as_ref(), value_to_py(py, v)?)?;
+            }
+            Ok(dict.into())
+        }
+    }
+}
+
+// how input_json() hands results back
+struct JsonOutput {
+    format: JsonFormat,
+    // top-level strings are written without quotes, like `jq -r`
+    raw: bool,
+    // bytes instead of str
+    binary: bool,
+}
+
+impl JsonOutput {
+    fn to_py(&self, py: Python, val: &BorrowedValue) -> PyObject {
+        let mut text = String::new();
+        match val {
+            BorrowedValue::String(s) if self.raw => text.push_str(s),
+            _ => write_json(&mut text, val, &self.format, 0),
+        }
+        if self.binary { PyBytes::new(py, text.as_bytes()).into() } else { text.into_py(py) }
+    }
+}
+
+// results are evaluated one at a time as Python iterates
+#[pyclass]
+pub struct RustyJqIter {
+    results: Results,
+    // the query, to quote in runtime errors
+    query: Arc<str>,
+    // serialize results to JSON text instead of converting them to Python objects
+    json: Option<JsonOutput>,
+}
+
+#[pymethods]
+impl RustyJqIter {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(mut slf: PyRefMut<'_, Self>, py: Python) -> PyResult<Option<PyObject>> {
+        slf.next_value(py)
+    }
+}
+
+impl RustyJqIter {
+    fn next_value(&mut self, py: Python) -> PyResult<Option<PyObject>> {
+        let json = &self.json;
+        let next = self.results.next_with(|val| match json {
+            Some(json) => Ok(json.to_py(py, val)),
+            None => value_to_py(py, val),
+        });
+        match next {
+            Some(Ok(val)) => val.map(Some),
+            Some(Err(e)) => Err(failure(py, e, &self.query)),
+            None => {
+                raise_halt(py, self.results.halted(), &self.query)?;
+                Ok(None)
+            }
+        }
+    }
+}
+
+// takes JSON text in chunks as it arrives and returns results as soon as each value is complete
+#[pyclass]
+struct RustyStreamer {
+    splitter: Splitter,
+    query: Arc<str>,
+    program: Arc<Program>,
+    projection: Projection,
+    vars: Values,
+    // for queries starting with `.[]`, what runs on each element of a top-level array
+    each: Option<(Arc<Program>, Projection, Values)>,
+    // results that came before a malformed value, handed back by the next call
+    carry: Vec<PyObject>,
+    closed: bool,
+    // after `halt` or `halt_error`, the rest of the input is ignored; a runtime error is taken out
+    // once raised, and the values after it carry on
+    halt: OnceLock<Halt>,
+    // enforced on the run over each value
+    limits: Limits,
+    parse_limits: ParseLimits,
+}
+
+impl RustyStreamer {
+    fn drain(&mut self, py: Python, at_end: bool) -> PyResult<PyObject> {
+        let mut results = std::mem::take(&mut self.carry);
+        while self.halt.get().is_none() {
+            let Some(piece) = self.splitter.next(at_end) else { break };
+            if let Err(e) = self.run(py, piece, &mut results) {
+                self.carry = results;
+                return Err(e);
+            }
+        }
+        Ok(PyList::new(py, results).into())
+    }
+
+    fn run(&mut self, py: Python, piece: Result<Piece, StreamError>, results: &mut Vec<PyObject>) -> PyResult<()> {
+        let piece = piece.map_err(|e| e.into_py(py))?;
+        let (program, projection, vars) = match &self.each {
+            Some((program, projection, vars)) if piece.element => (program, projection, vars),
+            _ => (&self.program, &self.projection, &self.vars),
+        };
+        let document = parse_document(piece.bytes, piece.line, projection, &self.parse_limits).map_err(|e| e.into_py(py))?;
+        let meter = Meter::new(self.limits);
+        for val in program.run(Cow::Borrowed(document.root())).with_vars(vars).with_halt(&self.halt).with_meter(&meter) {
+            results.push(value_to_py(py, &val)?);
+        }
+        if let Some(Halt::Error(_)) = self.halt.get() {
+            if let Some(Halt::Error(e)) = self.halt.take() { return Err(runtime_error(py, e, &self.query)); }
+        }
+        raise_halt(py, self.halt.get(), &self.query)
+    }
+}
+
+#[pymethods]
+impl RustyStreamer {
+    // add a chunk of a str or bytes-like input, returning the results of every value it completes
+    // a malformed value raises ValueError; values after it carry on with the next call
+    fn feed(&mut self, py: Python, chunk: &PyAny) -> PyResult<PyObject> {
+        if self.closed {
+            return Err(PyErr::new::<PyValueError, _>("feed() after close()"));
+        }
+        self.splitter.push(&input_bytes(py, chunk)?);
+        self.drain(py, false)
+    }
+
+    // end the input, returning the results of whatever value it completes
+    fn close(&mut self, py: Python) -> PyResult<PyObject> {
+        self.closed = true;
+        self.drain(py, true)
+    }
+}
+
+// compiled jq-style query, exposed to Python as RustyProgram
+#[pyclass]
+struct RustyProgram {
+    program: Arc<Program>,
+    // the query it was compiled from
+    query: Arc<str>,
+    // the parts of an input document the query can observe
+    projection: Projection,
+    // the optimized query, to derive programs over parts of the input from
+    filters: Vec<Filter>,
+    declared: Declared,
+    limits: Limits,
+    // what every input document is held to
+    parse_limits: ParseLimits,
+}
+
+#[pymethods]
+impl RustyProgram {
+    // `json_text` is a str or any bytes-like object holding one or more whitespace-separated
+    // documents (NDJSON included); results of each document follow those of the previous one
+    // with `inplace=True` a bytearray is parsed inside its own memory, overwriting its contents
+    // with `slurp=True` all documents are gathered into one array first
+    // with `stream=True` the documents are the `[path, leaf]` events of the input, parsed incrementally
+    // with `null_input=True` the query runs once on null and reads the documents through `input`/`inputs`
+    // other keyword arguments bind the variables declared in compile(), e.g. `input(doc, uid=5)`;
+    // every method running the program takes them
+    #[pyo3(signature = (json_text, inplace=false, slurp=false, stream=false, null_input=false, **vars))]
+    #[allow(clippy::too_many_arguments)]
+    fn input(&self, py: Python, json_text: &PyAny, inplace: bool, slurp: bool, stream: bool, null_input: bool, vars: Option<&PyDict>) -> PyResult<RustyJqIter> {
+        let vars = self.bind(py, vars)?;
+        let documents = load_stream(py, json_text, &self.projection, &self.parse_limits, inplace, slurp, stream)?;
+        Ok(self.iterate(documents, vars, null_input))
+    }
+
+    #[pyo3(signature = (json_text, inplace=false, slurp=false, stream=false, null_input=false, **vars))]
+    #[allow(clippy::too_many_arguments)]
+    fn first(&self, py: Python, json_text: &PyAny, inplace: bool, slurp: bool, stream: bool, null_input: bool, vars: Option<&PyDict>) -> PyResult<PyObject> {
+        let vars = self.bind(py, vars)?;
+        let documents = load_stream(py, json_text, &self.projection, &self.parse_limits, inplace, slurp, stream)?;
+        self.first_of(py, documents, vars, null_input)
+    }
+
+    // same as input(), yielding each result as JSON text serialized straight from the engine's values
+    #[pyo3(signature = (json_text, indent=None, sort_keys=false, ascii=false, raw=false, binary=false, slurp=false, stream=false, null_input=false, **vars))]
+    #[allow(clippy::too_many_arguments)]
+    fn input_json(&self, py: Python, json_text: &PyAny, indent: Option<usize>, sort_keys: bool, ascii: bool, raw: bool, binary: bool, slurp: bool, stream: bool, null_input: bool, vars: Option<&PyDict>) -> PyResult<RustyJqIter> {
+        let vars = self.bind(py, vars)?;
+        let documents = load_stream(py, json_text, &self.projection, &self.parse_limits, false, slurp, stream)?;
+        let mut results = self.iterate(documents, vars, null_input);
+        results.json = Some(JsonOutput { format: JsonFormat { indent, sort_keys, ascii, tab: false }, raw, binary });
+        Ok(results)
+    }
+
+    // same as input(), reading the documents of a file; plain files are memory-mapped,
+    // gzip and zstd files are decompressed and read incrementally
+    #[pyo3(signature = (path, slurp=false, stream=false, null_input=false, **vars))]
+    fn input_file(&self, py: Python, path: PathBuf, slurp: bool, stream: bool, null_input: bool, vars: Option<&PyDict>) -> PyResult<RustyJqIter> {
+        let vars = self.bind(py, vars)?;
+        let documents = load_file(py, &path, &self.projection, &self.parse_limits, slurp, stream)?;
+        Ok(self.iterate(documents, vars, null_input))
+    }
+
+    // same as input(), reading documents incrementally from a binary or text file-like object
+    #[pyo3(signature = (file, slurp=false, stream=false, null_input=false, **vars))]
+    fn input_reader(&self, py: Python, file: &PyAny, slurp: bool, stream: bool, null_input: bool, vars: Option<&PyDict>) -> PyResult<RustyJqIter> {
+        let vars = self.bind(py, vars)?;
+        let documents = load_reader(py, file, &self.projection, &self.parse_limits, slurp, stream)?;
+        Ok(self.iterate(documents, vars, null_input))
+    }
+
+    // a push parser running this program over input that arrives in chunks
+    #[pyo3(signature = (**vars))]
+    fn streamer(&self, py: Python, vars: Option<&PyDict>) -> PyResult<RustyStreamer> {
+        let bound = self.bound(py, vars)?;
+        let each = element_query(&self.filters).map(|rest| {
+            let program = Program::compile(&rest);
+            let vars = bound.values_for(&program);
+            (Arc::new(program), projection::analyze(&rest), vars)
+        });
+        Ok(RustyStreamer {
+            splitter: Splitter::new(each.is_some()),
+            query: Arc::clone(&self.query),
+            program: Arc::clone(&self.program),
+            projection: self.projection.clone(),
+            vars: bound.values_for(&self.program),
+            each,
+            carry: Vec::new(),
+            closed: false,
+            halt: OnceLock::new(),
+            limits: self.limits,
+            parse_limits: self.parse_limits,
+        })
+    }
+
+    // same as input(), against a document parsed once with rusty_jq.parse()
+    #[pyo3(signature = (doc, **vars))]
+    fn input_doc(&self, py: Python, doc: &RustyDocument, vars: Option<&PyDict>) -> PyResult<RustyJqIter> {
+        let vars = self.bind(py, vars)?;
+        Ok(self.iterate(DocumentStream::single(doc.document()?), vars, false))
+    }
+
+    #[pyo3(signature = (doc, **vars))]
+    fn first_doc(&self, py: Python, doc: &RustyDocument, vars: Option<&PyDict>) -> PyResult<PyObject> {
+        let vars = self.bind(py, vars)?;
+        self.first_of(py, DocumentStream::single(doc.document()?), vars, false)
+    }
+
+    // same as input(), on an already deserialized Python object instead of JSON text
+    #[pyo3(signature = (obj, default=None, **vars))]
+    fn input_obj(&self, py: Python, obj: &PyAny, default: Option<&PyAny>, vars: Option<&PyDict>) -> PyResult<RustyJqIter> {
+        let vars = self.bind(py, vars)?;
+        let document = load_obj(py, obj, &self.projection, &self.parse_limits, default)?;
+        Ok(self.iterate(DocumentStream::single(Arc::new(document)), vars, false))
+    }
+
+    #[pyo3(signature = (obj, default=None, **vars))]
+    fn first_obj(&self, py: Python, obj: &PyAny, default: Option<&PyAny>, vars: Option<&PyDict>) -> PyResult<PyObject> {
+        let vars = self.bind(py, vars)?;
+        let document = load_obj(py, obj, &self.projection, &self.parse_limits, default)?;
+        self.first_of(py, DocumentStream::single(Arc::new(document)), vars, false)
+    }
+
+    // every output for each input, evaluated in parallel without holding the GIL
+    #[pyo3(signature = (inputs, threads=None, **vars))]
+    fn input_many(&self, py: Python, inputs: &PyList, threads: Option<usize>, vars: Option<&PyDict>) -> PyResult<PyObject> {
+        let vars = self.bind(py, vars)?;
+        let texts = batch_texts(py, inputs)?;
+        let threads = threads.unwrap_or_else(default_threads);
+        let mut lists = Vec::with_capacity(texts.len());
+        for (n, chunk) in texts.chunks(BATCH_CHUNK).enumerate() {
+            let documents = self.parse_many(py, chunk, n * BATCH_CHUNK, threads)?;
+            let program = self.program.as_ref();
+            // each document gets its own cell and meter, so one halting does not stop the others
+            let runs: Vec<_> = documents.iter().map(|doc| (doc, OnceLock::new(), Meter::new(self.limits))).collect();
+            let results = py.allow_threads(|| parallel_map(&runs, threads, |(doc, halt, meter)| {
+                program.run(Cow::Borrowed(doc.root())).with_vars(&vars).with_halt(halt).with_meter(meter).collect::<Vec<_>>()
+            }));
+            for (outputs, (_, halt, _)) in results.iter().zip(&runs) {
+                raise_halt(py, halt.get(), &self.query)?;
+                let items = outputs.iter().map(|val| value_to_py(py, val)).collect::<PyResult<Vec<_>>>()?;
+                lists.push(PyList::new(py, items));
+            }
+        }
+        Ok(PyList::new(py, lists).into())
+    }
+
+    // the first output for each input (None when there is none), evaluated in parallel
+    #[pyo3(signature = (inputs, threads=None, **vars))]
+    fn first_many(&self, py: Python, inputs: &PyList, threads: Option<usize>, vars: Option<&PyDict>) -> PyResult<PyObject> {
+        let vars = self.bind(py, vars)?;
+        let texts = batch_texts(py, inputs)?;
+        let threads = threads.unwrap_or_else(default_threads);
+        let mut values = Vec::with_capacity(texts.len());
+        for (n, chunk) in texts.chunks(BATCH_CHUNK).enumerate() {
+            let documents = self.parse_many(py, chunk, n * BATCH_CHUNK, threads)?;
+            let program = self.program.as_ref();
+            let runs: Vec<_> = documents.iter().map(|doc| (doc, OnceLock::new(), Meter::new(self.limits))).collect();
+            let results = py.allow_threads(|| parallel_map(&runs, threads, |(doc, halt, meter)| {
+                program.run(Cow::Borrowed(doc.root())).with_vars(&vars).with_halt(halt).with_meter(meter).next()
+            }));
+            for (first, (_, halt, _)) in results.iter().zip(&runs) {
+                raise_halt(py, halt.get(), &self.query)?;
+                values.push(match first {
+                    Some(val) => value_to_py(py, val)?,
+                    None => py.None(),
+                });
+            }
+        }
+        Ok(PyList::new(py, values).into())
+    }
+
+    // the limits given to compile(), or None
+    #[getter]
+    fn limits(&self) -> Option<RustyLimits> {
+        (self.limits != Limits::default()).then_some(RustyLimits { limits: self.limits })
+    }
+
+    // the parse limits given to compile(), or the defaults every input is held to otherwise
+    #[getter]
+    fn parse_limits(&self) -> RustyParseLimits {
+        RustyParseLimits { limits: self.parse_limits }
+    }
+
+    // the query as parsed, before optimization: a list of filters, each a dict naming its kind
+    // under "type"; equal for any two spellings of one query
+    fn ast(&self, py: Python) -> PyResult<PyObject> {
+        pipeline_to_py(py, &self.parsed()?)
+    }
+
+    // the query in a canonical form, which compiles into the same program
+    fn to_jq(&self) -> PyResult<String> {
+        Ok(printer::to_jq(&self.parsed()?))
+    }
+
+    // the program in a versioned binary format, for rusty_jq.load(); variables keep their defaults
+    fn to_bytes(&self, py: Python) -> PyObject {
+        PyBytes::new(py, &encoding::encode(&self.query, &self.filters, &self.declared, &self.limits, &self.parse_limits)).into()
+    }
+
+    // pickled as a call to rusty_jq.load(), so workers get the program without compiling the query
+    fn __reduce__(&self, py: Python) -> PyResult<(PyObject, (PyObject,))> {
+        let load = py.import("rusty_jq")?.getattr("load")?;
+        Ok((load.into(), (self.to_bytes(py),)))
+    }
+
+    fn __repr__(&self, py: Python) -> PyResult<String> {
+        Ok(format!("<RustyProgram {}>", PyString::new(py, &self.query).repr()?))
+    }
+}
+
+impl RustyProgram {
+    // the program for the optimized `filters` of `query`; variables it reads that `args` did not
+    // declare have no default, so each call binds them
+    fn new(query: &str, filters: Vec<Filter>, mut declared: Declared, limits: Limits, parse_limits: ParseLimits) -> PyResult<Self> {
+        let projection = projection::analyze(&filters);
+        let program = Program::compile(&filters);
+        declared.declare_free(&program);
+        Ok(RustyProgram { program: Arc::new(program), query: Arc::from(query), projection, filters, declared, limits, parse_limits })
+    }
+
+    // `filters` is optimized, so the query is parsed again for the filters as written; load() only
+    // takes programs whose query parses, but the bytes it was given are not trusted with a panic
+    fn parsed(&self) -> PyResult<Vec<Filter>> {
+        syntax::parse(&self.query)
+            .map_err(|e| PyErr::new::<PyValueError, _>(format!("corrupt serialized program: its query does not parse: {}", e)))
+    }
+
+    fn bind(&self, py: Python, vars: Option<&PyDict>) -> PyResult<Values> {
+        Ok(self.bound(py, vars)?.values_for(&self.program))
+    }
+
+    // keyword arguments over the defaults declared in compile()
+    fn bound(&self, py: Python, vars: Option<&PyDict>) -> PyResult<Bound<'_>> {
+        let given = vars.into_iter()
+            .flat_map(|vars| vars.iter())
+            .map(|(name, value)| Ok((name.extract()?, bound_value(py, value)?)))
+            .collect::<PyResult<_>>()?;
+        self.declared.bind(given).map_err(PyErr::new::<PyTypeError, _>)
+    }
+
+    fn iterate(&self, documents: DocumentStream, vars: Values, null_input: bool) -> RustyJqIter {
+        let results = Results::new(documents, Arc::clone(&self.program), vars, null_input).with_limits(self.limits);
+        RustyJqIter { results, query: Arc::clone(&self.query), json: None }
+    }
+
+    // `offset` is the position of `texts` within the whole batch, for error messages
+    fn parse_many(&self, py: Python, texts: &[Cow<[u8]>], offset: usize, threads: usize) -> PyResult<Vec<Document>> {
+        let parsed = py.allow_threads(|| parallel_map(texts, threads, |text| Document::parse(text.to_vec(), &self.projection, &self.parse_limits)));
+        parsed.into_iter()
+            .enumerate()
+            .map(|(i, doc)| doc.map_err(|e| input_error(py, &texts[i], e, &format!("input {}: ", offset + i))))
+            .collect()
+    }
+
+    fn first_of(&self, py: Python, documents: DocumentStream, vars: Values, null_input: bool) -> PyResult<PyObject> {
+        let mut results = Results::new(documents, Arc::clone(&self.program), vars, null_input).with_limits(self.limits);
+        match results.next_with(|val| value_to_py(py, val)) {
+            Some(Ok(val)) => val,
+            Some(Err(e)) => Err(failure(py, e, &self.query)),
+            None => {
+                raise_halt(py, results.halted(), &self.query)?;
+                Ok(py.None())
+            }
+        }
+    }
+}
+
+// the text of every batch input; `str` and `bytes` are immutable and kept alive by the list, so
+// borrowed slices stay valid while the GIL is released, and mutable buffers are copied up front
+fn batch_texts<'py>(py: Python, inputs: &'py PyList) -> PyResult<Vec<Cow<'py, [u8]>>> {
+    inputs.iter().map(|item| input_bytes(py, item)).collect()
+}
+
+// batches are processed this many inputs at a time, so only one chunk of parsed documents
+// and results is held in memory while the next is converted to Python objects
+const BATCH_CHUNK: usize = 1024;
+
+fn default_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+// `halt_error` raises ValueError with its message, a runtime error RustyJqRuntimeError and going
+// over a limit RustyJqLimitExceeded; a plain `halt` just ends the results
+fn raise_halt(py: Python, halt: Option<&Halt>, query: &str) -> PyResult<()> {
+    match halt {
+        Some(Halt::Exit { message: Some(message), .. }) => Err(PyErr::new::<PyValueError, _>(message.trim_end_matches('\n').to_string())),
+        Some(Halt::Error(e)) => Err(runtime_error(py, e.clone(), query)),
+        Some(Halt::Limit(e)) => Err(limit_exceeded(py, *e)),
+        Some(Halt::Exit { message: None, .. }) | None => Ok(()),
+    }
+}
+
+fn failure(py: Python, failure: Failure, query: &str) -> PyErr {
+    match failure {
+        Failure::Stream(e) => e.into_py(py),
+        Failure::Runtime(e) => runtime_error(py, e, query),
+    }
+}
+
+// RustyJqRuntimeError, with the failing part of the query and the value to blame as attributes
+fn runtime_error(py: Python, e: RuntimeError, query: &str) -> PyErr {
+    let e = e.in_query(query);
+    let err = RustyJqRuntimeError::new_err(e.to_string());
+    let value = err.value(py);
+    // the span in characters, as Python indexes the query
+    let chars = |at: usize| query.get(..at).map_or(0, |before| before.chars().count());
+    let path = e.path.as_ref().map(|path| path.iter().map(|step| match step {
+        PathStep::Field(key) => key.into_py(py),
+        PathStep::Index(i) => i.into_py(py),
+    }).collect::<Vec<_>>());
+    let attributes = [
+        ("span", (chars(e.span.start), chars(e.span.end)).into_py(py)),
+        ("filter", e.filter.into_py(py)),
+        ("value", e.value.into_py(py)),
+        ("path", path.into_py(py)),
+    ];
+    for (name, attribute) in attributes {
+        if let Err(e) = value.setattr(name, attribute) { return e; }
+    }
+    err
+}
+
+// RustyJqLimitExceeded, with the name of the limit as a keyword of Limits and its value
+fn limit_exceeded(py: Python, e: LimitExceeded) -> PyErr {
+    let err = RustyJqLimitExceeded::new_err(e.to_string());
+    let value = err.value(py);
+    let limit = match e {
+        LimitExceeded::Steps(n) | LimitExceeded::Outputs(n) => n,
+        LimitExceeded::Depth(n) | LimitExceeded::Memory(n) => n as u64,
+        LimitExceeded::Timeout(timeout) => timeout.as_millis() as u64,
+    };
+    let name = match e {
+        LimitExceeded::Timeout(_) => "timeout_ms",
+        e => e.name(),
+    };
+    for (attr, attribute) in [("limit", name.into_py(py)), ("value", limit.into_py(py))] {
+        if let Err(e) = value.setattr(attr, attribute) { return e; }
+    }
+    err
+}
+
+// a parsed query as Python data, for ast(); the operands of `,`, arithmetic and calls are lists of
+// filters like the query itself, and a literal argument is a list of its one literal filter
+fn pipeline_to_py(py: Python, filters: &[Filter]) -> PyResult<PyObject> {
+    let items = filters.iter().map(|filter| filter_to_py(py, filter)).collect::<PyResult<Vec<_>>>()?;
+    Ok(PyList::new(py, items).into())
+}
+
+fn node(py: Python, kind: &str, fields: Vec<(&str, PyObject)>) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("type", kind)?;
+    for (name, value) in fields {
+        dict.set_item(name, value)?;
+    }
+    Ok(dict.into())
+}
+
+// `name(args...)`, for builtins and every other filter called by name
+fn call_to_py(py: Python, name: &str, args: &[&[Filter]]) -> PyResult<PyObject> {
+    let args = args.iter().map(|arg| pipeline_to_py(py, arg)).collect::<PyResult<Vec<_>>>()?;
+    node(py, "call", vec![("name", name.into_py(py)), ("args", args.into_py(py))])
+}
+
+fn literal_arg(py: Python, lit: &Literal) -> PyResult<PyObject> {
+    let lit = node(py, "literal", vec![("value", literal_to_py(py, lit)?)])?;
+    Ok(PyList::new(py, [lit]).into())
+}
+
+fn filter_to_py(py: Python, filter: &Filter) -> PyResult<PyObject> {
+    let pipelines = |branches: &[Vec<Filter>]| branches.iter().map(|f| pipeline_to_py(py, f)).collect::<PyResult<Vec<_>>>();
+    match &filter.kind {
+        RustyFilter::Identity => node(py, "identity", vec![]),
+        RustyFilter::Field(name) => node(py, "field", vec![("name", name.into_py(py))]),
+        RustyFilter::Index(i) => node(py, "index", vec![("index", i.into_py(py))]),
+        RustyFilter::Iterator => node(py, "iterator", vec![]),
+        RustyFilter::Slice(start, end) => node(py, "slice", vec![("start", start.into_py(py)), ("end", end.into_py(py))]),
+        RustyFilter::RecurseDescent => node(py, "recursive_descent", vec![]),
+        RustyFilter::Object(fields) => {
+            let fields = fields.iter().map(|(key, value)| {
+                let field = PyDict::new(py);
+                field.set_item("key", key)?;
+                field.set_item("value", pipeline_to_py(py, value)?)?;
+                Ok(field.into())
+            }).collect::<PyResult<Vec<PyObject>>>()?;
+            node(py, "object", vec![("fields", fields.into_py(py))])
+        }
+        RustyFilter::Select(c) => node(py, "select", vec![("condition", condition_to_py(py, c)?)]),
+        RustyFilter::Comma(branches) => node(py, "comma", vec![("branches", pipelines(branches)?.into_py(py))]),
+        RustyFilter::Arithmetic(left, op, right) => node(py, "arithmetic", vec![
+            ("op", op.symbol().into_py(py)),
+            ("left", pipeline_to_py(py, left)?),
+            ("right", pipeline_to_py(py, right)?),
+        ]),
+        RustyFilter::LiteralValue(lit) => node(py, "literal", vec![("value", literal_to_py(py, lit)?)]),
+        RustyFilter::Builtin0(b) => call_to_py(py, b.name(), &[]),
+        RustyFilter::Builtin1(b, arg) => node(py, "call", vec![("name", b.name().into_py(py)), ("args", vec![literal_arg(py, arg)?].into_py(py))]),
+        RustyFilter::Limit(n, f) => node(py, "call", vec![
+            ("name", "limit".into_py(py)),
+            ("args", vec![literal_arg(py, &Literal::Int(*n as i64))?, pipeline_to_py(py, f)?].into_py(py)),
+        ]),
+        RustyFilter::FirstOf(f) => call_to_py(py, "first", &[f]),
+        RustyFilter::IsEmpty(f) => call_to_py(py, "isempty", &[f]),
+        RustyFilter::FromStream(f) => call_to_py(py, "fromstream", &[f]),
+        RustyFilter::TruncateStream(f) => call_to_py(py, "truncate_stream", &[f]),
+        RustyFilter::IndexBy(stream, key) => call_to_py(py, "INDEX", &[stream, key]),
+        RustyFilter::In(source, values) if source.is_empty() => call_to_py(py, "IN", &[values]),
+        RustyFilter::In(source, values) => call_to_py(py, "IN", &[source, values]),
+        RustyFilter::Join { index, stream, key, join } => {
+            let args: Vec<&[Filter]> = [Some(index), stream.as_ref(), Some(key), join.as_ref()].into_iter().flatten().map(Vec::as_slice).collect();
+            call_to_py(py, "JOIN", &args)
+        }
+        RustyFilter::Input => call_to_py(py, "input", &[]),
+        RustyFilter::Inputs => call_to_py(py, "inputs", &[]),
+        RustyFilter::InputFilename => call_to_py(py, "input_filename", &[]),
+        RustyFilter::InputLineNumber => call_to_py(py, "input_line_number", &[]),
+        RustyFilter::Halt => call_to_py(py, "halt", &[]),
+        RustyFilter::HaltError(code) => node(py, "call", vec![("name", "halt_error".into_py(py)), ("args", vec![literal_arg(py, &Literal::Int(*code as i64))?].into_py(py))]),
+        RustyFilter::Variable(name) => node(py, "variable", vec![("name", name.into_py(py))]),
+        RustyFilter::Compare(left, op, right) => node(py, "compare", vec![
+            ("op", op.symbol().into_py(py)),
+            ("left", pipeline_to_py(py, left)?),
+            ("right", pipeline_to_py(py, right)?),
+        ]),
+        RustyFilter::And(left, right) => node(py, "and", vec![("left", pipeline_to_py(py, left)?), ("right", pipeline_to_py(py, right)?)]),
+        RustyFilter::Or(left, right) => node(py, "or", vec![("left", pipeline_to_py(py, left)?), ("right", pipeline_to_py(py, right)?)]),
+        RustyFilter::Negate(operand) => node(py, "negate", vec![("operand", pipeline_to_py(py, operand)?)]),
+        RustyFilter::Alternative(left, right) => node(py, "alternative", vec![("left", pipeline_to_py(py, left)?), ("right", pipeline_to_py(py, right)?)]),
+        RustyFilter::Assign(paths, op, value) => node(py, "assign", vec![
+            ("op", op.symbol().into_py(py)),
+            ("left", pipeline_to_py(py, paths)?),
+            ("right", pipeline_to_py(py, value)?),
+        ]),
+        RustyFilter::Array(items) => node(py, "array", vec![("items", pipeline_to_py(py, items)?)]),
+        RustyFilter::If(branches, otherwise) => {
+            let branches = branches.iter().map(|(cond, then)| {
+                let branch = PyDict::new(py);
+                branch.set_item("condition", pipeline_to_py(py, cond)?)?;
+                branch.set_item("then", pipeline_to_py(py, then)?)?;
+                Ok(branch.into())
+            }).collect::<PyResult<Vec<PyObject>>>()?;
+            let otherwise = otherwise.as_ref().map(|f| pipeline_to_py(py, f)).transpose()?;
+            node(py, "if", vec![("branches", branches.into_py(py)), ("else", otherwise.into_py(py))])
+        }
+        RustyFilter::Try(body, handler) => {
+            let handler = handler.as_ref().map(|f| pipeline_to_py(py, f)).transpose()?;
+            node(py, "try", vec![("body", pipeline_to_py(py, body)?), ("catch", handler.into_py(py))])
+        }
+        RustyFilter::Bind(source, name, body) => node(py, "bind", vec![
+            ("source", pipeline_to_py(py, source)?),
+            ("name", name.into_py(py)),
+            ("body", pipeline_to_py(py, body)?),
+        ]),
+        RustyFilter::Reduce { source, name, init, update } => node(py, "reduce", vec![
+            ("source", pipeline_to_py(py, source)?),
+            ("name", name.into_py(py)),
+            ("init", pipeline_to_py(py, init)?),
+            ("update", pipeline_to_py(py, update)?),
+        ]),
+        RustyFilter::Foreach { source, name, init, update, extract } => {
+            let extract = extract.as_ref().map(|f| pipeline_to_py(py, f)).transpose()?;
+            node(py, "foreach", vec![
+                ("source", pipeline_to_py(py, source)?),
+                ("name", name.into_py(py)),
+                ("init", pipeline_to_py(py, init)?),
+                ("update", pipeline_to_py(py, update)?),
+                ("extract", extract.into_py(py)),
+            ])
+        }
+        // only produced by the optimizer, which ast() does not run
+        RustyFilter::Path(steps) => {
+            let steps = steps.iter().map(|step| match step {
+                PathStep::Field(key) => key.into_py(py),
+                PathStep::Index(i) => i.into_py(py),
+            }).collect::<Vec<_>>();
+            node(py, "path", vec![("path", steps.into_py(py))])
+        }
+        RustyFilter::IterSelect(c) => node(py, "iter_select", vec![("condition", condition_to_py(py, c)?)]),
+    }
+}
+
+fn condition_to_py(py: Python, c: &Condition) -> PyResult<PyObject> {
+    match c {
+        Condition::Comparison(path, op, expr) => {
+            let right = match expr {
+                Expr::Literal(lit) => literal_arg(py, lit)?,
+                Expr::Path(path) => pipeline_to_py(py, path)?,
+            };
+            node(py, "compare", vec![("op", op.symbol().into_py(py)), ("left", pipeline_to_py(py, path)?), ("right", right)])
+        }
+        Condition::BoolPath(path) => node(py, "truthy", vec![("filter", pipeline_to_py(py, path)?)]),
+        Condition::And(left, right) => node(py, "and", vec![("left", condition_to_py(py, left)?), ("right", condition_to_py(py, right)?)]),
+        Condition::Or(left, right) => node(py, "or", vec![("left", condition_to_py(py, left)?), ("right", condition_to_py(py, right)?)]),
+        Condition::Not(inner) => node(py, "not", vec![("condition", condition_to_py(py, inner)?)]),
+    }
+}
+
+fn literal_to_py(py: Python, lit: &Literal) -> PyResult<PyObject> {
+    Ok(match lit {
+        Literal::Int(i) => i.into_py(py),
+        Literal::Float(f) => f.into_py(py),
+        Literal::String(s) => s.into_py(py),
+        Literal::Bool(b) => b.into_py(py),
+        Literal::Null => py.None(),
+        Literal::Array(items) => {
+            let items = items.iter().map(|item| literal_to_py(py, item)).collect::<PyResult<Vec<_>>>()?;
+            PyList::new(py, items).into()
+        }
+        Literal::Object(fields) => {
+            let dict = PyDict::new(py);
+            for (key, value) in fields {
+                dict.set_item(key, literal_to_py(py, value)?)?;
+            }
+            dict.into()
+        }
+    })
+}
+
+// a JSON document parsed once and evaluated by any number of programs, from any thread; nothing
+// here changes it, but one parsed in place is only as stable as the bytearray it reads from
+#[pyclass(frozen)]
+struct RustyDocument {
+    // None once release() has let go of it
+    document: Mutex<Option<Arc<Document>>>,
+}
+
+impl RustyDocument {
+    fn new(document: Arc<Document>) -> Self {
+        RustyDocument { document: Mutex::new(Some(document)) }
+    }
+
+    fn document(&self) -> PyResult<Arc<Document>> {
+        self.document.lock().unwrap().clone().ok_or_else(|| PyErr::new::<PyValueError, _>("the document was released"))
+    }
+}
+
+#[pymethods]
+impl RustyDocument {
+    // drops the parsed document; a bytearray parsed in place is exported again only once the
+    // results still reading it are gone too
+    fn release(&self) {
+        self.document.lock().unwrap().take();
+    }
+}
+
+// a document parsed in place reads straight from the bytearray: it stays exported, so it cannot be
+// resized, and writing to it through another view corrupts the document, until release() is called
+// `parse_limits` is what the document is held to, the defaults if not given
+#[pyfunction]
+#[pyo3(signature = (json_text, inplace=false, parse_limits=None))]
+fn parse(py: Python, json_text: &PyAny, inplace: bool, parse_limits: Option<&RustyParseLimits>) -> PyResult<RustyDocument> {
+    // programs see the whole document, so nothing can be projected away
+    let document = load(py, json_text, &Projection::all(), &parse_limits_or_default(parse_limits), inplace)?;
+    Ok(RustyDocument::new(Arc::new(document)))
+}
+
+// the document in a file, for binding to a variable like jq's `--slurpfile`; a file holding several
+// documents has to be slurped into an array of them
+#[pyfunction]
+#[pyo3(signature = (path, slurp=false, parse_limits=None))]
+fn parse_file(py: Python, path: PathBuf, slurp: bool, parse_limits: Option<&RustyParseLimits>) -> PyResult<RustyDocument> {
+    let documents = load_file(py, &path, &Projection::all(), &parse_limits_or_default(parse_limits), slurp, false)?;
+    let document = match documents.next_document() {
+        Some(document) => document.map_err(|e| e.into_py(py))?,
+        None => return Err(PyErr::new::<PyValueError, _>(format!("{}: no JSON document; pass slurp=True to get an empty array", path.display()))),
+    };
+    if documents.next_document().is_some() {
+        return Err(PyErr::new::<PyValueError, _>(format!("{}: expected one document; pass slurp=True", path.display())));
+    }
+    Ok(RustyDocument::new(document))
+}
+
+// `args` declares the variables the query may read, as a dict of default values or a list of
+// names bound on every run; `positional` is what `$ARGS.positional` holds
+// `limits` caps what each run may do, raising RustyJqLimitExceeded once it goes over one
+// `parse_limits` is what every input document is held to, the defaults if not given
+// programs are cached, so compiling the same query with the same arguments again is a lookup
+#[pyfunction]
+#[pyo3(signature = (query, args=None, positional=None, limits=None, parse_limits=None))]
+fn compile(py: Python, query: &str, args: Option<&PyAny>, positional: Option<&PyAny>, limits: Option<&RustyLimits>, parse_limits: Option<&RustyParseLimits>) -> PyResult<Py<RustyProgram>> {
+    let (declared, mut options) = declare(py, args, positional)?;
+    options.limits = limits.map_or(Limits::default(), |limits| limits.limits);
+    options.parse_limits = parse_limits_or_default(parse_limits);
+    cached(py, query, declared, options)
+}
+
+fn parse_limits_or_default(parse_limits: Option<&RustyParseLimits>) -> ParseLimits {
+    parse_limits.map_or(ParseLimits::default(), |parse_limits| parse_limits.limits)
+}
+
+// caps on each run of a program, for compile(limits=...); a limit left as None is not enforced
+#[pyclass(frozen, name = "Limits")]
+struct RustyLimits {
+    limits: Limits,
+}
+
+#[pymethods]
+impl RustyLimits {
+    #[new]
+    #[pyo3(signature = (max_steps=None, max_outputs=None, max_depth=None, timeout_ms=None, max_memory_bytes=None))]
+    fn new(max_steps: Option<u64>, max_outputs: Option<u64>, max_depth: Option<usize>, timeout_ms: Option<u64>, max_memory_bytes: Option<usize>) -> Self {
+        let timeout = timeout_ms.map(Duration::from_millis);
+        RustyLimits { limits: Limits { max_steps, max_outputs, max_depth, timeout, max_memory_bytes } }
+    }
+
+    #[getter]
+    fn max_steps(&self) -> Option<u64> {
+        self.limits.max_steps
+    }
+
+    #[getter]
+    fn max_outputs(&self) -> Option<u64> {
+        self.limits.max_outputs
+    }
+
+    #[getter]
+    fn max_depth(&self) -> Option<usize> {
+        self.limits.max_depth
+    }
+
+    #[getter]
+    fn timeout_ms(&self) -> Option<u64> {
+        self.limits.timeout.map(|timeout| timeout.as_millis() as u64)
+    }
+
+    #[getter]
+    fn max_memory_bytes(&self) -> Option<usize> {
+        self.limits.max_memory_bytes
+    }
+
+    // the limits that are set, as keyword arguments
+    fn __repr__(&self) -> String {
+        let set = [
+            ("max_steps", self.limits.max_steps),
+            ("max_outputs", self.limits.max_outputs),
+            ("max_depth", self.limits.max_depth.map(|n| n as u64)),
+            ("timeout_ms", self.timeout_ms()),
+            ("max_memory_bytes", self.limits.max_memory_bytes.map(|n| n as u64)),
+        ];
+        let args: Vec<String> = set.iter().filter_map(|(name, value)| value.map(|value| format!("{}={}", name, value))).collect();
+        format!("Limits({})", args.join(", "))
+    }
+}
+
+// what input documents are held to, for compile(parse_limits=...) and rusty_jq.parse()
+// nesting is always capped, since converting deeper values could run out of stack
+#[pyclass(frozen, name = "ParseLimits")]
+struct RustyParseLimits {
+    limits: ParseLimits,
+}
+
+#[pymethods]
+impl RustyParseLimits {
+    #[new]
+    #[pyo3(signature = (max_depth=ParseLimits::default().max_depth, max_bytes=None, max_string_length=None, duplicate_keys="last"))]
+    fn new(max_depth: usize, max_bytes: Option<usize>, max_string_length: Option<usize>, duplicate_keys: &str) -> PyResult<Self> {
+        if max_depth > ParseLimits::MAX_DEPTH {
+            return Err(PyErr::new::<PyValueError, _>(format!("max_depth can be at most {}", ParseLimits::MAX_DEPTH)));
+        }
+        let Some(duplicate_keys) = DuplicateKeys::from_name(duplicate_keys) else {
+            return Err(PyErr::new::<PyValueError, _>(format!("duplicate_keys must be 'first', 'last' or 'error', not {:?}", duplicate_keys)));
+        };
+        Ok(RustyParseLimits { limits: ParseLimits { max_depth, max_bytes, max_string_length, duplicate_keys } })
+    }
+
+    #[getter]
+    fn max_depth(&self) -> usize {
+        self.limits.max_depth
+    }
+
+    #[getter]
+    fn max_bytes(&self) -> Option<usize> {
+        self.limits.max_bytes
+    }
+
+    #[getter]
+    fn max_string_length(&self) -> Option<usize> {
+        self.limits.max_string_length
+    }
+
+    #[getter]
+    fn duplicate_keys(&self) -> &'static str {
+        self.limits.duplicate_keys.name()
+    }
+
+    // the depth and duplicate key policy, and the other limits that are set
+    fn __repr__(&self) -> String {
+        let mut args = vec![format!("max_depth={}", self.limits.max_depth)];
+        args.extend(self.limits.max_bytes.map(|n| format!("max_bytes={}", n)));
+        args.extend(self.limits.max_string_length.map(|n| format!("max_string_length={}", n)));
+        args.push(format!("duplicate_keys='{}'", self.limits.duplicate_keys.name()));
+        format!("ParseLimits({})", args.join(", "))
+    }
+}
+
+// the outputs of `query` on `json_text` as a list, for one-off queries; keyword arguments declare
+// the variables they bind, so programs are cached by their names and not by their values
+#[pyfunction]
+#[pyo3(name = "run", signature = (query, json_text, **vars))]
+fn run_query(py: Python, query: &str, json_text: &PyAny, vars: Option<&PyDict>) -> PyResult<PyObject> {
+    let program = cached_with_names(py, query, vars)?;
+    let mut results = program.borrow(py).input(py, json_text, false, false, false, false, vars)?;
+    let mut values = Vec::new();
+    while let Some(value) = results.next_value(py)? {
+        values.push(value);
+    }
+    Ok(PyList::new(py, values).into())
+}
+
+// the first output of `query` on `json_text`, or None, like run()
+#[pyfunction]
+#[pyo3(name = "first", signature = (query, json_text, **vars))]
+fn first_query(py: Python, query: &str, json_text: &PyAny, vars: Option<&PyDict>) -> PyResult<PyObject> {
+    let program = cached_with_names(py, query, vars)?;
+    let first = program.borrow(py).first(py, json_text, false, false, false, false, vars);
+    first
+}
+
+// compiled programs, shared by compile(), run() and first() across threads
+static PROGRAMS: LazyLock<Mutex<Lru<CacheKey, Py<RustyProgram>>>> = LazyLock::new(|| Mutex::new(Lru::new(DEFAULT_CACHE_SIZE)));
+const DEFAULT_CACHE_SIZE: usize = 256;
+
+#[derive(PartialEq, Eq, Hash)]
+struct CacheKey {
+    query: String,
+    options: Options,
+}
+
+// what compile() was given besides the query
+#[derive(PartialEq, Eq, Hash)]
+struct Options {
+    named: Vec<(String, Option<ValueKey>)>,
+    positional: Vec<ValueKey>,
+    limits: Limits,
+    parse_limits: ParseLimits,
+}
+
+// a variable's value as part of a cache key; floats by their bits, so 1 and 1.0 differ
+#[derive(PartialEq, Eq, Hash)]
+enum ValueKey {
+    Null,
+    Bool(bool),
+    I64(i64),
+    U64(u64),
+    F64(u64),
+    String(String),
+    Array(Vec<ValueKey>),
+    Object(Vec<(String, ValueKey)>),
+    // a document from rusty_jq.parse(), by its address: the cached program holds on to the
+    // document, so no other one can take the address while the key is in the cache
+    Document(usize),
+}
+
+fn value_key(val: &BorrowedValue) -> ValueKey {
+    match val {
+        BorrowedValue::Static(StaticNode::Null) => ValueKey::Null,
+        BorrowedValue::Static(StaticNode::Bool(b)) => ValueKey::Bool(*b),
+        BorrowedValue::Static(StaticNode::I64(i)) => ValueKey::I64(*i),
+        BorrowedValue::Static(StaticNode::U64(u)) => ValueKey::U64(*u),
+        BorrowedValue::Static(StaticNode::F64(f)) => ValueKey::F64(f.to_bits()),
+        BorrowedValue::String(s) => ValueKey::String(s.to_string()),
+        BorrowedValue::Array(items) => ValueKey::Array(items.iter().map(value_key).collect()),
+        BorrowedValue::Object(fields) => ValueKey::Object(fields.iter().map(|(k, v)| (k.to_string(), value_key(v))).collect()),
+    }
+}
+
+// the program for `query` from the cache, or compiled and cached
+fn cached(py: Python, query: &str, declared: Declared, options: Options) -> PyResult<Py<RustyProgram>> {
+    let (limits, parse_limits) = (options.limits, options.parse_limits);
+    let key = CacheKey { query: query.to_string(), options };
+    if let Some(program) = PROGRAMS.lock().unwrap().get(&key) {
+        return Ok(program.clone_ref(py));
+    }
+    let filters = syntax::parse(query).map_err(|e| syntax_error(py, e))?;
+    let program = Py::new(py, RustyProgram::new(query, optimize(filters, &limits), declared, limits, parse_limits)?)?;
+    let dropped = PROGRAMS.lock().unwrap().insert(key, program.clone_ref(py));
+    // programs pushed out are dropped once the lock is released
+    drop(dropped);
+    Ok(program)
+}
+
+// the program for `query` with the variables named in `vars` declared and no defaults
+fn cached_with_names(py: Python, query: &str, vars: Option<&PyDict>) -> PyResult<Py<RustyProgram>> {
+    let names = vars.map_or(Ok(Vec::new()), |vars| vars.keys().iter().map(variable_name).collect::<PyResult<Vec<_>>>())?;
+    let options = Options { named: names.iter().map(|name| (name.clone(), None)).collect(), positional: Vec::new(), limits: Limits::default(), parse_limits: ParseLimits::default() };
+    let declared = Declared::new(names.into_iter().map(|name| (name, None)).collect(), Vec::new());
+    cached(py, query, declared, options)
+}
+
+// statistics of the program cache, named like functools.lru_cache's
+#[pyfunction]
+fn cache_info(py: Python) -> PyResult<PyObject> {
+    let cache = PROGRAMS.lock().unwrap();
+    let info = PyDict::new(py);
+    info.set_item("hits", cache.hits)?;
+    info.set_item("misses", cache.misses)?;
+    info.set_item("maxsize", cache.capacity())?;
+    info.set_item("currsize", cache.len())?;
+    Ok(info.into())
+}
+
+// empty the program cache and reset its statistics
+#[pyfunction]
+fn cache_clear() {
+    let dropped = PROGRAMS.lock().unwrap().clear();
+    drop(dropped);
+}
+
+// keep up to `maxsize` programs in the cache; 0 turns caching off
+#[pyfunction]
+fn set_cache_size(maxsize: usize) {
+    let dropped = PROGRAMS.lock().unwrap().resize(maxsize);
+    drop(dropped);
+}
+
+// a program serialized by RustyProgram.to_bytes(), as pickle does, compiled again without parsing
+// or optimizing its query
+#[pyfunction]
+#[pyo3(name = "load")]
+fn load_program(data: &[u8]) -> PyResult<RustyProgram> {
+    let (query, filters, declared, limits, parse_limits) = encoding::decode(data).map_err(PyErr::new::<PyValueError, _>)?;
+    RustyProgram::new(&query, filters, declared, limits, parse_limits)
+}
+
+// RustyJqSyntaxError, with the position and expectations as attributes
+fn syntax_error(py: Python, e: SyntaxError) -> PyErr {
+    let err = RustyJqSyntaxError::new_err(e.to_string());
+    let value = err.value(py);
+    let attributes = [
+        ("line", e.line.into_py(py)),
+        ("column", e.column.into_py(py)),
+        ("offset", e.offset.into_py(py)),
+        ("expected", e.expected.into_py(py)),
+        ("hint", e.hint.into_py(py)),
+    ];
+    for (name, attribute) in attributes {
+        if let Err(e) = value.setattr(name, attribute) { return e; }
+    }
+    err
+}
+
+// `args` maps names to default values, or lists names that every run has to bind; the options
+// come back as a cache key too
+fn declare(py: Python, args: Option<&PyAny>, positional: Option<&PyAny>) -> PyResult<(Declared, Options)> {
+    let mut named: Vec<(String, Option<Arc<Document>>)> = Vec::new();
+    let mut options = Options { named: Vec::new(), positional: Vec::new(), limits: Limits::default(), parse_limits: ParseLimits::default() };
+    match args {
+        None => {}
+        Some(args) if args.is_instance_of::<PyString>() => {
+            return Err(PyErr::new::<PyTypeError, _>("args must be a dict of default values or an iterable of names, not str"));
+        }
+        Some(args) => match args.downcast::<PyDict>() {
+            Ok(dict) => for (name, value) in dict.iter() {
+                let name = variable_name(name)?;
+                let (value, key) = declared_value(py, value)?;
+                options.named.push((name.clone(), Some(key)));
+                named.push((name, Some(value)));
+            },
+            Err(_) => for name in args.iter()? {
+                let name = variable_name(name?)?;
+                options.named.push((name.clone(), None));
+                named.push((name, None));
+            },
+        },
+    }
+    let mut values = Vec::new();
+    for value in positional.map(PyAny::iter).transpose()?.into_iter().flatten() {
+        let (value, key) = declared_value(py, value?)?;
+        options.positional.push(key);
+        values.push(value);
+    }
+    Ok((Declared::new(named, values), options))
+}
+
+// a value for compile(), as bound_value() takes it, and its cache key
+fn declared_value(py: Python, value: &PyAny) -> PyResult<(Arc<Document>, ValueKey)> {
+    let is_document = value.extract::<PyRef<RustyDocument>>().is_ok();
+    let value = bound_value(py, value)?;
+    let key = if is_document { ValueKey::Document(Arc::as_ptr(&value) as usize) } else { value_key(value.root()) };
+    Ok((value, key))
+}
+
+fn variable_name(name: &PyAny) -> PyResult<String> {
+    let name: String = name.extract()?;
+    check_name(&name).map_err(PyErr::new::<PyValueError, _>)?;
+    Ok(name)
+}
+
+// a document from rusty_jq.parse() is bound as it is, without copying; anything else is converted
+fn bound_value(py: Python, value: &PyAny) -> PyResult<Arc<Document>> {
+    match value.extract::<PyRef<RustyDocument>>() {
+        Ok(doc) => doc.document(),
+        Err(_) => Ok(Arc::new(Document::from_value(value_from_py(py, value)?))),
+    }
+}
+
+// PyO3 module initialisation (entry-point)
+#[pymodule]
+fn rusty_jq(py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(compile, m)?)?;
+    m.add_function(wrap_pyfunction!(parse, m)?)?;
+    m.add_function(wrap_pyfunction!(parse_file, m)?)?;
+    m.add_function(wrap_pyfunction!(load_program, m)?)?;
+    m.add_function(wrap_pyfunction!(run_query, m)?)?;
+    m.add_function(wrap_pyfunction!(first_query, m)?)?;
+    m.add_function(wrap_pyfunction!(cache_info, m)?)?;
+    m.add_function(wrap_pyfunction!(cache_clear, m)?)?;
+    m.add_function(wrap_pyfunction!(set_cache_size, m)?)?;
+    m.add_class::<RustyProgram>()?;
+    m.add_class::<RustyJqIter>()?;
+    m.add_class::<RustyDocument>()?;
+    m.add_class::<RustyStreamer>()?;
+    m.add_class::<RustyLimits>()?;
+    m.add_class::<RustyParseLimits>()?;
+    m.add("RustyJqSyntaxError", py.get_type::<RustyJqSyntaxError>())?;
+    m.add("RustyJqRuntimeError", py.get_type::<RustyJqRuntimeError>())?;
+    m.add("RustyJqLimitExceeded", py.get_type::<RustyJqLimitExceeded>())?;
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,115 @@
+use self_cell::self_cell;
+use simd_json::{BorrowedValue, StaticNode};
+use std::borrow::Cow;
+use std::sync::{Arc, OnceLock};
+
+use crate::bytecode::Program;
+use crate::document::Document;
+use crate::limits::{Limits, Meter};
+use crate::stream::{DocumentStream, StreamError};
+use crate::variables::Values;
+use crate::vm::{Execution, Halt, RuntimeError};
+
+// everything the run over one document borrows from: the shared `document`, `program`, `vars`,
+// `inputs` and `halt`, and the document's own `meter`
+struct Run {
+    document: Arc<Document>,
+    program: Arc<Program>,
+    vars: Values,
+    inputs: Arc<DocumentStream>,
+    halt: Arc<OnceLock<Halt>>,
+    meter: Meter,
+}
+
+self_cell!(
+    // a lazy result stream over one document together with the run it borrows from
+    struct ResultStream {
+        owner: Run,
+
+        #[not_covariant]
+        dependent: Execution,
+    }
+);
+
+impl ResultStream {
+    fn start(document: Arc<Document>, results: &Results) -> Self {
+        let (program, vars, inputs, halt) = (Arc::clone(&results.program), Arc::clone(&results.vars), Arc::clone(&results.documents), Arc::clone(&results.halt));
+        let run = Run { document, program, vars, inputs, halt, meter: Meter::new(results.limits) };
+        ResultStream::new(run, |run| {
+            run.program.run_with_inputs(Cow::Borrowed(run.document.root()), run.inputs.as_ref()).with_vars(&run.vars).with_halt(&run.halt).with_meter(&run.meter)
+        })
+    }
+}
+
+// why a document has no more results: it is not valid JSON, or the query failed on it
+pub enum Failure {
+    Stream(StreamError),
+    Runtime(RuntimeError),
+}
+
+// the results of a program over every document of a stream, one document after another
+pub struct Results {
+    // declared first so it is dropped before the documents it borrows
+    current: Option<ResultStream>,
+    documents: Arc<DocumentStream>,
+    program: Arc<Program>,
+    vars: Values,
+    // run the program once on null, leaving every document to `input` and `inputs`, like `jq -n`
+    null_input: bool,
+    started: bool,
+    // set by `halt` or `halt_error`, which end the results of every later document too, as going
+    // over a limit does, or by a runtime error, which only ends those of its own document
+    halt: Arc<OnceLock<Halt>>,
+    // enforced on the run over each document
+    limits: Limits,
+}
+
+impl Results {
+    pub fn new(documents: DocumentStream, program: Arc<Program>, vars: Values, null_input: bool) -> Self {
+        Results { current: None, documents: Arc::new(documents), program, vars, null_input, started: false, halt: Arc::default(), limits: Limits::default() }
+    }
+
+    #[cfg(feature = "python")]
+    pub fn with_limits(self, limits: Limits) -> Self {
+        Results { limits, ..self }
+    }
+
+    // what `read` makes of the next result, or the error of a document that failed to parse or that
+    // the query failed on; results borrow from the current documents, so they are only lent to `read`
+    pub fn next_with<R>(&mut self, read: impl FnOnce(&BorrowedValue) -> R) -> Option<Result<R, Failure>> {
+        let mut read = Some(read);
+        loop {
+            if let Some(current) = &mut self.current {
+                // taken by the first result, which returns
+                let next = current.with_dependent_mut(|_, stream| stream.next().and_then(|val| Some(read.take()?(&val))));
+                // a document read through `input` failed
+                if let Some(e) = self.documents.take_error() { return Some(Err(Failure::Stream(e))); }
+                if let Some(out) = next { return Some(Ok(out)); }
+            }
+            self.current = None;
+            self.documents.release_inputs();
+            // the stream that shared the cell is gone, so the error can be taken out for the next document
+            if let Some(Halt::Error(_)) = self.halt.get() {
+                if let Some(Halt::Error(e)) = Arc::get_mut(&mut self.halt).and_then(OnceLock::take) {
+                    return Some(Err(Failure::Runtime(e)));
+                }
+            }
+            if self.halt.get().is_some() { return None; }
+            if self.null_input {
+                if std::mem::replace(&mut self.started, true) { return None; }
+                let document = Arc::new(Document::from_value(BorrowedValue::Static(StaticNode::Null)));
+                self.current = Some(ResultStream::start(document, self));
+                continue;
+            }
+            match self.documents.next_document()? {
+                Ok(document) => self.current = Some(ResultStream::start(document, self)),
+                Err(e) => return Some(Err(Failure::Stream(e))),
+            }
+        }
+    }
+
+    // how `halt` or `halt_error` ended the results, once `next` has run out
+    pub fn halted(&self) -> Option<&Halt> {
+        self.halt.get()
+    }
+}
@@ -0,0 +1,102 @@
+use simd_json::{BorrowedValue, StaticNode};
+
+// how values are written out as JSON text
+// the default is jq's compact form, as produced by `tojson` and `jq -c`
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonFormat {
+    // spaces per nesting level; `None` writes everything on one line
+    pub indent: Option<usize>,
+    // indent with a tab per level instead, like `jq --tab`
+    pub tab: bool,
+    pub sort_keys: bool,
+    // escape every non-ASCII character as \uXXXX
+    pub ascii: bool,
+}
+
+pub fn to_json(val: &BorrowedValue, format: &JsonFormat) -> String {
+    let mut out = String::new();
+    write_json(&mut out, val, format, 0);
+    out
+}
+
+// appends `val` to `out` without building intermediate strings
+pub fn write_json(out: &mut String, val: &BorrowedValue, format: &JsonFormat, depth: usize) {
+    match val {
+        BorrowedValue::Static(StaticNode::Null) => out.push_str("null"),
+        BorrowedValue::Static(StaticNode::Bool(b)) => out.push_str(if *b { "true" } else { "false" }),
+        BorrowedValue::Static(StaticNode::I64(i)) => out.push_str(&i.to_string()),
+        BorrowedValue::Static(StaticNode::U64(u)) => out.push_str(&u.to_string()),
+        BorrowedValue::Static(StaticNode::F64(f)) => write_f64(out, *f),
+        BorrowedValue::String(s) => write_string(out, s, format.ascii),
+        BorrowedValue::Array(arr) => {
+            if arr.is_empty() { out.push_str("[]"); return; }
+            out.push('[');
+            for (i, item) in arr.iter().enumerate() {
+                if i > 0 { out.push(','); }
+                newline(out, format, depth + 1);
+                write_json(out, item, format, depth + 1);
+            }
+            newline(out, format, depth);
+            out.push(']');
+        }
+        BorrowedValue::Object(obj) => {
+            if obj.is_empty() { out.push_str("{}"); return; }
+            let mut entries: Vec<_> = obj.iter().collect();
+            if format.sort_keys { entries.sort_by(|a, b| a.0.cmp(b.0)); }
+            out.push('{');
+            for (i, (k, v)) in entries.into_iter().enumerate() {
+                if i > 0 { out.push(','); }
+                newline(out, format, depth + 1);
+                write_string(out, k, format.ascii);
+                out.push_str(if format.indent.is_some() { ": " } else { ":" });
+                write_json(out, v, format, depth + 1);
+            }
+            newline(out, format, depth);
+            out.push('}');
+        }
+    }
+}
+
+fn newline(out: &mut String, format: &JsonFormat, depth: usize) {
+    if let Some(indent) = format.indent {
+        out.push('\n');
+        if format.tab {
+            out.extend(std::iter::repeat_n('\t', depth));
+        } else {
+            out.extend(std::iter::repeat_n(' ', indent * depth));
+        }
+    }
+}
+
+// like jq, infinities are clamped to the largest finite double and NaN becomes null
+fn write_f64(out: &mut String, f: f64) {
+    if f.is_nan() {
+        out.push_str("null");
+    } else if f.is_infinite() {
+        out.push_str(if f > 0.0 { "1.7976931348623157e+308" } else { "-1.7976931348623157e+308" });
+    } else {
+        out.push_str(&f.to_string());
+    }
+}
+
+pub fn write_string(out: &mut String, s: &str, ascii: bool) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c if ascii && !c.is_ascii() => {
+                let mut units = [0u16; 2];
+                for unit in c.encode_utf16(&mut units) {
+                    out.push_str(&format!("\\u{:04x}", unit));
+                }
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
@@ -0,0 +1,595 @@
+use flate2::read::MultiGzDecoder;
+use memmap2::MmapOptions;
+#[cfg(feature = "python")]
+use pyo3::exceptions::{PyOSError, PyValueError};
+#[cfg(feature = "python")]
+use pyo3::prelude::*;
+use simd_json::BorrowedValue;
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::ops::Range;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "python")]
+use crate::document::{input_bytes, input_error, writable_buffer};
+use crate::document::{Buffer, Document};
+use crate::events::EventReader;
+use crate::limits::{ParseError, ParseLimits};
+use crate::projection::Projection;
+use crate::vm::InputSource;
+
+// where one document sits in a stream
+#[derive(Debug, Clone)]
+struct Span {
+    range: Range<usize>,
+    // 1-based lines the document starts and ends on
+    line: usize,
+    end_line: usize,
+}
+
+// split whitespace-separated JSON values (including NDJSON) into the span of each one
+fn split_documents(bytes: &[u8]) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let (mut pos, mut line) = (0, 1);
+    while let Some(span) = scan(bytes, pos, line, true) {
+        (pos, line) = (span.range.end, span.end_line);
+        spans.push(span);
+    }
+    spans
+}
+
+// the next value at or after `from`, which is on `line`
+// only brackets, quotes and escapes are looked at here; simd-json validates each value on its own
+// a value cut off by the end of `bytes` is only returned `at_end`, when no more bytes can follow
+fn scan(bytes: &[u8], from: usize, mut line: usize, at_end: bool) -> Option<Span> {
+    let mut i = from;
+    while i < bytes.len() && bytes[i].is_ascii_whitespace() {
+        if bytes[i] == b'\n' { line += 1; }
+        i += 1;
+    }
+    if i == bytes.len() { return None; }
+    let (start, start_line) = (i, line);
+    let complete = match bytes[i] {
+        b'{' | b'[' => {
+            let mut depth = 0usize;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'{' | b'[' => depth += 1,
+                    b'}' | b']' => depth -= 1,
+                    b'"' => i = skip_string(bytes, i),
+                    b'\n' => line += 1,
+                    _ => {}
+                }
+                i += 1;
+                if depth == 0 { break; }
+            }
+            depth == 0
+        }
+        b'"' => {
+            i = skip_string(bytes, i) + 1;
+            i <= bytes.len()
+        }
+        // numbers and literals run up to the next delimiter
+        _ => {
+            i += 1;
+            while i < bytes.len() && !bytes[i].is_ascii_whitespace() && !b"{}[]\"".contains(&bytes[i]) {
+                i += 1;
+            }
+            i < bytes.len()
+        }
+    };
+    if !complete && !at_end { return None; }
+    Some(Span { range: start..i.min(bytes.len()), line: start_line, end_line: line })
+}
+
+// index of the closing quote of the string opening at `start`, or the end of the input
+fn skip_string(bytes: &[u8], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' => return i,
+            b'\\' => i += 2,
+            _ => i += 1,
+        }
+    }
+    bytes.len()
+}
+
+// readers pull at least this many bytes at a time
+const CHUNK: usize = 64 * 1024;
+
+// reads documents one at a time, holding only the current one and the bytes read ahead of it
+pub struct ChunkReader {
+    reader: Box<dyn Read + Send>,
+    // documents larger than this fail before they are read whole
+    max_bytes: Option<usize>,
+    pub pending: Vec<u8>,
+    // where the unread part of `pending` starts, and its line
+    pub pos: usize,
+    pub line: usize,
+    pub eof: bool,
+}
+
+impl ChunkReader {
+    pub fn new(reader: Box<dyn Read + Send>, max_bytes: Option<usize>) -> Self {
+        ChunkReader { reader, max_bytes, pending: Vec::new(), pos: 0, line: 1, eof: false }
+    }
+
+    // the bytes of the next document, with its span within them
+    fn next(&mut self) -> Result<Option<(Vec<u8>, Span)>, StreamError> {
+        loop {
+            if let Some(span) = scan(&self.pending, self.pos, self.line, self.eof) {
+                (self.pos, self.line) = (span.range.end, span.end_line);
+                let bytes = self.pending[span.range.clone()].to_vec();
+                let range = 0..bytes.len();
+                return Ok(Some((bytes, Span { range, ..span })));
+            }
+            if self.eof { return Ok(None); }
+            self.check_size()?;
+            self.fill().map_err(StreamError::read)?;
+        }
+    }
+
+    // fail once the document being read is already larger than allowed
+    fn check_size(&self) -> Result<(), StreamError> {
+        let Some(max) = self.max_bytes else { return Ok(()) };
+        let unread = &self.pending[self.pos..];
+        let Some(start) = unread.iter().position(|b| !b.is_ascii_whitespace()) else { return Ok(()) };
+        if unread.len() - start <= max { return Ok(()); }
+        let line = self.line + unread[..start].iter().filter(|&&b| b == b'\n').count();
+        Err(StreamError::parse(line, ParseError::Size(max), Vec::new()))
+    }
+
+    // drop what was handed out and read more
+    // each read is at least as large as what is pending, so a document spanning many chunks
+    // is only scanned a logarithmic number of times
+    pub fn fill(&mut self) -> io::Result<()> {
+        self.pending.drain(..self.pos);
+        self.pos = 0;
+        let start = self.pending.len();
+        let end = start + CHUNK.max(start);
+        self.pending.resize(end, 0);
+        let mut filled = start;
+        while filled < end {
+            match self.reader.read(&mut self.pending[filled..]) {
+                Ok(0) => { self.eof = true; break; }
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => { self.pending.truncate(filled); return Err(e); }
+            }
+        }
+        self.pending.truncate(filled);
+        Ok(())
+    }
+}
+
+// an in-memory buffer, for parsers that read their input in chunks
+struct BufferReader {
+    buffer: Arc<Buffer>,
+    pos: usize,
+}
+
+impl Read for BufferReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = (&self.buffer.bytes()[self.pos..]).read(buf)?;
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// a Python file-like object, read through its `read` method in binary or text mode
+#[cfg(feature = "python")]
+struct PyReader {
+    file: PyObject,
+    // bytes returned by `read` but not consumed yet; text reads count characters, not bytes
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+#[cfg(feature = "python")]
+impl Read for PyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            self.chunk = Python::with_gil(|py| {
+                let chunk = self.file.call_method1(py, "read", (buf.len(),))?;
+                Ok(input_bytes(py, chunk.as_ref(py))?.into_owned())
+            }).map_err(io::Error::other::<PyErr>)?;
+            self.pos = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+// compressed inputs are recognized by their magic number, whatever their name
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+fn is_compressed(head: &[u8]) -> bool {
+    head.starts_with(GZIP_MAGIC) || head.starts_with(ZSTD_MAGIC)
+}
+
+// a reader that decompresses gzip and zstd input, and passes anything else through
+pub fn decompress(reader: impl Read + Send + 'static) -> io::Result<Box<dyn Read + Send>> {
+    let mut reader = BufReader::with_capacity(CHUNK, reader);
+    let head = reader.fill_buf()?;
+    Ok(if head.starts_with(GZIP_MAGIC) {
+        // concatenated gzip members decompress to the concatenation of their contents
+        Box::new(MultiGzDecoder::new(reader))
+    } else if head.starts_with(ZSTD_MAGIC) {
+        Box::new(zstd::Decoder::with_buffer(reader)?)
+    } else {
+        Box::new(reader)
+    })
+}
+
+// a failed read re-raises the exception of a Python `read`, anything else becomes an OSError
+#[cfg(feature = "python")]
+fn read_error(e: io::Error) -> PyErr {
+    if e.get_ref().is_some_and(|inner| inner.is::<PyErr>()) {
+        return *e.into_inner().unwrap().downcast::<PyErr>().unwrap();
+    }
+    PyErr::from(e)
+}
+
+// an OSError naming the file, like the ones open() raises
+#[cfg(feature = "python")]
+fn open_error(path: &Path, e: io::Error) -> PyErr {
+    match e.raw_os_error() {
+        Some(code) => {
+            let message = e.to_string().replace(&format!(" (os error {})", code), "");
+            PyOSError::new_err((code, message, path.to_string_lossy().into_owned()))
+        }
+        None => PyErr::from(e),
+    }
+}
+
+// a document in a stream that failed to parse, or the stream failing to read
+pub struct StreamError {
+    // where the document starts, prefixed to the message
+    location: String,
+    cause: Cause,
+}
+
+enum Cause {
+    // the document's bytes come along to locate invalid UTF-8
+    Parse { error: ParseError, #[cfg_attr(not(feature = "python"), allow(dead_code))] text: Vec<u8> },
+    Read(io::Error),
+    // malformed text between documents
+    Structure(&'static str),
+}
+
+impl StreamError {
+    pub fn parse(line: usize, error: ParseError, text: Vec<u8>) -> Self {
+        StreamError { location: format!("line {}: ", line), cause: Cause::Parse { error, text } }
+    }
+
+    pub fn read(e: io::Error) -> Self {
+        StreamError { location: String::new(), cause: Cause::Read(e) }
+    }
+
+    pub fn structure(line: usize, message: &'static str) -> Self {
+        StreamError { location: format!("line {}: ", line), cause: Cause::Structure(message) }
+    }
+
+    #[cfg(feature = "python")]
+    pub fn into_py(self, py: Python) -> PyErr {
+        match self.cause {
+            Cause::Parse { error, text } => input_error(py, &text, error, &self.location),
+            Cause::Read(e) => read_error(e),
+            Cause::Structure(message) => PyValueError::new_err(format!("{}{}", self.location, message)),
+        }
+    }
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.cause {
+            Cause::Parse { error, .. } => write!(f, "{}{}", self.location, error),
+            Cause::Read(e) => write!(f, "{}{}", self.location, e),
+            Cause::Structure(message) => write!(f, "{}{}", self.location, message),
+        }
+    }
+}
+
+// parse one document cut out of a stream, which started on `line`
+#[cfg(feature = "python")]
+pub fn parse_document(bytes: Vec<u8>, line: usize, projection: &Projection, limits: &ParseLimits) -> Result<Document, StreamError> {
+    let buffer = Arc::new(Buffer::from_vec(bytes));
+    let whole = 0..buffer.len();
+    // SAFETY: the buffer is fresh, so this is the only parse of its bytes
+    unsafe { Document::parse_range(&buffer, whole.clone(), projection, limits) }
+        .map_err(|error| StreamError::parse(line, error, buffer.bytes()[whole].to_vec()))
+}
+
+// where the documents of a stream come from
+enum Source {
+    Done,
+    // split up front from one buffer in memory
+    Split { buffer: Arc<Buffer>, spans: Vec<Span>, next: usize },
+    Reader(ChunkReader),
+    // `[path, leaf]` events of the input rather than its documents
+    Events(EventReader),
+}
+
+// what a source hands out next
+enum Next {
+    // a document to parse from its span of a buffer
+    Text(Arc<Buffer>, Span),
+    // an event, and the line the parser is on after it
+    Event(BorrowedValue<'static>, usize),
+}
+
+impl Source {
+    fn next(&mut self) -> Option<Result<Next, StreamError>> {
+        match self {
+            Source::Done => None,
+            Source::Split { buffer, spans, next } => {
+                let span = spans.get(*next)?.clone();
+                *next += 1;
+                Some(Ok(Next::Text(Arc::clone(buffer), span)))
+            }
+            Source::Reader(reader) => {
+                let next = reader.next().transpose();
+                if !matches!(next, Some(Ok(_))) { *self = Source::Done; }
+                next.map(|read| read.map(|(bytes, span)| Next::Text(Arc::new(Buffer::from_vec(bytes)), span)))
+            }
+            Source::Events(events) => Some(events.next()?.map(|event| Next::Event(event, events.line()))),
+        }
+    }
+}
+
+// the documents of one input, handed out one at a time to the top-level loop and to `input`
+pub struct DocumentStream {
+    state: Mutex<StreamState>,
+    // set while `error` holds something, so the top-level loop can check it cheaply
+    failed: AtomicBool,
+    projection: Projection,
+    limits: ParseLimits,
+    // files and readers report where the current document came from
+    filename: Option<String>,
+    track_lines: bool,
+}
+
+struct StreamState {
+    source: Source,
+    // a document parsed ahead of the source
+    ready: Option<Arc<Document>>,
+    // documents read through `input`, kept alive until the current top-level run is dropped
+    retained: Vec<Arc<Document>>,
+    // a document that failed while being read through `input`
+    error: Option<StreamError>,
+    // the line the last document handed out ends on
+    line: usize,
+}
+
+impl DocumentStream {
+    // a stream of exactly one already parsed document
+    pub fn single(document: Arc<Document>) -> Self {
+        let mut stream = DocumentStream::new(Source::Done, Projection::default(), ParseLimits::default());
+        stream.state.get_mut().unwrap().ready = Some(document);
+        stream
+    }
+
+    fn new(source: Source, projection: Projection, limits: ParseLimits) -> Self {
+        DocumentStream {
+            state: Mutex::new(StreamState { source, ready: None, retained: Vec::new(), error: None, line: 0 }),
+            failed: AtomicBool::new(false),
+            projection,
+            limits,
+            filename: None,
+            track_lines: false,
+        }
+    }
+
+    // the next document, `None` once the stream is exhausted
+    // a document that fails to parse is reported on its own, and the stream continues after it
+    pub fn next_document(&self) -> Option<Result<Arc<Document>, StreamError>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(document) = state.ready.take() {
+            return Some(Ok(document));
+        }
+        Some(self.parse_next(&mut state)?.map(Arc::new))
+    }
+
+    fn parse_next(&self, state: &mut StreamState) -> Option<Result<Document, StreamError>> {
+        let (buffer, span) = match state.source.next()? {
+            Ok(Next::Text(buffer, span)) => (buffer, span),
+            Ok(Next::Event(event, line)) => {
+                if self.track_lines { state.line = line; }
+                return Some(Ok(Document::from_value(event)));
+            }
+            // malformed event input and oversized documents are reported by line, without the file
+            Err(mut e) => {
+                if let (Some(name), false) = (&self.filename, e.location.is_empty()) {
+                    e.location = format!("{}, {}", name, e.location);
+                }
+                return Some(Err(e));
+            }
+        };
+        if self.track_lines { state.line = span.end_line; }
+        // SAFETY: spans do not overlap, and the source moves past each one before it is parsed
+        let parsed = unsafe { Document::parse_range(&buffer, span.range.clone(), &self.projection, &self.limits) };
+        Some(parsed.map_err(|error| StreamError {
+            location: match &self.filename {
+                Some(name) => format!("{}, line {}: ", name, span.line),
+                None => format!("line {}: ", span.line),
+            },
+            cause: Cause::Parse { error, text: buffer.bytes()[span.range].to_vec() },
+        }))
+    }
+
+    // every remaining document gathered into one array
+    fn slurp(&self) -> Result<Document, StreamError> {
+        let mut state = self.state.lock().unwrap();
+        let mut documents = Vec::new();
+        while let Some(document) = self.parse_next(&mut state) {
+            documents.push(document?);
+        }
+        Ok(Document::slurp(documents))
+    }
+
+    // drop the documents `input` handed out; only once nothing borrows from them anymore
+    pub fn release_inputs(&self) {
+        self.state.lock().unwrap().retained.clear();
+    }
+
+    pub fn take_error(&self) -> Option<StreamError> {
+        if !self.failed.load(Ordering::Relaxed) { return None; }
+        self.failed.store(false, Ordering::Relaxed);
+        self.state.lock().unwrap().error.take()
+    }
+}
+
+impl<'a> InputSource<'a> for DocumentStream {
+    fn next_input(&self) -> Option<Cow<'a, BorrowedValue<'a>>> {
+        match self.next_document()? {
+            Ok(document) => {
+                // a document owning all of its strings, like a stream event, is handed over
+                // rather than kept until the end of a run that may read millions of them
+                let document = match Arc::try_unwrap(document).map(Document::into_value) {
+                    Ok(Ok(value)) => return Some(Cow::Owned(value)),
+                    Ok(Err(document)) => Arc::new(document),
+                    Err(document) => document,
+                };
+                // SAFETY: the document is retained until `release_inputs`, which the top-level
+                // loop only calls after dropping the execution that received this value
+                let root: &'static BorrowedValue<'static> = unsafe { &*(document.root() as *const BorrowedValue<'_>).cast() };
+                self.state.lock().unwrap().retained.push(document);
+                Some(Cow::Borrowed(root))
+            }
+            Err(error) => {
+                self.state.lock().unwrap().error = Some(error);
+                self.failed.store(true, Ordering::Relaxed);
+                None
+            }
+        }
+    }
+
+    fn failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    fn filename(&self) -> Option<String> {
+        self.filename.clone()
+    }
+
+    fn line_number(&self) -> usize {
+        self.state.lock().unwrap().line
+    }
+}
+
+// with slurp the program sees an array of documents, so each one is projected like an element
+fn each_document(projection: &Projection, slurp: bool) -> Projection {
+    if slurp { projection.element().cloned().unwrap_or_default() } else { projection.clone() }
+}
+
+// the documents of a buffer, or with `events` their `[path, leaf]` events
+fn in_memory(buffer: Arc<Buffer>, events: bool, limits: &ParseLimits) -> Source {
+    if events {
+        Source::Events(EventReader::new(ChunkReader::new(Box::new(BufferReader { buffer, pos: 0 }), None), limits))
+    } else {
+        let spans = split_documents(buffer.bytes());
+        Source::Split { buffer, spans, next: 0 }
+    }
+}
+
+// events are read a scalar at a time, so no document is ever held whole to be too large
+fn from_reader(reader: Box<dyn Read + Send>, events: bool, limits: &ParseLimits) -> Source {
+    if events {
+        Source::Events(EventReader::new(ChunkReader::new(reader, None), limits))
+    } else {
+        Source::Reader(ChunkReader::new(reader, limits.max_bytes))
+    }
+}
+
+// parse a Python input holding any number of whitespace-separated documents
+// with `slurp`, they are gathered into one array, which becomes the only document
+// with `events`, the documents are `[path, leaf]` events of the input, as with `jq --stream`
+#[cfg(feature = "python")]
+pub fn load_stream(py: Python, json: &PyAny, projection: &Projection, limits: &ParseLimits, inplace: bool, slurp: bool, events: bool) -> PyResult<DocumentStream> {
+    let each = each_document(projection, slurp);
+    let buffer = if inplace {
+        Arc::new(writable_buffer(json)?)
+    } else {
+        // the common single-document case parses in one go; anything else is split and
+        // parsed again from a fresh copy, since a failed parse may have rewritten the first one
+        if !events {
+            if let Ok(document) = Document::parse(input_bytes(py, json)?.into_owned(), &each, limits) {
+                let document = if slurp { Document::slurp(vec![document]) } else { document };
+                return Ok(DocumentStream::single(Arc::new(document)));
+            }
+        }
+        Arc::new(Buffer::from_vec(input_bytes(py, json)?.into_owned()))
+    };
+    start(DocumentStream::new(in_memory(buffer, events, limits), each, *limits), slurp).map_err(|e| e.into_py(py))
+}
+
+#[cfg(feature = "python")]
+pub fn load_file(py: Python, path: &Path, projection: &Projection, limits: &ParseLimits, slurp: bool, events: bool) -> PyResult<DocumentStream> {
+    let stream = open_file(path, projection, limits, slurp, events).map_err(|e| open_error(path, e))?;
+    start(stream, slurp).map_err(|e| e.into_py(py))
+}
+
+// read the documents of a Python file-like object incrementally, through its `read` method
+#[cfg(feature = "python")]
+pub fn load_reader(py: Python, file: &PyAny, projection: &Projection, limits: &ParseLimits, slurp: bool, events: bool) -> PyResult<DocumentStream> {
+    let reader = PyReader { file: file.into(), chunk: Vec::new(), pos: 0 };
+    let mut stream = open_reader(decompress(reader).map_err(read_error)?, projection, limits, slurp, events);
+    stream.filename = file.getattr("name").ok().and_then(|name| name.extract().ok());
+    start(stream, slurp).map_err(|e| e.into_py(py))
+}
+
+// the documents of a file; plain files are memory-mapped, compressed ones decompressed
+// as they are read
+pub fn open_file(path: &Path, projection: &Projection, limits: &ParseLimits, slurp: bool, events: bool) -> io::Result<DocumentStream> {
+    let file = File::open(path)?;
+    let metadata = file.metadata()?;
+    let mut source = None;
+    if metadata.is_file() && metadata.len() > 0 {
+        // SAFETY: the mapping is private, so parsing never writes to the file; like any mapped
+        // file, it must not be truncated by someone else while it is read
+        let map = unsafe { MmapOptions::new().map_copy(&file) }?;
+        if !is_compressed(&map) {
+            source = Some(in_memory(Arc::new(Buffer::from_mmap(map)), events, limits));
+        }
+    }
+    let source = match source {
+        Some(source) => source,
+        None => from_reader(decompress(file)?, events, limits),
+    };
+    let mut stream = DocumentStream::new(source, each_document(projection, slurp), *limits);
+    stream.filename = Some(path.to_string_lossy().into_owned());
+    stream.track_lines = true;
+    Ok(stream)
+}
+
+// the documents of a reader, read incrementally as they are needed
+pub fn open_reader(reader: Box<dyn Read + Send>, projection: &Projection, limits: &ParseLimits, slurp: bool, events: bool) -> DocumentStream {
+    let mut stream = DocumentStream::new(from_reader(reader, events, limits), each_document(projection, slurp), *limits);
+    stream.track_lines = true;
+    stream
+}
+
+// get an opened stream going: with `slurp` its documents are gathered into one array up front,
+// otherwise the first document is parsed, so a malformed single document fails straight away
+pub fn start(stream: DocumentStream, slurp: bool) -> Result<DocumentStream, StreamError> {
+    if slurp {
+        let document = stream.slurp()?;
+        let mut slurped = DocumentStream::single(Arc::new(document));
+        slurped.filename = stream.filename;
+        return Ok(slurped);
+    }
+    match stream.next_document() {
+        Some(Err(e)) => return Err(e),
+        Some(Ok(first)) => stream.state.lock().unwrap().ready = Some(first),
+        None => {}
+    }
+    Ok(stream)
+}
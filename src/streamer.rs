@@ -0,0 +1,196 @@
+use crate::parser::{Filter, RustyFilter};
+use crate::stream::StreamError;
+
+// what follows a leading `.[]`, which can run on each element of a top-level array on its own
+pub fn element_query(filters: &[Filter]) -> Option<Vec<Filter>> {
+    let first = filters.first()?;
+    match &first.kind {
+        RustyFilter::Iterator => Some(filters[1..].to_vec()),
+        RustyFilter::IterSelect(condition) => {
+            let select = Filter::new(RustyFilter::Select(condition.clone()), first.span);
+            Some(std::iter::once(select).chain(filters[1..].iter().cloned()).collect())
+        }
+        _ => None,
+    }
+}
+
+// a complete value cut out of the input
+pub struct Piece {
+    pub bytes: Vec<u8>,
+    // 1-based line the value starts on
+    pub line: usize,
+    // an element of a top-level array rather than a top-level value
+    pub element: bool,
+}
+
+// the value being scanned
+struct Scan {
+    start: usize,
+    line: usize,
+    kind: Kind,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+#[derive(PartialEq)]
+enum Kind {
+    Container,
+    String,
+    // numbers and literals
+    Scalar,
+}
+
+// where the splitter is in a top-level array whose elements are handed out one by one
+#[derive(PartialEq)]
+enum Array {
+    Outside,
+    // expecting the first element or `]`
+    Open,
+    // an element ended, expecting `,` or `]`
+    AfterElement,
+    AfterComma,
+}
+
+// splits JSON text arriving in chunks into whitespace-separated values, each one as soon as it ends
+// scanning picks up where the previous chunk left off, so every byte is looked at once
+pub struct Splitter {
+    pending: Vec<u8>,
+    // the next byte to look at, and its line
+    pos: usize,
+    line: usize,
+    scan: Option<Scan>,
+    // hand out the elements of top-level arrays instead of the arrays
+    elements: bool,
+    array: Array,
+}
+
+impl Splitter {
+    pub fn new(elements: bool) -> Self {
+        Splitter { pending: Vec::new(), pos: 0, line: 1, scan: None, elements, array: Array::Outside }
+    }
+
+    pub fn push(&mut self, chunk: &[u8]) {
+        // drop everything before the value being scanned
+        let keep = self.scan.as_ref().map_or(self.pos, |scan| scan.start);
+        self.pending.drain(..keep);
+        self.pos -= keep;
+        if let Some(scan) = &mut self.scan { scan.start -= keep; }
+        self.pending.extend_from_slice(chunk);
+    }
+
+    // the next complete value; `at_end` once no more chunks will come, so values cut off by the
+    // end of the input are handed out as they are, to fail parsing
+    // malformed structure around values is reported here; the values themselves are validated when parsed
+    pub fn next(&mut self, at_end: bool) -> Option<Result<Piece, StreamError>> {
+        loop {
+            if let Some(end) = self.advance() {
+                return Some(Ok(self.take(end)));
+            }
+            if self.scan.is_some() {
+                return if at_end { Some(Ok(self.take(self.pending.len()))) } else { None };
+            }
+            while self.pos < self.pending.len() && self.pending[self.pos].is_ascii_whitespace() {
+                if self.pending[self.pos] == b'\n' { self.line += 1; }
+                self.pos += 1;
+            }
+            let Some(&c) = self.pending.get(self.pos) else {
+                if at_end && self.array != Array::Outside {
+                    self.array = Array::Outside;
+                    return Some(Err(self.error("unterminated array")));
+                }
+                return None;
+            };
+            match (&self.array, c) {
+                (Array::Outside, b'[') if self.elements => {
+                    self.array = Array::Open;
+                    self.pos += 1;
+                    continue;
+                }
+                (Array::AfterComma, b']') => {
+                    self.array = Array::Outside;
+                    self.pos += 1;
+                    return Some(Err(self.error("expected a value after ','")));
+                }
+                (Array::Open | Array::AfterElement, b']') => {
+                    self.array = Array::Outside;
+                    self.pos += 1;
+                    continue;
+                }
+                (Array::AfterElement, b',') => {
+                    self.array = Array::AfterComma;
+                    self.pos += 1;
+                    continue;
+                }
+                (Array::Open | Array::AfterComma, b',') => {
+                    self.pos += 1;
+                    return Some(Err(self.error("expected a value before ','")));
+                }
+                (Array::AfterElement, _) => {
+                    // carry on as if the comma were there
+                    self.array = Array::AfterComma;
+                    return Some(Err(self.error("expected ',' or ']' after an array element")));
+                }
+                _ => {}
+            }
+            let kind = match c {
+                b'{' | b'[' => Kind::Container,
+                b'"' => Kind::String,
+                _ => Kind::Scalar,
+            };
+            self.scan = Some(Scan { start: self.pos, line: self.line, kind, depth: 0, in_string: false, escaped: false });
+        }
+    }
+
+    // scan the current value up to its end, if it has arrived
+    fn advance(&mut self) -> Option<usize> {
+        let scan = self.scan.as_mut()?;
+        let bytes = &self.pending;
+        while self.pos < bytes.len() {
+            let c = bytes[self.pos];
+            self.pos += 1;
+            if c == b'\n' { self.line += 1; }
+            if scan.in_string {
+                match c {
+                    _ if scan.escaped => scan.escaped = false,
+                    b'\\' => scan.escaped = true,
+                    b'"' => {
+                        scan.in_string = false;
+                        if scan.kind == Kind::String { return Some(self.pos); }
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            match (&scan.kind, c) {
+                (Kind::Scalar, _) if self.pos > scan.start + 1 && (c.is_ascii_whitespace() || b",[]{}\"".contains(&c)) => {
+                    // the delimiter belongs to what follows
+                    self.pos -= 1;
+                    if c == b'\n' { self.line -= 1; }
+                    return Some(self.pos);
+                }
+                (Kind::Scalar, _) => {}
+                (_, b'"') => scan.in_string = true,
+                (Kind::Container, b'{' | b'[') => scan.depth += 1,
+                (Kind::Container, b'}' | b']') => {
+                    scan.depth -= 1;
+                    if scan.depth == 0 { return Some(self.pos); }
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    fn take(&mut self, end: usize) -> Piece {
+        let scan = self.scan.take().expect("a value is being scanned");
+        self.pos = end;
+        let element = self.array != Array::Outside;
+        if element { self.array = Array::AfterElement; }
+        Piece { bytes: self.pending[scan.start..end].to_vec(), line: scan.line, element }
+    }
+
+    fn error(&self, message: &'static str) -> StreamError {
+        StreamError::structure(self.line, message)
+    }
+}
@@ -0,0 +1,272 @@
+use nom::IResult;
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+use crate::parser::{parse_query, Filter, Span};
+
+// something the parser would have accepted where a query went wrong
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Expected {
+    Char(char),
+    Token(&'static str),
+    // a whole construct, such as "a filter"
+    Label(&'static str),
+}
+
+impl fmt::Display for Expected {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expected::Char(c) => write!(f, "`{}`", c),
+            Expected::Token(token) => write!(f, "`{}`", token),
+            Expected::Label(label) => f.write_str(label),
+        }
+    }
+}
+
+// the furthest point any parser failed at, and what each one wanted there
+// parsers backtrack all the time, so the failure that got furthest is where the query goes wrong
+#[derive(Default)]
+struct Furthest {
+    // bytes left in the query at that point; the fewest is the furthest
+    remaining: Option<usize>,
+    // with repeats, as backtracking tries the same things again; they are dropped in the error
+    expected: Vec<Expected>,
+    // keywords tried there, to suggest one for a misspelt name
+    words: Vec<&'static str>,
+}
+
+impl Furthest {
+    // make `remaining` the failure point if it is at least as far as the current one
+    fn reach(&mut self, remaining: usize) -> bool {
+        match self.remaining {
+            Some(furthest) if furthest < remaining => false,
+            Some(furthest) if furthest == remaining => true,
+            _ => {
+                *self = Furthest { remaining: Some(remaining), ..Furthest::default() };
+                true
+            }
+        }
+    }
+}
+
+thread_local! {
+    static FURTHEST: RefCell<Furthest> = const { RefCell::new(Furthest { remaining: None, expected: Vec::new(), words: Vec::new() }) };
+    // the length of the query being parsed, to turn the suffixes the parser sees into offsets
+    static QUERY_LEN: Cell<usize> = const { Cell::new(0) };
+}
+
+// the span of the query between the suffixes `from` and `to` of it
+pub fn span(from: &str, to: &str) -> Span {
+    let len = QUERY_LEN.with(Cell::get);
+    Span { start: len.saturating_sub(from.len()), end: len.saturating_sub(to.len()) }
+}
+
+// pass `result` through, noting `what` as expected at `input` when it failed
+pub fn expect<'a, O>(input: &'a str, what: Expected, result: IResult<&'a str, O>) -> IResult<&'a str, O> {
+    if let Err(nom::Err::Error(_)) = &result {
+        FURTHEST.with(|furthest| {
+            let mut furthest = furthest.borrow_mut();
+            if furthest.reach(input.len()) { furthest.expected.push(what); }
+        });
+    }
+    result
+}
+
+// note that the keyword `word` did not match at `input`
+pub fn tried(input: &str, word: &'static str) {
+    FURTHEST.with(|furthest| {
+        let mut furthest = furthest.borrow_mut();
+        if furthest.reach(input.len()) {
+            furthest.expected.push(Expected::Token(word));
+            furthest.words.push(word);
+        }
+    });
+}
+
+// report a failure of `parser` that gets no further than its start as `label`, instead of every
+// token it tried there
+pub fn label<'a, O>(label: &'static str, parser: impl FnMut(&'a str) -> IResult<&'a str, O>) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    relabel(Some(label), parser)
+}
+
+// report nothing for a failure of `parser` that gets no further than its start, for optional parts
+// whose absence is no mistake
+pub fn quiet<'a, O>(parser: impl FnMut(&'a str) -> IResult<&'a str, O>) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    relabel(None, parser)
+}
+
+fn relabel<'a, O>(label: Option<&'static str>, mut parser: impl FnMut(&'a str) -> IResult<&'a str, O>) -> impl FnMut(&'a str) -> IResult<&'a str, O> {
+    move |input: &'a str| {
+        let before = FURTHEST.with(|furthest| {
+            let furthest = furthest.borrow();
+            if furthest.remaining == Some(input.len()) { furthest.expected.len() } else { 0 }
+        });
+        let result = parser(input);
+        if let Err(nom::Err::Error(_)) = &result {
+            FURTHEST.with(|furthest| {
+                let mut furthest = furthest.borrow_mut();
+                if furthest.reach(input.len()) {
+                    furthest.expected.truncate(before);
+                    if let Some(label) = label { furthest.expected.push(Expected::Label(label)); }
+                }
+            });
+        }
+        result
+    }
+}
+
+/// A query that is not valid jq, located in the query text.
+///
+/// Positions count characters, not bytes; `line` and `column` start at 1 and `offset` at 0.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// What went wrong, without the position.
+    pub message: String,
+    pub line: usize,
+    pub column: usize,
+    pub offset: usize,
+    /// What would have been accepted at the error, such as `` `)` `` or `a filter`.
+    pub expected: Vec<String>,
+    /// A likely fix, such as the builtin a misspelt name was meant to be.
+    pub hint: Option<String>,
+    // the line of the query with the error, shown above a caret
+    source_line: String,
+}
+
+impl SyntaxError {
+    // the error at byte `at` of `query`
+    fn new(query: &str, at: usize, message: String, expected: Vec<String>, hint: Option<String>) -> Self {
+        let before = &query[..at];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let line_end = query[at..].find('\n').map_or(query.len(), |i| at + i);
+        SyntaxError {
+            message,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+            offset: before.chars().count(),
+            expected,
+            hint,
+            source_line: query[line_start..line_end].to_string(),
+        }
+    }
+}
+
+impl fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{} at line {}, column {}", self.message, self.line, self.column)?;
+        writeln!(f, "    {}", self.source_line)?;
+        write!(f, "    {}^", " ".repeat(self.column - 1))?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\nhint: {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
+// parse a whole query, or locate what is wrong with it
+pub fn parse(query: &str) -> Result<Vec<Filter>, SyntaxError> {
+    FURTHEST.with(|furthest| *furthest.borrow_mut() = Furthest::default());
+    QUERY_LEN.with(|len| len.set(query.len()));
+    let result = parse_query(query.trim_start());
+    let furthest = FURTHEST.with(|furthest| furthest.take());
+    // the parser only ever sees suffixes of the query, so remaining lengths locate its failures
+    let failed_at = furthest.remaining.map(|remaining| query.len() - remaining);
+    let at = match result {
+        Ok((rest, filters)) => {
+            let rest = rest.trim_start();
+            if rest.is_empty() { return Ok(filters); }
+            let stopped = query.len() - rest.len();
+            match failed_at {
+                Some(at) if at >= stopped => at,
+                _ => {
+                    let message = format!("unexpected {}", found(&query[stopped..]));
+                    return Err(SyntaxError::new(query, stopped, message, Vec::new(), None));
+                }
+            }
+        }
+        // a malformed string, reported where it goes wrong rather than where it starts
+        Err(nom::Err::Failure(e)) if e.code == nom::error::ErrorKind::Char => {
+            let at = query.len() - e.input.len();
+            return Err(SyntaxError::new(query, at, "invalid escape sequence in string".to_string(), Vec::new(), None));
+        }
+        // a comparison or assignment chained onto another without parentheses
+        Err(nom::Err::Failure(e)) => {
+            let at = query.len() - e.input.len();
+            let op: String = e.input.chars().take_while(|c| "=!<>|+-*/%".contains(*c)).collect();
+            let message = format!("unexpected `{}`, comparisons and assignments cannot be chained without parentheses", op);
+            return Err(SyntaxError::new(query, at, message, Vec::new(), None));
+        }
+        Err(_) => failed_at.unwrap_or(query.len() - query.trim_start().len()),
+    };
+
+    let mut expected: Vec<String> = Vec::new();
+    for what in furthest.expected.iter().map(Expected::to_string) {
+        if !expected.contains(&what) { expected.push(what); }
+    }
+    let mut message = format!("unexpected {}", found(&query[at..]));
+    match expected.as_slice() {
+        [] => {}
+        [one] => message.push_str(&format!(", expected {}", one)),
+        many => message.push_str(&format!(", expected one of {}", many.join(", "))),
+    }
+    let hint = hint(&query[at..], &furthest);
+    Err(SyntaxError::new(query, at, message, expected, hint))
+}
+
+// what the query has at a failure point
+fn found(rest: &str) -> String {
+    match word(rest) {
+        "" => match rest.chars().next() {
+            Some(c) => format!("`{}`", c),
+            None => "end of query".to_string(),
+        },
+        word => format!("`{}`", word),
+    }
+}
+
+// the name or keyword `rest` starts with, if any
+fn word(rest: &str) -> &str {
+    if !rest.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') { return ""; }
+    let end = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+    &rest[..end]
+}
+
+fn hint(rest: &str, furthest: &Furthest) -> Option<String> {
+    let word = word(rest);
+    let wants_filter = furthest.expected.contains(&Expected::Label("a filter"));
+    if !word.is_empty() {
+        let suggestion = furthest.words.iter()
+            .map(|&candidate| (distance(word, candidate), candidate))
+            // one edit in a short name already makes most other names
+            .filter(|&(d, candidate)| d <= if word.chars().count() <= 4 { 1 } else { 2 } && candidate != word)
+            .min_by_key(|&(d, _)| d)
+            .map(|(_, candidate)| candidate);
+        return match (wants_filter, suggestion) {
+            (true, Some(name)) => Some(format!("unknown function `{}`, did you mean `{}`?", word, name)),
+            (true, None) => Some(format!("unknown function `{}`", word)),
+            (false, Some(name)) => Some(format!("did you mean `{}`?", name)),
+            (false, None) => None,
+        };
+    }
+    None
+}
+
+// edit distance counting a swap of neighbouring characters as one edit, so `lenght` is one from `length`
+fn distance(a: &str, b: &str) -> usize {
+    let (a, b): (Vec<char>, Vec<char>) = (a.chars().collect(), b.chars().collect());
+    let mut rows = vec![(0..=b.len()).collect::<Vec<_>>()];
+    for i in 1..=a.len() {
+        let mut row = vec![i; b.len() + 1];
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            row[j] = (rows[i - 1][j] + 1).min(row[j - 1] + 1).min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                row[j] = row[j].min(rows[i - 2][j - 2] + 1);
+            }
+        }
+        rows.push(row);
+    }
+    rows[a.len()][b.len()]
+}
@@ -0,0 +1,121 @@
+use simd_json::borrowed::Object;
+use simd_json::BorrowedValue;
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use crate::bytecode::Program;
+use crate::document::Document;
+
+// every program can read these: `$ARGS` is `{"positional": [...], "named": {...}}` as in jq,
+// and `$__prog_args` is the named arguments alone
+const ARGS: &str = "ARGS";
+const PROG_ARGS: &str = "__prog_args";
+
+// the value of each variable a program reads, in the order of `Program::vars`
+pub type Values = Arc<[Arc<Document>]>;
+
+// the named variables a program may read, with their defaults, and the positional arguments
+#[derive(Default)]
+pub struct Declared {
+    named: Vec<(String, Option<Arc<Document>>)>,
+    positional: Vec<Arc<Document>>,
+}
+
+impl Declared {
+    pub fn new(named: Vec<(String, Option<Arc<Document>>)>, positional: Vec<Arc<Document>>) -> Self {
+        Declared { named, positional }
+    }
+
+    // for encoding programs
+    #[cfg(feature = "python")]
+    pub fn named(&self) -> &[(String, Option<Arc<Document>>)] {
+        &self.named
+    }
+
+    #[cfg(feature = "python")]
+    pub fn positional(&self) -> &[Arc<Document>] {
+        &self.positional
+    }
+
+    // a query may only read declared variables, like jq rejects undefined ones when compiling
+    pub fn undefined<'p>(&self, program: &'p Program) -> Option<&'p str> {
+        let defined = |name: &str| name == ARGS || name == PROG_ARGS || self.named.iter().any(|(n, _)| n == name);
+        program.vars.iter().map(String::as_str).find(|name| !defined(name))
+    }
+
+    // declare the variables the query reads that were not declared, with no default, so every run
+    // has to bind them
+    #[cfg(feature = "python")]
+    pub fn declare_free(&mut self, program: &Program) {
+        while let Some(name) = self.undefined(program) {
+            self.named.push((name.to_string(), None));
+        }
+    }
+
+    // the values of one run: `given` over the defaults
+    pub fn bind(&self, given: Vec<(String, Arc<Document>)>) -> Result<Bound<'_>, String> {
+        let mut values: Vec<Option<Arc<Document>>> = self.named.iter().map(|(_, default)| default.clone()).collect();
+        for (name, value) in given {
+            match self.named.iter().position(|(n, _)| *n == name) {
+                Some(i) => values[i] = Some(value),
+                None => return Err(format!("unknown variable ${}; declare it with compile(..., args=...)", name)),
+            }
+        }
+        let values = values.into_iter().zip(&self.named)
+            .map(|(value, (name, _))| value.ok_or_else(|| format!("missing value for variable ${}", name)))
+            .collect::<Result<_, _>>()?;
+        Ok(Bound { declared: self, values })
+    }
+}
+
+// the named variables of one run, in declaration order
+pub struct Bound<'d> {
+    declared: &'d Declared,
+    values: Vec<Arc<Document>>,
+}
+
+impl Bound<'_> {
+    pub fn values_for(&self, program: &Program) -> Values {
+        program.vars.iter().map(|name| match name.as_str() {
+            ARGS => {
+                let parts: Vec<&Document> = self.values.iter().chain(&self.declared.positional).map(Arc::as_ref).collect();
+                Arc::new(Document::assemble(&parts, |mut values| {
+                    let positional = values.split_off(self.values.len());
+                    let mut args = Object::default();
+                    args.insert(Cow::Borrowed("positional"), BorrowedValue::Array(Box::new(positional)));
+                    args.insert(Cow::Borrowed("named"), self.named(values));
+                    BorrowedValue::Object(Box::new(args))
+                }))
+            }
+            PROG_ARGS => {
+                let parts: Vec<&Document> = self.values.iter().map(Arc::as_ref).collect();
+                Arc::new(Document::assemble(&parts, |values| self.named(values)))
+            }
+            name => match self.declared.named.iter().position(|(n, _)| n == name) {
+                Some(i) => Arc::clone(&self.values[i]),
+                None => Arc::new(Document::from_value(BorrowedValue::default())),
+            },
+        }).collect()
+    }
+
+    // the object of named variables, from their values in declaration order
+    fn named(&self, values: Vec<BorrowedValue<'static>>) -> BorrowedValue<'static> {
+        let named = self.declared.named.iter().zip(values)
+            .map(|((name, _), value)| (Cow::Owned(name.clone()), value))
+            .collect();
+        BorrowedValue::Object(Box::new(named))
+    }
+}
+
+// names must be usable as `$name` in a query
+pub fn check_name(name: &str) -> Result<(), String> {
+    let valid = name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("invalid variable name {:?}", name));
+    }
+    if name == ARGS || name == PROG_ARGS {
+        return Err(format!("${} is predefined", name));
+    }
+    Ok(())
+}
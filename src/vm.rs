@@ -0,0 +1,1053 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::{Deref, Range};
+use std::sync::{Arc, OnceLock};
+use simd_json::BorrowedValue;
+use simd_json::borrowed::Object;
+use simd_json::prelude::*;
+
+use crate::bytecode::{Assignment, Op, PathExpr, Program};
+use crate::document::Document;
+use crate::engine::{add_in_place, apply_arith, arith_blames_right, compare_values, delete_paths, exec_builtin0, exec_builtin1, get_field, get_index, get_path, invalid_path, is_truthy, iterate, lookup_path, negate, path_to, recurse_lazy, set_path, slice, truncated_json, value_hash, value_to_string_repr, Fault, Outputs, ValueStream};
+use crate::events::{truncate, FromStream};
+use crate::limits::{nests_too_deep, LimitExceeded, Meter, MAX_VALUE_DEPTH};
+use crate::parser::{ArithOp, AssignOp, CompareOp, PathStep};
+use crate::serialize::{to_json, JsonFormat};
+
+type Value<'a> = Cow<'a, BorrowedValue<'a>>;
+
+// a value on the stack, in a local or saved by a fork point: borrowed from a document or the
+// program, or built by the run and shared, so saving the stack at a fork copies no values
+#[derive(Clone)]
+enum Entry<'a> {
+    Borrowed(&'a BorrowedValue<'a>),
+    Shared(Arc<BorrowedValue<'a>>),
+}
+
+impl<'a> Entry<'a> {
+    fn owned(value: BorrowedValue<'a>) -> Self {
+        Entry::Shared(Arc::new(value))
+    }
+
+    // the value, copied only when something else still holds it
+    fn into_value(self) -> Value<'a> {
+        match self {
+            Entry::Borrowed(value) => Cow::Borrowed(value),
+            Entry::Shared(value) => Cow::Owned(Arc::unwrap_or_clone(value)),
+        }
+    }
+
+    // the value a stack entry borrows from a document, if it does
+    fn borrowed(&self) -> Option<&'a BorrowedValue<'a>> {
+        match self {
+            Entry::Borrowed(value) => Some(value),
+            Entry::Shared(_) => None,
+        }
+    }
+
+    fn to_mut(&mut self) -> &mut BorrowedValue<'a> {
+        if let Entry::Borrowed(value) = self { *self = Entry::owned((*value).clone()); }
+        match self {
+            Entry::Shared(value) => Arc::make_mut(value),
+            Entry::Borrowed(_) => unreachable!(),
+        }
+    }
+
+    // a built value nothing else holds, which may be changed in place
+    fn unique_mut(&mut self) -> Option<&mut BorrowedValue<'a>> {
+        match self {
+            Entry::Shared(value) => Arc::get_mut(value),
+            Entry::Borrowed(_) => None,
+        }
+    }
+
+    // a part of the value: `take` takes apart a value nothing else holds, and `find` looks the part
+    // up in a shared one, which only the part is copied out of
+    fn project(self, take: impl FnOnce(Value<'a>) -> Option<Value<'a>>, find: impl for<'v> FnOnce(&'v BorrowedValue<'a>) -> Option<&'v BorrowedValue<'a>>) -> Option<Entry<'a>> {
+        match self {
+            Entry::Borrowed(value) => take(Cow::Borrowed(value)).map(Entry::from),
+            Entry::Shared(value) => match Arc::try_unwrap(value) {
+                Ok(value) => take(Cow::Owned(value)).map(Entry::from),
+                Err(value) => find(&value).map(|part| Entry::owned(part.clone())),
+            },
+        }
+    }
+}
+
+impl<'a> From<Value<'a>> for Entry<'a> {
+    fn from(value: Value<'a>) -> Self {
+        match value {
+            Cow::Borrowed(value) => Entry::Borrowed(value),
+            Cow::Owned(value) => Entry::owned(value),
+        }
+    }
+}
+
+impl<'a> Deref for Entry<'a> {
+    type Target = BorrowedValue<'a>;
+
+    fn deref(&self) -> &BorrowedValue<'a> {
+        match self {
+            Entry::Borrowed(value) => value,
+            Entry::Shared(value) => value,
+        }
+    }
+}
+
+// where `input` and `inputs` read further documents from
+pub trait InputSource<'a>: Sync {
+    fn next_input(&self) -> Option<Value<'a>>;
+    // whether the last `next_input` ran into a document that failed to parse, which the reader of
+    // the stream reports, rather than the run
+    fn failed(&self) -> bool;
+    // the file the current document came from, if any
+    fn filename(&self) -> Option<String>;
+    // the line the current document ends on, 0 when lines are not tracked
+    fn line_number(&self) -> usize;
+}
+
+// a source read through a shorter lifetime, by sub-programs run on values borrowed for less than the run
+impl<'v, 'a: 'v> InputSource<'v> for &'a dyn InputSource<'a> {
+    fn next_input(&self) -> Option<Value<'v>> {
+        (**self).next_input().map(|v| match v {
+            Cow::Borrowed(v) => Cow::Borrowed(v),
+            Cow::Owned(v) => Cow::Owned(v),
+        })
+    }
+
+    fn failed(&self) -> bool {
+        (**self).failed()
+    }
+
+    fn filename(&self) -> Option<String> {
+        (**self).filename()
+    }
+
+    fn line_number(&self) -> usize {
+        (**self).line_number()
+    }
+}
+
+// how a run ended early
+#[derive(Debug)]
+pub enum Halt {
+    // `halt` and `halt_error`: jq's exit status, and for `halt_error` the text it writes to stderr
+    Exit { code: i32, message: Option<String> },
+    // an error in the query, which ends the run of the input it happened on
+    Error(RuntimeError),
+    // the run went over one of its limits
+    Limit(LimitExceeded),
+}
+
+/// An error raised while running a query, such as adding a number to a string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    /// What went wrong, naming the values involved as jq does.
+    pub message: String,
+    /// The bytes of the query that failed.
+    pub span: Range<usize>,
+    /// The text of the query at `span`.
+    pub filter: String,
+    /// The JSON text of the value to blame, cut short when it is long.
+    pub value: String,
+    /// Where that value is in the input, when it was read from the input rather than computed.
+    pub path: Option<Vec<PathStep>>,
+}
+
+impl RuntimeError {
+    // fill in the text of the failing filter from the query the program was compiled from
+    pub(crate) fn in_query(mut self, query: &str) -> Self {
+        self.filter = query.get(self.span.clone()).unwrap_or_default().to_string();
+        self
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)?;
+        match (self.filter.is_empty(), &self.path) {
+            (false, Some(path)) => write!(f, " (in `{}`, at {})", self.filter, display_path(path)),
+            (false, None) => write!(f, " (in `{}`)", self.filter),
+            (true, Some(path)) => write!(f, " (at {})", display_path(path)),
+            (true, None) => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+// a path as jq writes it, such as `.items[3].price`
+pub fn display_path(path: &[PathStep]) -> String {
+    if path.is_empty() { return ".".to_string(); }
+    let mut text = String::new();
+    for step in path {
+        match step {
+            PathStep::Field(key) if is_identifier(key) => { text.push('.'); text.push_str(key); }
+            PathStep::Field(key) => text.push_str(&format!(".[{}]", to_json(&BorrowedValue::String(Cow::Borrowed(key)), &JsonFormat::default()))),
+            PathStep::Index(i) => text.push_str(&format!("[{}]", i)),
+        }
+    }
+    text
+}
+
+fn is_identifier(key: &str) -> bool {
+    key.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+// how execution continues when backtracking reaches a fork point
+enum Resume<'a> {
+    // jump to the saved pc once
+    Branch,
+    // push the next output of a generator and continue at the saved pc, until it runs dry
+    Stream(ValueStream<'a>),
+    // a `try`: an error while it is active resumes at the handler; backtracking past it goes on
+    Catch { active: bool },
+    // left by the end of a `try` body, so backtracking into the body makes its catch active again
+    Reopen(usize),
+}
+
+struct ForkPoint<'a> {
+    pc: usize,
+    stack: Vec<Entry<'a>>,
+    resume: Resume<'a>,
+}
+
+// one run of a program over one input, pulling outputs on demand
+pub struct Execution<'a> {
+    program: &'a Program,
+    pc: usize,
+    stack: Vec<Entry<'a>>,
+    forks: Vec<ForkPoint<'a>>,
+    slots: Vec<usize>,
+    // the query's own variables, indexed as the compiler handed them out; grown as they are bound
+    locals: Vec<Entry<'a>>,
+    root: Option<Entry<'a>>,
+    // the input document, to locate the values runtime errors blame in; None when it is not borrowed
+    document: Option<&'a BorrowedValue<'a>>,
+    inputs: Option<&'a dyn InputSource<'a>>,
+    // the value of each of `Program::vars`
+    vars: &'a [Arc<Document>],
+    // where a halt is recorded, shared with sub-programs and with the runs of later inputs
+    halt: Option<&'a OnceLock<Halt>>,
+    // what the run has used of its limits, shared with sub-programs; None when it has none
+    meter: Option<&'a Meter>,
+    // run by another program, whose outputs are the ones counted against the limits
+    nested: bool,
+}
+
+// what sub-programs share with the program running them, cloned into the generators built from them
+#[derive(Clone)]
+struct Context<'a> {
+    program: &'a Program,
+    document: Option<&'a BorrowedValue<'a>>,
+    inputs: Option<&'a dyn InputSource<'a>>,
+    vars: &'a [Arc<Document>],
+    // the locals bound when the sub-program was started
+    locals: Arc<[Entry<'a>]>,
+    halt: Option<&'a OnceLock<Halt>>,
+    meter: Option<&'a Meter>,
+}
+
+impl<'a> Context<'a> {
+    // a sub-program run on `root`, reading from the same input stream and variables
+    fn run(&self, p: u32, root: Entry<'a>) -> Execution<'a> {
+        let program = &self.program.subprograms[p as usize];
+        Execution {
+            document: self.document,
+            inputs: self.inputs,
+            vars: self.vars,
+            locals: self.locals.to_vec(),
+            halt: self.halt,
+            meter: self.meter,
+            nested: true,
+            ..program.start(root)
+        }
+    }
+
+    // the same context for sub-programs run on values borrowed for less than the run, such as the
+    // parts of an assignment's input its paths are looked for in
+    fn narrowed<'v>(&'v self) -> Context<'v> {
+        Context {
+            program: self.program,
+            document: self.document,
+            inputs: self.inputs.as_ref().map(|inputs| inputs as &dyn InputSource<'v>),
+            vars: self.vars,
+            locals: self.locals.clone(),
+            halt: self.halt,
+            meter: self.meter,
+        }
+    }
+
+    // `input` with the paths of an assignment set, or the error that stopped it
+    fn assign(&self, assignment: &Assignment, input: Entry<'a>, value: Option<Entry<'a>>) -> Result<BorrowedValue<'a>, Fault> {
+        let mut found = Vec::new();
+        self.paths(&assignment.paths, &input, &mut Vec::new(), &mut found)?;
+        let paths: Vec<Vec<PathStep>> = found.into_iter().map(|(path, _)| path).collect();
+        let mut result = input.into_value().into_owned();
+        let mut deleted = Vec::new();
+        for path in paths {
+            let current = lookup_path(&result, &path).unwrap_or(&NULL);
+            let new = match (assignment.op, &value) {
+                (AssignOp::Set, Some(value)) => (**value).clone(),
+                (AssignOp::Arith(op), Some(value)) => match apply_arith(current, &op, value) {
+                    Ok(new) => new,
+                    Err(message) => {
+                        let culprit = if arith_blames_right(current, value) { value } else { current };
+                        return Err(Fault { message, value: truncated_json(culprit), at: Vec::new() });
+                    }
+                },
+                (AssignOp::Alternative, Some(value)) => {
+                    if is_truthy(current) { continue; }
+                    (**value).clone()
+                }
+                // the first output of the update, none deleting the path
+                _ => {
+                    let update = assignment.update.expect("`|=` has an update");
+                    match self.run(update, Entry::owned(current.clone())).next() {
+                        Some(new) => new.into_owned(),
+                        None => {
+                            deleted.push(path);
+                            continue;
+                        }
+                    }
+                }
+            };
+            nest(&new, path.len())?;
+            set_path(&mut result, &path, new)?;
+        }
+        delete_paths(&mut result, deleted);
+        Ok(result)
+    }
+
+    // the paths `expr` refers to in `value`, which is at `at`, each with the value there
+    // like `.a` itself, a step that cannot be taken leads to null, and `set_path` reports it
+    fn paths<'v>(&self, expr: &PathExpr, value: &'v BorrowedValue<'a>, at: &mut Vec<PathStep>, out: &mut Vec<(Vec<PathStep>, &'v BorrowedValue<'a>)>) -> Result<(), Fault> {
+        match expr {
+            PathExpr::Step(step) => {
+                let (step, child) = match (step, value) {
+                    (PathStep::Field(key), BorrowedValue::Object(obj)) => (step.clone(), obj.get(key.as_str())),
+                    (PathStep::Index(idx), BorrowedValue::Array(arr)) => {
+                        let i = if *idx < 0 { arr.len() as i64 + *idx as i64 } else { *idx as i64 };
+                        match usize::try_from(i) {
+                            Ok(i) => (PathStep::Index(i as i32), arr.get(i)),
+                            Err(_) => (step.clone(), None),
+                        }
+                    }
+                    _ => (step.clone(), None),
+                };
+                at.push(step);
+                out.push((at.clone(), child.unwrap_or(&NULL)));
+                at.pop();
+            }
+            PathExpr::Each => match value {
+                BorrowedValue::Array(arr) => for (i, v) in arr.iter().enumerate() {
+                    at.push(PathStep::Index(i as i32));
+                    out.push((at.clone(), v));
+                    at.pop();
+                },
+                BorrowedValue::Object(obj) => for (k, v) in obj.iter() {
+                    at.push(PathStep::Field(k.to_string()));
+                    out.push((at.clone(), v));
+                    at.pop();
+                },
+                _ => {}
+            },
+            PathExpr::Recurse => {
+                out.push((at.clone(), value));
+                let mut children = Vec::new();
+                self.paths(&PathExpr::Each, value, at, &mut children)?;
+                for (mut path, child) in children {
+                    self.paths(&PathExpr::Recurse, child, &mut path, out)?;
+                }
+            }
+            PathExpr::Select(p) => {
+                if self.narrowed().run(*p, Entry::Borrowed(value)).next().is_some() { out.push((at.clone(), value)); }
+            }
+            PathExpr::Pipe(exprs) => self.pipe(exprs, value, at, out)?,
+            PathExpr::Comma(branches) => for branch in branches {
+                self.paths(branch, value, at, out)?;
+            },
+            PathExpr::Limit(n, expr) => {
+                let mut found = Vec::new();
+                self.paths(expr, value, at, &mut found)?;
+                out.extend(found.into_iter().take(*n));
+            }
+            PathExpr::If(branches, otherwise) => self.branch(branches, otherwise, value, at, out)?,
+            // the truthy paths on the left, or the right's when there are none
+            PathExpr::Alternative(left, right) => {
+                let mut found = Vec::new();
+                let _ = self.paths(left, value, at, &mut found);
+                found.retain(|(_, v)| is_truthy(v));
+                if found.is_empty() { self.paths(right, value, at, out)?; } else { out.extend(found); }
+            }
+            PathExpr::Invalid(p) => {
+                if let Some(result) = self.narrowed().run(*p, Entry::Borrowed(value)).next() { return Err(invalid_path(&result)); }
+            }
+        }
+        Ok(())
+    }
+
+    // the paths of each expression followed from those of the one before
+    fn pipe<'v>(&self, exprs: &[PathExpr], value: &'v BorrowedValue<'a>, at: &mut Vec<PathStep>, out: &mut Vec<(Vec<PathStep>, &'v BorrowedValue<'a>)>) -> Result<(), Fault> {
+        let Some((first, rest)) = exprs.split_first() else {
+            out.push((at.clone(), value));
+            return Ok(());
+        };
+        let mut found = Vec::new();
+        self.paths(first, value, at, &mut found)?;
+        for (mut path, v) in found {
+            self.pipe(rest, v, &mut path, out)?;
+        }
+        Ok(())
+    }
+
+    // `if`, each output of the first condition taking its branch or going on to the next condition
+    fn branch<'v>(&self, branches: &[(u32, PathExpr)], otherwise: &PathExpr, value: &'v BorrowedValue<'a>, at: &mut Vec<PathStep>, out: &mut Vec<(Vec<PathStep>, &'v BorrowedValue<'a>)>) -> Result<(), Fault> {
+        let Some(((cond, then), rest)) = branches.split_first() else { return self.paths(otherwise, value, at, out) };
+        for c in self.narrowed().run(*cond, Entry::Borrowed(value)) {
+            if is_truthy(&c) { self.paths(then, value, at, out)?; } else { self.branch(rest, otherwise, value, at, out)?; }
+        }
+        Ok(())
+    }
+}
+
+static NULL: BorrowedValue<'static> = BorrowedValue::Static(StaticNode::Null);
+
+impl Program {
+    pub fn run<'a>(&'a self, root: Value<'a>) -> Execution<'a> {
+        self.start(Entry::from(root))
+    }
+
+    fn start<'a>(&'a self, root: Entry<'a>) -> Execution<'a> {
+        let document = root.borrowed();
+        Execution {
+            program: self,
+            pc: 0,
+            stack: Vec::with_capacity(8),
+            forks: Vec::new(),
+            slots: vec![0; self.slots],
+            locals: Vec::new(),
+            root: Some(root),
+            document,
+            inputs: None,
+            vars: &[],
+            halt: None,
+            meter: None,
+            nested: false,
+        }
+    }
+
+    // run with `input` and `inputs` reading from `inputs`; without a source they produce nothing
+    pub fn run_with_inputs<'a>(&'a self, root: Value<'a>, inputs: &'a dyn InputSource<'a>) -> Execution<'a> {
+        Execution { inputs: Some(inputs), ..self.run(root) }
+    }
+}
+
+impl<'a> Execution<'a> {
+    // bind the program's variables; unbound ones read as null
+    pub fn with_vars(self, vars: &'a [Arc<Document>]) -> Self {
+        Execution { vars, ..self }
+    }
+
+    // record `halt` and `halt_error` in `halt`; without it they only end this run
+    pub fn with_halt(self, halt: &'a OnceLock<Halt>) -> Self {
+        Execution { halt: Some(halt), ..self }
+    }
+
+    // enforce the limits `meter` was made with; going over one halts the run with Halt::Limit
+    pub fn with_meter(self, meter: &'a Meter) -> Self {
+        Execution { meter: meter.limited().then_some(meter), ..self }
+    }
+
+    fn halted(&self) -> bool {
+        self.halt.is_some_and(|halt| halt.get().is_some())
+    }
+
+    // end the run for good; only the first halt of a run is kept
+    fn stop(&mut self, halt: Halt) -> Option<Value<'a>> {
+        if let Some(cell) = self.halt { let _ = cell.set(halt); }
+        self.forks.clear();
+        None
+    }
+
+    // raise an error from the instruction just executed on `input`: the innermost active `try`
+    // catches it, returning true, and otherwise it ends the run, located in the document when
+    // `input` was borrowed from there
+    #[cold]
+    fn fail(&mut self, fault: Fault, input: Option<&BorrowedValue>) -> bool {
+        if let Some(i) = self.forks.as_slice().iter().rposition(|fork| matches!(fork.resume, Resume::Catch { active: true })) {
+            let catch = self.forks.swap_remove(i);
+            self.forks.truncate(i);
+            self.stack = catch.stack;
+            *self.top() = Entry::owned(BorrowedValue::String(Cow::Owned(fault.message)));
+            self.pc = catch.pc;
+            return true;
+        }
+        let path = self.document.zip(input).and_then(|(document, input)| path_to(document, input)).map(|mut path| {
+            path.extend(fault.at);
+            path
+        });
+        let span = self.program.spans[self.pc - 1];
+        let error = RuntimeError { message: fault.message, span: span.start..span.end, filter: String::new(), value: fault.value, path };
+        self.stop(Halt::Error(error));
+        false
+    }
+
+    fn pop(&mut self) -> Entry<'a> {
+        self.stack.pop().expect("vm stack underflow")
+    }
+
+    fn top(&mut self) -> &mut Entry<'a> {
+        self.stack.last_mut().expect("vm stack underflow")
+    }
+
+    // continue with the first output of a primitive, forking over the rest
+    // returns false when there was no output at all
+    fn push_outputs(&mut self, outputs: Outputs<'a>) -> bool {
+        match outputs {
+            Outputs::Empty => false,
+            Outputs::One(v) => { self.stack.push(Entry::from(v)); true }
+            Outputs::Many(mut stream) => match stream.next() {
+                Some(v) => {
+                    self.forks.push(ForkPoint { pc: self.pc, stack: self.stack.clone(), resume: Resume::Stream(stream) });
+                    self.stack.push(Entry::from(v));
+                    true
+                }
+                None => false,
+            },
+        }
+    }
+
+    fn context(&self) -> Context<'a> {
+        Context {
+            program: self.program,
+            document: self.document,
+            inputs: self.inputs,
+            vars: self.vars,
+            locals: self.locals.clone().into(),
+            halt: self.halt,
+            meter: self.meter,
+        }
+    }
+
+    // count a value built by the last instruction against the memory limit
+    fn charge(&self, v: &BorrowedValue) -> Result<(), LimitExceeded> {
+        self.meter.map_or(Ok(()), |meter| meter.build(v))
+    }
+
+    fn push_option(&mut self, v: Option<Entry<'a>>) -> bool {
+        match v {
+            Some(v) => { self.stack.push(v); true }
+            None => false,
+        }
+    }
+
+    // resume the most recent live fork point, returns false once every path is exhausted
+    fn backtrack(&mut self) -> bool {
+        while let Some(fork) = self.forks.last_mut() {
+            match &mut fork.resume {
+                Resume::Branch => {
+                    let fork = self.forks.pop().unwrap();
+                    self.stack = fork.stack;
+                    self.pc = fork.pc;
+                    return true;
+                }
+                Resume::Stream(stream) => {
+                    if let Some(v) = stream.next() {
+                        self.stack.clone_from(&fork.stack);
+                        self.stack.push(Entry::from(v));
+                        self.pc = fork.pc;
+                        return true;
+                    }
+                    self.forks.pop();
+                }
+                Resume::Catch { .. } => { self.forks.pop(); }
+                Resume::Reopen(i) => {
+                    let i = *i;
+                    self.forks.pop();
+                    self.forks[i].resume = Resume::Catch { active: true };
+                }
+            }
+        }
+        false
+    }
+
+    // run until the next output; `None` once the program is exhausted
+    fn execute(&mut self) -> Option<Value<'a>> {
+        let program = self.program;
+        loop {
+            if let Some(meter) = self.meter {
+                if let Err(e) = meter.step() { return self.stop(Halt::Limit(e)); }
+                // a sub-program went over a limit
+                if self.halted() {
+                    self.forks.clear();
+                    return None;
+                }
+            }
+            let op = program.code[self.pc];
+            self.pc += 1;
+            let ok = match op {
+                Op::Dup => {
+                    let v = self.top().clone();
+                    self.stack.push(v);
+                    true
+                }
+                Op::Over => {
+                    let v = self.stack[self.stack.len() - 2].clone();
+                    self.stack.push(v);
+                    true
+                }
+                Op::Swap => {
+                    let len = self.stack.len();
+                    self.stack.swap(len - 1, len - 2);
+                    true
+                }
+                Op::Pop => { self.pop(); true }
+                Op::LoadConst(k) => {
+                    *self.top() = Entry::Borrowed(&program.consts[k as usize]);
+                    true
+                }
+                Op::Field(k) => {
+                    let key = program.keys[k as usize].as_str();
+                    let child = self.pop().project(|v| get_field(v, key), |v| v.as_object()?.get(key));
+                    self.push_option(child)
+                }
+                Op::Index(i) => {
+                    let child = self.pop().project(|v| get_index(v, i), |v| lookup_path(v, &[PathStep::Index(i)]));
+                    self.push_option(child)
+                }
+                Op::Path(p) => {
+                    let steps = &program.paths[p as usize];
+                    let child = self.pop().project(|v| get_path(v, steps), |v| lookup_path(v, steps));
+                    self.push_option(child)
+                }
+                Op::Slice(start, end) => {
+                    let v = self.pop();
+                    let sliced = slice(&v, start, end);
+                    if let Some(Err(e)) = sliced.as_ref().map(|s| self.charge(s)) { return self.stop(Halt::Limit(e)); }
+                    self.push_option(sliced.map(Entry::owned))
+                }
+                Op::Each => {
+                    let v = self.pop();
+                    self.push_outputs(iterate(v.into_value()))
+                }
+                Op::EachWhere(p) => {
+                    let v = self.pop();
+                    let predicate = &program.predicates[p as usize];
+                    let matches = match iterate(v.into_value()) {
+                        Outputs::Many(elements) => Outputs::Many(Box::new(elements.filter(move |e| predicate.eval(e)))),
+                        other => other,
+                    };
+                    self.push_outputs(matches)
+                }
+                Op::Recurse => {
+                    let v = self.pop();
+                    if let Some(meter) = self.meter {
+                        if let Err(e) = meter.recurse(&v, v.borrowed().is_none()) { return self.stop(Halt::Limit(e)); }
+                    }
+                    self.push_outputs(recurse_lazy(v.into_value()))
+                }
+                Op::Builtin0(b) => {
+                    let v = self.pop();
+                    let input = v.borrowed();
+                    let mut out = Outputs::Empty;
+                    match exec_builtin0(&b, v.into_value(), &mut out) {
+                        Ok(()) => {
+                            if let Outputs::One(Cow::Owned(built)) = &out {
+                                if let Err(e) = self.charge(built) { return self.stop(Halt::Limit(e)); }
+                                if let Err(fault) = nest(built, 0) {
+                                    if self.fail(fault, input) { continue; }
+                                    return None;
+                                }
+                            }
+                            self.push_outputs(out)
+                        }
+                        Err(fault) => {
+                            if self.fail(fault, input) { continue; }
+                            return None;
+                        }
+                    }
+                }
+                Op::Builtin1(b, a) => {
+                    let v = self.pop();
+                    let mut out = Outputs::Empty;
+                    exec_builtin1(&b, &program.args[a as usize], v.into_value(), &mut out);
+                    if let Outputs::One(Cow::Owned(built)) = &out {
+                        if let Err(e) = self.charge(built) { return self.stop(Halt::Limit(e)); }
+                        if let Err(fault) = nest(built, 0) {
+                            if self.fail(fault, None) { continue; }
+                            return None;
+                        }
+                    }
+                    self.push_outputs(out)
+                }
+                Op::Arith(op) => {
+                    let mut lhs = self.pop();
+                    let rhs = self.pop();
+                    // a sum on a value nothing else holds, like the state of `reduce`, grows it in place
+                    if op == ArithOp::Add && lhs.unique_mut().is_some_and(|sum| add_in_place(sum, &rhs)) {
+                        if let Err(e) = self.charge(&rhs) { return self.stop(Halt::Limit(e)); }
+                        self.stack.push(lhs);
+                        continue;
+                    }
+                    match apply_arith(&lhs, &op, &rhs) {
+                        Ok(result) => {
+                            if let Err(e) = self.charge(&result) { return self.stop(Halt::Limit(e)); }
+                            self.stack.push(Entry::owned(result));
+                            true
+                        }
+                        Err(message) => {
+                            let culprit = if arith_blames_right(&lhs, &rhs) { &rhs } else { &lhs };
+                            let fault = Fault { message, value: truncated_json(culprit), at: Vec::new() };
+                            let input = culprit.borrowed();
+                            if self.fail(fault, input) { continue; }
+                            return None;
+                        }
+                    }
+                }
+                Op::Compare(op) => {
+                    let lhs = self.pop();
+                    let rhs = self.pop();
+                    self.stack.push(Entry::owned(BorrowedValue::Static(StaticNode::Bool(compare_values(&lhs, &op, &rhs)))));
+                    true
+                }
+                Op::ToBool => {
+                    let b = is_truthy(self.top());
+                    *self.top() = Entry::owned(BorrowedValue::Static(StaticNode::Bool(b)));
+                    true
+                }
+                Op::Negate => {
+                    let v = self.pop();
+                    match negate(&v) {
+                        Ok(negated) => {
+                            self.stack.push(Entry::owned(negated));
+                            true
+                        }
+                        Err(message) => {
+                            let fault = Fault { message, value: truncated_json(&v), at: Vec::new() };
+                            if self.fail(fault, v.borrowed()) { continue; }
+                            return None;
+                        }
+                    }
+                }
+                Op::ObjNew => {
+                    self.stack.push(Entry::owned(BorrowedValue::Object(Box::new(Object::new()))));
+                    true
+                }
+                Op::ObjInsert(k) => {
+                    let v = self.pop();
+                    // a value borrowed from the input is copied into the object
+                    if let Some(copied) = v.borrowed() {
+                        if let Err(e) = self.charge(copied) { return self.stop(Halt::Limit(e)); }
+                    }
+                    if let Err(fault) = nest(&v, 1) {
+                        if self.fail(fault, None) { continue; }
+                        return None;
+                    }
+                    let v = v.into_value().into_owned();
+                    if let BorrowedValue::Object(obj) = self.top().to_mut() {
+                        obj.insert(Cow::Owned(program.keys[k as usize].clone()), v);
+                    }
+                    true
+                }
+                Op::Fork(target) => {
+                    self.forks.push(ForkPoint { pc: target as usize, stack: self.stack.clone(), resume: Resume::Branch });
+                    true
+                }
+                Op::Jump(target) => {
+                    self.pc = target as usize;
+                    true
+                }
+                Op::JumpIfFalse(target) => {
+                    if !is_truthy(&self.pop()) { self.pc = target as usize; }
+                    true
+                }
+                Op::Guard => is_truthy(&self.pop()),
+                Op::Backtrack => false,
+                Op::TryBegin(handler) => {
+                    self.forks.push(ForkPoint { pc: handler as usize, stack: self.stack.clone(), resume: Resume::Catch { active: true } });
+                    true
+                }
+                Op::TryEnd => {
+                    // the body had an output: errors after it are not the body's
+                    let i = self.forks.as_slice().iter().rposition(|fork| matches!(fork.resume, Resume::Catch { active: true }))
+                        .expect("`TryEnd` without its `TryBegin`");
+                    self.forks[i].resume = Resume::Catch { active: false };
+                    self.forks.push(ForkPoint { pc: 0, stack: Vec::new(), resume: Resume::Reopen(i) });
+                    true
+                }
+                Op::ClearFlag(s) => {
+                    self.slots[s as usize] = 0;
+                    true
+                }
+                Op::SetFlag(s) => {
+                    self.slots[s as usize] = 1;
+                    true
+                }
+                Op::UnlessFlag(s) => self.slots[s as usize] == 0,
+                Op::Label(s) => {
+                    self.slots[s as usize] = self.forks.len();
+                    true
+                }
+                Op::Cut(s) => {
+                    self.forks.truncate(self.slots[s as usize]);
+                    true
+                }
+                Op::LimitInit(s, n) => {
+                    self.slots[s as usize] = n as usize;
+                    n > 0
+                }
+                Op::LimitCheck(s, label) => {
+                    self.slots[s as usize] -= 1;
+                    if self.slots[s as usize] == 0 { self.forks.truncate(self.slots[label as usize]); }
+                    true
+                }
+                Op::Input => {
+                    let v = self.pop();
+                    match self.inputs.and_then(|source| source.next_input()) {
+                        Some(next) => {
+                            self.stack.push(Entry::from(next));
+                            true
+                        }
+                        None if self.inputs.is_some_and(|source| source.failed()) => false,
+                        None => {
+                            let fault = Fault { message: "No more inputs".to_string(), value: truncated_json(&v), at: Vec::new() };
+                            if self.fail(fault, None) { continue; }
+                            return None;
+                        }
+                    }
+                }
+                Op::Inputs => {
+                    self.pop();
+                    match self.inputs {
+                        Some(source) => self.push_outputs(Outputs::Many(Box::new(std::iter::from_fn(move || source.next_input())))),
+                        None => false,
+                    }
+                }
+                Op::InputFilename => {
+                    let name = self.inputs.and_then(|source| source.filename());
+                    *self.top() = Entry::owned(match name {
+                        Some(name) => BorrowedValue::String(Cow::Owned(name)),
+                        None => BorrowedValue::Static(StaticNode::Null),
+                    });
+                    true
+                }
+                Op::InputLineNumber => {
+                    let line = self.inputs.map_or(0, |source| source.line_number());
+                    *self.top() = Entry::owned(BorrowedValue::Static(StaticNode::I64(line as i64)));
+                    true
+                }
+                Op::LoadVar(v) => {
+                    *self.top() = match self.vars.get(v as usize) {
+                        Some(document) => Entry::Borrowed(document.root()),
+                        None => Entry::Borrowed(&NULL),
+                    };
+                    true
+                }
+                Op::StoreLocal(l) => {
+                    let v = self.pop();
+                    let l = l as usize;
+                    if l >= self.locals.len() { self.locals.resize(l + 1, Entry::Borrowed(&NULL)); }
+                    self.locals[l] = v;
+                    true
+                }
+                Op::LoadLocal(l) => {
+                    let v = self.locals.get(l as usize).cloned().unwrap_or(Entry::Borrowed(&NULL));
+                    *self.top() = v;
+                    true
+                }
+                Op::TakeLocal(l) => {
+                    let v = self.locals.get_mut(l as usize).map(|v| std::mem::replace(v, Entry::Borrowed(&NULL)));
+                    *self.top() = v.unwrap_or(Entry::Borrowed(&NULL));
+                    true
+                }
+                Op::Append(l) => {
+                    let v = self.pop();
+                    // a value borrowed from the input is copied into the array
+                    if let Some(copied) = v.borrowed() {
+                        if let Err(e) = self.charge(copied) { return self.stop(Halt::Limit(e)); }
+                    }
+                    if let Err(fault) = nest(&v, 1) {
+                        if self.fail(fault, None) { continue; }
+                        return None;
+                    }
+                    if let Some(BorrowedValue::Array(arr)) = self.locals.get_mut(l as usize).map(Entry::to_mut) {
+                        arr.push(v.into_value().into_owned());
+                    }
+                    true
+                }
+                Op::Assign(a) => {
+                    let assignment = &program.assignments[a as usize];
+                    let value = (assignment.op != AssignOp::Update).then(|| self.pop());
+                    let input = self.pop();
+                    match self.context().assign(assignment, input, value) {
+                        Ok(result) => {
+                            if let Err(e) = self.charge(&result) { return self.stop(Halt::Limit(e)); }
+                            self.stack.push(Entry::owned(result));
+                            true
+                        }
+                        Err(fault) => {
+                            // the input is gone, so the error is not located in the document
+                            self.stack.push(Entry::Borrowed(&NULL));
+                            if self.fail(fault, None) { continue; }
+                            return None;
+                        }
+                    }
+                }
+                Op::FromStream(p) => {
+                    let root = self.pop();
+                    let events = self.context().run(p, root);
+                    self.push_outputs(Outputs::Many(Box::new(FromStream::new(events))))
+                }
+                Op::TruncateStream(p) => {
+                    // jq's `.[$depth:]` takes a fractional depth down to an integer
+                    match self.pop().cast_f64().filter(|depth| *depth >= 0.0) {
+                        Some(depth) => {
+                            let events = self.context().run(p, Entry::Borrowed(&NULL));
+                            let events = events.filter_map(move |event| truncate(event, depth as usize));
+                            self.push_outputs(Outputs::Many(Box::new(events)))
+                        }
+                        None => false,
+                    }
+                }
+                Op::Collect(p) => {
+                    let root = self.pop();
+                    let mut values = Vec::new();
+                    let mut fault = None;
+                    // counted one value at a time, so a collection too large stops before it is built
+                    for v in self.context().run(p, root) {
+                        if let Err(e) = self.charge(&v) { return self.stop(Halt::Limit(e)); }
+                        if let Err(e) = nest(&v, 1) {
+                            fault = Some(e);
+                            break;
+                        }
+                        values.push(v.into_owned());
+                    }
+                    if let Some(fault) = fault {
+                        if self.fail(fault, None) { continue; }
+                        return None;
+                    }
+                    self.stack.push(Entry::owned(BorrowedValue::Array(Box::new(values))));
+                    true
+                }
+                Op::IndexBy(stream, key) => {
+                    let root = self.pop();
+                    let context = self.context();
+                    let mut index = Object::default();
+                    let mut fault = None;
+                    for row in context.run(stream, root) {
+                        let row = Entry::from(row);
+                        if let Err(e) = nest(&row, 1) {
+                            fault = Some(e);
+                            break;
+                        }
+                        for k in context.run(key, row.clone()) {
+                            if let Err(e) = self.charge(&row) { return self.stop(Halt::Limit(e)); }
+                            index.insert(Cow::Owned(value_to_string_repr(&k)), (*row).clone());
+                        }
+                    }
+                    if let Some(fault) = fault {
+                        if self.fail(fault, None) { continue; }
+                        return None;
+                    }
+                    self.stack.push(Entry::owned(BorrowedValue::Object(Box::new(index))));
+                    true
+                }
+                Op::In(source, values) => {
+                    let root = self.pop();
+                    let context = self.context();
+                    // the values are produced as far as needed, and kept by hash for the next candidate
+                    let mut values = context.run(values, root.clone());
+                    let mut seen: HashMap<u64, Vec<Value<'a>>> = HashMap::new();
+                    let found = context.run(source, root).any(|candidate| {
+                        let equal = |v: &Value<'a>| compare_values(v, &CompareOp::Eq, &candidate);
+                        if seen.get(&value_hash(&candidate)).is_some_and(|same| same.iter().any(equal)) { return true; }
+                        for v in values.by_ref() {
+                            let hit = equal(&v);
+                            seen.entry(value_hash(&v)).or_default().push(v);
+                            if hit { return true; }
+                        }
+                        false
+                    });
+                    self.stack.push(Entry::owned(BorrowedValue::Static(StaticNode::Bool(found))));
+                    true
+                }
+                Op::Join(index, stream, key, join) => {
+                    let root = self.pop();
+                    let context = self.context();
+                    let rows_of = root.clone();
+                    let (rows, keys, joins) = (context.clone(), context.clone(), context.clone());
+                    let pairs = context.run(index, root).flat_map(move |index| {
+                        let index = Arc::new(index);
+                        let keys = keys.clone();
+                        rows.run(stream, rows_of.clone()).flat_map(move |row| {
+                            let (index, row) = (Arc::clone(&index), Entry::from(row));
+                            keys.run(key, row.clone()).map(move |k| {
+                                let pair = vec![(*row).clone(), lookup(&index, &k)];
+                                Cow::Owned(BorrowedValue::Array(Box::new(pair)))
+                            })
+                        })
+                    });
+                    let joined: ValueStream<'a> = match join {
+                        Some(join) => Box::new(pairs.flat_map(move |pair| joins.run(join, Entry::from(pair)))),
+                        None => Box::new(pairs),
+                    };
+                    self.push_outputs(Outputs::Many(joined))
+                }
+                Op::Halt => return self.stop(Halt::Exit { code: 0, message: None }),
+                Op::HaltError(code) => {
+                    // strings are written as they are, anything else as a line of JSON, like jq
+                    let message = match &*self.pop() {
+                        BorrowedValue::String(s) => s.to_string(),
+                        v => to_json(v, &JsonFormat::default()) + "\n",
+                    };
+                    return self.stop(Halt::Exit { code, message: Some(message) });
+                }
+                // a sub-program halted while this run was consuming its outputs
+                Op::Output if self.halted() => {
+                    self.forks.clear();
+                    return None;
+                }
+                Op::Output => {
+                    if let Some(meter) = self.meter.filter(|_| !self.nested) {
+                        if let Err(e) = meter.output() { return self.stop(Halt::Limit(e)); }
+                    }
+                    return Some(self.pop().into_value());
+                }
+            };
+            if !ok && !self.backtrack() {
+                return None;
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Execution<'a> {
+    type Item = Value<'a>;
+
+    fn next(&mut self) -> Option<Value<'a>> {
+        if self.halted() { return None; }
+        match self.root.take() {
+            Some(root) => self.stack.push(root),
+            None => {
+                if !self.backtrack() { return None; }
+            }
+        }
+        self.execute()
+    }
+}
+
+// an error unless `value` can be put `below` levels down in a new value
+fn nest(value: &BorrowedValue, below: usize) -> Result<(), Fault> {
+    if !nests_too_deep(value, below) { return Ok(()); }
+    Err(Fault { message: format!("value nests more than {} levels deep", MAX_VALUE_DEPTH), value: truncated_json(value), at: Vec::new() })
+}
+
+// `$idx[key]` of `JOIN`, looking scalar keys up by their `tostring` as `INDEX` stores them
+fn lookup<'a>(index: &BorrowedValue<'a>, key: &BorrowedValue) -> BorrowedValue<'a> {
+    match index {
+        BorrowedValue::Object(rows) => rows.get(value_to_string_repr(key).as_str()).cloned().unwrap_or_default(),
+        _ => BorrowedValue::Static(StaticNode::Null),
+    }
+}
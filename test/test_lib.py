@@ -1,5 +1,13 @@
 import pytest
+import gzip
+import io
 import json
+import os
+import pathlib
+import pickle
+import subprocess
+import sys
+import time
 import rusty_jq
 import jq
 
@@ -250,3 +258,1651 @@ def test_from_entries_roundtrip(json_string):
     result = rusty_jq.compile('.metadata | to_entries | from_entries').first(json_string)
     assert result["source"] == "payment_gateway"
     assert result["timestamp"] == 1700000000
+
+
+# ─── Lazy evaluation: generators stop upstream work early ────────────────────
+
+@pytest.mark.parametrize("query,expected", [
+    ("limit(1; .users | .[] | .name)", ["John"]),
+    ("limit(0; .users | .[] | .name)", []),
+    ("limit(5; .users | .[] | .id)", [1, 2]),
+    ("first(.users | .[] | .name)", ["John"]),
+    ("first(.users | .[] | select(.id == 999))", []),
+    (".users | .[] | isempty(.transactions | .[])", [False, True]),
+])
+def test_lazy_generators(json_string, query, expected):
+    program = rusty_jq.compile(query)
+    assert list(program.input(json_string)) == expected
+
+
+def test_iterator_is_lazy(json_string):
+    """Results are produced one at a time as the iterator is advanced."""
+    it = rusty_jq.compile(".users | .[] | .name").input(json_string)
+    assert next(it) == "John"
+    assert next(it) == "Bob"
+    with pytest.raises(StopIteration):
+        next(it)
+    # the second result fails, so getting the first proves nothing after it was evaluated yet
+    it = rusty_jq.compile(".[] | 1 / .").input("[1, 0]")
+    assert next(it) == 1
+    with pytest.raises(rusty_jq.RustyJqRuntimeError, match="divisor is zero"):
+        next(it)
+
+
+def test_iterator_outlives_program(json_string):
+    """An iterator keeps its compiled program and document alive on its own."""
+    it = rusty_jq.compile(".users | .[] | .id").input(json_string)
+    assert list(it) == [1, 2]
+
+
+def test_first_stops_recursive_descent(json_string):
+    """first() stops the recursive descent after the first match."""
+    assert rusty_jq.compile('first(.. | select(type == "number"))').first(json_string) == 1700000000
+
+
+# ─── Bytecode VM: generators inside compound expressions ─────────────────────
+
+@pytest.mark.parametrize("query,expected", [
+    # generator inside an object value expands into one object per output
+    (".users | .[0] | {name: .name, tx: .transactions | .[] | .id}", [
+        {"name": "John", "tx": 101},
+        {"name": "John", "tx": 102},
+    ]),
+    # generator inside an arithmetic operand
+    (".users | .[0] | .transactions | .[] | .amount + 1", [501, 1201]),
+    # comma branches resume after each other
+    (".metadata | .source, .timestamp, .missing", ["payment_gateway", 1700000000]),
+    # select conditions only look at the first output of their paths
+    (".users | .[] | select(.transactions | .[] | .amount > 100) | .id", [1]),
+    (".users | .[] | select(.nonexistent | not) | .id", [1, 2]),
+])
+def test_vm_generators(json_string, query, expected):
+    assert list(rusty_jq.compile(query).input(json_string)) == expected
+
+
+@pytest.mark.parametrize("query,expected", [
+    # a value still held elsewhere is copied before it grows
+    ("[1] | (. + [2]), .", [[1, 2], [1]]),
+    ("[1] as $a | ($a + [2]), $a", [[1, 2], [1]]),
+    ('"ab" as $s | [$s + "c", $s]', [["abc", "ab"]]),
+    ("[1] | [(. + [2]), (. + [3])]", [[[1, 2], [1, 3]]]),
+    ('{"a": [1]} | (.a + [2]), .a', [[1, 2], [1]]),
+    ("[foreach (1, 2) as $x ([]; . + [$x])]", [[[1], [1, 2]]]),
+    ("[[1]] | .[0] |= . + [2]", [[[1, 2]]]),
+])
+def test_vm_shared_values(query, expected):
+    assert list(rusty_jq.compile(query).input("null")) == expected
+
+
+# ─── Optimizer: rewritten queries keep their results ─────────────────────────
+
+@pytest.mark.parametrize("query,expected", [
+    # fused path lookups, on borrowed and on constructed values
+    (".users | .[0] | .profile.location", ["Hong Kong"]),
+    (".users | .[-1] | .profile | .title", ["Manager"]),
+    (".users | .[0] | {p: .profile} | .p.location", ["Hong Kong"]),
+    (".users | .[0] | .transactions | .[5] | .id", []),
+    # identity no-ops
+    (". | .metadata | . | .source | .", ["payment_gateway"]),
+    # constant folding and hoisting out of `.[]`
+    (".users | .[] | .id * 10 + 2 * 3", [16, 26]),
+    (".users | .[] | {id: .id, tag: \"user-\" + \"row\"}", [
+        {"id": 1, "tag": "user-row"},
+        {"id": 2, "tag": "user-row"},
+    ]),
+    # fused `.[] | select(...)` with native and bytecode conditions
+    (".users | .[] | select(.id >= 2) | .name", ["Bob"]),
+    (".users | .[] | select(.profile.location == \"London\" or .id == 1) | .id", [1, 2]),
+    (".users | .[] | select(.transactions | .[0] | not) | .id", [2]),
+    (".users | .[] | select(.name | startswith(\"B\")) | .id", [2]),
+    (".metadata | .[] | select(. == 1700000000)", [1700000000]),
+])
+def test_optimized_queries(json_string, query, expected):
+    assert list(rusty_jq.compile(query).input(json_string)) == expected
+
+
+# constants that fail to evaluate are left unfolded, and raise when the query runs
+@pytest.mark.parametrize("query, expected", [
+    ("try (5 % 0) catch .", ["number (5) and number (0) cannot be divided because the divisor is zero"]),
+    ("try (1, 5 / 0, 2) catch \"caught\"", [1, "caught"]),
+    ("[.[] | . + (1 % 0)?]", [[]]),
+])
+def test_failing_constants_are_not_folded(query, expected):
+    assert list(rusty_jq.compile(query).input("[1, 2]")) == expected
+
+
+def test_failing_constant_raises_at_run_time():
+    program = rusty_jq.compile("1, 5 % 0")
+    with pytest.raises(rusty_jq.RustyJqRuntimeError, match="divisor is zero") as e:
+        list(program.input("null"))
+    assert e.value.filter == "5 % 0"
+
+
+# ─── Projection pushdown: only the parts a query touches are parsed ──────────
+
+@pytest.mark.parametrize("query,expected", [
+    # pruned containers still report their type and truthiness
+    (".users | .[0] | .profile | type", ["object"]),
+    (".users | .[] | select(.transactions) | .id", [1, 2]),
+    ("isempty(.users | .[1] | .transactions | .[0])", [True]),
+    # object values and select conditions pull in their own paths
+    (".users | .[] | {id: .id, city: .profile.location}", [
+        {"id": 1, "city": "Hong Kong"},
+        {"id": 2, "city": "London"},
+    ]),
+    (".users | .[] | select(.profile.location == \"London\") | .name", ["Bob"]),
+    (".users | first | .transactions | last | .currency", ["USD"]),
+    (".metadata | .source, .timestamp", ["payment_gateway", 1700000000]),
+    # builtins that need the whole value see it unpruned
+    (".users | .[0] | .profile | keys", [["location", "title"]]),
+    (".users | .[0] | .transactions | length", [2]),
+    (".metadata | tojson", ['{"source":"payment_gateway","timestamp":1700000000}']),
+    (".metadata | ..", [
+        {"source": "payment_gateway", "timestamp": 1700000000},
+        "payment_gateway",
+        1700000000,
+    ]),
+])
+def test_projection_pushdown(json_string, query, expected):
+    assert list(rusty_jq.compile(query).input(json_string)) == expected
+
+
+def test_projection_still_validates_input():
+    """Skipped subtrees are still checked for syntax errors."""
+    with pytest.raises(ValueError):
+        rusty_jq.compile(".a").first('{"a": 1, "b": [1, 2,]}')
+
+
+# ─── Parse-once documents ─────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("query,expected", [
+    (".metadata | .timestamp", [1700000000]),
+    (".users | .[] | .name", ["John", "Bob"]),
+    (".users | .[0] | .profile | keys", [["location", "title"]]),
+    (".missing", []),
+])
+def test_input_doc(json_string, query, expected):
+    doc = rusty_jq.parse(json_string)
+    program = rusty_jq.compile(query)
+    assert list(program.input_doc(doc)) == expected
+    assert program.first_doc(doc) == (expected[0] if expected else None)
+
+
+def test_document_reused_across_programs(json_string):
+    """One parsed document serves every program, with results matching input()."""
+    doc = rusty_jq.parse(json_string)
+    queries = [".metadata | .source", ".users | .[] | .id", ".users | length", ".. | select(type == \"number\")"]
+    for query in queries:
+        program = rusty_jq.compile(query)
+        assert list(program.input_doc(doc)) == list(program.input(json_string))
+
+
+def test_document_outlives_references(json_string):
+    """Result iterators keep the document alive after the caller drops it."""
+    doc = rusty_jq.parse(json_string)
+    results = rusty_jq.compile(".users | .[] | .name").input_doc(doc)
+    del doc
+    assert list(results) == ["John", "Bob"]
+
+
+def test_document_shared_across_threads(json_string):
+    from concurrent.futures import ThreadPoolExecutor
+    doc = rusty_jq.parse(json_string)
+    program = rusty_jq.compile(".users | .[] | .transactions | .[] | .amount")
+    with ThreadPoolExecutor(max_workers=4) as pool:
+        results = list(pool.map(lambda _: list(program.input_doc(doc)), range(32)))
+    assert results == [[500, 1200]] * 32
+
+
+def test_parse_invalid_json():
+    with pytest.raises(ValueError):
+        rusty_jq.parse('{"a": 1')
+
+
+# ─── Batch evaluation ─────────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("threads", [None, 1, 4])
+def test_input_many_keeps_order(threads):
+    docs = [json.dumps({"id": i, "tags": list(range(i % 3))}) for i in range(200)]
+    program = rusty_jq.compile(".tags | .[]")
+    assert program.input_many(docs, threads=threads) == [list(range(i % 3)) for i in range(200)]
+
+
+@pytest.mark.parametrize("threads", [None, 1, 4])
+def test_first_many_keeps_order(threads):
+    docs = [json.dumps({"id": i}) if i % 5 else "{}" for i in range(200)]
+    program = rusty_jq.compile(".id")
+    assert program.first_many(docs, threads=threads) == [i if i % 5 else None for i in range(200)]
+
+
+def test_batch_accepts_str_and_bytes(json_string):
+    program = rusty_jq.compile(".users | .[] | .name")
+    assert program.input_many([json_string, json_string.encode()]) == [["John", "Bob"]] * 2
+    assert program.first_many([json_string.encode(), json_string]) == ["John", "John"]
+    assert program.input_many([]) == []
+
+
+def test_batch_errors():
+    program = rusty_jq.compile(".a")
+    with pytest.raises(ValueError, match="input 1"):
+        program.input_many(['{"a": 1}', '{"a": '], threads=2)
+    with pytest.raises(TypeError, match="bytes-like"):
+        program.first_many(['{"a": 1}', 42])
+
+
+@pytest.mark.skipif(not os.path.isdir("/proc/self/task"), reason="counts threads through /proc")
+def test_batch_workers_are_reused():
+    """Batches share one pool of workers, whose stacks take the deepest values a run may build."""
+    program = rusty_jq.compile("reduce .[] as $x (1; [.]) | tojson | length")
+    docs = [json.dumps(list(range(2047)))] * 4
+    assert program.first_many(docs, threads=4) == [4095] * 4
+    workers = lambda: {t for t in os.listdir("/proc/self/task") if pathlib.Path(f"/proc/self/task/{t}/comm").read_text().startswith("rusty-jq-batch")}
+    started = workers()
+    for _ in range(20):
+        program.input_many(docs, threads=4)
+    assert started and workers() == started
+
+
+# ─── Bytes-like input ─────────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("wrap", [str, str.encode, lambda s: bytearray(s.encode()), lambda s: memoryview(s.encode())])
+def test_bytes_like_input(json_string, wrap):
+    program = rusty_jq.compile(".users | .[] | .name")
+    assert list(program.input(wrap(json_string))) == ["John", "Bob"]
+    assert program.first(wrap(json_string)) == "John"
+    assert list(program.input_doc(rusty_jq.parse(wrap(json_string)))) == ["John", "Bob"]
+    assert program.input_many([wrap(json_string)]) == [["John", "Bob"]]
+
+
+def test_memoryview_slice():
+    data = memoryview(b'{"a": 1}  {"a": 2}')[10:]
+    assert rusty_jq.compile(".a").first(data) == 2
+
+
+def test_inplace_bytearray():
+    """In-place parsing reuses the caller's buffer, which stays pinned while results are alive."""
+    data = bytearray(b'{"name": "caf\\u00e9", "tags": ["a", "b"]}')
+    results = rusty_jq.compile(".name, .tags").input(data, inplace=True)
+    with pytest.raises(BufferError):
+        data.extend(b" ")
+    assert list(results) == ["caf\u00e9", ["a", "b"]]
+    del results
+    data.extend(b" ")
+    assert rusty_jq.compile(".tags | .[1]").first(bytearray(b'{"tags": [1, 2]}'), inplace=True) == 2
+
+
+def test_release_inplace_document():
+    """release() lets go of a bytearray parsed in place, and the document cannot be used after it."""
+    data = bytearray(b'{"a": [1, 2]}')
+    doc = rusty_jq.parse(data, inplace=True)
+    program = rusty_jq.compile(".a | length")
+    assert program.first_doc(doc) == 2
+    with pytest.raises(BufferError):
+        data.extend(b" ")
+    doc.release()
+    data.extend(b" ")
+    doc.release()
+    for use in (lambda: program.first_doc(doc), lambda: program.input_doc(doc), lambda: rusty_jq.compile("$d").first("{}", d=doc)):
+        with pytest.raises(ValueError, match="the document was released"):
+            use()
+
+
+def test_inplace_failed_parse():
+    """A buffer that is not valid JSON may still have been rewritten, but invalid UTF-8 is caught first."""
+    data = bytearray(b'{"a": "x\\u0041", "b": tru}')
+    with pytest.raises(ValueError, match="ExpectedTrue"):
+        rusty_jq.compile(".a").first(data, inplace=True)
+    assert data.startswith(b'{"a": "xA')
+    data = bytearray(b'{"a": "x\\u0041", "b": "\xff"}')
+    with pytest.raises(UnicodeDecodeError) as e:
+        rusty_jq.compile(".a").first(data, inplace=True)
+    assert (e.value.start, bytes(data)) == (23, b'{"a": "x\\u0041", "b": "\xff"}')
+
+
+@pytest.mark.parametrize("data", [b'{"a": 1}', '{"a": 1}', memoryview(b'{"a": 1}')])
+def test_inplace_needs_writable_buffer(data):
+    with pytest.raises(TypeError, match="writable"):
+        rusty_jq.compile(".a").first(data, inplace=True)
+
+
+@pytest.mark.parametrize("data,start", [
+    (b'{"name": "\xff"}', 10),
+    (bytearray(b'["ok", "\xc3\x28"]'), 8),
+])
+def test_invalid_utf8_reports_offset(data, start):
+    with pytest.raises(UnicodeDecodeError) as err:
+        rusty_jq.compile(".name").first(data)
+    assert err.value.start == start
+    with pytest.raises(UnicodeDecodeError, match="input 1"):
+        rusty_jq.compile(".name").first_many([b"{}", data])
+
+
+def test_unsupported_input_type():
+    with pytest.raises(TypeError, match="bytes-like"):
+        rusty_jq.compile(".").first(42)
+
+
+# ─── Native Python objects ────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("query,expected", [
+    (".users | .[] | .name", ["John", "Bob"]),
+    (".users | .[0] | .transactions | .[] | select(.amount > 600) | .id", [102]),
+    (".users | .[1] | .transactions | length", [0]),
+    (".metadata | keys", [["source", "timestamp"]]),
+    (".", None),
+])
+def test_input_obj(complex_data, json_string, query, expected):
+    program = rusty_jq.compile(query)
+    expected = [complex_data] if expected is None else expected
+    assert list(program.input_obj(complex_data)) == expected
+    assert list(program.input_obj(complex_data)) == list(program.input(json_string))
+    assert program.first_obj(complex_data) == expected[0]
+
+
+def test_obj_conversions():
+    from decimal import Decimal
+    data = {
+        "tuple": (1, "two", None),
+        "flags": [True, False],
+        "big": 2 ** 64 - 1,
+        "huge": 10 ** 30,
+        "price": Decimal("12.50"),
+        "count": Decimal("3"),
+        1: "int key", None: "null key", False: "bool key", 2.5: "float key",
+    }
+    program = rusty_jq.compile(".")
+    assert program.first_obj(data) == {
+        "tuple": [1, "two", None],
+        "flags": [True, False],
+        "big": 2 ** 64 - 1,
+        "huge": 1e30,
+        "price": 12.5,
+        "count": 3,
+        "1": "int key", "null": "null key", "false": "bool key", "2.5": "float key",
+    }
+    assert rusty_jq.compile(".price * 2").first_obj(data) == 25.0
+
+
+def test_obj_default_hook():
+    import datetime
+    when = datetime.date(2024, 1, 2)
+    program = rusty_jq.compile(".when")
+    with pytest.raises(TypeError, match="not JSON serializable"):
+        program.first_obj({"when": when})
+    assert program.first_obj({"when": when}, default=lambda o: o.isoformat()) == "2024-01-02"
+    assert program.first_obj({"when": {1, 2}}, default=sorted) == [1, 2]
+
+
+def test_obj_only_converts_what_the_query_reads():
+    """Fields outside the query's projection are never converted."""
+    data = {"id": 7, "blob": object()}
+    assert rusty_jq.compile(".id").first_obj(data) == 7
+    with pytest.raises(TypeError):
+        rusty_jq.compile(".blob").first_obj(data)
+
+
+def test_obj_errors():
+    looped = {"a": 1}
+    looped["self"] = looped
+    with pytest.raises(ValueError, match="Circular reference"):
+        rusty_jq.compile(".").first_obj(looped)
+    with pytest.raises(TypeError, match="keys must be"):
+        rusty_jq.compile(".").first_obj({(1, 2): "tuple key"})
+    with pytest.raises(ValueError, match="Circular reference"):
+        rusty_jq.compile(".").first_obj({"x": object()}, default=lambda o: o)
+
+
+# ─── JSON text output ─────────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("query,kwargs,expected", [
+    (".metadata", {}, ['{"source":"payment_gateway","timestamp":1700000000}']),
+    (".users | .[] | .name", {}, ['"John"', '"Bob"']),
+    (".users | .[] | .name", {"raw": True}, ["John", "Bob"]),
+    (".users | .[1] | .transactions", {"indent": 2}, ["[]"]),
+    (".users | .[0] | .profile", {"indent": 2, "sort_keys": True}, [
+        '{\n  "location": "Hong Kong",\n  "title": "Data Engineer"\n}',
+    ]),
+    (".metadata | .timestamp", {"binary": True}, [b"1700000000"]),
+    (".users | .[0] | .name", {"raw": True, "binary": True}, [b"John"]),
+])
+def test_input_json(json_string, query, kwargs, expected):
+    assert list(rusty_jq.compile(query).input_json(json_string, **kwargs)) == expected
+
+
+@pytest.mark.parametrize("kwargs", [{}, {"indent": 4}, {"sort_keys": True}, {"indent": 2, "sort_keys": True}])
+def test_input_json_roundtrips(complex_data, json_string, kwargs):
+    (text,) = rusty_jq.compile(".").input_json(json_string, **kwargs)
+    assert json.loads(text) == complex_data
+    if kwargs.get("indent"):
+        assert text == json.dumps(complex_data, **kwargs)
+
+
+def test_input_json_escaping():
+    data = json.dumps({"k\"ey": "tab\there", "uni": "café \U0001F600"})
+    (text,) = rusty_jq.compile(".").input_json(data)
+    assert text == '{"k\\"ey":"tab\\there","uni":"café \U0001F600"}'
+    (text,) = rusty_jq.compile(".").input_json(data, ascii=True)
+    assert text == json.dumps(json.loads(data), separators=(",", ":"))
+    assert list(rusty_jq.compile(".uni").input_json(data, raw=True)) == ["café \U0001F600"]
+    assert rusty_jq.compile("tojson").first(data) == '{"k\\"ey":"tab\\there","uni":"café \U0001F600"}'
+
+
+# ─── Multi-document input ─────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("text", [
+    '{"a": 1}\n{"a": 2}\n{"a": 3}\n',
+    '{"a": 1}{"a": 2}{"a": 3}',
+    ' {"a": 1} \r\n\n {"a": 2}\t{"a": 3}',
+])
+def test_multiple_documents(text):
+    assert list(rusty_jq.compile(".a").input(text)) == [1, 2, 3]
+    assert list(rusty_jq.compile(".a").input(text.encode())) == [1, 2, 3]
+    assert rusty_jq.compile(".a").first(text) == 1
+
+
+def test_multiple_scalar_documents():
+    assert list(rusty_jq.compile(".").input('1 "two" [3] null true -4.5')) == [1, "two", [3], None, True, -4.5]
+    assert list(rusty_jq.compile(".").input("")) == []
+    assert list(rusty_jq.compile(".").input(" \n ")) == []
+
+
+@pytest.mark.parametrize("query,expected", [
+    ("input", [2, 4]),
+    (".,input", [1, 2, 3, 4]),
+    ("inputs", [2, 3, 4]),
+    ("inputs | . * 10", [20, 30, 40]),
+    ("first(inputs)", [2, 4]),
+])
+def test_input_builtins(query, expected):
+    assert list(rusty_jq.compile(query).input("1 2 3 4")) == expected
+
+
+def test_input_builtins_on_single_document():
+    assert list(rusty_jq.compile("inputs").input('{"a": 1}')) == []
+    doc = rusty_jq.parse('{"a": 1}')
+    assert list(rusty_jq.compile("., inputs").input_doc(doc)) == [{"a": 1}]
+
+
+# like jq, `input` with no documents left is an error, which `try` can catch
+def test_input_with_no_more_inputs(cli):
+    results = rusty_jq.compile("input").input("1 2 3")
+    assert next(results) == 2
+    with pytest.raises(rusty_jq.RustyJqRuntimeError, match="No more inputs") as e:
+        next(results)
+    assert e.value.filter == "input"
+    with pytest.raises(rusty_jq.RustyJqRuntimeError, match="No more inputs"):
+        rusty_jq.compile("input").input_many(['{"a": 1}'])
+    with pytest.raises(rusty_jq.RustyJqRuntimeError, match="No more inputs"):
+        rusty_jq.compile("input").first_many(["1"])
+    assert list(rusty_jq.compile('try input catch "none"').input("1")) == ["none"]
+    assert list(rusty_jq.compile("[., input]").input("1 2")) == [[1, 2]]
+    result = cli("-c", "input", input="1 2 3")
+    assert (result.returncode, result.stdout) == (5, b"2\n")
+    assert result.stderr == b"rusty-jq: error: No more inputs (in `input`)\n"
+
+
+def test_slurp():
+    assert rusty_jq.compile(".").first("1 2 3", slurp=True) == [1, 2, 3]
+    assert rusty_jq.compile(".").first('{"a": 1}', slurp=True) == [{"a": 1}]
+    assert rusty_jq.compile(".").first("", slurp=True) == []
+    assert list(rusty_jq.compile(".[] | .a").input('{"a": 1, "b": 2}\n{"a": 3}', slurp=True)) == [1, 3]
+    assert list(rusty_jq.compile("length").input_json("1 [2]", slurp=True)) == ["2"]
+    assert list(rusty_jq.compile(".").input_json("1 [2]", slurp=True)) == ["[1,[2]]"]
+
+
+def test_multiple_documents_inplace():
+    buf = bytearray(b'{"a": "x"}\n{"a": "y"}\n')
+    assert list(rusty_jq.compile(".a").input(buf, inplace=True)) == ["x", "y"]
+
+
+def test_malformed_document_in_stream():
+    """A bad document raises when iteration reaches it, and the documents after it still run."""
+    results = rusty_jq.compile(".").input('1\n{"a": }\n3\n[1,\n2]\n{"b": tru}\n5')
+    assert next(results) == 1
+    with pytest.raises(ValueError, match="line 2"):
+        next(results)
+    assert next(results) == 3
+    assert next(results) == [1, 2]
+    with pytest.raises(ValueError, match="line 6"):
+        next(results)
+    assert list(results) == [5]
+
+
+def test_malformed_document_errors():
+    with pytest.raises(ValueError, match="line 1"):
+        rusty_jq.compile(".").input('{"a": }\n1')
+    with pytest.raises(ValueError, match="line 3"):
+        list(rusty_jq.compile("inputs").input('1\n2\n{x}'))
+    with pytest.raises(ValueError, match="line 2"):
+        rusty_jq.compile(".").first("1\n[", slurp=True)
+    results = rusty_jq.compile(".").input(b'1\n"\xff"\n2')
+    assert next(results) == 1
+    with pytest.raises(UnicodeDecodeError):
+        next(results)
+    assert list(results) == [2]
+
+
+# ─── Files and readers ────────────────────────────────────────────────────────
+
+NDJSON = b'{"a": 1}\n{"a": 2}\n{"a": 3}\n'
+ZSTD_NDJSON = b'(\xb5/\xfd\x04X\x81\x00\x00{"a":1}\n{"a":2}\n\xee\xed\xac\xf2'
+
+
+@pytest.mark.parametrize("name,content,expected", [
+    ("data.ndjson", NDJSON, [1, 2, 3]),
+    ("data.json", b'{"a": [1, 2]}', [[1, 2]]),
+    ("data.ndjson.gz", gzip.compress(NDJSON), [1, 2, 3]),
+    ("data.ndjson.zst", ZSTD_NDJSON, [1, 2]),
+    ("no-extension", gzip.compress(NDJSON), [1, 2, 3]),
+    ("two-members.gz", gzip.compress(b'{"a": 1}\n') + gzip.compress(b'{"a": 2}\n'), [1, 2]),
+    ("empty.json", b"", []),
+])
+def test_input_file(tmp_path, name, content, expected):
+    path = tmp_path / name
+    path.write_bytes(content)
+    assert list(rusty_jq.compile(".a").input_file(path)) == expected
+    assert list(rusty_jq.compile(".a").input_file(str(path))) == expected
+
+
+def test_input_file_slurp(tmp_path):
+    path = tmp_path / "data.ndjson.gz"
+    path.write_bytes(gzip.compress(NDJSON))
+    assert list(rusty_jq.compile("length").input_file(path, slurp=True)) == [3]
+    assert list(rusty_jq.compile(".[] | .a").input_file(path, slurp=True)) == [1, 2, 3]
+
+
+def test_input_filename_and_line_number(tmp_path):
+    path = tmp_path / "data.ndjson"
+    path.write_bytes(b'{"a": 1}\n\n{"a":\n 2}\n3\n')
+    program = rusty_jq.compile("input_filename, input_line_number")
+    assert list(program.input_file(path)) == [str(path), 1, str(path), 4, str(path), 5]
+    assert list(program.input('{"a": 1}')) == [None, 0]
+
+
+def test_input_file_errors(tmp_path):
+    with pytest.raises(FileNotFoundError, match="missing.json"):
+        rusty_jq.compile(".").input_file(tmp_path / "missing.json")
+    path = tmp_path / "bad.ndjson"
+    path.write_bytes(b'1\n{"a": }\n3\n')
+    results = rusty_jq.compile(".").input_file(path)
+    assert next(results) == 1
+    with pytest.raises(ValueError, match="bad.ndjson, line 2"):
+        next(results)
+    assert list(results) == [3]
+    corrupt = tmp_path / "corrupt.gz"
+    corrupt.write_bytes(b"\x1f\x8b" + b"garbage" * 10)
+    with pytest.raises(OSError):
+        list(rusty_jq.compile(".").input_file(corrupt))
+
+
+@pytest.mark.parametrize("file", [
+    lambda: io.BytesIO(NDJSON),
+    lambda: io.StringIO(NDJSON.decode()),
+    lambda: io.BytesIO(gzip.compress(NDJSON)),
+    lambda: gzip.GzipFile(fileobj=io.BytesIO(gzip.compress(NDJSON))),
+])
+def test_input_reader(file):
+    assert list(rusty_jq.compile(".a").input_reader(file())) == [1, 2, 3]
+    assert list(rusty_jq.compile("length").input_reader(file(), slurp=True)) == [3]
+
+
+def test_input_reader_large_documents():
+    """Documents larger than a read chunk and many small ones are split the same way."""
+    big = json.dumps({"items": list(range(100_000)), "name": "é" * 50_000})
+    assert list(rusty_jq.compile(".items | length").input_reader(io.StringIO(big + "\n" + big))) == [100_000, 100_000]
+    many = "".join(f'{{"i": {i}}}\n' for i in range(50_000)).encode()
+    assert sum(rusty_jq.compile(".i").input_reader(io.BytesIO(many))) == sum(range(50_000))
+
+
+def test_input_reader_errors():
+    class Failing:
+        def read(self, n):
+            raise KeyError("boom")
+    with pytest.raises(KeyError, match="boom"):
+        rusty_jq.compile(".").input_reader(Failing())
+    with pytest.raises(TypeError, match="bytes-like"):
+        rusty_jq.compile(".").input_reader(type("Odd", (), {"read": lambda self, n: 42})())
+
+
+# ─── Push parsing ─────────────────────────────────────────────────────────────
+
+def test_streamer_emits_array_elements_as_they_complete():
+    streamer = rusty_jq.compile(".[] | .a").streamer()
+    assert streamer.feed(b'[{"a": 1}, {"a"') == [1]
+    assert streamer.feed(b': 2}, {"a": 3}') == [2, 3]
+    assert streamer.feed(b"]") == []
+    assert streamer.close() == []
+
+
+def test_streamer_emits_top_level_values():
+    streamer = rusty_jq.compile(".a").streamer()
+    assert streamer.feed('{"a": 1}{"a"') == [1]
+    assert streamer.feed(':2}\n{"a": 3') == [2]
+    assert streamer.feed("}") == [3]
+    assert streamer.close() == []
+    streamer = rusty_jq.compile(".").streamer()
+    assert streamer.feed("1 2 3") == [1, 2]
+    assert streamer.close() == [3]
+
+
+@pytest.mark.parametrize("query", [".[] | .id", ".[]", ".[] | select(.id > 100) | .s", "length", ".[0]", "."])
+@pytest.mark.parametrize("chunk", [1, 7, 64, 100_000])
+def test_streamer_matches_input(query, chunk):
+    data = [{"id": i, "s": 'q"]' * (i % 3), "n": [i, {"z": None}]} for i in range(300)]
+    text = json.dumps(data).encode()
+    program = rusty_jq.compile(query)
+    streamer = program.streamer()
+    results = []
+    for i in range(0, len(text), chunk):
+        results += streamer.feed(text[i:i + chunk])
+    results += streamer.close()
+    assert results == list(program.input(text))
+
+
+def test_streamer_element_mode_falls_back_for_other_values():
+    streamer = rusty_jq.compile(".[]").streamer()
+    assert streamer.feed('{"a": 1, "b": 2} [3]') == [1, 2, 3]
+    assert streamer.close() == []
+
+
+def test_streamer_errors():
+    streamer = rusty_jq.compile(".").streamer()
+    with pytest.raises(ValueError, match="line 1"):
+        streamer.feed('1 {"a": } 3')
+    assert streamer.feed(" 4") == [1, 3]
+    assert streamer.close() == [4]
+    with pytest.raises(ValueError, match="after close"):
+        streamer.feed("1")
+
+    streamer = rusty_jq.compile(".[]").streamer()
+    with pytest.raises(ValueError, match="expected ','"):
+        streamer.feed("[1 2]")
+    assert streamer.close() == [1, 2]
+
+    streamer = rusty_jq.compile(".[]").streamer()
+    assert streamer.feed("[1,") == [1]
+    with pytest.raises(ValueError, match="unterminated array"):
+        streamer.close()
+
+    streamer = rusty_jq.compile(".").streamer()
+    assert streamer.feed('{"a": [1, 2') == []
+    with pytest.raises(ValueError):
+        streamer.close()
+
+
+# ─── Streaming events ─────────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("data, expected", [
+    ({"a": [1, 2]}, [[["a", 0], 1], [["a", 1], 2], [["a", 1]], [["a"]]]),
+    ({"a": {}, "b": []}, [[["a"], {}], [["b"], []], [["b"]]]),
+    ([[None]], [[[0, 0], None], [[0, 0]], [[0]]]),
+    (3, [[[], 3]]),
+    ([], [[[], []]]),
+])
+def test_tostream(data, expected):
+    assert list(rusty_jq.compile("tostream").input(json.dumps(data))) == expected
+    assert list(rusty_jq.compile(".").input(json.dumps(data), stream=True)) == expected
+
+
+@pytest.mark.parametrize("data", [
+    {"a": [1, {"b": None}], "c": {}, "d": [], "e": "x"},
+    [[1, [2]], [], {}],
+    "s", 0, None,
+])
+def test_fromstream_round_trips(data):
+    assert rusty_jq.compile("fromstream(tostream)").first(json.dumps(data)) == data
+    assert list(rusty_jq.compile("fromstream(inputs)").input(json.dumps(data), stream=True, null_input=True)) == [data]
+
+
+# events read back from text, as saved `--stream` output is, have unsigned indices
+@pytest.mark.parametrize("events, expected", [
+    ("[[0],1] [[0]]", [[1]]),
+    ('[[0,"a"],1] [[0,"a"]] [[1],2] [[1]]', [[{"a": 1}, 2]]),
+    ('[["a",1],"x"] [["a",1]] [["a"]]', [{"a": [None, "x"]}]),
+])
+def test_fromstream_of_parsed_events(cli, events, expected):
+    assert list(rusty_jq.compile("fromstream(inputs)").input(events, null_input=True)) == expected
+    assert rusty_jq.compile("[fromstream(.[])]").first("[" + events.replace("] [", "], [") + "]") == expected
+    result = cli("-n", "-c", "fromstream(inputs)", input=events)
+    assert result.stdout.decode().splitlines() == [json.dumps(v, separators=(",", ":")) for v in expected]
+
+
+def test_truncate_stream_of_parsed_events(cli):
+    """The request's example on saved events: `1 |` throws `.` away, but the documents are read whole."""
+    events = '[[0,"a"],1] [[0,"a"]] [[1,0],2] [[1,0]] [[1]]'
+    program = rusty_jq.compile("fromstream(1|truncate_stream(inputs))")
+    assert list(program.input(events, null_input=True)) == [{"a": 1}, [2]]
+    assert cli("-n", "-c", "fromstream(1|truncate_stream(inputs))", input=events).stdout == b'{"a":1}\n[2]\n'
+    assert list(rusty_jq.compile("1 | [inputs]").input("[1] [2]", null_input=True)) == [[[1], [2]]]
+
+
+def test_truncate_stream_yields_elements():
+    data = [{"id": i, "tags": ["x"] * i} for i in range(50)]
+    program = rusty_jq.compile("fromstream(1|truncate_stream(inputs))")
+    assert list(program.input(json.dumps(data), stream=True, null_input=True)) == data
+    # a scalar at the top of the truncated depth has no path left, as in jq
+    assert list(program.input('[{"a": 1}, 2, [3]]', stream=True, null_input=True)) == [{"a": 1}, [3]]
+    events = rusty_jq.compile("1|truncate_stream(inputs)").input('{"a": [1]}', stream=True, null_input=True)
+    assert list(events) == [[[0], 1], [[0]]]
+
+
+def test_stream_input_decodes_leaves():
+    text = r'["a\"b", "é", "\u00e9", -12, 1.5, 1e3, 12345678901234567890, true, null]'
+    assert list(rusty_jq.compile("fromstream(inputs)").input(text, stream=True, null_input=True)) == [json.loads(text)]
+
+
+def test_stream_input_from_files(tmp_path):
+    data = [{"id": i, "v": [i] * 3} for i in range(1000)]
+    path = tmp_path / "data.json"
+    path.write_text(json.dumps(data))
+    gz = tmp_path / "data.json.gz"
+    gz.write_bytes(gzip.compress(path.read_bytes()))
+    program = rusty_jq.compile("fromstream(1|truncate_stream(inputs)) | .id")
+    assert list(program.input_file(path, stream=True, null_input=True)) == list(range(1000))
+    assert list(program.input_file(gz, stream=True, null_input=True)) == list(range(1000))
+    with open(path, "rb") as f:
+        assert list(program.input_reader(f, stream=True, null_input=True)) == list(range(1000))
+    bad = tmp_path / "bad.json"
+    bad.write_text('[1,\n{"a" 2}]')
+    with pytest.raises(ValueError, match="bad.json, line 2: unexpected token"):
+        list(rusty_jq.compile(".").input_file(bad, stream=True))
+
+
+def test_null_input():
+    assert list(rusty_jq.compile(".").input("1 2", null_input=True)) == [None]
+    assert list(rusty_jq.compile("input").input("1 2", null_input=True)) == [1]
+    assert list(rusty_jq.compile("inputs").input("1 2", null_input=True)) == [1, 2]
+    assert rusty_jq.compile("input").first("[1, 2]", slurp=True, null_input=True) == [[1, 2]]
+
+
+@pytest.mark.parametrize("text, message", [
+    ("[1, 2 3]", "unexpected token"),
+    ('{"a" 1}', "unexpected token"),
+    ("[1}", "mismatched closing bracket"),
+    ("[1, tru]", "line 1"),
+    ('[1,\n{"a": ', "unfinished value"),
+])
+def test_stream_input_errors(text, message):
+    with pytest.raises(ValueError, match=message):
+        list(rusty_jq.compile(".").input(text, stream=True))
+
+
+# ─── Variables ────────────────────────────────────────────────────────────────
+
+def test_variables_bound_per_call(json_string):
+    program = rusty_jq.compile(".users | .[] | select(.id == $uid) | .name", args=["uid"])
+    assert list(program.input(json_string, uid=1)) == ["John"]
+    assert list(program.input(json_string, uid=2)) == ["Bob"]
+    assert program.first(json_string, uid=99) is None
+
+
+def test_undeclared_variables_bound_per_call(json_string):
+    doc = json.dumps({"users": [{"id": 5, "name": "Eve"}, {"id": 6, "name": "Dan"}]})
+    assert list(rusty_jq.compile('.users[] | select(.id == $uid)').input(doc, uid=5)) == [{"id": 5, "name": "Eve"}]
+    program = rusty_jq.compile(".users | .[] | select(.id == $uid) | .name + $suffix", args={"suffix": "!"})
+    assert program.first(json_string, uid=2) == "Bob!"
+    assert pickle.loads(pickle.dumps(program)).first(json_string, uid=1, suffix="?") == "John?"
+
+
+@pytest.mark.parametrize("query, expected", [
+    (".a + $n", 11),
+    ("{v: $n, w: .a}", {"v": 10, "w": 1}),
+    ("$n | tostring", "10"),
+    ("$items | length", 2),
+    ("$items | .[1] | .k", None),
+    ("1 + $n", 11),
+])
+def test_variables_in_expressions(query, expected):
+    program = rusty_jq.compile(query, args={"n": 10, "items": [1, {"k": None}]})
+    assert program.first('{"a": 1}') == expected
+
+
+def test_variable_defaults_and_overrides():
+    program = rusty_jq.compile(".[] | select(. > $min)", args={"min": 1})
+    assert list(program.input("[1, 2, 3]")) == [2, 3]
+    assert list(program.input("[1, 2, 3]", min=2)) == [3]
+    assert program.input_many(["[1, 2, 3]", "[0, 5]"], min=2) == [[3], [5]]
+    assert program.first_many(["[1, 2, 3]", "[0]"]) == [2, None]
+    assert program.first_obj([0, 4], min=3) == 4
+    assert list(program.input_doc(rusty_jq.parse("[1, 2]"))) == [2]
+
+
+def test_args_object():
+    program = rusty_jq.compile("$ARGS", args={"x": 1, "y": [1, {"z": None}]}, positional=[1, "two"])
+    assert program.first("null") == {"positional": [1, "two"], "named": {"x": 1, "y": [1, {"z": None}]}}
+    assert program.first("null", x=9)["named"] == {"x": 9, "y": [1, {"z": None}]}
+    assert rusty_jq.compile("$ARGS").first("null") == {"positional": [], "named": {}}
+    assert rusty_jq.compile("$__prog_args", args={"a": "b"}).first("null") == {"a": "b"}
+    assert rusty_jq.compile("$ARGS.named.a", args=["a"]).first("null", a=[1]) == [1]
+
+
+def test_variables_in_streamer_and_generators():
+    streamer = rusty_jq.compile(".[] | select(.n > $n) | .n", args=["n"]).streamer(n=1)
+    assert streamer.feed('[{"n": 1}, {"n": 2}, {"n": 3}]') == [2, 3]
+    assert streamer.close() == []
+    program = rusty_jq.compile("first(.[] | select(. == $x))", args=["x"])
+    assert program.first("[1, 2, 3]", x=2) == 2
+    program = rusty_jq.compile("fromstream(inputs | select(.[0] | .[0] == $key))", args=["key"])
+    assert program.first('[1, 2]', stream=True, null_input=True, key=0) is None
+
+
+@pytest.mark.parametrize("compile_kwargs, call_kwargs, error, match", [
+    ({}, {}, TypeError, r"missing value for variable \$x"),
+    ({"args": ["x"]}, {}, TypeError, r"missing value for variable \$x"),
+    ({"args": ["x"]}, {"x": 1, "y": 2}, TypeError, r"unknown variable \$y"),
+    ({"args": "x"}, {}, TypeError, "not str"),
+    ({"args": ["a-b"]}, {}, ValueError, "invalid variable name"),
+    ({"args": ["ARGS"]}, {}, ValueError, "predefined"),
+    ({"args": ["x"]}, {"x": object()}, TypeError, "not JSON serializable"),
+])
+def test_variable_errors(compile_kwargs, call_kwargs, error, match):
+    with pytest.raises(error, match=match):
+        rusty_jq.compile("$x", **compile_kwargs).first("null", **call_kwargs)
+
+
+# ─── Lookup joins ─────────────────────────────────────────────────────────────
+
+ROWS = '[{"id": 1, "name": "a"}, {"id": 2, "name": "b"}, {"id": 2, "name": "c"}]'
+
+
+@pytest.mark.parametrize("query, expected", [
+    ("INDEX(.id)", {"1": {"id": 1, "name": "a"}, "2": {"id": 2, "name": "c"}}),
+    ("INDEX(.[]; .name) | keys", ["a", "b", "c"]),
+    ("INDEX(.[] | select(.id > 1); .name) | keys", ["b", "c"]),
+    ("INDEX(.[]; .id, .name) | length", 5),
+    ("INDEX(.[]; empty)", {}),
+    (".[] | .id | IN(2, 3)", [False, True, True]),
+    ("IN(.[] | .id; 5, 1)", True),
+    ("IN(.[] | .id; 5, 7)", False),
+    ("IN(.[]; empty)", False),
+    # arrays and objects are members by value, whatever the order of their keys
+    ("[.[] | [.id] | IN([1], [3])]", [True, False, False]),
+    ('[.[] | IN({"name": "b", "id": 2.0}, [1])]', [False, True, False]),
+    ('[.[] | .id | IN("1", -0.0, 2)]', [False, True, True]),
+])
+def test_index_and_in(query, expected):
+    results = list(rusty_jq.compile(query).input(ROWS))
+    assert (results if len(results) > 1 else results[0]) == expected
+
+
+@pytest.mark.parametrize("query, expected", [
+    ("JOIN($idx; .id)", [[[{"id": 1, "name": "a"}, "x"], [{"id": 2, "name": "b"}, None], [{"id": 2, "name": "c"}, None]]]),
+    ("JOIN($idx; .[]; .id)", [[{"id": 1, "name": "a"}, "x"], [{"id": 2, "name": "b"}, None], [{"id": 2, "name": "c"}, None]]),
+    ("JOIN($idx; .[]; .name; .[1])", [None, "y", None]),
+    ("JOIN($idx; .[] | select(.id == 1); .id; .[0] | .name, .id)", ["a", 1]),
+    ("JOIN(INDEX(.name); .[]; .name; .[1] | .id)", [1, 2, 2]),
+])
+def test_join(query, expected):
+    program = rusty_jq.compile(query, args={"idx": {"1": "x", "b": "y"}})
+    assert list(program.input(ROWS)) == expected
+
+
+def test_documents_bound_to_variables(tmp_path):
+    products = tmp_path / "products.json"
+    products.write_text('[{"sku": "a1", "price": 9.5}, {"sku": "b2", "price": 12.0}]')
+    program = rusty_jq.compile(
+        "JOIN(INDEX($products | .[]; .sku); .items | .[]; .sku; .[1] | .price)", args=["products"]
+    )
+    order = '{"items": [{"sku": "b2"}, {"sku": "zz"}, {"sku": "a1"}]}'
+    # "zz" pairs with null, which has no `.price`
+    assert list(program.input(order, products=rusty_jq.parse_file(products))) == [12.0, 9.5]
+    assert list(program.input(order, products=rusty_jq.parse(products.read_text()))) == [12.0, 9.5]
+    # a document default is shared by every run, and by $ARGS
+    program = rusty_jq.compile("$ARGS", args={"d": rusty_jq.parse("[1]")}, positional=[rusty_jq.parse('{"a": 2}')])
+    assert program.first("null") == {"positional": [{"a": 2}], "named": {"d": [1]}}
+    assert program.input_many(["null"] * 3, threads=2) == [[{"positional": [{"a": 2}], "named": {"d": [1]}}]] * 3
+
+
+def test_parse_file_documents(tmp_path):
+    many = tmp_path / "many.json"
+    many.write_text('{"a": 1}\n{"a": 2}\n')
+    with pytest.raises(ValueError, match="expected one document; pass slurp=True"):
+        rusty_jq.parse_file(many)
+    assert rusty_jq.compile(".[] | .a").input_doc(rusty_jq.parse_file(many, slurp=True)).__next__() == 1
+    empty = tmp_path / "empty.json"
+    empty.write_text("")
+    with pytest.raises(ValueError, match="no JSON document"):
+        rusty_jq.parse_file(empty)
+    assert rusty_jq.compile(".").first_doc(rusty_jq.parse_file(empty, slurp=True)) == []
+
+
+def test_join_scales_with_hashing():
+    rows = json.dumps([{"id": i, "v": i * 2} for i in range(50_000)])
+    idx = rusty_jq.parse(rows)
+    program = rusty_jq.compile("JOIN(INDEX($rows | .[]; .id); .[]; .id; .[1] | .v)", args=["rows"])
+    results = list(program.input(rows, rows=idx))
+    assert len(results) == 50_000 and results[-1] == 99_998
+
+
+# ─── Halting ──────────────────────────────────────────────────────────────────
+
+def test_halt_stops_every_document():
+    assert list(rusty_jq.compile("., halt").input("1 2 3")) == [1]
+    assert rusty_jq.compile("select(. == 2) | halt").first("2") is None
+    assert rusty_jq.compile("1, halt, 2").input_many(["null", "null"]) == [[1], [1]]
+
+
+@pytest.mark.parametrize("query, message", [
+    ('"stop\\n" | halt_error', "stop"),
+    ('{a: 1} | halt_error(1)', '{"a":1}'),
+])
+def test_halt_error_raises(query, message):
+    with pytest.raises(ValueError, match=message):
+        list(rusty_jq.compile(query).input("null"))
+    with pytest.raises(ValueError, match=message):
+        rusty_jq.compile(query).first_many(["null"])
+
+
+# ─── Command-line tool ────────────────────────────────────────────────────────
+
+@pytest.fixture
+def cli():
+    # built by `cargo build`; RUSTY_JQ_BIN points elsewhere
+    path = os.environ.get("RUSTY_JQ_BIN") or pathlib.Path(__file__).parent.parent / "target" / "debug" / "rusty-jq"
+    if not os.path.exists(path):
+        pytest.skip("the rusty-jq binary is not built")
+
+    def run(*args, input=""):
+        return subprocess.run([str(path), *args], input=input.encode(), capture_output=True)
+    return run
+
+
+@pytest.mark.parametrize("args, input, expected", [
+    ([".a"], '{"a": [1, "x"]}', '[\n  1,\n  "x"\n]\n'),
+    (["-c", "."], '{"a": [1, "x"]} 2', '{"a":[1,"x"]}\n2\n'),
+    (["-r", ".[]"], '["x", 1, null]', 'x\n1\nnull\n'),
+    (["-j", ".[]"], '["x", 1, "y"]', 'x1y'),
+    (["-rc", ".[]"], '["x", {"b": 2}]', 'x\n{"b":2}\n'),
+    (["-n", "inputs"], '1 2 3', '1\n2\n3\n'),
+    (["-n", "input"], '1 2', '1\n'),
+    (["-s", "-c", "."], '1 2', '[1,2]\n'),
+    (["-S", "-c", "."], '{"b": 1, "a": 2}', '{"a":2,"b":1}\n'),
+    (["--tab", "."], '{"a": [1]}', '{\n\t"a": [\n\t\t1\n\t]\n}\n'),
+    (["--indent", "1", "."], '{"a": 1}', '{\n "a": 1\n}\n'),
+    (["--indent", "0", "."], '{"a": 1}', '{"a":1}\n'),
+    (["--seq", "-c", ".[]"], '[1, [2]]', '\x1e1\n\x1e[2]\n'),
+    (["-c", "--stream", "."], '{"a": 1}', '[["a"],1]\n[["a"]]\n'),
+    (["--arg", "x", "5", "-c", "{x: $x, named: $ARGS.named}"], 'null', '{"x":"5","named":{"x":"5"}}\n'),
+    (["--argjson", "x", '{"y": 5}', "$x.y"], 'null', '5\n'),
+    (["-n", "1, halt, 2"], '', '1\n'),
+])
+def test_cli_output(cli, args, input, expected):
+    result = cli(*args, input=input)
+    assert result.returncode == 0, result.stderr
+    assert result.stdout.decode() == expected
+
+
+@pytest.mark.parametrize("args, input, status", [
+    (["-e", ".a"], '{"a": true}', 0),
+    (["-e", ".a"], '{"a": false}', 1),
+    (["-e", "."], 'null', 1),
+    (["-e", "empty"], '1', 4),
+    ([".[]"], '{"a": 1', 2),
+    (["--indent", "8", "."], '1', 2),
+    (["--bogus", "."], '1', 2),
+    (["--arg", "x"], '1', 2),
+    ([], '1', 2),
+    (["-c"], '1', 2),
+    ([".a |"], '1', 3),
+    (["$nope"], '1', 3),
+    (["-n", '"boom\\n" | halt_error(6)'], '', 6),
+])
+def test_cli_exit_status(cli, args, input, status):
+    assert cli(*args, input=input).returncode == status
+
+
+def test_cli_without_filter_prints_usage(cli):
+    result = cli("-c", input="1")
+    assert (result.returncode, result.stdout) == (2, b"")
+    assert result.stderr.startswith(b"Usage: rusty-jq [OPTIONS] FILTER")
+
+
+def test_cli_halt_error_writes_stderr(cli):
+    # strings go out as they are, anything else as JSON with a newline
+    result = cli("-n", '"boom\\n" | halt_error')
+    assert (result.returncode, result.stdout, result.stderr) == (5, b"", b"boom\n")
+    result = cli("-n", '{a: 1} | halt_error(1)')
+    assert (result.returncode, result.stderr) == (1, b'{"a":1}\n')
+
+
+def test_cli_files(cli, tmp_path):
+    (tmp_path / "a.json").write_text('{"n": 1}')
+    with gzip.open(tmp_path / "b.json.gz", "wt") as f:
+        f.write('{"n": 2} {"n": 3}')
+    (tmp_path / "query.jq").write_text("{n: .n, raw: $raw, all: $all}")
+    (tmp_path / "raw.txt").write_text("text")
+    result = cli("-c", "--rawfile", "raw", str(tmp_path / "raw.txt"), "--slurpfile", "all", str(tmp_path / "a.json"),
+                 "-f", str(tmp_path / "query.jq"), str(tmp_path / "a.json"), str(tmp_path / "b.json.gz"))
+    assert [json.loads(line) for line in result.stdout.splitlines()] == [
+        {"n": n, "raw": "text", "all": [{"n": 1}]} for n in (1, 2, 3)
+    ]
+    result = cli("input_filename", str(tmp_path / "a.json"))
+    assert result.stdout.decode() == json.dumps(str(tmp_path / "a.json")) + "\n"
+    result = cli(".", str(tmp_path / "missing.json"))
+    assert result.returncode == 2 and b"could not open" in result.stderr
+
+
+def test_cli_continues_after_bad_document(cli):
+    result = cli("-c", ".a", input='{"a": 1}\n{"a": \n{"a": 3}')
+    assert result.returncode == 2 and b"line 2" in result.stderr
+
+
+# ─── Syntax errors ────────────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("query, line, column, expected", [
+    (".a |", 1, 5, ["a filter"]),
+    (".a | lenght", 1, 6, ["a filter"]),
+    ("{a: .b", 1, 7, ["an operator", "`,`", "`}`"]),
+    ("has(.x)", 1, 5, ["a literal"]),
+    ('"abc', 1, 5, ['`"`']),
+    ("select(.a == 1 adn .b)", 1, 16, ["an operator", "`)`"]),
+    (".a |\n  .b | .c )", 2, 11, ["an operator"]),
+    ("if . then 1 els 2 end", 1, 13, ["an operator", "`elif`", "`else`", "`end`"]),
+    ("reduce .[] as $x (0)", 1, 20, ["an operator", "`;`"]),
+    ("try", 1, 4, ["a filter"]),
+    ('"é" | $', 1, 8, ["a variable name"]),
+    # `not` is a filter of its own, as in jq, rather than a prefix operator
+    ("select(not .a)", 1, 12, ["an operator", "`)`"]),
+    (".a and not .b", 1, 12, ["an operator"]),
+])
+def test_syntax_error_position(query, line, column, expected):
+    with pytest.raises(rusty_jq.RustyJqSyntaxError) as e:
+        rusty_jq.compile(query)
+    assert (e.value.line, e.value.column, e.value.expected) == (line, column, expected)
+    # offsets count characters, like Python string indices
+    lines = query.split("\n")
+    assert e.value.offset == sum(len(l) + 1 for l in lines[:line - 1]) + column - 1
+
+
+@pytest.mark.parametrize("query, hint", [
+    (".a | lenght", "unknown function `lenght`, did you mean `length`?"),
+    ("tojsno", "unknown function `tojsno`, did you mean `tojson`?"),
+    ("mapp(.x)", "unknown function `mapp`"),
+    ("select(.a == 1 adn .b)", "did you mean `and`?"),
+    ("if . then 1 els 2 end", "did you mean `else`?"),
+    (".a )", None),
+])
+def test_syntax_error_hint(query, hint):
+    with pytest.raises(rusty_jq.RustyJqSyntaxError) as e:
+        rusty_jq.compile(query)
+    assert e.value.hint == hint
+
+
+def test_syntax_error_message():
+    with pytest.raises(ValueError) as e:
+        rusty_jq.compile('.users | .[] | select(.id == 1 adn .x)')
+    assert str(e.value) == (
+        "unexpected `adn`, expected one of an operator, `)` at line 1, column 32\n"
+        "    .users | .[] | select(.id == 1 adn .x)\n"
+        "                                   ^\n"
+        "hint: did you mean `and`?"
+    )
+    with pytest.raises(rusty_jq.RustyJqSyntaxError, match="unexpected `==`, comparisons and assignments cannot be chained"):
+        rusty_jq.compile(".a == 1 == 2")
+    with pytest.raises(rusty_jq.RustyJqSyntaxError, match="invalid escape sequence in string at line 1, column 3"):
+        rusty_jq.compile('"a\\q"')
+
+
+# ─── Runtime errors ───────────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("query, doc, message, filter, value, path", [
+    (".items | .[] | .price * 2", '{"items": [{"price": 1}, {"price": "x"}]}',
+     'string ("x") and number (2) cannot be multiplied', ".price * 2", '"x"', ["items", 1, "price"]),
+    ("1 + .a", '{"a": [1]}', "number (1) and array ([1]) cannot be added", "1 + .a", "[1]", ["a"]),
+    ("{x: .a - 1}", '{"a": {}}', "object ({}) and number (1) cannot be subtracted", ".a - 1", "{}", ["a"]),
+    ('.a | tostring | . / 2', '{"a": 1}', 'string ("1") and number (2) cannot be divided', ". / 2", '"1"', None),
+    (".a % .b", '{"a": 5, "b": 0}', "number (5) and number (0) cannot be divided because the divisor is zero", ".a % .b", "0", ["b"]),
+    (".a / .b", '{"a": 5, "b": 0}', "number (5) and number (0) cannot be divided because the divisor is zero", ".a / .b", "0", ["b"]),
+    (".cp | implode", '{"cp": [65, -1]}', "number (-1) is not a valid codepoint to implode", "implode", "-1", ["cp", 1]),
+    (".cp | implode", '{"cp": "A"}', 'implode input must be an array, not string ("A")', "implode", '"A"', ["cp"]),
+])
+def test_runtime_error(query, doc, message, filter, value, path):
+    with pytest.raises(rusty_jq.RustyJqRuntimeError) as e:
+        list(rusty_jq.compile(query).input(doc))
+    assert str(e.value).startswith(message)
+    assert (e.value.filter, e.value.value, e.value.path) == (filter, value, path)
+    start, end = e.value.span
+    assert query[start:end] == filter
+
+
+def test_integer_overflow_falls_back_to_floats():
+    doc = json.dumps({"max": 2 ** 63 - 1, "min": -(2 ** 63)})
+    query = ".max + 1, .max * 2, .min - 1, .min % -1, .max % 10"
+    assert list(rusty_jq.compile(query).input(doc)) == [2.0 ** 63, 2.0 ** 64, -(2.0 ** 63), 0, 7]
+
+
+def test_runtime_error_message():
+    with pytest.raises(ValueError) as e:
+        rusty_jq.compile(".a + 1").first('{"a": "' + "x" * 40 + '"}')
+    # long values are cut short
+    assert str(e.value) == 'string ("' + "x" * 29 + '...) and number (1) cannot be added (in `.a + 1`, at .a)'
+    assert e.value.value == '"' + "x" * 29 + "..."
+
+
+def test_runtime_error_span_counts_characters():
+    query = 'select(.n != "é") | .a + 1'
+    with pytest.raises(rusty_jq.RustyJqRuntimeError) as e:
+        rusty_jq.compile(query).first('{"a": "s", "n": 1}')
+    start, end = e.value.span
+    assert query[start:end] == ".a + 1"
+
+
+def test_runtime_error_ends_only_its_document():
+    program = rusty_jq.compile(".a + 1")
+    results = program.input('{"a": 1} {"a": "x"} {"a": 3}')
+    assert next(results) == 2
+    with pytest.raises(rusty_jq.RustyJqRuntimeError):
+        next(results)
+    assert list(results) == [4]
+    with pytest.raises(rusty_jq.RustyJqRuntimeError, match="at .a"):
+        program.input_many(['{"a": 1}', '{"a": "x"}'])
+    streamer = program.streamer()
+    with pytest.raises(rusty_jq.RustyJqRuntimeError):
+        streamer.feed('{"a": 1} {"a": "x"} ')
+    assert streamer.feed('{"a": 5}') == [2, 6]
+
+
+def test_cli_runtime_error(cli):
+    result = cli("-c", ".a + 1", input='{"a": 1} {"a": "x"} {"a": 3}')
+    assert (result.returncode, result.stdout) == (5, b"2\n4\n")
+    assert result.stderr == b'rusty-jq: error: string ("x") and number (1) cannot be added (in `.a + 1`, at .a)\n'
+
+
+# ─── AST and printing ─────────────────────────────────────────────────────────
+
+@pytest.mark.parametrize("query, canonical", [
+    (".users|.[]|select(.id==2)|{name:.name}", ".users | .[] | select(.id == 2) | {name: .name}"),
+    (".a.b|.[0:2],  .c.[0]", ".a | .b | .[0:2], .c.[0]"),
+    (".[].price*2 + .tax", ".[].price * 2 + .tax"),
+    ("select(.a==1 and (.b or .c|not))", "select(.a == 1 and (.b or .c | not))"),
+    ("select(.a == 1|not)", "select(.a == 1 | not)"),
+    ("INDEX(.id) | IN(1, 2)", "INDEX(.[]; .id) | IN(1, 2)"),
+    ("halt_error(5)", "halt_error"),
+    ('has("a\\u00e9\\t")', 'has("aé\\t")'),
+    ("100000000000000000000000.0 | 1", "100000000000000000000000.0 | 1"),
+])
+def test_to_jq(query, canonical):
+    assert rusty_jq.compile(query).to_jq() == canonical
+
+
+@pytest.mark.parametrize("query", [
+    ".", "..", '."a-b".c', ".a-1", ".[-1]", ".[:3], .[2:]", ".[]length", "$uid", '"x" + .a', "null, true, -1.5",
+    "{a: .x | .y, b: 1}", ".a - 1 - 2", ".a * 2 + 3 * .b % 4", ".a / 2, keys_unsorted | tojson",
+    "select(.a != .b | not)", "select(.a and .b or .c and (.d | not))", "select((.a or .b) and .c)",
+    "select(.a | startswith(\"J\")) | has(\"k\")", "limit(2; .[]), first(.[]), isempty(empty)",
+    "fromstream(tostream), truncate_stream(.[] | tostream)", "INDEX(.users | .[]; .id)",
+    "IN(.[]; 1, 2)", "JOIN($idx; .[]; .id; add)", "JOIN($idx; .id)", "input, inputs",
+    "input_filename, input_line_number", "halt", "halt_error(1)", "flatten(2), index(\"a\")",
+    "-(.a + 1), -.b * 2", "(1, 2) // 3 // null", ".a = 1 | .b |= . + 1, .c += 2, .d //= 3",
+    "if .a then 1 elif .b then 2 else 3 end", "try .a catch .b | .c", "(.a, .b)?", ".a?.b",
+    "reduce .[] as $x (0; . + $x) | . * 2", "foreach .[] as $x (0; . + 1; [$x, .])",
+    ". as $x | [$x, 1], []", "{if: .and, \"a b\": {}}", ".end, .[\"a b\"]",
+])
+def test_to_jq_round_trip(query):
+    program = rusty_jq.compile(query, args=["uid", "idx"])
+    printed = rusty_jq.compile(program.to_jq(), args=["uid", "idx"])
+    assert printed.ast() == program.ast()
+    assert printed.to_jq() == program.to_jq()
+
+
+def test_ast():
+    program = rusty_jq.compile(".users | .[] | select(.id == 2) | {name: .name + 1}")
+    assert program.ast() == [
+        {"type": "field", "name": "users"},
+        {"type": "iterator"},
+        {"type": "select", "condition": {
+            "type": "compare", "op": "==",
+            "left": [{"type": "field", "name": "id"}],
+            "right": [{"type": "literal", "value": 2}],
+        }},
+        {"type": "object", "fields": [{"key": "name", "value": [{
+            "type": "arithmetic", "op": "+",
+            "left": [{"type": "field", "name": "name"}],
+            "right": [{"type": "literal", "value": 1}],
+        }]}]},
+    ]
+    # calls carry their arguments as pipelines, literal ones included
+    assert rusty_jq.compile("limit(2; .[])").ast() == [{"type": "call", "name": "limit", "args": [
+        [{"type": "literal", "value": 2}], [{"type": "iterator"}],
+    ]}]
+    # the query as written, not as the optimizer rewrote it
+    assert rusty_jq.compile(".a.b").ast() == rusty_jq.compile(".a | .b").ast()
+
+
+def test_program_repr():
+    assert repr(rusty_jq.compile(".a |\n.b")) == "<RustyProgram '.a |\\n.b'>"
+
+
+# ─── Pickling ─────────────────────────────────────────────────────────────────
+
+def test_pickle_program():
+    program = rusty_jq.compile(".[] | select(.id == $uid) | {name: .name, args: $ARGS}", args={"uid": 2}, positional=[1.5, "x"])
+    loaded = pickle.loads(pickle.dumps(program))
+    data = '[{"id": 1, "name": "John"}, {"id": 2, "name": "Bob"}]'
+    assert list(loaded.input(data)) == list(program.input(data)) == [
+        {"name": "Bob", "args": {"positional": [1.5, "x"], "named": {"uid": 2}}},
+    ]
+    assert loaded.first(data, uid=1)["name"] == "John"
+    assert (repr(loaded), loaded.to_jq(), loaded.ast()) == (repr(program), program.to_jq(), program.ast())
+    with pytest.raises(TypeError, match="unknown variable"):
+        loaded.first(data, other=1)
+
+
+def test_load_program_bytes():
+    program = rusty_jq.compile(".a | tostring")
+    data = program.to_bytes()
+    assert data.startswith(b"RJQP") and rusty_jq.load(data).first('{"a": 1}') == "1"
+    with pytest.raises(ValueError, match="truncated serialized program"):
+        rusty_jq.load(data[:-1])
+    with pytest.raises(ValueError, match="corrupt serialized program"):
+        rusty_jq.load(data + b"x")
+    # a query that no longer parses, from crafted bytes or another grammar
+    with pytest.raises(ValueError, match="corrupt serialized program: its query does not parse"):
+        rusty_jq.load(data.replace(b".a | tostring", b".a | tostr)ng"))
+    # loaded in a fresh interpreter, as multiprocessing and cluster workers do
+    script = "import pickle, sys, rusty_jq; print(pickle.loads(sys.stdin.buffer.read()).first('{\"a\": [2]}'))"
+    result = subprocess.run([sys.executable, "-c", script], input=pickle.dumps(program), capture_output=True, check=True)
+    assert result.stdout == b"[2]\n"
+
+
+@pytest.mark.parametrize("data, message", [
+    (b"", "not a serialized rusty-jq program"),
+    (b'{"a": 1}', "not a serialized rusty-jq program"),
+    (b"RJQP\x01", "truncated serialized program"),
+    (b"RJQP\x63\x00", "program serialized in format version 99, but this version of rusty-jq reads version 4"),
+])
+def test_load_rejects(data, message):
+    with pytest.raises(ValueError, match=message):
+        rusty_jq.load(data)
+
+
+# ─── Compile cache ────────────────────────────────────────────────────────────
+
+@pytest.fixture
+def program_cache():
+    rusty_jq.cache_clear()
+    yield
+    rusty_jq.set_cache_size(256)
+    rusty_jq.cache_clear()
+
+
+def test_cache_returns_compiled_program(program_cache):
+    program = rusty_jq.compile(".a")
+    assert rusty_jq.compile(".a") is program
+    assert rusty_jq.compile(".b") is not program
+    assert rusty_jq.cache_info() == {"hits": 1, "misses": 2, "maxsize": 256, "currsize": 2}
+    rusty_jq.cache_clear()
+    assert rusty_jq.cache_info() == {"hits": 0, "misses": 0, "maxsize": 256, "currsize": 0}
+    assert rusty_jq.compile(".a") is not program
+
+
+@pytest.mark.parametrize("first, second", [
+    ({"args": {"x": 1}}, {"args": {"x": 2}}),
+    ({"args": {"x": 1}}, {"args": {"x": 1.0}}),
+    ({"args": {"x": 1}}, {"args": {"y": 1}}),
+    ({"args": {"x": 1}}, {"args": ["x"]}),
+    ({"args": {"x": {"a": None}}}, {"args": {"x": ["a"]}}),
+    ({"positional": [1]}, {"positional": [1, 2]}),
+    ({}, {"args": ["x"]}),
+])
+def test_cache_keys_on_options(program_cache, first, second):
+    assert rusty_jq.compile("$ARGS", **first) is rusty_jq.compile("$ARGS", **first)
+    assert rusty_jq.compile("$ARGS", **first) is not rusty_jq.compile("$ARGS", **second)
+
+
+def test_cache_keys_documents_by_identity(program_cache):
+    doc = rusty_jq.parse('{"a": 1}')
+    program = rusty_jq.compile("$x", args={"x": doc})
+    assert rusty_jq.compile("$x", args={"x": doc}) is program
+    assert rusty_jq.compile("$x", args={"x": rusty_jq.parse('{"a": 1}')}) is not program
+    assert rusty_jq.compile("$x", args={"x": {"a": 1}}) is not program
+
+
+def test_cache_evicts_least_recently_used(program_cache):
+    rusty_jq.set_cache_size(2)
+    a = rusty_jq.compile(".a")
+    rusty_jq.compile(".b")
+    rusty_jq.compile(".a")
+    rusty_jq.compile(".c")
+    assert rusty_jq.compile(".a") is a
+    assert rusty_jq.cache_info() == {"hits": 2, "misses": 3, "maxsize": 2, "currsize": 2}
+    rusty_jq.set_cache_size(1)
+    assert rusty_jq.cache_info()["currsize"] == 1
+    # a size of 0 turns caching off
+    rusty_jq.set_cache_size(0)
+    assert rusty_jq.compile(".a") is not rusty_jq.compile(".a")
+    assert rusty_jq.cache_info()["currsize"] == 0
+
+
+def test_cache_does_not_keep_failures(program_cache):
+    for _ in range(2):
+        with pytest.raises(rusty_jq.RustyJqSyntaxError):
+            rusty_jq.compile(".a |")
+    assert rusty_jq.cache_info() == {"hits": 0, "misses": 2, "maxsize": 256, "currsize": 0}
+
+
+def test_run_and_first(program_cache):
+    data = '[{"id": 1, "name": "John"}, {"id": 2, "name": "Bob"}]'
+    assert rusty_jq.run(".[] | .name", data) == ["John", "Bob"]
+    assert rusty_jq.run(".[] | select(.id > 5)", data) == []
+    assert rusty_jq.first(".[] | .name", data) == "John"
+    assert rusty_jq.first(".[] | select(.id > 5)", data) is None
+    assert rusty_jq.cache_info()["currsize"] == 2
+    # variables are bound per call, so one program serves every value
+    assert rusty_jq.first(".[] | select(.id == $uid) | .name", data, uid=2) == "Bob"
+    assert rusty_jq.run(".[] | select(.id == $uid) | .name", data, uid=1) == ["John"]
+    assert rusty_jq.cache_info() == {"hits": 3, "misses": 3, "maxsize": 256, "currsize": 3}
+    with pytest.raises(rusty_jq.RustyJqRuntimeError):
+        rusty_jq.run(".[] | .name + 1", data)
+
+
+# ─── Resource limits ──────────────────────────────────────────────────────────
+
+DEEP = json.dumps({"a": {"b": {"c": [1]}}})
+WIDE = json.dumps([[i] * 50 for i in range(200)])
+LONG = json.dumps(list(range(20_000)))
+
+
+@pytest.mark.parametrize("query, data, limits, outputs", [
+    (".[]", "[1, 2, 3]", {"max_outputs": 3}, [1, 2, 3]),
+    (".[] | . + 1", "[1, 2, 3]", {"max_steps": 1000}, [2, 3, 4]),
+    ("..", DEEP, {"max_depth": 4}, [{"a": {"b": {"c": [1]}}}, {"b": {"c": [1]}}, {"c": [1]}, [1], 1]),
+    (".. | tojson | length", WIDE, {"max_memory_bytes": 10_000_000}, None),
+    (".[] | .[0]", WIDE, {"timeout_ms": 10_000}, list(range(200))),
+    # the state of reduce grows in place rather than being copied on every step
+    ("reduce .[] as $x ([]; . + [$x]) | length", LONG, {"timeout_ms": 2_000}, [20_000]),
+    ("[limit(2000; .[])] | reduce .[] as $x (null; [.]) | length", LONG, {"timeout_ms": 2_000}, [1]),
+])
+def test_within_limits(query, data, limits, outputs):
+    program = rusty_jq.compile(query, limits=rusty_jq.Limits(**limits))
+    results = list(program.input(data))
+    assert results == outputs or outputs is None and results
+
+
+@pytest.mark.parametrize("query, data, limits, name, message", [
+    (".[]", "[1, 2, 3]", {"max_outputs": 2}, "max_outputs", "query produced more than 2 outputs"),
+    (".[] | . + 1", "[1, 2, 3]", {"max_steps": 5}, "max_steps", "query ran for more than 5 steps"),
+    ("..", DEEP, {"max_depth": 3}, "max_depth", "query descended more than 3 levels deep"),
+    (".. | tojson", WIDE, {"max_memory_bytes": 100_000}, "max_memory_bytes", "query built more than 100000 bytes of values"),
+    ("{a: .}", WIDE, {"max_memory_bytes": 1000}, "max_memory_bytes", "query built more than 1000 bytes of values"),
+    ("INDEX(.[]; .[0])", WIDE, {"max_memory_bytes": 1000}, "max_memory_bytes", "query built more than 1000 bytes of values"),
+    (".. | .. | .. | tojson", json.dumps([[[list(range(60))] * 60] * 20]), {"timeout_ms": 20}, "timeout_ms", "query ran for more than 20 ms"),
+])
+def test_limit_exceeded(query, data, limits, name, message):
+    program = rusty_jq.compile(query, limits=rusty_jq.Limits(**limits))
+    with pytest.raises(rusty_jq.RustyJqLimitExceeded, match=message) as e:
+        list(program.input(data))
+    assert (e.value.limit, e.value.value) == (name, limits[name])
+    with pytest.raises(rusty_jq.RustyJqLimitExceeded, match=message):
+        program.input_many([data])
+
+
+# constant folding runs parts of a query inside compile(), on a small budget of its own that the
+# program's limits narrow further; what goes over it is left to the run, and to its limits
+def test_folding_is_limited():
+    digits = "(" + ", ".join(map(str, range(10))) + ")"
+    sums = lambda n: "[" + " + ".join([digits] * n) + "] | length"
+    limits = rusty_jq.Limits(max_steps=100, timeout_ms=10, max_memory_bytes=1000)
+    start = time.perf_counter()
+    program = rusty_jq.compile(sums(6), limits=limits)
+    rusty_jq.compile(sums(7))
+    assert time.perf_counter() - start < 1
+    with pytest.raises(rusty_jq.RustyJqLimitExceeded):
+        program.first("null")
+    assert rusty_jq.compile(sums(2)).first("null") == 100
+
+
+def test_limits_apply_to_each_document():
+    program = rusty_jq.compile(".[]", limits=rusty_jq.Limits(max_outputs=2))
+    assert list(program.input("[1, 2] [3, 4]")) == [1, 2, 3, 4]
+    results = program.input("[1] [2, 3, 4] [5]")
+    assert next(results) == 1 and next(results) == 2 and next(results) == 3
+    # the outputs before the limit are kept, and the documents after it are not run
+    with pytest.raises(rusty_jq.RustyJqLimitExceeded):
+        list(results)
+    assert program.first("[1, 2, 3]") == 1
+
+
+def test_limits_object():
+    limits = rusty_jq.Limits(max_steps=10, timeout_ms=5)
+    assert (limits.max_steps, limits.max_outputs, limits.timeout_ms) == (10, None, 5)
+    assert repr(limits) == "Limits(max_steps=10, timeout_ms=5)"
+    assert rusty_jq.compile(".", limits=limits).limits.max_steps == 10
+    assert rusty_jq.compile(".").limits is None
+    # limits are part of the compile cache key, and are kept through pickling
+    assert rusty_jq.compile(".a", limits=limits) is not rusty_jq.compile(".a")
+    loaded = pickle.loads(pickle.dumps(rusty_jq.compile(".[]", limits=rusty_jq.Limits(max_outputs=1))))
+    assert repr(loaded.limits) == "Limits(max_outputs=1)"
+    with pytest.raises(rusty_jq.RustyJqLimitExceeded):
+        list(loaded.input("[1, 2]"))
+
+
+
+# values built by a run nest at most twice as deep as a document may, so walking them cannot run out of stack
+@pytest.mark.parametrize("query", [
+    "reduce .[] as $x (null; [.])",
+    "reduce .[] as $x (null; {a: .})",
+    "reduce .[] as $x (null; .a = .)",
+    "reduce .[] as $x (null; [[.], 1] | .[0])",
+    "reduce .[] as $x (null; {a: .} | to_entries | from_entries)",
+])
+def test_values_built_too_deep(query):
+    with pytest.raises(rusty_jq.RustyJqRuntimeError, match="value nests more than 2048 levels deep"):
+        list(rusty_jq.compile(query).input(LONG))
+    assert rusty_jq.compile(f"try ({query}) catch .").first(LONG) == "value nests more than 2048 levels deep"
+
+
+def test_values_built_deep():
+    value = rusty_jq.compile("[limit(2047; .[])] | reduce .[] as $x (1; [.])").first(LONG)
+    depth = 0
+    while isinstance(value, list):
+        value, depth = value[0], depth + 1
+    assert (value, depth) == (1, 2047)
+    text = rusty_jq.compile("[limit(2047; .[])] | reduce .[] as $x (1; [.]) | tojson").first(LONG)
+    assert text == "[" * 2047 + "1" + "]" * 2047
+
+# ─── Parse limits ─────────────────────────────────────────────────────────────
+
+def nested(depth):
+    return "[" * depth + "]" * depth
+
+
+# documents nested deeper than any tree walk can take fail to parse rather than overflow the stack
+@pytest.mark.parametrize("run", [
+    lambda program, text: list(program.input(text)),
+    lambda program, text: list(program.input(text, stream=True)),
+    lambda program, text: list(program.input_reader(io.BytesIO(text.encode()))),
+    lambda program, text: program.input_many([text], threads=2),
+    lambda program, text: program.streamer().feed(text),
+    lambda program, text: rusty_jq.parse(text),
+])
+@pytest.mark.parametrize("text", [nested(100_000), '{"a":' * 100_000 + "1" + "}" * 100_000])
+def test_deep_documents_fail_to_parse(run, text):
+    with pytest.raises(ValueError, match="document nests more than 512 levels deep"):
+        run(rusty_jq.compile("."), text)
+
+
+def test_max_depth():
+    assert list(rusty_jq.compile("length").input(nested(512))) == [1]
+    program = rusty_jq.compile(".[0]", parse_limits=rusty_jq.ParseLimits(max_depth=2))
+    assert list(program.input("[[1]]")) == [[1]]
+    assert list(rusty_jq.compile(".", parse_limits=rusty_jq.ParseLimits(max_string_length=3)).input(r'"\u0041BC"')) == ["ABC"]
+    with pytest.raises(ValueError, match="line 2: document nests more than 2 levels deep"):
+        list(program.input("[[1]]\n[[[1]]]"))
+    with pytest.raises(ValueError, match="document nests more than 2 levels deep"):
+        list(program.input_obj([[[1]]]))
+    # `fromjson` is held to the defaults, and like jq's `try fromjson` outputs nothing on failure
+    assert list(rusty_jq.compile("fromjson").input(json.dumps(nested(600)))) == []
+    # events that would rebuild a value deeper than any document may be are skipped
+    events = json.dumps([[[0] * 2000, 1], [[0]]])
+    assert list(rusty_jq.compile("fromstream(.[])").input(events)) == [None]
+
+
+@pytest.mark.parametrize("limits, text, message", [
+    ({"max_bytes": 8}, "[1, 2, 3]", "document is larger than 8 bytes"),
+    ({"max_string_length": 3}, '["abcd"]', "document has a string longer than 3 bytes"),
+    ({"max_string_length": 3}, '{"abcd": 1}', "document has a string longer than 3 bytes"),
+    # strings are measured in UTF-8 bytes once decoded
+    ({"max_string_length": 3}, r'"\u00e9\u00e9"', "document has a string longer than 3 bytes"),
+    ({"duplicate_keys": "error"}, '{"a": 1, "b": {"c": 2, "c": 3}}', 'duplicate key "c" in an object'),
+])
+def test_parse_limit_exceeded(limits, text, message):
+    # the parts of the document the query does not read are checked too
+    program = rusty_jq.compile(".x", parse_limits=rusty_jq.ParseLimits(**limits))
+    with pytest.raises(ValueError, match=message):
+        list(program.input(text))
+    with pytest.raises(ValueError, match="input 0: " + message):
+        program.input_many([text])
+    with pytest.raises(ValueError, match=message):
+        rusty_jq.parse(text, parse_limits=rusty_jq.ParseLimits(**limits))
+
+
+def test_parse_limits_apply_to_each_document():
+    program = rusty_jq.compile(".", parse_limits=rusty_jq.ParseLimits(max_bytes=6))
+    assert list(program.input('[1, 2]\n"abcd"')) == [[1, 2], "abcd"]
+    results = program.input('[1, 2]\n[1, 2, 3]\n[3]')
+    assert next(results) == [1, 2]
+    with pytest.raises(ValueError, match="line 2: document is larger than 6 bytes"):
+        next(results)
+    assert list(results) == [[3]]
+
+
+def test_max_bytes_stops_reading():
+    class Endless(io.RawIOBase):
+        reads = 0
+        def readable(self):
+            return True
+        def readinto(self, buffer):
+            self.reads += 1
+            buffer[:1] = b"["
+            buffer[1:] = b"1," * ((len(buffer) - 1) // 2) + b" " * ((len(buffer) - 1) % 2)
+            return len(buffer)
+    reader = Endless()
+    program = rusty_jq.compile(".", parse_limits=rusty_jq.ParseLimits(max_bytes=100_000))
+    with pytest.raises(ValueError, match="line 1: document is larger than 100000 bytes"):
+        list(program.input_reader(reader))
+    assert reader.reads < 10
+
+
+@pytest.mark.parametrize("policy, query, expected", [
+    ("last", ".", [{"a": 3, "b": 2}]),
+    ("last", "keys, length, tojson", [["a", "b"], 2, '{"a":3,"b":2}']),
+    ("first", ".", [{"a": 1, "b": 2}]),
+    ("first", ".a", [1]),
+    ("last", ".a", [3]),
+])
+def test_duplicate_keys(policy, query, expected):
+    program = rusty_jq.compile(query, parse_limits=rusty_jq.ParseLimits(duplicate_keys=policy))
+    assert list(program.input('{"a": 1, "b": 2, "a": 3}')) == expected
+    assert program.input_many(['{"a": 1, "b": 2, "a": 3}']) == [expected]
+
+
+def test_parse_limits_object():
+    limits = rusty_jq.ParseLimits(max_bytes=100, duplicate_keys="error")
+    assert (limits.max_depth, limits.max_bytes, limits.max_string_length, limits.duplicate_keys) == (512, 100, None, "error")
+    assert repr(limits) == "ParseLimits(max_depth=512, max_bytes=100, duplicate_keys='error')"
+    assert repr(rusty_jq.compile(".").parse_limits) == "ParseLimits(max_depth=512, duplicate_keys='last')"
+    with pytest.raises(ValueError, match="max_depth can be at most 1024"):
+        rusty_jq.ParseLimits(max_depth=2000)
+    with pytest.raises(ValueError, match="duplicate_keys must be 'first', 'last' or 'error'"):
+        rusty_jq.ParseLimits(duplicate_keys="merge")
+    # parse limits are part of the compile cache key, and are kept through pickling
+    assert rusty_jq.compile(".b", parse_limits=limits) is not rusty_jq.compile(".b")
+    loaded = pickle.loads(pickle.dumps(rusty_jq.compile(".", parse_limits=limits)))
+    assert loaded.parse_limits.duplicate_keys == "error"
+    with pytest.raises(ValueError, match="duplicate key"):
+        list(loaded.input('{"a": 1, "a": 1}'))
+
+
+# ─── Grammar ──────────────────────────────────────────────────────────────────
+
+@pytest.fixture
+def grammar_doc():
+    return {"a": 1, "b": None, "c": [3, 1, 2], "d": {"e": 1}, "if": "k", "and": 5, "end": 6}
+
+
+@pytest.mark.parametrize("query, expected", [
+    # precedence, loosest to tightest: | , // assignment or and compare +- */% ?
+    ("1 + 2 * 3", [7]),
+    ("(1 + 2) * 3", [9]),
+    ("1, 2 | . * 10", [10, 20]),
+    (".a + 1 == 2 and .b == null", [True]),
+    # arrays and objects compare by value, and values of different types by jq's order of types
+    (".c == [3, 1, 2] and .d == {\"e\": 1.0}", [True]),
+    ('[[1] < [1, 0], {"a": 2} < {"b": 1}, {"a": 1} < {"a": 2}, "z" < [], [] < {}, null < false]', [[True] * 6]),
+    ("-.a + 3", [2]),
+    ("-(.c | length)", [-3]),
+    ("[.c[] | -.]", [[-3, -1, -2]]),
+    (".b // .a", [1]),
+    (".x // .b // 7", [7]),
+    ("empty // 1", [1]),
+    ("try (1 + \"a\") catch \"bad\" // 2", ["bad"]),
+    # if, try and ?
+    ('if .a == 1 then "one" elif .a == 2 then "two" else "many" end', ["one"]),
+    ("[.c[] | if . > 1 then . * 10 end]", [[30, 1, 20]]),
+    ("try (.a + \"x\") catch \"bad\"", ["bad"]),
+    ("try (1 + \"a\") catch .", ['number (1) and string ("a") cannot be added']),
+    ("[.[] | (. + 1)?]", [[2, 1, 6, 7]]),
+    (".a.x?", []),
+    # reduce, foreach and bindings
+    ("reduce .c[] as $x (0; . + $x)", [6]),
+    ("[foreach .c[] as $x (0; . + $x)]", [[3, 4, 6]]),
+    ("[foreach .c[] as $x (0; . + $x; [$x, .])]", [[[3, 3], [1, 4], [2, 6]]]),
+    ("[foreach .c[] as $x (0; if $x == 1 then empty else . + $x end)]", [[3, 2]]),
+    (".a as $v | [.c[] | . + $v]", [[4, 2, 3]]),
+    ("reduce .c[] as $x (0; . + $x) | . * 2", [12]),
+    # assignments
+    (".a = 2 | .a", [2]),
+    (".c[0] = 9 | .c", [[9, 1, 2]]),
+    (".d.e |= . + 1 | .d", [{"e": 2}]),
+    (".c[] += 10 | .c", [[13, 11, 12]]),
+    (".b //= 4 | .b", [4]),
+    (".x.y = 1 | .x", [{"y": 1}]),
+    (".c |= [.[] | select(. > 1)] | .c", [[3, 2]]),
+    (".c[] |= empty | .c", [[]]),
+    # comments, keywords as field names, arrays and objects
+    (".a # the a field\n| . + 1", [2]),
+    (".if, .and, .end", ["k", 5, 6]),
+    ("{if: 1, and: 2}", [{"if": 1, "and": 2}]),
+    ("[.a, .c[0]], [], {}", [[1, 3], [], {}]),
+    (".c[1]", [1]),
+    # names are `[A-Za-z_][A-Za-z0-9_]*`, so a `-` after one is the operator
+    (".a-1, .and-.a", [0, 4]),
+    ('."a-1"', []),
+    # numbers with exponents, and integers too large for 64 bits
+    ("1e2, 2.5E-3, -1e-1, 1E+1 + 1", [100.0, 0.0025, -0.1, 11.0]),
+    ("100000000000000000000 | . > 1e19", [True]),
+    (".a and (.b | not)", [True]),
+])
+def test_grammar(grammar_doc, query, expected):
+    assert list(rusty_jq.compile(query).input(json.dumps(grammar_doc))) == expected
+
+
+@pytest.mark.parametrize("query", [
+    ".a == 1 == 2", ".a < .b > .c", ".a = 1 = 2", ".a |= 1 += 2",
+])
+def test_chained_comparisons_need_parentheses(query):
+    with pytest.raises(rusty_jq.RustyJqSyntaxError, match="cannot be chained without parentheses"):
+        rusty_jq.compile(query)
+
+
+def test_grammar_ast():
+    assert rusty_jq.compile("-.a // 1").ast() == [{
+        "type": "alternative",
+        "left": [{"type": "negate", "operand": [{"type": "field", "name": "a"}]}],
+        "right": [{"type": "literal", "value": 1}],
+    }]
+    assert rusty_jq.compile(".a |= . + 1").ast() == [{
+        "type": "assign", "op": "|=",
+        "left": [{"type": "field", "name": "a"}],
+        "right": [{"type": "arithmetic", "op": "+", "left": [{"type": "identity"}], "right": [{"type": "literal", "value": 1}]}],
+    }]
+    assert rusty_jq.compile("reduce .[] as $x (0; $x)").ast() == [{
+        "type": "reduce", "source": [{"type": "iterator"}], "name": "x",
+        "init": [{"type": "literal", "value": 0}], "update": [{"type": "variable", "name": "x"}],
+    }]
+    assert rusty_jq.compile("if . then 1 end").ast() == [{
+        "type": "if", "branches": [{"condition": [{"type": "identity"}], "then": [{"type": "literal", "value": 1}]}],
+        "else": None,
+    }]
+    assert rusty_jq.compile(".a?").ast() == [{"type": "try", "body": [{"type": "field", "name": "a"}], "catch": None}]
@@ -0,0 +1,169 @@
+use rusty_jq::simd_json::json;
+use rusty_jq::{compile, parse, parse_with_limits, DuplicateKeys, Error, LimitExceeded, Limits, ParseLimits, PathStep, Value};
+
+fn outputs(query: &str, input: &Value) -> Vec<Value> {
+    compile(query).unwrap().run(input).collect::<Result<_, _>>().unwrap()
+}
+
+#[test]
+fn runs_a_pipeline() {
+    let input = parse(r#"{"users": [{"id": 1, "name": "John"}, {"id": 2, "name": "Bob"}]}"#).unwrap();
+    assert_eq!(outputs(".users | .[] | select(.id == 2) | .name", &input), vec![json!("Bob")]);
+    assert_eq!(outputs(".users | .[] | {id: .id}", &input), vec![json!({"id": 1}), json!({"id": 2})]);
+    assert_eq!(outputs(".users | length", &input), vec![json!(2)]);
+    assert_eq!(outputs(".missing", &input), Vec::<Value>::new());
+}
+
+#[test]
+fn builds_new_values() {
+    let input = json!({"a": [3, 1, 2], "s": "a,b"});
+    assert_eq!(outputs(".a | sort", &input), vec![json!([1, 2, 3])]);
+    assert_eq!(outputs(".s | split(\",\")", &input), vec![json!(["a", "b"])]);
+    assert_eq!(outputs(".a | .[0] + 10, tojson", &input), vec![json!(13), json!("[3,1,2]")]);
+    assert_eq!(outputs("$ARGS", &input), vec![json!({"positional": [], "named": {}})]);
+}
+
+#[test]
+fn reads_only_what_the_query_needs() {
+    // runs borrow the parts of the input the query can reach, and see the rest as missing
+    let input = json!({"a": {"b": [1, 2]}, "c": "x", "d": [{"e": 1}, {"e": 2, "f": 3}]});
+    assert_eq!(outputs(".a.b[1], .d[].e", &input), vec![json!(2), json!(1), json!(2)]);
+    assert_eq!(outputs(".d | length, (.[1] | keys)", &input), vec![json!(2), json!(["e", "f"])]);
+    assert_eq!(outputs("has(\"c\"), (.a | type), .missing", &input), vec![json!(true), json!("object")]);
+    assert_eq!(outputs("([.. | select(type == \"number\")] | length), .d[1]", &input), vec![json!(5), json!({"e": 2, "f": 3})]);
+    assert_eq!(outputs(".", &input), vec![input.clone()]);
+}
+
+#[test]
+fn pulls_outputs_lazily() {
+    let input = json!([1, 2, 3]);
+    let program = compile(".[]").unwrap();
+    let mut run = program.run(&input);
+    assert_eq!(run.next(), Some(Ok(json!(1))));
+    drop(run);
+    assert_eq!(program.run(&input).count(), 3);
+}
+
+#[test]
+fn runs_on_borrowed_values() {
+    let mut text = br#"{"items": [{"price": 1}, {"price": "x"}]}"#.to_vec();
+    let input = rusty_jq::simd_json::to_borrowed_value(&mut text).unwrap();
+    let program = compile(".items | .[] | .price * 2").unwrap();
+    // the same input runs any number of times, and errors still locate the value they blame
+    for _ in 0..2 {
+        let results: Vec<_> = program.run_borrowed(&input).collect();
+        let [Ok(first), Err(Error::Runtime(e))] = results.as_slice() else { panic!("expected an output, then a runtime error") };
+        assert_eq!(first, &json!(2));
+        assert_eq!(e.path, Some(vec![PathStep::Field("items".to_string()), PathStep::Index(1), PathStep::Field("price".to_string())]));
+    }
+}
+
+#[test]
+fn reports_compile_errors() {
+    match compile(".a |\n  .b | lenght") {
+        Err(Error::Syntax(e)) => {
+            assert_eq!((e.line, e.column, e.offset), (2, 8, 12));
+            assert_eq!(e.expected, vec!["a filter"]);
+            assert_eq!(e.hint.as_deref(), Some("unknown function `lenght`, did you mean `length`?"));
+        }
+        _ => panic!("expected a syntax error"),
+    }
+    assert!(matches!(compile("$nope"), Err(Error::Compile(message)) if message == "$nope is not defined"));
+    assert!(matches!(parse("{\"a\": "), Err(Error::Parse(_))));
+}
+
+#[test]
+fn halts() {
+    let input = json!(null);
+    assert_eq!(outputs("1, halt, 2", &input), vec![json!(1)]);
+    let results: Vec<_> = compile("., halt_error(3), 2").unwrap().run(&json!("stop\n")).collect();
+    assert_eq!(results, vec![Ok(json!("stop\n")), Err(Error::Halt { code: 3, message: "stop\n".to_string() })]);
+    let results: Vec<_> = compile("{a: 1} | halt_error").unwrap().run(&input).collect();
+    assert_eq!(results, vec![Err(Error::Halt { code: 5, message: "{\"a\":1}\n".to_string() })]);
+}
+
+#[test]
+fn reports_runtime_errors() {
+    let input = json!({"items": [{"price": 1}, {"price": "x"}]});
+    let results: Vec<_> = compile(".items | .[] | .price * 2").unwrap().run(&input).collect();
+    let [Ok(first), Err(Error::Runtime(e))] = results.as_slice() else { panic!("expected an output, then a runtime error") };
+    assert_eq!(first, &json!(2));
+    assert_eq!(e.message, "string (\"x\") and number (2) cannot be multiplied");
+    assert_eq!((e.span.clone(), e.filter.as_str(), e.value.as_str()), (15..25, ".price * 2", "\"x\""));
+    assert_eq!(e.path, Some(vec![PathStep::Field("items".to_string()), PathStep::Index(1), PathStep::Field("price".to_string())]));
+    assert_eq!(e.to_string(), "string (\"x\") and number (2) cannot be multiplied (in `.price * 2`, at .items[1].price)");
+}
+
+#[test]
+fn enforces_limits() {
+    let limited = |query: &str, limits: Limits, input: &Value| -> Vec<Result<Value, Error>> {
+        compile(query).unwrap().with_limits(limits).run(input).collect()
+    };
+    let input = json!({"a": {"b": [1, 2, 3]}});
+    let results = limited("..", Limits { max_outputs: Some(2), ..Limits::default() }, &input);
+    assert_eq!(results, vec![Ok(input.clone()), Ok(json!({"b": [1, 2, 3]})), Err(Error::Limit(LimitExceeded::Outputs(2)))]);
+    let results = limited("..", Limits { max_depth: Some(2), ..Limits::default() }, &input);
+    assert_eq!(results, vec![Err(Error::Limit(LimitExceeded::Depth(2)))]);
+    assert_eq!(limited("..", Limits { max_depth: Some(3), ..Limits::default() }, &input).len(), 6);
+    let results = limited(".a.b | .[] | . * 2", Limits { max_steps: Some(8), ..Limits::default() }, &input);
+    assert_eq!(results.last(), Some(&Err(Error::Limit(LimitExceeded::Steps(8)))));
+    let results = limited("{x: .a}, tojson", Limits { max_memory_bytes: Some(64), ..Limits::default() }, &input);
+    assert_eq!(results, vec![Err(Error::Limit(LimitExceeded::Memory(64)))]);
+    assert_eq!(Error::Limit(LimitExceeded::Steps(8)).to_string(), "query ran for more than 8 steps");
+}
+
+#[test]
+fn enforces_parse_limits() {
+    let deep = format!("{}{}", "[".repeat(100_000), "]".repeat(100_000));
+    assert_eq!(parse(&deep), Err(Error::Parse("document nests more than 512 levels deep".to_string())));
+    let limits = ParseLimits { max_depth: 100_000, ..ParseLimits::default() };
+    assert_eq!(parse_with_limits(&deep, &limits), Err(Error::Parse("document nests more than 1024 levels deep".to_string())));
+    let limits = ParseLimits { max_bytes: Some(8), max_string_length: Some(3), ..ParseLimits::default() };
+    assert_eq!(parse_with_limits(r#"["abc"]"#, &limits), Ok(json!(["abc"])));
+    assert_eq!(parse_with_limits(r#"["abcd"]"#, &limits), Err(Error::Parse("document has a string longer than 3 bytes".to_string())));
+    assert_eq!(parse_with_limits(r#"[1, 2, 3]"#, &limits), Err(Error::Parse("document is larger than 8 bytes".to_string())));
+    let text = r#"{"a": 1, "b": 2, "a": 3}"#;
+    assert_eq!(parse(text), Ok(json!({"a": 3, "b": 2})));
+    let keeping = |duplicate_keys| parse_with_limits(text, &ParseLimits { duplicate_keys, ..ParseLimits::default() });
+    assert_eq!(keeping(DuplicateKeys::First), Ok(json!({"a": 1, "b": 2})));
+    assert_eq!(keeping(DuplicateKeys::Error), Err(Error::Parse(r#"duplicate key "a" in an object"#.to_string())));
+    // `fromjson` holds the text it parses to the defaults, and outputs nothing for what goes over them
+    let input = Value::from(deep);
+    assert_eq!(outputs("fromjson", &input), Vec::<Value>::new());
+}
+
+#[test]
+fn follows_jq_precedence() {
+    let input = json!({"a": 1, "items": [3, 1, 2]});
+    assert_eq!(outputs("1 + 2 * 3, -.a // 5", &input), vec![json!(7), json!(-1)]);
+    assert_eq!(outputs(".a-1, .a -1, 1e2, 2.5E-3", &input), vec![json!(0), json!(0), json!(100.0), json!(0.0025)]);
+    assert_eq!(outputs("reduce .items[] as $x (0; . + $x) | . * 2", &input), vec![json!(12)]);
+    assert_eq!(outputs(".items[0] = 9 | .a |= . + 1", &input), vec![json!({"a": 2, "items": [9, 1, 2]})]);
+    assert_eq!(outputs("try (.a + \"x\") catch \"bad\", if .a > 1 then 1 else 2 end # trailing comment", &input), vec![json!("bad"), json!(2)]);
+    assert!(matches!(compile(".a == 1 == 2"), Err(Error::Syntax(e)) if e.message.contains("cannot be chained")));
+}
+
+#[test]
+fn prints_queries_back() {
+    let program = compile("INDEX(.id)|halt_error(5)").unwrap();
+    assert_eq!(program.to_jq(), "INDEX(.[]; .id) | halt_error");
+    let program = compile(".a.b|.[]|select(.n>1 and (.x|not))|{id:.id},.n*2").unwrap();
+    assert_eq!(program.to_jq(), ".a | .b | .[] | select(.n > 1 and (.x | not)) | {id: .id}, .n * 2");
+    let printed = compile(&program.to_jq()).unwrap();
+    assert_eq!(printed.to_jq(), program.to_jq());
+    let input = json!({"a": {"b": [{"id": "k", "n": 2}, {"id": "m", "n": 3, "x": true}]}});
+    assert_eq!(outputs(&printed.to_jq(), &input), vec![json!({"id": "k"}), json!(4)]);
+}
+
+#[test]
+fn shares_a_program_between_threads() {
+    let program = compile(".n * 2").unwrap();
+    let doubled: Vec<Value> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..4).map(|n| {
+            let program = &program;
+            scope.spawn(move || program.run(&json!({"n": n})).next().unwrap().unwrap())
+        }).collect();
+        workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+    });
+    assert_eq!(doubled, vec![json!(0), json!(2), json!(4), json!(6)]);
+}